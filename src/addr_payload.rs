@@ -0,0 +1,362 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use binrw::binrw;
+
+use crate::{
+    command::Command,
+    message_preparable::MessagePreparable,
+    onion::OnionAddress,
+    services::ServiceFlags,
+    utils::base32_encode,
+    varint::{CompactSize, VarString, VarStringArgs},
+    version_payload::NetworkAddress,
+};
+
+// Bitcoin Core's MAX_ADDR_TO_SEND; peers sending more in one message are misbehaving
+pub const MAX_ADDR_TO_SEND: u64 = 1000;
+
+// BIP 155's ceiling on an address of any network
+pub const MAX_ADDRV2_SIZE: usize = 512;
+
+fn from_unix_time(time: u32) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(u64::from(time))
+}
+
+fn to_unix_time(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |duration| {
+        duration.as_secs().min(u64::from(u32::MAX)) as u32
+    })
+}
+
+// Refuses oversized lists before anything is allocated for them
+fn check_count(count: CompactSize) -> Result<CompactSize, String> {
+    if count.0 > MAX_ADDR_TO_SEND {
+        return Err(format!(
+            "{} addresses exceed the maximum of {MAX_ADDR_TO_SEND}",
+            count.0
+        ));
+    }
+    Ok(count)
+}
+
+#[derive(Debug)]
+#[binrw]
+#[brw(little)]
+pub struct TimestampedAddress {
+    time: u32,
+    address: NetworkAddress,
+}
+
+impl TimestampedAddress {
+    pub fn new(last_seen: SystemTime, address: NetworkAddress) -> Self {
+        Self {
+            time: to_unix_time(last_seen),
+            address,
+        }
+    }
+
+    pub fn last_seen(&self) -> SystemTime {
+        from_unix_time(self.time)
+    }
+
+    pub fn address(&self) -> &NetworkAddress {
+        &self.address
+    }
+}
+
+// `binrw` has to come first here, or the derive sees the temporary count field
+#[binrw]
+#[derive(Debug)]
+#[brw(little)]
+pub struct AddrPayload {
+    #[br(temp, try_map = check_count)]
+    #[bw(calc = CompactSize(addresses.len() as u64))]
+    count: CompactSize,
+    #[br(count = count.0)]
+    addresses: Vec<TimestampedAddress>,
+}
+
+impl AddrPayload {
+    pub fn new(addresses: Vec<TimestampedAddress>) -> Self {
+        Self { addresses }
+    }
+
+    pub fn addresses(&self) -> &[TimestampedAddress] {
+        &self.addresses
+    }
+}
+
+impl MessagePreparable for AddrPayload {
+    const COMMAND_TYPE: Command = Command::Addr;
+
+    fn size_hint(&self) -> usize {
+        CompactSize(self.addresses.len() as u64).encoded_len()
+            + self.addresses.len() * (4 + NetworkAddress::BYTE_SIZE)
+    }
+}
+
+// Where an addrv2 entry points, by its BIP 155 network ID
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AddrV2Host {
+    Ip(IpAddr),
+    TorV3(OnionAddress),
+    // The SHA-256 of the I2P destination
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    // Networks we don't know, including the retired Tor v2, and known ones of the wrong length
+    Unknown { network_id: u8, address: Vec<u8> },
+}
+
+impl AddrV2Host {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::Ip(ip) => Some(*ip),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AddrV2Host {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(ip) => ip.fmt(f),
+            Self::TorV3(onion_address) => onion_address.fmt(f),
+            Self::I2p(hash) => write!(f, "{}.b32.i2p", base32_encode(hash)),
+            Self::Cjdns(ip) => ip.fmt(f),
+            Self::Unknown {
+                network_id,
+                address,
+            } => write!(f, "network {network_id} address {}", hex::encode(address)),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[binrw]
+#[brw(little)]
+pub struct AddrV2Entry {
+    time: u32,
+    #[br(map = |services: CompactSize| ServiceFlags::from_bits(services.0))]
+    #[bw(map = |services: &ServiceFlags| CompactSize(services.bits()))]
+    services: ServiceFlags,
+    network_id: u8,
+    #[br(args_raw = VarStringArgs { max_length: MAX_ADDRV2_SIZE })]
+    address: VarString,
+    #[brw(big)]
+    port: u16,
+}
+
+impl AddrV2Entry {
+    const IPV4: u8 = 1;
+    const IPV6: u8 = 2;
+    const TORV3: u8 = 4;
+    const I2P: u8 = 5;
+    const CJDNS: u8 = 6;
+
+    pub fn new(
+        last_seen: SystemTime,
+        host: &AddrV2Host,
+        port: u16,
+        services: ServiceFlags,
+    ) -> Self {
+        let (network_id, address) = match host {
+            AddrV2Host::Ip(IpAddr::V4(ip)) => (Self::IPV4, ip.octets().to_vec()),
+            AddrV2Host::Ip(IpAddr::V6(ip)) => (Self::IPV6, ip.octets().to_vec()),
+            AddrV2Host::TorV3(onion_address) => (Self::TORV3, onion_address.public_key().to_vec()),
+            AddrV2Host::I2p(hash) => (Self::I2P, hash.to_vec()),
+            AddrV2Host::Cjdns(ip) => (Self::CJDNS, ip.octets().to_vec()),
+            AddrV2Host::Unknown {
+                network_id,
+                address,
+            } => (*network_id, address.clone()),
+        };
+        Self {
+            time: to_unix_time(last_seen),
+            services,
+            network_id,
+            address: address.into(),
+            port,
+        }
+    }
+
+    pub fn last_seen(&self) -> SystemTime {
+        from_unix_time(self.time)
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn host(&self) -> AddrV2Host {
+        let address = self.address.as_bytes();
+        match (self.network_id, address.len()) {
+            (Self::IPV4, 4) => AddrV2Host::Ip(IpAddr::V4(Ipv4Addr::from(
+                <[u8; 4]>::try_from(address).expect("four bytes"),
+            ))),
+            (Self::IPV6, 16) => AddrV2Host::Ip(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(address).expect("sixteen bytes"),
+            ))),
+            (Self::TORV3, 32) => AddrV2Host::TorV3(OnionAddress::from_public_key(
+                address.try_into().expect("thirty-two bytes"),
+            )),
+            (Self::I2P, 32) => AddrV2Host::I2p(address.try_into().expect("thirty-two bytes")),
+            (Self::CJDNS, 16) => AddrV2Host::Cjdns(Ipv6Addr::from(
+                <[u8; 16]>::try_from(address).expect("sixteen bytes"),
+            )),
+            (network_id, _) => AddrV2Host::Unknown {
+                network_id,
+                address: address.to_vec(),
+            },
+        }
+    }
+}
+
+// BIP 155's addrv2, which peers only send after we asked for it with sendaddrv2
+#[binrw]
+#[derive(Debug)]
+#[brw(little)]
+pub struct AddrV2Payload {
+    #[br(temp, try_map = check_count)]
+    #[bw(calc = CompactSize(entries.len() as u64))]
+    count: CompactSize,
+    #[br(count = count.0)]
+    entries: Vec<AddrV2Entry>,
+}
+
+impl AddrV2Payload {
+    pub fn new(entries: Vec<AddrV2Entry>) -> Self {
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[AddrV2Entry] {
+        &self.entries
+    }
+}
+
+impl MessagePreparable for AddrV2Payload {
+    const COMMAND_TYPE: Command = Command::AddrV2;
+}
+
+#[derive(Debug, Clone, Copy)]
+#[binrw]
+#[brw(little)]
+pub struct GetAddrPayload;
+
+impl MessagePreparable for GetAddrPayload {
+    const COMMAND_TYPE: Command = Command::GetAddr;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::{BinRead, BinWrite};
+
+    use super::*;
+
+    #[test]
+    fn test_addr_round_trip() {
+        let last_seen = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let payload = AddrPayload::new(vec![TimestampedAddress::new(
+            last_seen,
+            NetworkAddress::new(
+                "203.0.113.7:8333".parse().unwrap(),
+                ServiceFlags::NODE_NETWORK,
+            ),
+        )]);
+
+        let mut bytes = Cursor::new(Vec::new());
+        payload.write(&mut bytes).unwrap();
+        let bytes = bytes.into_inner();
+        assert_eq!(bytes.len(), payload.size_hint());
+        assert_eq!(bytes[0], 1);
+        assert_eq!(&bytes[1..5], &1_700_000_000u32.to_le_bytes());
+
+        let parsed = AddrPayload::read(&mut Cursor::new(&bytes)).unwrap();
+        let [entry] = parsed.addresses() else {
+            panic!("expected one address");
+        };
+        assert_eq!(entry.last_seen(), last_seen);
+        assert_eq!(
+            entry.address().socket_address(),
+            "203.0.113.7:8333".parse().unwrap()
+        );
+        assert_eq!(entry.address().services(), ServiceFlags::NODE_NETWORK);
+    }
+
+    #[test]
+    fn test_addr_count_limit() {
+        let mut bytes = vec![0xFD];
+        bytes.extend(1001u16.to_le_bytes());
+        assert!(AddrPayload::read(&mut Cursor::new(&bytes)).is_err());
+        assert!(AddrV2Payload::read(&mut Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn test_addrv2_hosts() {
+        let onion: OnionAddress = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+            .parse()
+            .unwrap();
+        let hosts = [
+            AddrV2Host::Ip("198.51.100.1".parse().unwrap()),
+            AddrV2Host::Ip("2001:db8::1".parse().unwrap()),
+            AddrV2Host::TorV3(onion),
+            AddrV2Host::I2p([0; 32]),
+            AddrV2Host::Cjdns("fc00::1".parse().unwrap()),
+            // Tor v2, long since retired
+            AddrV2Host::Unknown {
+                network_id: 3,
+                address: vec![1; 10],
+            },
+        ];
+        let last_seen = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let payload = AddrV2Payload::new(
+            hosts
+                .iter()
+                .map(|host| AddrV2Entry::new(last_seen, host, 8333, ServiceFlags::NODE_WITNESS))
+                .collect(),
+        );
+
+        let mut bytes = Cursor::new(Vec::new());
+        payload.write(&mut bytes).unwrap();
+        let parsed = AddrV2Payload::read(&mut Cursor::new(bytes.into_inner())).unwrap();
+        let parsed_hosts: Vec<_> = parsed.entries().iter().map(AddrV2Entry::host).collect();
+        assert_eq!(parsed_hosts, hosts);
+        assert!(parsed.entries().iter().all(|entry| entry.port() == 8333
+            && entry.services() == ServiceFlags::NODE_WITNESS
+            && entry.last_seen() == last_seen));
+
+        assert_eq!(
+            hosts[2].to_string(),
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+        );
+        assert_eq!(
+            hosts[3].to_string(),
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa.b32.i2p"
+        );
+        assert_eq!(hosts[4].ip(), None);
+    }
+
+    #[test]
+    fn test_addrv2_wrong_length_is_unknown() {
+        // An "IPv4" address of five bytes
+        let mut bytes = vec![1];
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend([0, 1, 5, 1, 2, 3, 4, 5, 0x20, 0x8D]);
+        let parsed = AddrV2Payload::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(
+            parsed.entries()[0].host(),
+            AddrV2Host::Unknown {
+                network_id: 1,
+                address: vec![1, 2, 3, 4, 5],
+            },
+        );
+    }
+}
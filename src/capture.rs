@@ -0,0 +1,208 @@
+use crate::{
+    command::command_name,
+    header::Header,
+    message::{
+        parse_message_with_options, skip_to_magic, MessageParseError, MessageType, ParseOptions,
+    },
+    network::Network,
+    protocol::MAX_SIZE,
+    utils::double_sha256_hash,
+};
+
+#[derive(Debug)]
+pub struct CapturedFrame {
+    // Where the frame's header starts in the capture
+    pub offset: usize,
+    pub header: Header,
+    pub checksum_valid: bool,
+    // Left undecoded when the checksum doesn't match
+    pub message: Result<MessageType, MessageParseError>,
+}
+
+#[derive(Debug)]
+pub enum CaptureEntry {
+    Frame(CapturedFrame),
+    // Bytes at `offset` didn't start a frame, so `skipped` of them were passed over to the next
+    // network magic
+    LostSync {
+        offset: usize,
+        skipped: usize,
+        reason: MessageParseError,
+    },
+    // The capture ends partway through the frame at `offset`
+    Truncated {
+        offset: usize,
+        available: usize,
+    },
+}
+
+// Splits concatenated frames, as captured off the wire, into their messages. A frame whose
+// checksum doesn't match is still stepped over by its declared length, while bytes that can't
+// be a header are skipped up to the next network magic.
+pub fn decode_capture(network: Network, data: &[u8]) -> Vec<CaptureEntry> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let remaining = &data[offset..];
+        let header = match Header::peek(remaining) {
+            Ok(peeked) => peeked.into_header(),
+            Err(MessageParseError::NotEnoughData) => {
+                entries.push(CaptureEntry::Truncated {
+                    offset,
+                    available: remaining.len(),
+                });
+                break;
+            }
+            Err(e) => {
+                offset += lose_sync(&mut entries, network, offset, remaining, e);
+                continue;
+            }
+        };
+        if header.magic() != network.magic() {
+            let reason = MessageParseError::MissingMagicNumber;
+            offset += lose_sync(&mut entries, network, offset, remaining, reason);
+            continue;
+        }
+        // No real peer sends a frame this large, so the length itself is garbage
+        if header.payload_size() > MAX_SIZE {
+            let reason = MessageParseError::OversizedPayload {
+                command: header.command_raw(),
+                length: header.payload_size(),
+            };
+            offset += lose_sync(&mut entries, network, offset, remaining, reason);
+            continue;
+        }
+
+        let frame_len = Header::HEADER_BYTE_SIZE + header.payload_size() as usize;
+        let Some(frame) = remaining.get(..frame_len) else {
+            entries.push(CaptureEntry::Truncated {
+                offset,
+                available: remaining.len(),
+            });
+            break;
+        };
+
+        let checksum =
+            header.validate_digest(&double_sha256_hash(&frame[Header::HEADER_BYTE_SIZE..]));
+        let checksum_valid = checksum.is_ok();
+        let message = match checksum {
+            Ok(()) => {
+                let options = ParseOptions {
+                    checksum_verified: true,
+                    ..ParseOptions::default()
+                };
+                parse_message_with_options(network, &options, frame)
+                    .map(|(parsed, _)| parsed.into_message())
+            }
+            Err(e) => Err(e.into()),
+        };
+        entries.push(CaptureEntry::Frame(CapturedFrame {
+            offset,
+            header,
+            checksum_valid,
+            message,
+        }));
+        offset += frame_len;
+    }
+    entries
+}
+
+// Records the lost sync and returns how many bytes to skip, always at least one
+fn lose_sync(
+    entries: &mut Vec<CaptureEntry>,
+    network: Network,
+    offset: usize,
+    remaining: &[u8],
+    reason: MessageParseError,
+) -> usize {
+    let skipped = 1 + skip_to_magic(network, &remaining[1..]);
+    entries.push(CaptureEntry::LostSync {
+        offset,
+        skipped,
+        reason,
+    });
+    skipped
+}
+
+// A one-line description of what a known message carries, for dumps and logs
+pub fn summarize(message: &MessageType) -> Option<String> {
+    match message {
+        MessageType::Version(version_payload) => Some(format!(
+            "version {}, services {}, user agent {:?}, start height {}, nonce {:#018x}",
+            version_payload.version(),
+            version_payload.services(),
+            String::from_utf8_lossy(version_payload.user_agent_bytes()),
+            version_payload.start_height(),
+            version_payload.nonce(),
+        )),
+        MessageType::Ping(ping_payload) => Some(format!("nonce {:#018x}", ping_payload.nonce())),
+        MessageType::Pong(pong_payload) => Some(format!("nonce {:#018x}", pong_payload.nonce())),
+        MessageType::Addr(addr_payload) => {
+            Some(format!("{} address(es)", addr_payload.addresses().len()))
+        }
+        MessageType::AddrV2(addr_payload) => {
+            Some(format!("{} address(es)", addr_payload.entries().len()))
+        }
+        MessageType::Alert(payload) => Some(format!("deprecated alert, {} byte(s)", payload.len())),
+        MessageType::Unknown { command, .. } => {
+            Some(format!("unknown command {:?}", command_name(command)))
+        }
+        MessageType::GetAddr
+        | MessageType::Verack
+        | MessageType::WtxidRelay
+        | MessageType::Custom { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        message::prepare_message, ping_payload::PingPayload, verack_payload::VerackPayload,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_decode_capture_truncated() {
+        let mut data = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        data.extend(&prepare_message(Network::Mainnet, PingPayload::new(1)).unwrap()[..30]);
+
+        let entries = decode_capture(Network::Mainnet, &data);
+        assert!(matches!(
+            entries.as_slice(),
+            [
+                CaptureEntry::Frame(CapturedFrame {
+                    offset: 0,
+                    checksum_valid: true,
+                    message: Ok(MessageType::Verack),
+                    ..
+                }),
+                CaptureEntry::Truncated {
+                    offset: 24,
+                    available: 30,
+                },
+            ],
+        ));
+    }
+
+    #[test]
+    fn test_decode_capture_wrong_network() {
+        let data = prepare_message(Network::Testnet3, VerackPayload).unwrap();
+
+        let entries = decode_capture(Network::Mainnet, &data);
+        assert!(matches!(
+            entries.as_slice(),
+            [
+                CaptureEntry::LostSync {
+                    offset: 0,
+                    reason: MessageParseError::MissingMagicNumber,
+                    ..
+                },
+                ..
+            ],
+        ));
+        assert!(entries
+            .iter()
+            .all(|entry| !matches!(entry, CaptureEntry::Frame(_))));
+    }
+}
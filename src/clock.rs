@@ -0,0 +1,16 @@
+use std::time::SystemTime;
+
+// Where wall-clock time comes from, for the timestamps we send and compare against, so tests can
+// pin it. See `testing::MockClock`
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -0,0 +1,207 @@
+// Lets any byte stream be wrapped in `Framed` without going through `MessagingSystem`
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    header::Header,
+    message::{
+        parse_message_with_options, prepare_message, MessageParseError, MessageType, ParseOptions,
+        PrepareMessageError,
+    },
+    network::Network,
+    protocol::MAX_MESSAGE_SIZE,
+    verack_payload::VerackPayload,
+    version_payload::VersionPayload,
+    wtxidrelay_payload::WtxidRelayPayload,
+};
+
+#[derive(Debug)]
+pub enum OutgoingMessage {
+    Verack,
+    Version(VersionPayload),
+    WtxidRelay,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BitcoinCodec {
+    network: Network,
+    pub max_message_size: u32,
+}
+
+impl BitcoinCodec {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Decoder for BitcoinCodec {
+    type Item = MessageType;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let options = ParseOptions {
+            max_message_size: self.max_message_size,
+            ..Default::default()
+        };
+        match parse_message_with_options(self.network, &options, src) {
+            Ok((parsed, bytes_read)) => {
+                src.advance(bytes_read);
+                Ok(Some(parsed.into_message()))
+            }
+            Err(MessageParseError::NotEnoughData) => {
+                // The header's size was already checked, so its frame length is safe to reserve
+                let frame_len = Header::peek(src)
+                    .map_or(Header::HEADER_BYTE_SIZE, |peeked| peeked.total_frame_len());
+                src.reserve(frame_len - src.len());
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Encoder<OutgoingMessage> for BitcoinCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: OutgoingMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let message_packet = match item {
+            OutgoingMessage::Verack => prepare_message(self.network, VerackPayload)?,
+            OutgoingMessage::Version(payload) => prepare_message(self.network, payload)?,
+            OutgoingMessage::WtxidRelay => prepare_message(self.network, WtxidRelayPayload)?,
+        };
+        dst.extend_from_slice(&message_packet);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Parse(MessageParseError),
+    Prepare(PrepareMessageError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse(e) => e.fmt(f),
+            Self::Prepare(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl From<MessageParseError> for CodecError {
+    fn from(value: MessageParseError) -> Self {
+        Self::Parse(value)
+    }
+}
+
+impl From<PrepareMessageError> for CodecError {
+    fn from(value: PrepareMessageError) -> Self {
+        Self::Prepare(value)
+    }
+}
+
+impl From<std::io::Error> for CodecError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_partial_frames() {
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        let mut codec = BitcoinCodec::new(Network::Mainnet);
+        let mut src = BytesMut::new();
+
+        // Every prefix short of the full frame waits for more
+        for &byte in &verack[..verack.len() - 1] {
+            src.extend_from_slice(&[byte]);
+            assert!(codec.decode(&mut src).unwrap().is_none());
+            assert!(src.capacity() >= Header::HEADER_BYTE_SIZE);
+        }
+
+        src.extend_from_slice(&verack[verack.len() - 1..]);
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(MessageType::Verack)
+        ));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_decode_reserves_declared_frame() {
+        let version =
+            prepare_message(Network::Mainnet, VersionPayload::builder().build().unwrap()).unwrap();
+        let mut codec = BitcoinCodec::new(Network::Mainnet);
+
+        let mut src = BytesMut::from(&version[..Header::HEADER_BYTE_SIZE]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() >= version.len());
+    }
+
+    #[test]
+    fn test_decode_surfaces_parse_errors() {
+        let mut codec = BitcoinCodec::new(Network::Testnet3);
+        let mut src = BytesMut::from(
+            &hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap()[..],
+        );
+
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(CodecError::Parse(MessageParseError::MissingMagicNumber)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_version_verack_exchange_over_duplex() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut client = Framed::new(client, BitcoinCodec::new(Network::Regtest));
+        let mut server = Framed::new(server, BitcoinCodec::new(Network::Regtest));
+
+        let server = tokio::spawn(async move {
+            let Some(Ok(MessageType::Version(version))) = server.next().await else {
+                panic!("expected a version message");
+            };
+            assert_eq!(version.nonce(), 1);
+
+            let version = VersionPayload::builder().nonce(2).build().unwrap();
+            server
+                .send(OutgoingMessage::Version(version))
+                .await
+                .unwrap();
+            server.send(OutgoingMessage::Verack).await.unwrap();
+
+            assert!(matches!(server.next().await, Some(Ok(MessageType::Verack))));
+        });
+
+        let version = VersionPayload::builder().nonce(1).build().unwrap();
+        client
+            .send(OutgoingMessage::Version(version))
+            .await
+            .unwrap();
+
+        let Some(Ok(MessageType::Version(version))) = client.next().await else {
+            panic!("expected a version message");
+        };
+        assert_eq!(version.nonce(), 2);
+        assert!(matches!(client.next().await, Some(Ok(MessageType::Verack))));
+        client.send(OutgoingMessage::Verack).await.unwrap();
+
+        server.await.unwrap();
+    }
+}
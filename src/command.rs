@@ -1,19 +1,96 @@
+const ADDR_COMMAND: [u8; 12] = *b"addr\0\0\0\0\0\0\0\0";
+const ADDRV2_COMMAND: [u8; 12] = *b"addrv2\0\0\0\0\0\0";
+const ALERT_COMMAND: [u8; 12] = *b"alert\0\0\0\0\0\0\0";
+const GETADDR_COMMAND: [u8; 12] = *b"getaddr\0\0\0\0\0";
+const PING_COMMAND: [u8; 12] = *b"ping\0\0\0\0\0\0\0\0";
+const PONG_COMMAND: [u8; 12] = *b"pong\0\0\0\0\0\0\0\0";
 const VERACK_COMMAND: [u8; 12] = *b"verack\0\0\0\0\0\0";
 const VERSION_COMMAND: [u8; 12] = *b"version\0\0\0\0\0";
+const WTXIDRELAY_COMMAND: [u8; 12] = *b"wtxidrelay\0\0";
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
+    Addr,
+    AddrV2,
+    Alert,
+    GetAddr,
+    Ping,
+    Pong,
     Verack,
     Version,
+    WtxidRelay,
+}
+
+impl Command {
+    pub const ALL: [Self; 9] = [
+        Self::Addr,
+        Self::AddrV2,
+        Self::Alert,
+        Self::GetAddr,
+        Self::Ping,
+        Self::Pong,
+        Self::Verack,
+        Self::Version,
+        Self::WtxidRelay,
+    ];
+
+    fn raw(self) -> &'static [u8; 12] {
+        match self {
+            Self::Addr => &ADDR_COMMAND,
+            Self::AddrV2 => &ADDRV2_COMMAND,
+            Self::Alert => &ALERT_COMMAND,
+            Self::GetAddr => &GETADDR_COMMAND,
+            Self::Ping => &PING_COMMAND,
+            Self::Pong => &PONG_COMMAND,
+            Self::Verack => &VERACK_COMMAND,
+            Self::Version => &VERSION_COMMAND,
+            Self::WtxidRelay => &WTXIDRELAY_COMMAND,
+        }
+    }
+
+    // The wire name without its NUL padding, e.g. "version"
+    pub fn as_str(self) -> &'static str {
+        let raw = self.raw();
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        std::str::from_utf8(&raw[..len]).expect("command constants should be ASCII")
+    }
+}
+
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|command| command.as_str() == s)
+            .ok_or(CommandError::UnknownCommand)
+    }
 }
 
 impl TryFrom<[u8; 12]> for Command {
     type Error = CommandError;
 
     fn try_from(value: [u8; 12]) -> Result<Self, Self::Error> {
+        if !is_well_formed(&value) {
+            return Err(Self::Error::MalformedCommand);
+        }
+
         let command = match value {
+            ADDR_COMMAND => Self::Addr,
+            ADDRV2_COMMAND => Self::AddrV2,
+            ALERT_COMMAND => Self::Alert,
+            GETADDR_COMMAND => Self::GetAddr,
+            PING_COMMAND => Self::Ping,
+            PONG_COMMAND => Self::Pong,
             VERACK_COMMAND => Self::Verack,
             VERSION_COMMAND => Self::Version,
+            WTXIDRELAY_COMMAND => Self::WtxidRelay,
             _ => return Err(Self::Error::UnknownCommand),
         };
         Ok(command)
@@ -22,24 +99,121 @@ impl TryFrom<[u8; 12]> for Command {
 
 impl From<Command> for [u8; 12] {
     fn from(value: Command) -> Self {
-        match value {
-            Command::Verack => VERACK_COMMAND,
-            Command::Version => VERSION_COMMAND,
-        }
+        *value.raw()
     }
 }
 
+// A command is printable ASCII followed only by NUL padding, same as Bitcoin Core's IsCommandValid
+fn is_well_formed(command: &[u8; 12]) -> bool {
+    let end = command
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(command.len());
+    let (name, padding) = command.split_at(end);
+    name.iter().all(|&b| (b' '..=b'~').contains(&b)) && padding.iter().all(|&b| b == 0)
+}
+
+// Commands whose payload is defined to be empty, whether or not we otherwise understand them
+const EMPTY_PAYLOAD_COMMANDS: [[u8; 12]; 7] = [
+    VERACK_COMMAND,
+    WTXIDRELAY_COMMAND,
+    GETADDR_COMMAND,
+    *b"mempool\0\0\0\0\0",
+    *b"sendheaders\0",
+    *b"filterclear\0",
+    *b"sendaddrv2\0\0",
+];
+
+pub fn requires_empty_payload(command: &[u8; 12]) -> bool {
+    EMPTY_PAYLOAD_COMMANDS.contains(command)
+}
+
+// Renders a raw command field for humans, dropping the NUL padding and escaping anything odd
+pub fn command_name(command: &[u8; 12]) -> String {
+    let end = command
+        .iter()
+        .rposition(|&b| b != 0)
+        .map_or(0, |last| last + 1);
+    command[..end].escape_ascii().to_string()
+}
+
 #[derive(Debug)]
 pub enum CommandError {
     UnknownCommand,
+    MalformedCommand,
 }
 
 impl std::fmt::Display for CommandError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
             Self::UnknownCommand => write!(f, "unknown command"),
+            Self::MalformedCommand => write!(f, "malformed command"),
         }
     }
 }
 
 impl std::error::Error for CommandError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(command_name(&VERSION_COMMAND), "version");
+        assert_eq!(command_name(b"sendtxrcncl\0"), "sendtxrcncl");
+        assert_eq!(command_name(&[0; 12]), "");
+        assert_eq!(command_name(b"bad\xFF\0\0\0\0\0\0\0\0"), "bad\\xff");
+    }
+
+    #[test]
+    fn test_command_padding() {
+        assert!(matches!(
+            Command::try_from(*b"sendtxrcncl\0"),
+            Err(CommandError::UnknownCommand),
+        ));
+        assert!(matches!(
+            Command::try_from(*b"version\0\0\0\0X"),
+            Err(CommandError::MalformedCommand),
+        ));
+        assert!(matches!(
+            Command::try_from(*b"ver\0sion\0\0\0\0"),
+            Err(CommandError::MalformedCommand),
+        ));
+        assert!(matches!(
+            Command::try_from(*b"bad\xFF\0\0\0\0\0\0\0\0"),
+            Err(CommandError::MalformedCommand),
+        ));
+    }
+
+    #[test]
+    fn test_requires_empty_payload() {
+        assert!(requires_empty_payload(&VERACK_COMMAND));
+        assert!(requires_empty_payload(b"getaddr\0\0\0\0\0"));
+        assert!(requires_empty_payload(b"filterclear\0"));
+        assert!(!requires_empty_payload(&VERSION_COMMAND));
+        assert!(!requires_empty_payload(&ALERT_COMMAND));
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        for command in Command::ALL {
+            let name = command.to_string();
+            assert_eq!(name, command.as_str());
+
+            let parsed = name.parse::<Command>().unwrap();
+            assert_eq!(parsed, command);
+
+            let raw: [u8; 12] = parsed.into();
+            assert_eq!(Command::try_from(raw).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn test_command_from_str_is_case_sensitive() {
+        assert_eq!("verack".parse::<Command>().unwrap(), Command::Verack);
+        assert!("VERACK".parse::<Command>().is_err());
+        assert!("verack\0".parse::<Command>().is_err());
+        assert!("sendcmpct".parse::<Command>().is_err());
+    }
+}
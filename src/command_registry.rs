@@ -0,0 +1,138 @@
+use std::{any::Any, collections::HashMap};
+
+use crate::{
+    command::{command_name, Command},
+    message::MessageParseError,
+};
+
+pub type CustomPayload = Box<dyn Any + Send>;
+
+type ParseFn = Box<dyn Fn(&[u8]) -> Result<CustomPayload, MessageParseError> + Send + Sync>;
+
+// Maps nonstandard command names to parsers so P2P extensions can be decoded without forking
+#[derive(Default)]
+pub struct CommandRegistry {
+    parsers: HashMap<[u8; 12], ParseFn>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<T, F>(&mut self, command: &str, parse: F) -> Result<(), CommandRegistryError>
+    where
+        T: Any + Send,
+        F: Fn(&[u8]) -> Result<T, MessageParseError> + Send + Sync + 'static,
+    {
+        let raw = encode_command(command)?;
+        if let Ok(builtin) = Command::try_from(raw) {
+            // Built-in commands are always parsed first, so a registration would never be reached
+            return Err(CommandRegistryError::Builtin(builtin));
+        }
+
+        self.parsers.insert(
+            raw,
+            Box::new(move |payload| {
+                parse(payload).map(|payload| Box::new(payload) as CustomPayload)
+            }),
+        );
+        Ok(())
+    }
+
+    pub fn contains(&self, command: &[u8; 12]) -> bool {
+        self.parsers.contains_key(command)
+    }
+
+    // Returns `None` when nothing is registered for the command
+    pub fn parse(
+        &self,
+        command: &[u8; 12],
+        payload: &[u8],
+    ) -> Option<Result<CustomPayload, MessageParseError>> {
+        self.parsers.get(command).map(|parse| parse(payload))
+    }
+}
+
+impl std::fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.parsers.keys().map(command_name))
+            .finish()
+    }
+}
+
+fn encode_command(command: &str) -> Result<[u8; 12], CommandRegistryError> {
+    let mut raw = [0u8; 12];
+    if command.is_empty()
+        || command.len() > raw.len()
+        || !command.bytes().all(|b| (b' '..=b'~').contains(&b))
+    {
+        return Err(CommandRegistryError::InvalidName(command.to_owned()));
+    }
+    raw[..command.len()].copy_from_slice(command.as_bytes());
+    Ok(raw)
+}
+
+#[derive(Debug)]
+pub enum CommandRegistryError {
+    InvalidName(String),
+    Builtin(Command),
+}
+
+impl std::fmt::Display for CommandRegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidName(name) => write!(f, "invalid command name {name:?}"),
+            Self::Builtin(command) => write!(f, "{command} is a built-in command"),
+        }
+    }
+}
+
+impl std::error::Error for CommandRegistryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_u32(payload: &[u8]) -> Result<u32, MessageParseError> {
+        let payload = payload
+            .try_into()
+            .map_err(|_| MessageParseError::MalformedData(None))?;
+        Ok(u32::from_le_bytes(payload))
+    }
+
+    #[test]
+    fn test_register_and_parse() {
+        let mut registry = CommandRegistry::new();
+        registry.register("myext", parse_u32).unwrap();
+
+        let command = *b"myext\0\0\0\0\0\0\0";
+        assert!(registry.contains(&command));
+
+        let payload = registry.parse(&command, &[42, 0, 0, 0]).unwrap().unwrap();
+        assert_eq!(payload.downcast_ref::<u32>(), Some(&42));
+
+        assert!(matches!(
+            registry.parse(&command, &[42]),
+            Some(Err(MessageParseError::MalformedData(_))),
+        ));
+        assert!(registry.parse(b"other\0\0\0\0\0\0\0", &[]).is_none());
+    }
+
+    #[test]
+    fn test_register_rejects_invalid_names() {
+        let mut registry = CommandRegistry::new();
+
+        for name in ["", "thirteen_char", "nul\0", "caf\u{e9}"] {
+            assert!(matches!(
+                registry.register(name, parse_u32),
+                Err(CommandRegistryError::InvalidName(_)),
+            ));
+        }
+        assert!(matches!(
+            registry.register("verack", parse_u32),
+            Err(CommandRegistryError::Builtin(Command::Verack)),
+        ));
+    }
+}
@@ -0,0 +1,667 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use crate::{
+    network::Network, output::OutputFormat, peer_address::PeerAddress,
+    user_agent::validate_user_agent,
+};
+
+// Settings read from a TOML file, each left unset when the file doesn't give it, so that flags
+// can be laid over the file and the file over the built-in defaults. Only the part of TOML this
+// needs is understood: comments, `[tables]` and dotted keys, and string, number, boolean, and
+// array values on a line each, with arrays allowed to run over several lines
+//
+//     network = "testnet3"
+//     peers = ["192.0.2.1:18333", "node.example.com"]
+//     user_agent = "/my-crawler:0.1/"
+//     output = "csv"
+//
+//     [timeouts]
+//     connect = "5s"
+//     read = "20s"
+//     handshake = 60
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub network: Option<Network>,
+    pub peers: Option<Vec<PeerAddress>>,
+    pub user_agent: Option<String>,
+    pub connect_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    pub proxy: Option<SocketAddr>,
+    pub output: Option<OutputFormat>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ParsedConfig {
+    pub config: Config,
+    // Keys that mean nothing here, which are worth a warning rather than refusing the file
+    pub unknown_keys: Vec<UnknownKey>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<ParsedConfig, ConfigError> {
+        let mut parsed = ParsedConfig::default();
+        let mut seen = Vec::new();
+        let mut table = String::new();
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+        while let Some((line, content)) = lines.next() {
+            let content = strip_comment(content).trim();
+            if content.is_empty() {
+                continue;
+            }
+            if let Some(header) = content.strip_prefix('[') {
+                let name = header
+                    .strip_suffix(']')
+                    .filter(|name| !name.starts_with('['))
+                    .ok_or_else(|| syntax(line, "expected a table name in [brackets]"))?;
+                table = parse_key(name.trim()).map_err(|message| syntax(line, message))?;
+                continue;
+            }
+
+            let (key, value) = content
+                .split_once('=')
+                .ok_or_else(|| syntax(line, "expected key = value"))?;
+            let key = parse_key(key.trim()).map_err(|message| syntax(line, message))?;
+            let key = if table.is_empty() {
+                key
+            } else {
+                format!("{table}.{key}")
+            };
+            if seen.contains(&key) {
+                return Err(ConfigError::DuplicateKey { line, key });
+            }
+            seen.push(key.clone());
+
+            // An array carries on until its closing bracket
+            let mut value = value.trim().to_owned();
+            if value.starts_with('[') {
+                while !array_closed(&value) {
+                    let (_, next) = lines
+                        .next()
+                        .ok_or_else(|| syntax(line, "array is never closed"))?;
+                    value.push(' ');
+                    value.push_str(strip_comment(next).trim());
+                }
+            }
+            let value = parse_value(&value).map_err(|message| syntax(line, message))?;
+            parsed
+                .config
+                .set(&key, value, line, &mut parsed.unknown_keys)?;
+        }
+        Ok(parsed)
+    }
+
+    fn set(
+        &mut self,
+        key: &str,
+        value: Value,
+        line: usize,
+        unknown_keys: &mut Vec<UnknownKey>,
+    ) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError::InvalidValue {
+            line,
+            key: key.to_owned(),
+            message,
+        };
+        match key {
+            "network" => {
+                let network = value.into_string().map_err(invalid)?;
+                self.network = Some(network.parse().map_err(|e| invalid(format!("{e}")))?);
+            }
+            "peers" => {
+                let peers = value
+                    .into_array()
+                    .map_err(invalid)?
+                    .into_iter()
+                    .map(|peer| {
+                        let peer = peer.into_string()?;
+                        peer.parse().map_err(|e| format!("{peer:?}: {e}"))
+                    })
+                    .collect::<Result<_, _>>()
+                    .map_err(invalid)?;
+                self.peers = Some(peers);
+            }
+            "user_agent" => {
+                let user_agent = value.into_string().map_err(invalid)?;
+                validate_user_agent(&user_agent).map_err(|e| invalid(e.to_string()))?;
+                self.user_agent = Some(user_agent);
+            }
+            "timeouts.connect" => {
+                self.connect_timeout = Some(value.into_duration().map_err(invalid)?)
+            }
+            "timeouts.read" => self.read_timeout = Some(value.into_duration().map_err(invalid)?),
+            "timeouts.handshake" => {
+                self.handshake_timeout = Some(value.into_duration().map_err(invalid)?)
+            }
+            "proxy" => {
+                let proxy = value.into_string().map_err(invalid)?;
+                self.proxy = Some(
+                    proxy
+                        .parse()
+                        .map_err(|_| invalid(format!("expected ip:port, got {proxy:?}")))?,
+                );
+            }
+            "output" => {
+                let output = value.into_string().map_err(invalid)?;
+                self.output = Some(output.parse().map_err(|e| invalid(format!("{e}")))?);
+            }
+            _ => unknown_keys.push(UnknownKey {
+                line,
+                key: key.to_owned(),
+            }),
+        }
+        Ok(())
+    }
+
+    // `over` laid on top of `self`: whatever `over` sets wins, and the rest is kept
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            network: over.network.or(self.network),
+            peers: over.peers.or(self.peers),
+            user_agent: over.user_agent.or(self.user_agent),
+            connect_timeout: over.connect_timeout.or(self.connect_timeout),
+            read_timeout: over.read_timeout.or(self.read_timeout),
+            handshake_timeout: over.handshake_timeout.or(self.handshake_timeout),
+            proxy: over.proxy.or(self.proxy),
+            output: over.output.or(self.output),
+        }
+    }
+}
+
+// The same TOML `parse` reads, with only the settings that are set
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(network) = self.network {
+            writeln!(f, "network = {}", quote(&network.to_string()))?;
+        }
+        if let Some(peers) = &self.peers {
+            let peers: Vec<_> = peers.iter().map(|peer| quote(&peer.to_string())).collect();
+            writeln!(f, "peers = [{}]", peers.join(", "))?;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            writeln!(f, "user_agent = {}", quote(user_agent))?;
+        }
+        if let Some(proxy) = self.proxy {
+            writeln!(f, "proxy = {}", quote(&proxy.to_string()))?;
+        }
+        if let Some(output) = self.output {
+            writeln!(f, "output = {}", quote(&output.to_string()))?;
+        }
+        let timeouts = [
+            ("connect", self.connect_timeout),
+            ("read", self.read_timeout),
+            ("handshake", self.handshake_timeout),
+        ];
+        if timeouts.iter().any(|(_, timeout)| timeout.is_some()) {
+            writeln!(f, "\n[timeouts]")?;
+            for (name, timeout) in timeouts {
+                if let Some(timeout) = timeout {
+                    writeln!(f, "{name} = {}", quote(&format_duration(timeout)))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownKey {
+    pub line: usize,
+    pub key: String,
+}
+
+impl std::fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ignoring unknown key {:?}", self.line, self.key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    Syntax {
+        line: usize,
+        message: String,
+    },
+    InvalidValue {
+        line: usize,
+        key: String,
+        message: String,
+    },
+    DuplicateKey {
+        line: usize,
+        key: String,
+    },
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Syntax { line, message } => write!(f, "line {line}: {message}"),
+            Self::InvalidValue { line, key, message } => {
+                write!(f, "line {line}: invalid {key}: {message}")
+            }
+            Self::DuplicateKey { line, key } => write!(f, "line {line}: {key} is set twice"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn syntax(line: usize, message: impl Into<String>) -> ConfigError {
+    ConfigError::Syntax {
+        line,
+        message: message.into(),
+    }
+}
+
+// $XDG_CONFIG_HOME/bitcoin-handshake/config.toml, or under ~/.config when that isn't set
+pub fn default_path() -> Option<PathBuf> {
+    let base = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            PathBuf::from(std::env::var_os("HOME").filter(|dir| !dir.is_empty())?).join(".config")
+        }
+    };
+    Some(base.join("bitcoin-handshake").join("config.toml"))
+}
+
+// Accepts e.g. "10s", "500ms", or a bare number of seconds
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let (number, scale) = if let Some(millis) = duration.strip_suffix("ms") {
+        (millis, 0.001)
+    } else {
+        (duration.strip_suffix('s').unwrap_or(duration), 1.0)
+    };
+    let seconds = number
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("invalid duration {duration:?}"))?
+        * scale;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("duration {duration:?} must be positive"));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|e| format!("invalid duration {duration:?}: {e}"))
+}
+
+// Whole seconds where that's exact, otherwise milliseconds
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::String(_) => "a string",
+            Self::Number(_) => "a number",
+            Self::Boolean(_) => "a boolean",
+            Self::Array(_) => "an array",
+        }
+    }
+
+    fn into_string(self) -> Result<String, String> {
+        match self {
+            Self::String(s) => Ok(s),
+            other => Err(format!("expected a string, got {}", other.kind())),
+        }
+    }
+
+    fn into_array(self) -> Result<Vec<Value>, String> {
+        match self {
+            Self::Array(values) => Ok(values),
+            other => Err(format!("expected an array, got {}", other.kind())),
+        }
+    }
+
+    // A string such as "10s", or a number of seconds
+    fn into_duration(self) -> Result<Duration, String> {
+        match self {
+            Self::String(s) => parse_duration(&s),
+            Self::Number(seconds) => parse_duration(&seconds.to_string()),
+            other => Err(format!(
+                "expected a duration such as \"10s\", got {}",
+                other.kind()
+            )),
+        }
+    }
+}
+
+fn parse_key(key: &str) -> Result<String, String> {
+    let parts: Vec<_> = key.split('.').map(str::trim).collect();
+    let bare = |part: &&str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    if !parts.iter().all(bare) {
+        return Err(format!("invalid key {key:?}"));
+    }
+    Ok(parts.join("."))
+}
+
+// Everything before a `#` that isn't inside a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(open), c) if c == open && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn array_closed(value: &str) -> bool {
+    let mut rest = value;
+    match parse_array(&mut rest) {
+        Ok(_) => true,
+        // Only running out of input means there is more to come
+        Err(message) => message != UNCLOSED_ARRAY,
+    }
+}
+
+const UNCLOSED_ARRAY: &str = "array is never closed";
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let mut rest = text;
+    let value = parse_next(&mut rest)?;
+    if !rest.trim().is_empty() {
+        return Err(format!("unexpected {:?} after the value", rest.trim()));
+    }
+    Ok(value)
+}
+
+// Takes one value off the front of `rest`
+fn parse_next(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start();
+    match rest.chars().next() {
+        None => Err("expected a value".to_owned()),
+        Some('"') => parse_basic_string(rest).map(Value::String),
+        Some('\'') => {
+            let end = rest[1..]
+                .find('\'')
+                .ok_or_else(|| "string is never closed".to_owned())?;
+            let s = rest[1..=end].to_owned();
+            *rest = &rest[end + 2..];
+            Ok(Value::String(s))
+        }
+        Some('[') => parse_array(rest).map(Value::Array),
+        Some(_) => {
+            let end = rest
+                .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+                .unwrap_or(rest.len());
+            let token = &rest[..end];
+            *rest = &rest[end..];
+            match token {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => token
+                    .replace('_', "")
+                    .parse()
+                    .map(Value::Number)
+                    .map_err(|_| format!("invalid value {token:?}")),
+            }
+        }
+    }
+}
+
+fn parse_basic_string(rest: &mut &str) -> Result<String, String> {
+    let mut s = String::new();
+    let mut chars = rest.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                *rest = &rest[i + 1..];
+                return Ok(s);
+            }
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => s.push('"'),
+                Some('\\') => s.push('\\'),
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    let c = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{hex}"))?;
+                    s.push(c);
+                }
+                Some(other) => return Err(format!("invalid escape \\{other}")),
+                None => break,
+            },
+            c => s.push(c),
+        }
+    }
+    Err("string is never closed".to_owned())
+}
+
+fn parse_array(rest: &mut &str) -> Result<Vec<Value>, String> {
+    *rest = &rest.trim_start()[1..];
+    let mut values = Vec::new();
+    loop {
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(']') {
+            *rest = after;
+            return Ok(values);
+        }
+        if rest.is_empty() {
+            return Err(UNCLOSED_ARRAY.to_owned());
+        }
+        values.push(parse_next(rest)?);
+        *rest = rest.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            *rest = after;
+        } else if !rest.starts_with(']') {
+            if rest.is_empty() {
+                return Err(UNCLOSED_ARRAY.to_owned());
+            }
+            return Err(format!("expected , or ] in the array, got {rest:?}"));
+        }
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2.5"), Ok(Duration::from_millis(2500)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("soon").is_err());
+        // Finite, but too big for a Duration
+        assert!(parse_duration("1e30")
+            .unwrap_err()
+            .starts_with("invalid duration \"1e30\": "));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let config = Config {
+            network: Some(Network::Signet),
+            peers: Some(vec![
+                "192.0.2.1:38333".parse().unwrap(),
+                "[2001:db8::1]".parse().unwrap(),
+                "node.example.com".parse().unwrap(),
+            ]),
+            user_agent: Some("/crawler:0.1(\"quoted\" \\)/".to_owned()),
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: Some(Duration::from_millis(2500)),
+            handshake_timeout: Some(Duration::from_secs(60)),
+            proxy: Some("127.0.0.1:9050".parse().unwrap()),
+            output: Some(OutputFormat::Csv),
+        };
+        let text = config.to_string();
+        assert_eq!(
+            Config::parse(&text),
+            Ok(ParsedConfig {
+                config,
+                unknown_keys: Vec::new(),
+            }),
+            "{text}",
+        );
+        assert_eq!(Config::default().to_string(), "");
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = Config::parse(
+            "\
+# Where to start
+network = 'testnet'  # short for testnet3
+peers = [
+    \"192.0.2.1\", # the first
+    \"192.0.2.2:18333\",
+]
+timeouts.connect = 3
+
+[timeouts]
+handshake = \"90s\"
+",
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.config,
+            Config {
+                network: Some(Network::Testnet3),
+                peers: Some(vec![
+                    "192.0.2.1".parse().unwrap(),
+                    "192.0.2.2:18333".parse().unwrap(),
+                ]),
+                connect_timeout: Some(Duration::from_secs(3)),
+                handshake_timeout: Some(Duration::from_secs(90)),
+                ..Config::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let file = Config {
+            network: Some(Network::Testnet3),
+            user_agent: Some("/from-file/".to_owned()),
+            connect_timeout: Some(Duration::from_secs(3)),
+            ..Config::default()
+        };
+        let flags = Config {
+            user_agent: Some("/from-flags/".to_owned()),
+            read_timeout: Some(Duration::from_secs(5)),
+            ..Config::default()
+        };
+        assert_eq!(
+            file.clone().merge(flags.clone()),
+            Config {
+                network: Some(Network::Testnet3),
+                user_agent: Some("/from-flags/".to_owned()),
+                connect_timeout: Some(Duration::from_secs(3)),
+                read_timeout: Some(Duration::from_secs(5)),
+                ..Config::default()
+            },
+        );
+        assert_eq!(file.clone().merge(Config::default()), file);
+        assert_eq!(Config::default().merge(flags.clone()), flags);
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let parsed = Config::parse(
+            "network = \"regtest\"\ncolour = true\n\n[timeouts]\nconnect = \"1s\"\nidle = 30\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.config.network, Some(Network::Regtest));
+        assert_eq!(
+            parsed.unknown_keys,
+            [
+                UnknownKey {
+                    line: 2,
+                    key: "colour".to_owned(),
+                },
+                UnknownKey {
+                    line: 6,
+                    key: "timeouts.idle".to_owned(),
+                },
+            ],
+        );
+        assert_eq!(
+            parsed.unknown_keys[1].to_string(),
+            "line 6: ignoring unknown key \"timeouts.idle\"",
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| Config::parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("\n\nnetwork = \"moonnet\""),
+            "line 3: invalid network: unknown network \"moonnet\", expected one of mainnet, \
+             testnet3, signet, regtest",
+        );
+        assert_eq!(
+            error("[timeouts]\nread = \"-1s\""),
+            "line 2: invalid timeouts.read: duration \"-1s\" must be positive",
+        );
+        assert_eq!(
+            error("peers = \"192.0.2.1\""),
+            "line 1: invalid peers: expected an array, got a string",
+        );
+        assert_eq!(
+            error("peers = [\"192.0.2.1\", 8333]"),
+            "line 1: invalid peers: expected a string, got a number",
+        );
+        assert_eq!(
+            error("output = \"csv\"\noutput = \"text\""),
+            "line 2: output is set twice",
+        );
+        assert_eq!(error("network"), "line 1: expected key = value");
+        assert_eq!(
+            error("peers = [\"192.0.2.1\""),
+            "line 1: array is never closed"
+        );
+        assert_eq!(
+            error("user_agent = \"/a/\" \"/b/\""),
+            "line 1: unexpected \"\\\"/b/\\\"\" after the value",
+        );
+    }
+}
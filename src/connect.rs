@@ -0,0 +1,613 @@
+use std::{future::Future, io::ErrorKind, net::SocketAddr, pin::pin, time::Duration};
+
+use rand::Rng;
+use tokio::net::TcpStream;
+
+use crate::{disconnect::DisconnectReason, log::event};
+
+// An unroutable address would otherwise hang for the operating system's default, often minutes
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn connect(
+    socket_address: SocketAddr,
+    timeout: Duration,
+) -> Result<TcpStream, ConnectError> {
+    event!(Debug, "connecting to {socket_address}");
+    let result = with_timeout(timeout, TcpStream::connect(socket_address)).await;
+    match &result {
+        Ok(_) => event!(Debug, "connected to {socket_address}"),
+        Err(e) => event!(Debug, "could not connect to {socket_address}: {e}"),
+    }
+    result
+}
+
+// How to pick among the addresses a hostname resolved to, in the spirit of RFC 8305: the
+// preferred family gets a head start of `delay`, after which the other family races it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HappyEyeballs {
+    // Otherwise every address is tried in turn, preferred family first
+    pub enabled: bool,
+    // Otherwise the family of the first address goes first
+    pub prefer_ipv6: bool,
+    pub delay: Duration,
+}
+
+impl Default for HappyEyeballs {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            prefer_ipv6: false,
+            delay: Duration::from_millis(250),
+        }
+    }
+}
+
+impl HappyEyeballs {
+    // Splits the addresses by family, keeping their order, with the family to start with first
+    fn lanes(&self, addresses: &[SocketAddr]) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        let ipv6_first = if self.prefer_ipv6 {
+            addresses.iter().any(SocketAddr::is_ipv6)
+        } else {
+            addresses.first().is_some_and(SocketAddr::is_ipv6)
+        };
+        addresses
+            .iter()
+            .partition(|socket_address| socket_address.is_ipv6() == ipv6_first)
+    }
+}
+
+// Connects to whichever of the addresses answers first, as `eyeballs` directs, and returns the
+// address it reached along with the stream
+pub async fn connect_any(
+    addresses: &[SocketAddr],
+    timeout: Duration,
+    eyeballs: &HappyEyeballs,
+) -> Result<(SocketAddr, TcpStream), ConnectError> {
+    race(addresses, eyeballs, |socket_address| {
+        connect(socket_address, timeout)
+    })
+    .await
+}
+
+async fn race<S, F, Fut>(
+    addresses: &[SocketAddr],
+    eyeballs: &HappyEyeballs,
+    connect: F,
+) -> Result<(SocketAddr, S), ConnectError>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<S, ConnectError>>,
+{
+    if addresses.is_empty() {
+        return Err(
+            std::io::Error::new(ErrorKind::InvalidInput, "no addresses to connect to").into(),
+        );
+    }
+    let (first, second) = eyeballs.lanes(addresses);
+    if !eyeballs.enabled || second.is_empty() {
+        let in_turn: Vec<_> = first.into_iter().chain(second).collect();
+        return in_order(&in_turn, &connect)
+            .await
+            .map_err(ConnectError::from_failures);
+    }
+
+    let mut first_lane = pin!(in_order(&first, &connect));
+    let mut second_lane = pin!(in_order(&second, &connect));
+    let mut head_start = pin!(tokio::time::sleep(eyeballs.delay));
+    let mut second_started = false;
+    let mut first_failures = None;
+    let mut second_failures = None;
+    // Dropping the lane that lost the race abandons its connection attempt
+    loop {
+        tokio::select! {
+            result = &mut first_lane, if first_failures.is_none() => match result {
+                Ok(connected) => return Ok(connected),
+                // No point in waiting out the head start once the preferred family is exhausted
+                Err(failures) => {
+                    first_failures = Some(failures);
+                    second_started = true;
+                }
+            },
+            _ = &mut head_start, if !second_started => second_started = true,
+            result = &mut second_lane, if second_started && second_failures.is_none() => {
+                match result {
+                    Ok(connected) => return Ok(connected),
+                    Err(failures) => second_failures = Some(failures),
+                }
+            }
+        }
+        if let (Some(first), Some(second)) = (&mut first_failures, &mut second_failures) {
+            first.append(second);
+            return Err(ConnectError::from_failures(std::mem::take(first)));
+        }
+    }
+}
+
+async fn in_order<S, F, Fut>(
+    addresses: &[SocketAddr],
+    connect: &F,
+) -> Result<(SocketAddr, S), Vec<(SocketAddr, ConnectError)>>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<S, ConnectError>>,
+{
+    let mut failures = Vec::new();
+    for &socket_address in addresses {
+        match connect(socket_address).await {
+            Ok(stream) => return Ok((socket_address, stream)),
+            Err(e) => failures.push((socket_address, e)),
+        }
+    }
+    Err(failures)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Attempts beyond the first, so zero never retries
+    pub retries: u32,
+    // Doubles after every failed attempt, up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub connect_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The longest wait before the given retry, counting from zero, before jitter shortens it
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+
+    // How long connecting could take if every attempt timed out, backoffs included
+    pub fn worst_case(&self) -> Duration {
+        (0..self.retries).map(|retry| self.backoff(retry)).fold(
+            self.connect_timeout
+                .saturating_mul(self.retries.saturating_add(1)),
+            Duration::saturating_add,
+        )
+    }
+
+    // Somewhere between half and all of the backoff, so clients that failed together don't
+    // all come back at once
+    fn jittered_backoff(&self, retry: u32) -> Duration {
+        self.backoff(retry)
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+// Retries connections that failed for reasons that may clear up on their own, like a node
+// restarting, but gives up straight away on anything else
+pub async fn connect_with_retry(
+    socket_address: SocketAddr,
+    policy: &RetryPolicy,
+) -> Result<TcpStream, ConnectError> {
+    retry(policy, || connect(socket_address, policy.connect_timeout)).await
+}
+
+// Like `connect_with_retry`, but every attempt races all the addresses
+pub async fn connect_any_with_retry(
+    addresses: &[SocketAddr],
+    policy: &RetryPolicy,
+    eyeballs: &HappyEyeballs,
+) -> Result<(SocketAddr, TcpStream), ConnectError> {
+    retry(policy, || {
+        connect_any(addresses, policy.connect_timeout, eyeballs)
+    })
+    .await
+}
+
+async fn retry<S, F, Fut>(policy: &RetryPolicy, mut connect: F) -> Result<S, ConnectError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<S, ConnectError>>,
+{
+    let mut failures = Vec::new();
+    loop {
+        let e = match connect().await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => e,
+        };
+        let retry = failures.len() as u32;
+        let give_up = retry >= policy.retries || !e.is_retryable();
+        failures.push(e);
+        if give_up {
+            break;
+        }
+        tokio::time::sleep(policy.jittered_backoff(retry)).await;
+    }
+
+    if failures.len() == 1 {
+        Err(failures.remove(0))
+    } else {
+        Err(ConnectError::Attempts(failures))
+    }
+}
+
+async fn with_timeout<S, F>(timeout: Duration, connecting: F) -> Result<S, ConnectError>
+where
+    F: Future<Output = std::io::Result<S>>,
+{
+    match tokio::time::timeout(timeout, connecting).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(ConnectError::TimedOut(timeout)),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Io(std::io::Error),
+    TimedOut(Duration),
+    // Every failed attempt of a retried connection, in order
+    Attempts(Vec<ConnectError>),
+    // Every address a single attempt tried, none of which could be reached
+    Unreachable(Vec<(SocketAddr, ConnectError)>),
+    // Given up on because we were asked to stop, rather than because of the peer
+    Interrupted,
+}
+
+impl ConnectError {
+    // A lone failure is reported as it is
+    fn from_failures(mut failures: Vec<(SocketAddr, ConnectError)>) -> Self {
+        if failures.len() == 1 {
+            failures.remove(0).1
+        } else {
+            Self::Unreachable(failures)
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Interrupted => DisconnectReason::Cancelled,
+            _ => DisconnectReason::ConnectFailed,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Io(e) => matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::TimedOut
+            ),
+            Self::TimedOut(_) => true,
+            Self::Attempts(_) | Self::Interrupted => false,
+            Self::Unreachable(failures) => failures.iter().all(|(_, e)| e.is_retryable()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => e.fmt(f),
+            Self::TimedOut(timeout) => write!(f, "connection timed out after {timeout:?}"),
+            Self::Attempts(failures) => {
+                write!(f, "gave up after {} attempt(s)", failures.len())?;
+                for (attempt, e) in failures.iter().enumerate() {
+                    write!(f, "; attempt {}: {e}", attempt + 1)?;
+                }
+                Ok(())
+            }
+            Self::Unreachable(failures) => {
+                write!(f, "none of {} address(es) could be reached", failures.len())?;
+                for (socket_address, e) in failures {
+                    write!(f, "; {socket_address}: {e}")?;
+                }
+                Ok(())
+            }
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => e.source(),
+            Self::TimedOut(_) | Self::Interrupted => None,
+            Self::Attempts(failures) => failures.last().map(|e| e as _),
+            Self::Unreachable(failures) => failures.last().map(|(_, e)| e as _),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_times_out() {
+        let timeout = Duration::from_millis(100);
+
+        // Behaves like a blackholed address, where the SYN is never answered
+        let started = Instant::now();
+        let result = with_timeout(timeout, std::future::pending::<std::io::Result<()>>()).await;
+        let elapsed = started.elapsed();
+
+        assert!(matches!(result, Err(ConnectError::TimedOut(t)) if t == timeout));
+        assert!(elapsed >= timeout);
+        assert!(elapsed < timeout * 5, "took {elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused_is_not_a_timeout() {
+        // Bind and drop a listener to find a port nothing is listening on
+        let socket_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let result = connect(socket_address, DEFAULT_CONNECT_TIMEOUT).await;
+        assert!(matches!(
+            result,
+            Err(ConnectError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused,
+        ));
+    }
+
+    fn refused() -> ConnectError {
+        std::io::Error::from(ErrorKind::ConnectionRefused).into()
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(3),
+            ..RetryPolicy::default()
+        };
+        let backoffs: Vec<_> = (0..5).map(|retry| policy.backoff(retry)).collect();
+        assert_eq!(
+            backoffs,
+            [
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(3),
+                Duration::from_secs(3),
+            ],
+        );
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(3));
+
+        let policy = RetryPolicy {
+            retries: 3,
+            ..policy
+        };
+        // Four 10s timeouts, with 500ms, 1s and 2s between them
+        assert_eq!(policy.worst_case(), Duration::from_millis(43_500));
+
+        for _ in 0..100 {
+            let jittered = policy.jittered_backoff(1);
+            assert!(jittered >= Duration::from_millis(500) && jittered <= Duration::from_secs(1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_until_connected() {
+        let policy = RetryPolicy {
+            retries: 3,
+            ..RetryPolicy::default()
+        };
+        let mut calls = 0;
+
+        let started = tokio::time::Instant::now();
+        retry(&policy, || {
+            calls += 1;
+            std::future::ready(if calls <= 2 { Err(refused()) } else { Ok(()) })
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls, 3);
+        // Backed off twice, by at least half of 500ms and then of 1s
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(750), "took {elapsed:?}");
+        assert!(elapsed <= Duration::from_millis(1500), "took {elapsed:?}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_gives_up_after_last_attempt() {
+        let policy = RetryPolicy {
+            retries: 2,
+            ..RetryPolicy::default()
+        };
+        let mut calls = 0;
+
+        let Err(e) = retry(&policy, || {
+            calls += 1;
+            std::future::ready(Err::<(), _>(ConnectError::TimedOut(Duration::from_secs(
+                10,
+            ))))
+        })
+        .await
+        else {
+            panic!("expected every attempt to fail");
+        };
+        assert_eq!(calls, 3);
+        assert!(matches!(&e, ConnectError::Attempts(failures) if failures.len() == 3));
+        assert_eq!(
+            e.to_string(),
+            "gave up after 3 attempt(s); \
+             attempt 1: connection timed out after 10s; \
+             attempt 2: connection timed out after 10s; \
+             attempt 3: connection timed out after 10s",
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retry_stops_on_permanent_error() {
+        let policy = RetryPolicy {
+            retries: 5,
+            ..RetryPolicy::default()
+        };
+        let mut calls = 0;
+
+        // Like an address that can't be connected to at all
+        let result = retry(&policy, || {
+            calls += 1;
+            std::future::ready(Err::<(), _>(
+                std::io::Error::from(ErrorKind::InvalidInput).into(),
+            ))
+        })
+        .await;
+        assert_eq!(calls, 1);
+        assert!(matches!(
+            result,
+            Err(ConnectError::Io(e)) if e.kind() == ErrorKind::InvalidInput,
+        ));
+
+        // Retries already made are still reported when a permanent error ends them
+        calls = 0;
+        let result = retry(&policy, || {
+            calls += 1;
+            std::future::ready(match calls {
+                1 => Err(refused()),
+                _ => Err::<(), _>(std::io::Error::from(ErrorKind::AddrNotAvailable).into()),
+            })
+        })
+        .await;
+        assert_eq!(calls, 2);
+        assert!(matches!(
+            result,
+            Err(ConnectError::Attempts(failures)) if failures.len() == 2,
+        ));
+    }
+
+    const IPV4: &str = "203.0.113.1:8333";
+    const IPV6: &str = "[2001:db8::1]:8333";
+
+    // Connects to IPv4 or IPv6 addresses straight away, and times the other family out after 10s
+    async fn one_family_hangs(
+        socket_address: SocketAddr,
+        working_ipv6: bool,
+    ) -> Result<SocketAddr, ConnectError> {
+        if socket_address.is_ipv6() == working_ipv6 {
+            Ok(socket_address)
+        } else {
+            tokio::time::sleep(DEFAULT_CONNECT_TIMEOUT).await;
+            Err(ConnectError::TimedOut(DEFAULT_CONNECT_TIMEOUT))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_happy_eyeballs_falls_over_to_other_family() {
+        let addresses = [IPV4.parse().unwrap(), IPV6.parse().unwrap()];
+        let eyeballs = HappyEyeballs::default();
+
+        let started = tokio::time::Instant::now();
+        let (socket_address, _) = race(&addresses, &eyeballs, |socket_address| {
+            one_family_hangs(socket_address, true)
+        })
+        .await
+        .unwrap();
+        assert!(socket_address.is_ipv6());
+        assert_eq!(started.elapsed(), eyeballs.delay);
+
+        // The other way around, IPv6 first and hanging
+        let eyeballs = HappyEyeballs {
+            prefer_ipv6: true,
+            ..eyeballs
+        };
+        let started = tokio::time::Instant::now();
+        let (socket_address, _) = race(&addresses, &eyeballs, |socket_address| {
+            one_family_hangs(socket_address, false)
+        })
+        .await
+        .unwrap();
+        assert!(socket_address.is_ipv4());
+        assert_eq!(started.elapsed(), eyeballs.delay);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_happy_eyeballs_prefers_first_family_that_answers() {
+        let addresses = [IPV6.parse().unwrap(), IPV4.parse().unwrap()];
+        let started = tokio::time::Instant::now();
+        let (socket_address, _) = race(&addresses, &HappyEyeballs::default(), |socket_address| {
+            std::future::ready(Ok::<_, ConnectError>(socket_address))
+        })
+        .await
+        .unwrap();
+        assert!(socket_address.is_ipv6());
+        assert_eq!(started.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_happy_eyeballs_disabled_waits_in_turn() {
+        let addresses = [IPV4.parse().unwrap(), IPV6.parse().unwrap()];
+        let eyeballs = HappyEyeballs {
+            enabled: false,
+            ..HappyEyeballs::default()
+        };
+
+        let started = tokio::time::Instant::now();
+        let (socket_address, _) = race(&addresses, &eyeballs, |socket_address| {
+            one_family_hangs(socket_address, true)
+        })
+        .await
+        .unwrap();
+        assert!(socket_address.is_ipv6());
+        assert_eq!(started.elapsed(), DEFAULT_CONNECT_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_happy_eyeballs_all_addresses_fail() {
+        let addresses = [
+            IPV4.parse().unwrap(),
+            "203.0.113.2:8333".parse().unwrap(),
+            IPV6.parse().unwrap(),
+        ];
+
+        let started = tokio::time::Instant::now();
+        let Err(e) = race(
+            &addresses,
+            &HappyEyeballs::default(),
+            |socket_address| async move {
+                if socket_address.is_ipv6() {
+                    Err::<(), _>(refused())
+                } else {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Err(ConnectError::TimedOut(Duration::from_secs(1)))
+                }
+            },
+        )
+        .await
+        else {
+            panic!("expected no address to be reachable");
+        };
+        // The IPv4 addresses one after the other, with IPv6 refused along the way
+        assert_eq!(started.elapsed(), Duration::from_secs(2));
+        assert!(e.is_retryable());
+        assert_eq!(
+            e.to_string(),
+            "none of 3 address(es) could be reached; \
+             203.0.113.1:8333: connection timed out after 1s; \
+             203.0.113.2:8333: connection timed out after 1s; \
+             [2001:db8::1]:8333: connection refused",
+        );
+
+        assert!(matches!(
+            race(&[], &HappyEyeballs::default(), |_| std::future::ready(Ok(()))).await,
+            Err(ConnectError::Io(e)) if e.kind() == ErrorKind::InvalidInput,
+        ));
+    }
+}
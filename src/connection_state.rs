@@ -0,0 +1,188 @@
+use crate::{command::Command, message::MessageType};
+
+// Where a connection stands in the version handshake, from our side of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    VersionSent,
+    // The peer's version arrived, whether or not we sent ours yet
+    VersionReceived,
+    // Versions and veracks went both ways
+    HandshakeComplete,
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connected => write!(f, "connected"),
+            Self::VersionSent => write!(f, "version sent"),
+            Self::VersionReceived => write!(f, "version received"),
+            Self::HandshakeComplete => write!(f, "handshake complete"),
+        }
+    }
+}
+
+// Tracks the handshake messages seen in each direction, refusing sends a peer would punish
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct HandshakeProgress {
+    pub(crate) peer_version: Option<i32>,
+    version_sent: bool,
+    verack_sent: bool,
+    verack_received: bool,
+}
+
+impl HandshakeProgress {
+    pub(crate) fn version_sent(&self) -> bool {
+        self.version_sent
+    }
+
+    pub(crate) fn state(&self) -> ConnectionState {
+        if self.version_sent
+            && self.verack_sent
+            && self.peer_version.is_some()
+            && self.verack_received
+        {
+            ConnectionState::HandshakeComplete
+        } else if self.peer_version.is_some() {
+            ConnectionState::VersionReceived
+        } else if self.version_sent {
+            ConnectionState::VersionSent
+        } else {
+            ConnectionState::Connected
+        }
+    }
+
+    pub(crate) fn check_send(&self, command: Command) -> Result<(), ProtocolStateError> {
+        match command {
+            Command::Version if self.version_sent => Err(ProtocolStateError::DuplicateVersion),
+            Command::Verack if self.verack_sent => Err(ProtocolStateError::DuplicateVerack),
+            // BIP 339 only allows wtxidrelay between the versions and our verack
+            Command::WtxidRelay if self.verack_sent => {
+                Err(ProtocolStateError::AfterVerack(command))
+            }
+            Command::Verack | Command::WtxidRelay if self.peer_version.is_none() => {
+                Err(ProtocolStateError::VersionNotReceived(command))
+            }
+            // Answering a ping is never our violation, however early the peer sent it
+            Command::Version | Command::Verack | Command::WtxidRelay | Command::Pong => Ok(()),
+            _ if self.state() != ConnectionState::HandshakeComplete => {
+                Err(ProtocolStateError::HandshakeIncomplete(command))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn record_sent(&mut self, command: Command) {
+        match command {
+            Command::Version => self.version_sent = true,
+            Command::Verack => self.verack_sent = true,
+            _ => {}
+        }
+    }
+
+    pub(crate) fn record_received(&mut self, message: &MessageType) {
+        match message {
+            MessageType::Version(version_payload) => {
+                self.peer_version = Some(version_payload.version())
+            }
+            MessageType::Verack => self.verack_received = true,
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolStateError {
+    DuplicateVersion,
+    DuplicateVerack,
+    VersionNotReceived(Command),
+    AfterVerack(Command),
+    HandshakeIncomplete(Command),
+}
+
+impl std::fmt::Display for ProtocolStateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DuplicateVersion => write!(f, "version was already sent"),
+            Self::DuplicateVerack => write!(f, "verack was already sent"),
+            Self::VersionNotReceived(command) => {
+                write!(f, "cannot send {command} before the peer's version")
+            }
+            Self::AfterVerack(command) => write!(f, "cannot send {command} after verack"),
+            Self::HandshakeIncomplete(command) => {
+                write!(f, "cannot send {command} before the handshake is complete")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolStateError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::version_payload::VersionPayload;
+
+    use super::*;
+
+    fn peer_version() -> MessageType {
+        MessageType::Version(VersionPayload::builder().build().unwrap())
+    }
+
+    #[test]
+    fn test_outbound_handshake() {
+        let mut progress = HandshakeProgress::default();
+        assert_eq!(progress.state(), ConnectionState::Connected);
+
+        progress.check_send(Command::Version).unwrap();
+        progress.record_sent(Command::Version);
+        assert_eq!(progress.state(), ConnectionState::VersionSent);
+
+        progress.record_received(&peer_version());
+        assert_eq!(progress.state(), ConnectionState::VersionReceived);
+
+        progress.check_send(Command::WtxidRelay).unwrap();
+        progress.check_send(Command::Verack).unwrap();
+        progress.record_sent(Command::Verack);
+        assert_eq!(progress.state(), ConnectionState::VersionReceived);
+
+        progress.record_received(&MessageType::Verack);
+        assert_eq!(progress.state(), ConnectionState::HandshakeComplete);
+        progress.check_send(Command::Ping).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_transitions() {
+        let mut progress = HandshakeProgress::default();
+        assert!(matches!(
+            progress.check_send(Command::Verack),
+            Err(ProtocolStateError::VersionNotReceived(Command::Verack)),
+        ));
+        assert!(matches!(
+            progress.check_send(Command::Ping),
+            Err(ProtocolStateError::HandshakeIncomplete(Command::Ping)),
+        ));
+        progress.check_send(Command::Pong).unwrap();
+
+        progress.record_sent(Command::Version);
+        assert!(matches!(
+            progress.check_send(Command::Version),
+            Err(ProtocolStateError::DuplicateVersion),
+        ));
+
+        // Still incomplete until the peer's verack arrives
+        progress.record_received(&peer_version());
+        progress.record_sent(Command::Verack);
+        assert!(matches!(
+            progress.check_send(Command::Ping),
+            Err(ProtocolStateError::HandshakeIncomplete(Command::Ping)),
+        ));
+        assert!(matches!(
+            progress.check_send(Command::Verack),
+            Err(ProtocolStateError::DuplicateVerack),
+        ));
+        assert!(matches!(
+            progress.check_send(Command::WtxidRelay),
+            Err(ProtocolStateError::AfterVerack(Command::WtxidRelay)),
+        ));
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    future::Future,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connect::ConnectError,
+    disconnect::DisconnectReason,
+    handshake_config::HandshakeConfig,
+    messaging_system::{MessagingSystem, DEFAULT_CLOSE_GRACE},
+    session::{collect_addresses, KnownAddress, SessionError},
+    stats::SessionStats,
+    survey::{connect_until_cancelled, PeerSummary, SurveyFailure},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrawlLimits {
+    // Hops away from the starting peers; zero crawls only them
+    pub max_depth: usize,
+    // Peers to connect to in all, starting peers included
+    pub max_peers: usize,
+    pub concurrency: usize,
+    // From connecting to having collected the addresses, so a stuck peer is given up on
+    pub peer_timeout: Duration,
+    // How long each peer gets to answer getaddr
+    pub addr_wait: Duration,
+}
+
+#[derive(Debug)]
+pub struct CrawledPeer {
+    pub address: SocketAddr,
+    pub depth: usize,
+    pub outcome: Result<CrawlSummary, SurveyFailure>,
+}
+
+#[derive(Debug)]
+pub struct CrawlSummary {
+    pub peer: PeerSummary,
+    // The handshake can succeed and the peer still hang up on getaddr
+    pub addresses: Result<Vec<KnownAddress>, SessionError>,
+    pub stats: SessionStats,
+    pub disconnect: DisconnectReason,
+}
+
+impl CrawledPeer {
+    // A peer given up on after `peer_timeout` counts as a handshake timeout, even when the
+    // handshake had finished and it was getaddr that went unanswered
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match &self.outcome {
+            Ok(summary) => summary.disconnect,
+            Err(e) => e.disconnect_reason(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CrawlReport {
+    // In the order they were connected to
+    pub peers: Vec<CrawledPeer>,
+    // Distinct addresses heard of from every peer, whatever their network
+    pub discovered: usize,
+    // IP addresses heard of but left alone because of the limits, or the interruption
+    pub unvisited: usize,
+    // Cancelled before the limits were reached, leaving out the peers still in progress
+    pub interrupted: bool,
+}
+
+impl CrawlReport {
+    pub fn reachable(&self) -> usize {
+        self.peers
+            .iter()
+            .filter(|crawled| crawled.outcome.is_ok())
+            .count()
+    }
+
+    // Traffic with every reachable peer, added up
+    pub fn stats(&self) -> SessionStats {
+        let mut stats = SessionStats::default();
+        for summary in self
+            .peers
+            .iter()
+            .filter_map(|crawled| crawled.outcome.as_ref().ok())
+        {
+            stats += &summary.stats;
+        }
+        stats
+    }
+}
+
+// Handshakes with each starting peer, asks it for addresses, and does the same to every new
+// IP address it hears of, breadth first, until `limits` say to stop. Every address is tried at
+// most once, however many peers mention it.
+pub async fn crawl<T, C, Fut>(
+    start: Vec<SocketAddr>,
+    config: &HandshakeConfig,
+    limits: CrawlLimits,
+    connect: C,
+) -> CrawlReport
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>> + Send + 'static,
+{
+    crawl_with_progress(
+        start,
+        config,
+        limits,
+        connect,
+        |_| {},
+        &CancellationToken::new(),
+    )
+    .await
+}
+
+// Like `crawl`, but hands each peer to `on_peer` as soon as it's done with, for crawls long
+// enough to be watched while they run. Cancelling `cancel` stops the crawl early: no more peers
+// are started, those in progress are closed and left out, and the report covers the rest
+pub async fn crawl_with_progress<T, C, Fut, P>(
+    start: Vec<SocketAddr>,
+    config: &HandshakeConfig,
+    limits: CrawlLimits,
+    connect: C,
+    mut on_peer: P,
+    cancel: &CancellationToken,
+) -> CrawlReport
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>> + Send + 'static,
+    P: FnMut(&CrawledPeer),
+{
+    let mut seen = HashSet::new();
+    let mut queue: VecDeque<_> = start
+        .into_iter()
+        .filter(|&address| seen.insert(address))
+        .map(|address| (address, 0))
+        .collect();
+    let mut discovered = HashSet::new();
+
+    let mut tasks = JoinSet::new();
+    let mut started = 0;
+    let mut peers = Vec::new();
+    loop {
+        while tasks.len() < limits.concurrency.max(1)
+            && started < limits.max_peers
+            && !cancel.is_cancelled()
+        {
+            let Some((address, depth)) = queue.pop_front() else {
+                break;
+            };
+            let connecting = connect(address);
+            let config = config.clone();
+            let cancel = cancel.clone();
+            let index = started;
+            tasks.spawn(async move {
+                let outcome = match tokio::time::timeout(
+                    limits.peer_timeout,
+                    crawl_one(connecting, &config, limits.addr_wait, &cancel),
+                )
+                .await
+                {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(SurveyFailure::TimedOut(limits.peer_timeout)),
+                };
+                (index, address, depth, outcome)
+            });
+            started += 1;
+        }
+
+        let Some(joined) = tasks.join_next().await else {
+            break;
+        };
+        // A panicking task takes its address with it, so it can't be reported
+        let Ok((index, address, depth, outcome)) = joined else {
+            continue;
+        };
+        if let Err(SurveyFailure::Interrupted) = outcome {
+            continue;
+        }
+        if let Ok(CrawlSummary {
+            addresses: Ok(addresses),
+            ..
+        }) = &outcome
+        {
+            for known in addresses {
+                discovered.insert((known.host.clone(), known.port));
+                let Some(socket_address) = known.socket_address() else {
+                    continue;
+                };
+                if seen.insert(socket_address) && depth < limits.max_depth {
+                    queue.push_back((socket_address, depth + 1));
+                }
+            }
+        }
+        let crawled = CrawledPeer {
+            address,
+            depth,
+            outcome,
+        };
+        on_peer(&crawled);
+        peers.push((index, crawled));
+    }
+
+    peers.sort_by_key(|(index, _)| *index);
+    CrawlReport {
+        unvisited: seen.len() - peers.len(),
+        peers: peers.into_iter().map(|(_, crawled)| crawled).collect(),
+        discovered: discovered.len(),
+        interrupted: cancel.is_cancelled(),
+    }
+}
+
+async fn crawl_one<T, Fut>(
+    connecting: Fut,
+    config: &HandshakeConfig,
+    addr_wait: Duration,
+    cancel: &CancellationToken,
+) -> Result<CrawlSummary, SurveyFailure>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>>,
+{
+    let started = Instant::now();
+    let mut messaging_system = connect_until_cancelled(connecting, cancel).await?;
+    let connect_time = started.elapsed();
+    let outcome = match messaging_system.handshake(config).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            let _ = messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+            return Err(e.into());
+        }
+    };
+    let addresses = collect_addresses(&mut messaging_system, addr_wait).await;
+    let stats = messaging_system.stats();
+    let recorded = messaging_system.disconnect_reason();
+    let disconnect = match messaging_system.close(DEFAULT_CLOSE_GRACE).await {
+        Ok(report) => report.reason,
+        // Shutting down fails on a connection the peer already tore down
+        Err(e) => recorded.unwrap_or(DisconnectReason::Io(e.kind())),
+    };
+
+    Ok(CrawlSummary {
+        peer: PeerSummary::from_outcome(&outcome, connect_time),
+        addresses,
+        stats,
+        disconnect,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::ErrorKind, time::SystemTime};
+
+    use tokio::io::DuplexStream;
+
+    use crate::{
+        addr_payload::{AddrV2Entry, AddrV2Host, AddrV2Payload},
+        handshake::HandshakeError,
+        message::MessageType,
+        onion::OnionAddress,
+        services::ServiceFlags,
+        stats::CommandStats,
+    };
+
+    use super::*;
+
+    const LIMITS: CrawlLimits = CrawlLimits {
+        max_depth: 2,
+        max_peers: 100,
+        concurrency: 4,
+        peer_timeout: Duration::from_secs(30),
+        addr_wait: Duration::from_secs(5),
+    };
+
+    fn peer(i: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, i], 8333))
+    }
+
+    // Who knows whom: each peer answers getaddr with its neighbours, then hangs up
+    fn topology() -> HashMap<SocketAddr, Vec<AddrV2Host>> {
+        let onion: OnionAddress = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+            .parse()
+            .unwrap();
+        let ip = |i| AddrV2Host::Ip(peer(i).ip());
+        HashMap::from([
+            (peer(1), vec![ip(2), ip(3)]),
+            (peer(2), vec![ip(1), ip(4)]),
+            (peer(3), vec![ip(4), ip(5), AddrV2Host::TorV3(onion)]),
+            (peer(4), vec![ip(6)]),
+            (peer(6), vec![ip(1)]),
+        ])
+    }
+
+    fn mock_connect(
+        topology: &HashMap<SocketAddr, Vec<AddrV2Host>>,
+        address: SocketAddr,
+    ) -> Result<MessagingSystem<DuplexStream>, ConnectError> {
+        // Peers missing from the topology refuse connections
+        let Some(neighbours) = topology.get(&address).cloned() else {
+            return Err(std::io::Error::from(ErrorKind::ConnectionRefused).into());
+        };
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut theirs = MessagingSystem::from_stream(theirs, address);
+            let config = HandshakeConfig::default().user_agent(&format!("/peer:{}/", address.ip()));
+            if theirs.handshake(&config).await.is_err() {
+                return;
+            }
+            while let Ok(message) = theirs.receive_message().await {
+                if matches!(message, MessageType::GetAddr) {
+                    let entries = neighbours
+                        .iter()
+                        .map(|host| {
+                            AddrV2Entry::new(
+                                SystemTime::now(),
+                                host,
+                                8333,
+                                ServiceFlags::NODE_NETWORK,
+                            )
+                        })
+                        .collect();
+                    let _ = theirs.send_payload(AddrV2Payload::new(entries)).await;
+                    break;
+                }
+            }
+        });
+        Ok(MessagingSystem::from_stream(ours, address))
+    }
+
+    fn listing(report: &CrawlReport) -> Vec<(SocketAddr, usize, Option<usize>)> {
+        report
+            .peers
+            .iter()
+            .map(|crawled| {
+                let addresses = match &crawled.outcome {
+                    Ok(summary) => Some(summary.addresses.as_ref().map_or(0, Vec::len)),
+                    Err(_) => None,
+                };
+                (crawled.address, crawled.depth, addresses)
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crawl_follows_topology_to_max_depth() {
+        let topology = topology();
+        let report = crawl(
+            vec![peer(1)],
+            &HandshakeConfig::default(),
+            LIMITS,
+            |address| std::future::ready(mock_connect(&topology, address)),
+        )
+        .await;
+
+        assert_eq!(
+            listing(&report),
+            [
+                (peer(1), 0, Some(2)),
+                (peer(2), 1, Some(2)),
+                (peer(3), 1, Some(3)),
+                (peer(4), 2, Some(1)),
+                (peer(5), 2, None),
+            ],
+            "{report:#?}",
+        );
+        // Peer 6 is a hop too far, and the onion address can't be connected to
+        assert_eq!(report.unvisited, 1);
+        assert_eq!(report.discovered, 7);
+        assert_eq!(report.reachable(), 4);
+        // One getaddr to each reachable peer, and an addrv2 back from each
+        let stats = report.stats();
+        assert_eq!(
+            stats.sent.commands.last().unwrap(),
+            &(
+                "getaddr".to_owned(),
+                CommandStats {
+                    messages: 4,
+                    bytes: 4 * 24
+                }
+            )
+        );
+        assert!(stats
+            .received
+            .commands
+            .iter()
+            .any(|(command, counted)| command == "addrv2" && counted.messages == 4));
+
+        let Ok(summary) = &report.peers[2].outcome else {
+            panic!("expected peer 3 to be reachable");
+        };
+        assert_eq!(summary.peer.user_agent, "/peer:192.0.2.3/");
+        assert!(matches!(
+            &report.peers[4].outcome,
+            Err(SurveyFailure::Handshake(HandshakeError::Connect(ConnectError::Io(e))))
+                if e.kind() == ErrorKind::ConnectionRefused,
+        ));
+        // Every reachable peer hangs up once it has answered getaddr
+        let reasons: Vec<_> = report
+            .peers
+            .iter()
+            .map(CrawledPeer::disconnect_reason)
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                DisconnectReason::PeerClosed,
+                DisconnectReason::PeerClosed,
+                DisconnectReason::PeerClosed,
+                DisconnectReason::PeerClosed,
+                DisconnectReason::ConnectFailed,
+            ],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crawl_stops_at_max_peers() {
+        let topology = topology();
+        let limits = CrawlLimits {
+            max_depth: 10,
+            max_peers: 3,
+            concurrency: 1,
+            ..LIMITS
+        };
+        let report = crawl(
+            vec![peer(1), peer(1)],
+            &HandshakeConfig::default(),
+            limits,
+            |address| std::future::ready(mock_connect(&topology, address)),
+        )
+        .await;
+
+        let crawled: Vec<_> = report.peers.iter().map(|crawled| crawled.address).collect();
+        assert_eq!(crawled, [peer(1), peer(2), peer(3)]);
+        // 4 and 5 were queued, but never connected to
+        assert_eq!(report.unvisited, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crawl_interrupted_keeps_finished_peers() {
+        let topology = topology();
+        let cancel = CancellationToken::new();
+        let report = crawl_with_progress(
+            vec![peer(9), peer(1)],
+            &HandshakeConfig::default(),
+            CrawlLimits {
+                concurrency: 2,
+                ..LIMITS
+            },
+            |address| {
+                let connected = mock_connect(&topology, address);
+                async move {
+                    if address == peer(9) {
+                        std::future::pending::<()>().await;
+                    }
+                    connected
+                }
+            },
+            |_| cancel.cancel(),
+            &cancel,
+        )
+        .await;
+
+        // Peer 9 was still connecting, and peer 1's neighbours were never started
+        assert_eq!(listing(&report), [(peer(1), 0, Some(2))], "{report:#?}");
+        assert!(report.interrupted);
+        assert_eq!(report.unvisited, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crawl_gives_up_on_stuck_peers() {
+        let topology = topology();
+        let report = crawl(
+            vec![peer(9), peer(1)],
+            &HandshakeConfig::default(),
+            CrawlLimits {
+                max_depth: 0,
+                ..LIMITS
+            },
+            |address| {
+                let connected = mock_connect(&topology, address);
+                async move {
+                    if address == peer(9) {
+                        std::future::pending::<()>().await;
+                    }
+                    connected
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(
+            listing(&report),
+            [(peer(9), 0, None), (peer(1), 0, Some(2))]
+        );
+        assert!(matches!(
+            report.peers[0].outcome,
+            Err(SurveyFailure::TimedOut(timeout)) if timeout == LIMITS.peer_timeout,
+        ));
+        assert_eq!(report.unvisited, 2);
+    }
+}
@@ -0,0 +1,352 @@
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    command::{command_name, requires_empty_payload},
+    command_registry::CommandRegistry,
+    header::Header,
+    hexdump::format_frame,
+    log::event,
+    message::{
+        parse_message_with_options, skip_to_magic, warn_checksum, MessageParseError, MessageType,
+        ParseOptions, ParsedMessage,
+    },
+    network::Network,
+    protocol::{MAX_MESSAGE_SIZE, MAX_SIZE},
+    recording::Direction,
+    stats::SessionStats,
+    utils::DoubleSha256,
+};
+
+// How much of a payload is read at a time
+const RECEIVE_CHUNK_SIZE: usize = 64 * 1024;
+
+// Enough for one maximum-size message and its header, with room to spare
+pub const DEFAULT_MAX_BUFFER_BYTES: usize = 5_000_000;
+
+// Where the decoder is within the current frame
+#[derive(Debug)]
+enum DecoderState {
+    AwaitingHeader,
+    AwaitingPayload {
+        header: Header,
+        hasher: DoubleSha256,
+        hashed: usize,
+    },
+}
+
+// Accepts bytes as they arrive and yields messages, parsing each header exactly once and hashing
+// the payload as it comes in rather than reparsing the whole buffer on every read
+#[derive(Debug)]
+pub struct MessageDecoder {
+    pub network: Network,
+    pub max_message_size: u32,
+    // Skip stray bytes up to the next network magic instead of failing
+    pub resync: bool,
+    // Dump each complete frame to stderr as annotated hex, whether or not it parses
+    pub trace_wire: bool,
+    buffer: BytesMut,
+    state: DecoderState,
+    registry: Option<CommandRegistry>,
+    skipped_bytes: usize,
+    checksum_validations: usize,
+    stats: SessionStats,
+}
+
+impl MessageDecoder {
+    pub fn new(network: Network, registry: Option<CommandRegistry>) -> Self {
+        Self {
+            network,
+            max_message_size: MAX_MESSAGE_SIZE,
+            resync: false,
+            trace_wire: false,
+            buffer: BytesMut::new(),
+            state: DecoderState::AwaitingHeader,
+            registry,
+            skipped_bytes: 0,
+            checksum_validations: 0,
+            stats: SessionStats::default(),
+        }
+    }
+
+    // For bytes that arrive some other way than through `read_from`
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    // Reads straight into the buffer, but never past what the current header or frame still needs
+    pub async fn read_from<R>(&mut self, reader: &mut R) -> std::io::Result<usize>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let missing_bytes = self.missing_bytes().min(RECEIVE_CHUNK_SIZE);
+        self.buffer.reserve(missing_bytes);
+        reader
+            .take(missing_bytes as u64)
+            .read_buf(&mut self.buffer)
+            .await
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // Stray bytes discarded while resynchronizing on the network magic
+    pub fn skipped_bytes(&self) -> usize {
+        self.skipped_bytes
+    }
+
+    // Every complete frame read so far, whether or not it parsed; nothing sent is counted here
+    pub fn stats(&self) -> &SessionStats {
+        &self.stats
+    }
+
+    // How many more bytes the current header or frame needs before the decoder can make progress
+    pub fn missing_bytes(&self) -> usize {
+        let needed = match &self.state {
+            DecoderState::AwaitingHeader => Header::HEADER_BYTE_SIZE,
+            DecoderState::AwaitingPayload { header, .. } => {
+                Header::HEADER_BYTE_SIZE + header.payload_size() as usize
+            }
+        };
+        needed.saturating_sub(self.buffer.len()).max(1)
+    }
+
+    // Returns `Ok(None)` until a whole frame has been pushed
+    pub fn next_message(&mut self) -> Result<Option<ParsedMessage>, MessageParseError> {
+        loop {
+            match &mut self.state {
+                DecoderState::AwaitingHeader => {
+                    let header = match Header::peek(&self.buffer) {
+                        Ok(peeked) => peeked.into_header(),
+                        Err(MessageParseError::NotEnoughData) => return Ok(None),
+                        Err(e) => return Err(e),
+                    };
+                    self.check_header(&header)?;
+                    if header.magic() != self.network.magic() {
+                        // Only reachable while resynchronizing
+                        continue;
+                    }
+                    self.state = DecoderState::AwaitingPayload {
+                        header,
+                        hasher: DoubleSha256::new(),
+                        hashed: 0,
+                    };
+                }
+                DecoderState::AwaitingPayload {
+                    header,
+                    hasher,
+                    hashed,
+                } => {
+                    let frame_len = Header::HEADER_BYTE_SIZE + header.payload_size() as usize;
+                    let available = self.buffer.len().min(frame_len);
+                    hasher.update(&self.buffer[Header::HEADER_BYTE_SIZE + *hashed..available]);
+                    *hashed = available - Header::HEADER_BYTE_SIZE;
+                    if available < frame_len {
+                        return Ok(None);
+                    }
+
+                    let DecoderState::AwaitingPayload { header, hasher, .. } =
+                        std::mem::replace(&mut self.state, DecoderState::AwaitingHeader)
+                    else {
+                        unreachable!("the decoder was awaiting a payload");
+                    };
+                    return self.finish_frame(&header, hasher, frame_len).map(Some);
+                }
+            }
+        }
+    }
+
+    // Everything that can be rejected from the header alone, before any payload is buffered
+    fn check_header(&mut self, header: &Header) -> Result<(), MessageParseError> {
+        if header.magic() != self.network.magic() {
+            if !self.resync {
+                return Err(MessageParseError::MissingMagicNumber);
+            }
+            // Drop at least the first byte, so the mismatched magic itself is never retried
+            let skipped = skip_to_magic(self.network, &self.buffer[1..]) + 1;
+            self.buffer.advance(skipped);
+            self.skipped_bytes += skipped;
+            event!(
+                Warn,
+                "skipped {skipped} byte(s) looking for the network magic"
+            );
+            return Ok(());
+        }
+        if header.payload_size() > self.max_message_size.min(MAX_SIZE) {
+            return Err(MessageParseError::OversizedPayload {
+                command: header.command_raw(),
+                length: header.payload_size(),
+            });
+        }
+        if header.payload_size() != 0 && requires_empty_payload(&header.command_raw()) {
+            return Err(MessageParseError::MalformedData(None));
+        }
+        Ok(())
+    }
+
+    fn finish_frame(
+        &mut self,
+        header: &Header,
+        hasher: DoubleSha256,
+        frame_len: usize,
+    ) -> Result<ParsedMessage, MessageParseError> {
+        self.checksum_validations += 1;
+        if self.trace_wire {
+            let payload = &self.buffer[Header::HEADER_BYTE_SIZE..frame_len];
+            eprint!("{}", format_frame(Direction::Received, header, payload));
+        }
+        let checksum = header
+            .validate_digest(&hasher.finalize())
+            .inspect_err(|e| warn_checksum(header, e));
+        self.stats.record_received(
+            &command_name(&header.command_raw()),
+            frame_len - Header::HEADER_BYTE_SIZE,
+        );
+        if checksum.is_err() {
+            self.stats.checksum_failures += 1;
+        }
+
+        let options = ParseOptions {
+            registry: self.registry.as_ref(),
+            max_message_size: self.max_message_size,
+            checksum_verified: true,
+        };
+        let result = checksum.map_err(MessageParseError::from).and_then(|()| {
+            parse_message_with_options(self.network, &options, &self.buffer[..frame_len])
+        });
+
+        if let Ok((parsed, _)) = &result {
+            if let MessageType::Unknown { .. } = parsed.message() {
+                self.stats.unknown_commands += 1;
+            }
+        }
+
+        // The frame is consumed whether or not it parsed, so the next one starts cleanly
+        self.buffer.advance(frame_len);
+        result.map(|(parsed, _)| parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        message::{prepare_message, MessageType},
+        verack_payload::VerackPayload,
+        version_payload::VersionPayload,
+    };
+
+    use super::*;
+
+    fn next(decoder: &mut MessageDecoder) -> Result<Option<MessageType>, MessageParseError> {
+        Ok(decoder.next_message()?.map(ParsedMessage::into_message))
+    }
+
+    #[test]
+    fn test_version_frame_one_byte_at_a_time() {
+        let frame = prepare_message(
+            Network::Mainnet,
+            VersionPayload::builder().nonce(7).build().unwrap(),
+        )
+        .unwrap();
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+
+        for &byte in &frame[..frame.len() - 1] {
+            decoder.push(&[byte]);
+            assert!(next(&mut decoder).unwrap().is_none());
+        }
+        assert_eq!(decoder.missing_bytes(), 1);
+        assert_eq!(decoder.checksum_validations, 0);
+
+        decoder.push(&frame[frame.len() - 1..]);
+        match next(&mut decoder).unwrap() {
+            Some(MessageType::Version(version_payload)) => {
+                assert_eq!(version_payload.nonce(), 7)
+            }
+            message => panic!("expected a version message, got {message:?}"),
+        }
+        assert_eq!(decoder.checksum_validations, 1);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn test_back_to_back_frames() {
+        let mut data = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        data.extend(prepare_message(Network::Mainnet, VerackPayload).unwrap());
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+
+        decoder.push(&data);
+        assert!(matches!(next(&mut decoder), Ok(Some(MessageType::Verack))));
+        assert!(matches!(next(&mut decoder), Ok(Some(MessageType::Verack))));
+        assert!(matches!(next(&mut decoder), Ok(None)));
+        assert_eq!(decoder.missing_bytes(), Header::HEADER_BYTE_SIZE);
+    }
+
+    #[test]
+    fn test_incorrect_checksum() {
+        let data = hex::decode("F9BEB4D976657261636B00000000000000000000DEADBEEF").unwrap();
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+
+        decoder.push(&data);
+        assert!(matches!(
+            next(&mut decoder),
+            Err(MessageParseError::IncorrectChecksum { .. }),
+        ));
+    }
+
+    #[test]
+    fn test_resync() {
+        let mut data = hex::decode("00F9BEB40102").unwrap();
+        data.extend(prepare_message(Network::Mainnet, VerackPayload).unwrap());
+
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+        decoder.push(&data);
+        assert!(matches!(
+            next(&mut decoder),
+            Err(MessageParseError::MissingMagicNumber),
+        ));
+
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+        decoder.resync = true;
+        decoder.push(&data);
+        assert!(matches!(next(&mut decoder), Ok(Some(MessageType::Verack))));
+        assert_eq!(decoder.skipped_bytes(), 6);
+    }
+
+    #[test]
+    fn test_many_back_to_back_frames() {
+        const FRAMES: usize = 1_000;
+
+        // Legacy alerts are the only frames we can build with an arbitrary payload
+        let payload = [0xA5; 1024];
+        let mut alert = hex::decode("F9BEB4D9616C6572740000000000000000040000").unwrap();
+        alert.extend(&crate::utils::double_sha256_hash(&payload)[..4]);
+        alert.extend(payload);
+        let data = alert.repeat(FRAMES);
+
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+        decoder.push(&data);
+        let mut decoded = 0;
+        while let Some(message) = next(&mut decoder).unwrap() {
+            assert!(matches!(message, MessageType::Alert(_)));
+            decoded += 1;
+        }
+        assert_eq!(decoded, FRAMES);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_from_stops_at_frame_boundary() {
+        let mut data = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        data.extend(prepare_message(Network::Mainnet, VerackPayload).unwrap());
+        let mut reader = &data[..];
+
+        let mut decoder = MessageDecoder::new(Network::Mainnet, None);
+        assert_eq!(
+            decoder.read_from(&mut reader).await.unwrap(),
+            Header::HEADER_BYTE_SIZE
+        );
+        assert!(matches!(next(&mut decoder), Ok(Some(MessageType::Verack))));
+        assert_eq!(reader.len(), Header::HEADER_BYTE_SIZE);
+    }
+}
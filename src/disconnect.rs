@@ -0,0 +1,112 @@
+use std::io::ErrorKind;
+
+use crate::command::Command;
+
+// Why a connection ended, or would end on the error just seen. Displays as a short token, such
+// as `peer_closed` or `protocol_violation:unexpected_verack`, for CSV and JSON
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // We were done with the peer and hung up
+    #[default]
+    Completed,
+    PeerClosed,
+    // There was never a connection to end
+    ConnectFailed,
+    ReadTimeout,
+    WriteTimeout,
+    HandshakeTimeout,
+    ProtocolViolation(Violation),
+    ChecksumMismatch,
+    // A frame, or the buffer it was arriving into, over the limit
+    OversizedMessage,
+    // The peer spoke properly, but not in a way we accept
+    Rejected(Rejection),
+    Cancelled,
+    // The task handling the peer died
+    Aborted,
+    Io(ErrorKind),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    BadMagic,
+    MalformedPayload,
+    UnexpectedMessage(Command),
+    // Ours rather than the peer's: a message we couldn't build, or refused to send at that point
+    InvalidOutgoing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    ConnectedToSelf,
+    ObsoletePeer,
+    MissingServices,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Completed => write!(f, "completed"),
+            Self::PeerClosed => write!(f, "peer_closed"),
+            Self::ConnectFailed => write!(f, "connect_failed"),
+            Self::ReadTimeout => write!(f, "read_timeout"),
+            Self::WriteTimeout => write!(f, "write_timeout"),
+            Self::HandshakeTimeout => write!(f, "handshake_timeout"),
+            Self::ProtocolViolation(violation) => write!(f, "protocol_violation:{violation}"),
+            Self::ChecksumMismatch => write!(f, "checksum_mismatch"),
+            Self::OversizedMessage => write!(f, "oversized_message"),
+            Self::Rejected(rejection) => write!(f, "rejected:{rejection}"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Aborted => write!(f, "aborted"),
+            Self::Io(kind) => write!(f, "io_error:{}", kind.to_string().replace(' ', "_")),
+        }
+    }
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "bad_magic"),
+            Self::MalformedPayload => write!(f, "malformed_payload"),
+            Self::UnexpectedMessage(command) => write!(f, "unexpected_{command}"),
+            Self::InvalidOutgoing => write!(f, "invalid_outgoing"),
+        }
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConnectedToSelf => write!(f, "connected_to_self"),
+            Self::ObsoletePeer => write!(f, "obsolete_peer"),
+            Self::MissingServices => write!(f, "missing_services"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        for (reason, expected) in [
+            (DisconnectReason::Completed, "completed"),
+            (DisconnectReason::PeerClosed, "peer_closed"),
+            (
+                DisconnectReason::ProtocolViolation(Violation::UnexpectedMessage(Command::Verack)),
+                "protocol_violation:unexpected_verack",
+            ),
+            (
+                DisconnectReason::Rejected(Rejection::ObsoletePeer),
+                "rejected:obsolete_peer",
+            ),
+            (
+                DisconnectReason::Io(ErrorKind::ConnectionReset),
+                "io_error:connection_reset",
+            ),
+        ] {
+            assert_eq!(reason.to_string(), expected);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+// What the binary exits with, so scripts can tell the kinds of failure apart
+pub const SUCCESS: i32 = 0;
+// Anything outside the categories below, like invalid flags or an unreadable file
+pub const FAILURE: i32 = 1;
+// The peer refused the connection, couldn't be reached, or didn't answer in time
+pub const CONNECTION_FAILED: i32 = 2;
+// The peer sent something malformed, or broke the handshake's rules
+pub const PROTOCOL_ERROR: i32 = 3;
+// The peer spoke the protocol, but we can't use it: too old, or missing services we need
+pub const PEER_REJECTED: i32 = 4;
+// The connection failed after it was made
+pub const SESSION_IO: i32 = 5;
+// Stopped by Ctrl-C or SIGTERM, after reporting whatever had finished; 128 + SIGINT, as shells do
+pub const INTERRUPTED: i32 = 130;
+
+// A few words on what went wrong, for the last line printed before exiting
+pub fn describe(code: i32) -> &'static str {
+    match code {
+        SUCCESS => "success",
+        CONNECTION_FAILED => "connection failed",
+        PROTOCOL_ERROR => "protocol error",
+        PEER_REJECTED => "peer incompatible",
+        SESSION_IO => "connection lost",
+        INTERRUPTED => "interrupted",
+        _ => "failure",
+    }
+}
@@ -0,0 +1,411 @@
+use std::{future::Future, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Instant,
+};
+
+use crate::{
+    command::Command,
+    connect::ConnectError,
+    disconnect::{DisconnectReason, Violation},
+    exit_code,
+    handshake_config::{HandshakeConfig, HandshakeConfigError},
+    log::{error_chain, event},
+    message::MessageType,
+    messaging_system::{
+        MessageReceiveError, MessageSendError, MessagingSystem, DEFAULT_CLOSE_GRACE,
+    },
+    seeds::{self, SeedError},
+    user_agent::UserAgentError,
+    version_payload::VersionPayload,
+};
+
+#[derive(Debug)]
+pub struct HandshakeOutcome {
+    pub peer_version: VersionPayload,
+    pub negotiated_version: i32,
+    // Seconds the peer's clock is ahead of ours, when it sent a usable timestamp
+    pub clock_skew: Option<i64>,
+    pub timings: HandshakeTimings,
+    // Everything else the peer sent before the handshake completed, in arrival order, pings
+    // included even though they were already answered
+    pub other_messages: Vec<MessageType>,
+}
+
+// Where the time went, for a handshake that finished
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeTimings {
+    // Opening the connection, retries included; unknown for a transport we were handed
+    pub connect: Option<Duration>,
+    // From sending our version to receiving the peer's, which is left out when the peer's came
+    // first because it opened the connection
+    pub version_rtt: Option<Duration>,
+    // From sending our version to receiving the peer's verack, however late its version was
+    pub verack: Duration,
+    // From sending our version, or waiting for the peer's when it opened the connection, to
+    // receiving the peer's verack
+    pub total: Duration,
+}
+
+impl std::fmt::Display for HandshakeTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(connect) = self.connect {
+            write!(f, "connect {connect:.0?}, ")?;
+        }
+        if let Some(version_rtt) = self.version_rtt {
+            write!(f, "version RTT {version_rtt:.0?}, ")?;
+        }
+        write!(f, "verack {:.0?}, total {:.0?}", self.verack, self.total)
+    }
+}
+
+impl<T> MessagingSystem<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // Exchanges version and verack messages, accepting the peer's in either order
+    pub async fn handshake(
+        &mut self,
+        config: &HandshakeConfig,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        self.run_handshake(config, Role::Initiator).await
+    }
+
+    // The other side of `handshake`, for connections the peer opened: waits for its version
+    // before sending ours and our verack
+    pub async fn accept_handshake(
+        &mut self,
+        config: &HandshakeConfig,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        self.run_handshake(config, Role::Responder).await
+    }
+
+    async fn run_handshake(
+        &mut self,
+        config: &HandshakeConfig,
+        role: Role,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        let result = self.exchange_versions(config, role).await;
+        if let Err(e) = &result {
+            self.note_disconnect(e.disconnect_reason());
+        }
+        result
+    }
+
+    async fn exchange_versions(
+        &mut self,
+        config: &HandshakeConfig,
+        role: Role,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        config.validate()?;
+        self.set_config(config.clone());
+
+        let mut step = match role {
+            Role::Initiator => HandshakeStep::SendingVersion,
+            Role::Responder => HandshakeStep::AwaitingVersion,
+        };
+        let timeout = config.handshake_timeout;
+        let remote_addr = self.remote_addr();
+        event!(Debug, "handshake with {remote_addr} started");
+        let result =
+            match tokio::time::timeout(timeout, self.drive_handshake(role, &mut step)).await {
+                Ok(result) => result,
+                Err(_) => Err(HandshakeError::Timeout { step, timeout }),
+            };
+        match &result {
+            Ok(outcome) => event!(
+                Info,
+                "handshake with {remote_addr} done in {:?}, protocol version {}",
+                outcome.timings.total,
+                outcome.negotiated_version,
+            ),
+            // Asked for, so not worth more than a note
+            Err(e) if e.is_interrupted() => {
+                event!(Info, "handshake with {remote_addr} interrupted")
+            }
+            Err(e) => event!(
+                Error,
+                "handshake with {remote_addr} failed: {}",
+                error_chain(e)
+            ),
+        }
+        if result.is_err() {
+            // Nothing more will be said on a failed handshake, so let the peer know straight away
+            // rather than leaving it to whenever the connection is dropped
+            let _ = self.shutdown().await;
+        }
+        result
+    }
+
+    // Keeps `step` up to date so a timeout can say what was still pending
+    async fn drive_handshake(
+        &mut self,
+        role: Role,
+        step: &mut HandshakeStep,
+    ) -> Result<HandshakeOutcome, HandshakeError> {
+        let started = Instant::now();
+        let mut version_sent = None;
+        if role == Role::Initiator {
+            version_sent = Some(Instant::now());
+            self.send_message(Command::Version).await?;
+        }
+
+        let mut timings = HandshakeTimings {
+            connect: self.connect_time(),
+            ..HandshakeTimings::default()
+        };
+        let mut peer_version = None;
+        let mut got_verack = false;
+        let mut other_messages = Vec::new();
+        while peer_version.is_none() || !got_verack {
+            *step = if peer_version.is_none() {
+                HandshakeStep::AwaitingVersion
+            } else {
+                HandshakeStep::AwaitingVerack
+            };
+            match self.receive_message().await? {
+                // Either may come first, but a second copy of either is a protocol violation
+                MessageType::Version(_) if peer_version.is_some() => {
+                    return Err(HandshakeError::UnexpectedMessage(Command::Version))
+                }
+                MessageType::Verack if got_verack => {
+                    return Err(HandshakeError::UnexpectedMessage(Command::Verack))
+                }
+                // Nor can the peer acknowledge a version we haven't sent
+                MessageType::Verack if role == Role::Responder && peer_version.is_none() => {
+                    return Err(HandshakeError::UnexpectedMessage(Command::Verack))
+                }
+                MessageType::Version(version_payload) => {
+                    timings.version_rtt = version_sent.map(|sent| sent.elapsed());
+                    peer_version = Some(version_payload);
+                    if role == Role::Responder {
+                        *step = HandshakeStep::SendingVersion;
+                        version_sent = Some(Instant::now());
+                        self.send_message(Command::Version).await?;
+                    }
+
+                    // Acknowledge right away, like Bitcoin Core, rather than waiting on the
+                    // peer's verack, which may itself be waiting on ours. Announce wtxid relay
+                    // first, since it must precede our verack
+                    *step = HandshakeStep::SendingVerack;
+                    if self.should_send_wtxidrelay() {
+                        self.send_message(Command::WtxidRelay).await?;
+                    }
+                    self.send_message(Command::Verack).await?;
+                }
+                MessageType::Verack => {
+                    // A responder always has, since a verack ahead of the version is refused
+                    timings.verack = version_sent.unwrap_or(started).elapsed();
+                    got_verack = true;
+                }
+                // Peers may ping before the handshake is done, and drop us if we never answer
+                MessageType::Ping(ping_payload) => {
+                    self.send_pong(ping_payload.nonce()).await?;
+                    other_messages.push(MessageType::Ping(ping_payload));
+                }
+                message => other_messages.push(message),
+            }
+        }
+        let peer_version = peer_version.expect("the loop only ends once a version arrived");
+        let clock_skew = peer_version.clock_skew(self.config().now());
+        timings.total = started.elapsed();
+
+        Ok(HandshakeOutcome {
+            negotiated_version: self
+                .negotiated_version()
+                .expect("the peer version was received"),
+            peer_version,
+            clock_skew,
+            timings,
+            other_messages,
+        })
+    }
+}
+
+// Connects to each candidate in order until a handshake succeeds, reporting the failures along
+// the way and closing each failed connection before moving on
+pub async fn handshake_any<A, T, C, Fut>(
+    candidates: Vec<A>,
+    config: &HandshakeConfig,
+    mut connect: C,
+) -> Result<(A, MessagingSystem<T>, HandshakeOutcome), SeedError>
+where
+    A: Clone + std::fmt::Display,
+    T: AsyncRead + AsyncWrite + Unpin,
+    C: FnMut(A) -> Fut,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>>,
+{
+    let max_attempts = candidates.len();
+    let (candidate, (messaging_system, outcome)) =
+        seeds::try_candidates(candidates, max_attempts, |candidate| {
+            connect_and_handshake(connect(candidate), config)
+        })
+        .await?;
+    Ok((candidate, messaging_system, outcome))
+}
+
+// Handshakes over the connection once it's made, closing it again if the handshake fails
+pub async fn connect_and_handshake<T, Fut>(
+    connecting: Fut,
+    config: &HandshakeConfig,
+) -> Result<(MessagingSystem<T>, HandshakeOutcome), HandshakeError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>>,
+{
+    let mut messaging_system = connecting.await.map_err(HandshakeError::Connect)?;
+    match messaging_system.handshake(config).await {
+        Ok(outcome) => Ok((messaging_system, outcome)),
+        Err(e) => {
+            let _ = messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+            Err(e)
+        }
+    }
+}
+
+// Which side opened the connection, and so speaks first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Initiator,
+    Responder,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStep {
+    SendingVersion,
+    AwaitingVersion,
+    AwaitingVerack,
+    SendingVerack,
+}
+
+impl std::fmt::Display for HandshakeStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SendingVersion => write!(f, "sending version"),
+            Self::AwaitingVersion => write!(f, "awaiting version"),
+            Self::AwaitingVerack => write!(f, "awaiting verack"),
+            Self::SendingVerack => write!(f, "sending verack"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    Connect(ConnectError),
+    InvalidConfig(HandshakeConfigError),
+    Send(MessageSendError),
+    Receive(MessageReceiveError),
+    UnexpectedMessage(Command),
+    Timeout {
+        step: HandshakeStep,
+        timeout: Duration,
+    },
+}
+
+impl HandshakeError {
+    // Which of the binary's exit codes this failure calls for
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            _ if self.is_interrupted() => exit_code::INTERRUPTED,
+            Self::Connect(_) => exit_code::CONNECTION_FAILED,
+            Self::InvalidConfig(_) => exit_code::FAILURE,
+            // A peer that goes quiet before the handshake is done never really answered, whichever
+            // timer gives up on it first
+            Self::Timeout { .. }
+            | Self::Send(MessageSendError::Timeout { .. })
+            | Self::Receive(MessageReceiveError::Timeout { .. }) => exit_code::CONNECTION_FAILED,
+            Self::Send(e) => e.exit_code(),
+            Self::Receive(e) => e.exit_code(),
+            Self::UnexpectedMessage(_) => exit_code::PROTOCOL_ERROR,
+        }
+    }
+
+    // A stable name for the kind of failure, for machine-readable output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            _ if self.is_interrupted() => "interrupted",
+            Self::Connect(_) => "connect",
+            Self::InvalidConfig(_) => "invalid_config",
+            Self::Send(_) => "send",
+            Self::Receive(_) => "receive",
+            Self::UnexpectedMessage(_) => "unexpected_message",
+            Self::Timeout { .. } => "timeout",
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Connect(e) => e.disconnect_reason(),
+            Self::InvalidConfig(_) => {
+                DisconnectReason::ProtocolViolation(Violation::InvalidOutgoing)
+            }
+            Self::Send(e) => e.disconnect_reason(),
+            Self::Receive(e) => e.disconnect_reason(),
+            Self::UnexpectedMessage(command) => {
+                DisconnectReason::ProtocolViolation(Violation::UnexpectedMessage(*command))
+            }
+            Self::Timeout { .. } => DisconnectReason::HandshakeTimeout,
+        }
+    }
+
+    // Whether we gave up on the handshake ourselves, rather than the peer failing it
+    pub fn is_interrupted(&self) -> bool {
+        matches!(
+            self,
+            Self::Connect(ConnectError::Interrupted)
+                | Self::Receive(MessageReceiveError::Interrupted)
+        )
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connect(e) => write!(f, "could not connect: {e}"),
+            Self::InvalidConfig(e) => e.fmt(f),
+            Self::Send(e) => e.fmt(f),
+            Self::Receive(e) => e.fmt(f),
+            Self::UnexpectedMessage(command) => {
+                write!(f, "unexpectedly received {command} message")
+            }
+            Self::Timeout { step, timeout } => {
+                write!(f, "handshake did not finish within {timeout:?} ({step})")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e) => Some(e),
+            Self::Receive(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<HandshakeConfigError> for HandshakeError {
+    fn from(value: HandshakeConfigError) -> Self {
+        Self::InvalidConfig(value)
+    }
+}
+
+impl From<UserAgentError> for HandshakeError {
+    fn from(value: UserAgentError) -> Self {
+        Self::InvalidConfig(value.into())
+    }
+}
+
+impl From<MessageSendError> for HandshakeError {
+    fn from(value: MessageSendError) -> Self {
+        Self::Send(value)
+    }
+}
+
+impl From<MessageReceiveError> for HandshakeError {
+    fn from(value: MessageReceiveError) -> Self {
+        Self::Receive(value)
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use rand::{rngs::OsRng, RngCore};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    network::Network,
+    protocol::{MIN_PEER_PROTO_VERSION, PROTOCOL_VERSION, WTXID_RELAY_VERSION},
+    services::ServiceFlags,
+    user_agent::{default_agent, validate_user_agent, UserAgentError},
+    version_payload::{VersionPayload, VersionPayloadBuildError},
+};
+
+// Bounds the whole version/verack exchange, however chatty the peer is in the meantime
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Everything we advertise or demand during a handshake, in one place
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub(crate) network: Network,
+    pub(crate) user_agent: String,
+    pub(crate) services: ServiceFlags,
+    pub(crate) relay: Option<bool>,
+    pub(crate) start_height: i32,
+    pub(crate) protocol_version: i32,
+    pub(crate) min_peer_version: i32,
+    pub(crate) required_services: ServiceFlags,
+    pub(crate) wtxidrelay: bool,
+    pub(crate) handshake_timeout: Duration,
+    // Stamps our version message and judges the peer's clock skew
+    pub(crate) clock: Arc<dyn Clock>,
+    // Draws the nonces for our version and pings
+    rng: NonceSource,
+}
+
+// Shared between clones, so a seeded sequence carries on wherever the config was copied to
+#[derive(Clone)]
+struct NonceSource(Arc<Mutex<dyn RngCore + Send>>);
+
+impl std::fmt::Debug for NonceSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("NonceSource")
+    }
+}
+
+impl HandshakeConfig {
+    pub fn new() -> Self {
+        Self {
+            network: Network::Mainnet,
+            user_agent: default_agent(),
+            services: ServiceFlags::NONE,
+            relay: None,
+            start_height: 0,
+            protocol_version: PROTOCOL_VERSION,
+            min_peer_version: MIN_PEER_PROTO_VERSION,
+            required_services: ServiceFlags::NONE,
+            wtxidrelay: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            clock: Arc::new(SystemClock),
+            rng: NonceSource(Arc::new(Mutex::new(OsRng))),
+        }
+    }
+
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    // The services we advertise
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    pub fn relay(mut self, relay: Option<bool>) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    pub fn start_height(mut self, start_height: i32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    pub fn protocol_version(mut self, protocol_version: i32) -> Self {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    pub fn min_peer_version(mut self, min_peer_version: i32) -> Self {
+        self.min_peer_version = min_peer_version;
+        self
+    }
+
+    // The services a peer must advertise for the handshake to go ahead
+    pub fn required_services(mut self, required_services: ServiceFlags) -> Self {
+        self.required_services = required_services;
+        self
+    }
+
+    // Announce BIP 339 wtxid relay when the negotiated version allows it
+    pub fn wtxidrelay(mut self, wtxidrelay: bool) -> Self {
+        self.wtxidrelay = wtxidrelay;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    // Seed one, e.g. `StdRng::seed_from_u64`, for nonces that come out the same every run
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = NonceSource(Arc::new(Mutex::new(rng)));
+        self
+    }
+
+    pub fn random_nonce(&self) -> u64 {
+        self.rng
+            .0
+            .lock()
+            .expect("nonce source lock poisoned")
+            .next_u64()
+    }
+
+    pub fn validate(&self) -> Result<(), HandshakeConfigError> {
+        validate_user_agent(&self.user_agent)?;
+        if self.handshake_timeout.is_zero() {
+            return Err(HandshakeConfigError::ZeroTimeout);
+        }
+        Ok(())
+    }
+
+    pub fn should_send_wtxidrelay(&self, negotiated_version: i32) -> bool {
+        self.wtxidrelay && negotiated_version >= WTXID_RELAY_VERSION
+    }
+
+    pub fn version_payload(
+        &self,
+        addr_recv: SocketAddr,
+        addr_from: Option<SocketAddr>,
+        nonce: u64,
+        timestamp: SystemTime,
+    ) -> Result<VersionPayload, VersionPayloadBuildError> {
+        let mut builder = VersionPayload::builder();
+        if let Some(addr_from) = addr_from {
+            builder = builder.addr_from(addr_from);
+        }
+        builder
+            .version(self.protocol_version)
+            .services(self.services)
+            .timestamp(timestamp)
+            .addr_recv(addr_recv)
+            .nonce(nonce)
+            .user_agent(&self.user_agent)
+            .start_height(self.start_height)
+            .relay(self.relay)
+            .build()
+    }
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum HandshakeConfigError {
+    InvalidUserAgent(UserAgentError),
+    ZeroTimeout,
+}
+
+impl std::fmt::Display for HandshakeConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUserAgent(e) => e.fmt(f),
+            Self::ZeroTimeout => write!(f, "the handshake timeout must be positive"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeConfigError {}
+
+impl From<UserAgentError> for HandshakeConfigError {
+    fn from(value: UserAgentError) -> Self {
+        Self::InvalidUserAgent(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::message::prepare_message;
+
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(HandshakeConfig::default().validate().is_ok());
+
+        let config = HandshakeConfig::default().user_agent(&"a".repeat(257));
+        assert!(matches!(
+            config.validate(),
+            Err(HandshakeConfigError::InvalidUserAgent(_)),
+        ));
+
+        let config = HandshakeConfig::default().handshake_timeout(Duration::ZERO);
+        assert!(matches!(
+            config.validate(),
+            Err(HandshakeConfigError::ZeroTimeout),
+        ));
+    }
+
+    #[test]
+    fn test_default_version_payload_matches_builder() {
+        let addr_recv = "46.19.137.74:8333".parse().unwrap();
+        let addr_from = "127.0.0.1:50000".parse().unwrap();
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1640961477);
+
+        let from_config = HandshakeConfig::default()
+            .version_payload(addr_recv, Some(addr_from), 42, timestamp)
+            .unwrap();
+        let from_builder = VersionPayload::builder()
+            .version(PROTOCOL_VERSION)
+            .timestamp(timestamp)
+            .addr_recv(addr_recv)
+            .addr_from(addr_from)
+            .nonce(42)
+            .user_agent(&default_agent())
+            .start_height(0)
+            .relay(None)
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            prepare_message(Network::Mainnet, from_config).unwrap(),
+            prepare_message(Network::Mainnet, from_builder).unwrap(),
+        );
+    }
+}
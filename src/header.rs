@@ -1,16 +1,20 @@
-use binrw::binrw;
-use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+use binrw::{binrw, BinRead};
 
 use crate::{
     command::{Command, CommandError},
+    message::MessageParseError,
+    network::Network,
+    protocol::MAX_MESSAGE_SIZE,
     utils::double_sha256_hash,
 };
 
 #[derive(Debug)]
 #[binrw]
-#[brw(magic = b"\xF9\xBE\xB4\xD9")]
 #[brw(little)]
 pub struct Header {
+    magic: [u8; 4],
     command: [u8; 12],
     length: u32,
     checksum: u32,
@@ -19,15 +23,52 @@ pub struct Header {
 impl Header {
     pub const HEADER_BYTE_SIZE: usize = 4 + 12 + 4 + 4;
 
-    pub fn create(command: Command, payload: &[u8]) -> Self {
-        let checksum = double_sha256_hash(payload);
-        let checksum = u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]);
+    pub fn create(
+        network: Network,
+        command: Command,
+        payload: &[u8],
+    ) -> Result<Self, HeaderCreateError> {
+        let mut header = Self::create_with_len(network, command, payload.len())?;
+        header.checksum = checksum(payload);
+        Ok(header)
+    }
+
+    // Validates the length alone, leaving the checksum zeroed, so huge payloads can be tested cheaply
+    fn create_with_len(
+        network: Network,
+        command: Command,
+        length: usize,
+    ) -> Result<Self, HeaderCreateError> {
+        // Peers ban anyone sending more than this, even though the length field could hold more
+        let length = u32::try_from(length)
+            .ok()
+            .filter(|&length| length <= MAX_MESSAGE_SIZE)
+            .ok_or(HeaderCreateError::PayloadTooLarge(length))?;
 
-        Self {
+        Ok(Self {
+            magic: network.magic(),
             command: command.into(),
-            length: payload.len() as u32, // FIXME: Should I handle payloads greater than 4 GiB?
-            checksum,
+            length,
+            checksum: 0,
+        })
+    }
+
+    // Parses only the fixed-size header, so callers can learn how long the whole frame will be
+    pub fn peek(data: &[u8]) -> Result<PeekedHeader, MessageParseError> {
+        if data.len() < Self::HEADER_BYTE_SIZE {
+            return Err(MessageParseError::NotEnoughData);
         }
+
+        let header = Self::read(&mut Cursor::new(&data[..Self::HEADER_BYTE_SIZE]))?;
+        Ok(PeekedHeader { header })
+    }
+
+    pub fn magic(&self) -> [u8; 4] {
+        self.magic
+    }
+
+    pub fn command_raw(&self) -> [u8; 12] {
+        self.command
     }
 
     pub fn command_type(&self) -> Result<Command, CommandError> {
@@ -38,6 +79,10 @@ impl Header {
         self.length
     }
 
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
     pub fn validate_checksum(&self, payload: &[u8]) -> Result<(), ChecksumError> {
         if payload.len() < self.length as usize {
             return Err(ChecksumError::InsufficientPayload(
@@ -46,27 +91,58 @@ impl Header {
             ));
         }
 
-        let mut hasher = Sha256::new();
-        hasher.update(&payload[..(self.length as usize)]);
-        let hash = hasher.finalize();
-
-        let mut hasher = Sha256::new();
-        hasher.update(hash);
-        let hash = hasher.finalize();
+        self.validate_digest(&double_sha256_hash(&payload[..(self.length as usize)]))
+    }
 
-        let hash = u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]]);
-        if self.checksum == hash {
+    // Checks a double SHA-256 of the payload that the caller computed as the bytes came in
+    pub fn validate_digest(&self, digest: &[u8; 32]) -> Result<(), ChecksumError> {
+        let computed = u32::from_le_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        if self.checksum == computed {
             Ok(())
         } else {
-            Err(ChecksumError::IncorrectChecksum)
+            Err(ChecksumError::IncorrectChecksum {
+                expected: self.checksum,
+                computed,
+            })
         }
     }
 }
 
+#[derive(Debug)]
+pub struct PeekedHeader {
+    header: Header,
+}
+
+impl PeekedHeader {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn into_header(self) -> Header {
+        self.header
+    }
+
+    // The header plus its declared payload
+    pub fn total_frame_len(&self) -> usize {
+        Header::HEADER_BYTE_SIZE + self.header.payload_size() as usize
+    }
+}
+
+// The first four bytes of the payload's double SHA-256, read the same way as the header field
+fn checksum(payload: &[u8]) -> u32 {
+    let hash = double_sha256_hash(payload);
+    u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+// Checksums are shown in wire byte order so they can be compared against a hex dump
+pub(crate) fn checksum_hex(checksum: u32) -> String {
+    hex::encode(checksum.to_le_bytes())
+}
+
 #[derive(Debug)]
 pub enum ChecksumError {
     InsufficientPayload(usize, u32),
-    IncorrectChecksum,
+    IncorrectChecksum { expected: u32, computed: u32 },
 }
 
 impl std::fmt::Display for ChecksumError {
@@ -78,16 +154,37 @@ impl std::fmt::Display for ChecksumError {
                     "expected {expected_length} but received only {actual_length} byte(s) for payload",
                 )
             }
-            ChecksumError::IncorrectChecksum => write!(f, "incorrect checksum for payload"),
+            ChecksumError::IncorrectChecksum { expected, computed } => write!(
+                f,
+                "incorrect checksum for payload: header has {} but payload hashes to {}",
+                checksum_hex(expected),
+                checksum_hex(computed),
+            ),
         }
     }
 }
 
+#[derive(Debug)]
+pub enum HeaderCreateError {
+    PayloadTooLarge(usize),
+}
+
+impl std::fmt::Display for HeaderCreateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            HeaderCreateError::PayloadTooLarge(length) => write!(
+                f,
+                "{length} byte payload exceeds the maximum of {MAX_MESSAGE_SIZE} bytes",
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HeaderCreateError {}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-
-    use binrw::{BinRead, BinWrite};
+    use binrw::BinWrite;
 
     use super::*;
 
@@ -128,4 +225,78 @@ mod tests {
 
         assert_eq!(encoded.into_inner(), raw_binary);
     }
+
+    #[test]
+    fn test_incorrect_checksum_reports_both_values() {
+        let raw_binary = hex::decode("F9BEB4D976657261636B00000000000000000000DEADBEEF").unwrap();
+
+        let verack_header = Header::read(&mut Cursor::new(&raw_binary)).unwrap();
+
+        let error = verack_header.validate_checksum(&[]).unwrap_err();
+        assert!(matches!(
+            error,
+            ChecksumError::IncorrectChecksum {
+                expected: 0xEFBEADDE,
+                computed: 0xE2E0F65D,
+            }
+        ));
+
+        let message = error.to_string();
+        assert!(message.contains("deadbeef"), "{message}");
+        assert!(message.contains("5df6e0e2"), "{message}");
+    }
+
+    #[test]
+    fn test_create_rejects_oversized_payload() {
+        let length = MAX_MESSAGE_SIZE as usize;
+        let header = Header::create_with_len(Network::Mainnet, Command::Version, length).unwrap();
+        assert_eq!(header.payload_size(), MAX_MESSAGE_SIZE);
+
+        assert!(matches!(
+            Header::create_with_len(Network::Mainnet, Command::Version, length + 1),
+            Err(HeaderCreateError::PayloadTooLarge(rejected)) if rejected == length + 1,
+        ));
+
+        // Lengths that don't even fit the field must not wrap around to something small
+        let length = u32::MAX as usize + 1;
+        assert!(matches!(
+            Header::create_with_len(Network::Mainnet, Command::Version, length),
+            Err(HeaderCreateError::PayloadTooLarge(rejected)) if rejected == length,
+        ));
+    }
+
+    #[test]
+    fn test_peek_truncated_header() {
+        let raw_binary = hex::decode("F9BEB4D976657273696F6E0000000000640000").unwrap();
+
+        assert!(matches!(
+            Header::peek(&raw_binary),
+            Err(MessageParseError::NotEnoughData),
+        ));
+    }
+
+    #[test]
+    fn test_peek_header_with_missing_payload() {
+        let raw_binary = hex::decode("F9BEB4D976657273696F6E000000000064000000358d4932").unwrap();
+
+        let peeked = Header::peek(&raw_binary).unwrap();
+        assert!(matches!(
+            peeked.header().command_type(),
+            Ok(Command::Version)
+        ));
+        assert_eq!(peeked.total_frame_len(), Header::HEADER_BYTE_SIZE + 100);
+    }
+
+    #[test]
+    fn test_peek_full_frame() {
+        let mut raw_binary =
+            hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap();
+        let frame_len = raw_binary.len();
+        // Whatever follows the frame is left alone
+        raw_binary.extend(hex::decode("F9BEB4D9").unwrap());
+
+        let peeked = Header::peek(&raw_binary).unwrap();
+        assert_eq!(peeked.total_frame_len(), frame_len);
+        assert!(matches!(peeked.header().validate_checksum(&[]), Ok(())));
+    }
 }
@@ -0,0 +1,107 @@
+use std::fmt::Write;
+
+use crate::{
+    command::command_name,
+    header::{checksum_hex, Header},
+    recording::Direction,
+};
+
+const ROW_LEN: usize = 16;
+
+// A frame as it crossed the wire: a line summarizing the header, then the payload in rows of
+// sixteen bytes with their offset and printable ASCII alongside
+pub fn format_frame(direction: Direction, header: &Header, payload: &[u8]) -> String {
+    let arrow = match direction {
+        Direction::Sent => ">>>",
+        Direction::Received => "<<<",
+    };
+    let mut dump = format!(
+        "{arrow} {} length {} checksum {}\n",
+        command_name(&header.command_raw()),
+        header.payload_size(),
+        checksum_hex(header.checksum()),
+    );
+    for (row, bytes) in payload.chunks(ROW_LEN).enumerate() {
+        let _ = write!(dump, "{:08x} ", row * ROW_LEN);
+        for column in 0..ROW_LEN {
+            // An extra space halfway along, like `hexdump -C`
+            if column % 8 == 0 {
+                dump.push(' ');
+            }
+            match bytes.get(column) {
+                Some(byte) => {
+                    let _ = write!(dump, "{byte:02x} ");
+                }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str(" |");
+        dump.extend(bytes.iter().map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                char::from(byte)
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+    dump
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::{
+        command::Command, network::Network, ping_payload::PingPayload, prepare_message,
+        version_payload::VersionPayload,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_format_frame() {
+        let version = VersionPayload::builder()
+            .version(70016)
+            .nonce(0x0102_0304_0506_0708)
+            .timestamp(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .user_agent("/Satoshi:25.0.0/")
+            .build()
+            .unwrap();
+        let frame = prepare_message(Network::Mainnet, version).unwrap();
+        let header = Header::peek(&frame).unwrap().into_header();
+        assert_eq!(
+            format_frame(Direction::Sent, &header, &frame[Header::HEADER_BYTE_SIZE..]),
+            "\
+>>> version length 101 checksum 48246ca5
+00000000  80 11 01 00 00 00 00 00  00 00 00 00 00 f1 53 65  |..............Se|
+00000010  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+00000020  00 00 00 00 00 00 ff ff  00 00 00 00 00 00 00 00  |................|
+00000030  00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00  |................|
+00000040  ff ff 7f 00 00 01 20 8d  08 07 06 05 04 03 02 01  |...... .........|
+00000050  10 2f 53 61 74 6f 73 68  69 3a 32 35 2e 30 2e 30  |./Satoshi:25.0.0|
+00000060  2f 00 00 00 00                                    |/....|
+",
+        );
+    }
+
+    #[test]
+    fn test_format_frame_short_payloads() {
+        let header = Header::create(Network::Mainnet, Command::Verack, &[]).unwrap();
+        assert_eq!(
+            format_frame(Direction::Received, &header, &[]),
+            "<<< verack length 0 checksum 5df6e0e2\n",
+        );
+        let frame = prepare_message(Network::Mainnet, PingPayload::new(0x4142)).unwrap();
+        let header = Header::peek(&frame).unwrap().into_header();
+        assert_eq!(
+            format_frame(
+                Direction::Received,
+                &header,
+                &frame[Header::HEADER_BYTE_SIZE..]
+            ),
+            "<<< ping length 8 checksum 88a320bf\n\
+             00000000  42 41 00 00 00 00 00 00                           |BA......|\n",
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use std::{collections::VecDeque, future::Future, ops::ControlFlow, pin::Pin, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::{
+    disconnect::DisconnectReason,
+    message::MessageType,
+    messaging_system::{MessageReceiveError, MessageReceiver, MessageSendError, MessageSender},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveStats {
+    pub pings_sent: u64,
+    pub pongs_received: u64,
+    // Pongs echoing a nonce we never sent, or already saw answered
+    pub mismatched_pongs: u64,
+}
+
+// Pings the peer every `interval` and gives up on it once a ping goes unanswered for
+// `pong_timeout`, while handing every other message to `on_message` until it breaks. The
+// peer's own pings are answered along the way.
+pub async fn keepalive<W, R, F>(
+    sender: &mut MessageSender<W>,
+    receiver: &mut MessageReceiver<R>,
+    interval: Duration,
+    pong_timeout: Duration,
+    on_message: F,
+) -> Result<KeepaliveStats, KeepaliveError>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+    F: FnMut(MessageType) -> ControlFlow<()>,
+{
+    // Never sends anything, but stays open so the outbox never runs dry
+    let (_sending, mut outbox) = mpsc::unbounded_channel();
+    keepalive_with_outbox(
+        sender,
+        receiver,
+        interval,
+        pong_timeout,
+        &mut outbox,
+        on_message,
+    )
+    .await
+}
+
+// A message to send on the connection, queued from outside the session
+pub(crate) type Outgoing<W> = Box<
+    dyn for<'a> FnOnce(
+            &'a mut MessageSender<W>,
+        )
+            -> Pin<Box<dyn Future<Output = Result<(), MessageSendError>> + Send + 'a>>
+        + Send,
+>;
+
+// Like `keepalive`, but also sends whatever arrives in `outbox` in between, and stops cleanly
+// once every sender to it is gone
+pub(crate) async fn keepalive_with_outbox<W, R, F>(
+    sender: &mut MessageSender<W>,
+    receiver: &mut MessageReceiver<R>,
+    interval: Duration,
+    pong_timeout: Duration,
+    outbox: &mut mpsc::UnboundedReceiver<Outgoing<W>>,
+    mut on_message: F,
+) -> Result<KeepaliveStats, KeepaliveError>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+    F: FnMut(MessageType) -> ControlFlow<()>,
+{
+    let mut stats = KeepaliveStats::default();
+    // Oldest first, so the front always holds the next deadline
+    let mut in_flight: VecDeque<(u64, Instant)> = VecDeque::new();
+
+    let mut ticker = tokio::time::interval_at(Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        let deadline = in_flight.front().map(|&(_, sent)| sent + pong_timeout);
+        tokio::select! {
+            // Receiving is cancel safe, so losing the race to a tick drops no bytes
+            received = receiver.receive_message() => match received {
+                Ok(MessageType::Pong(pong_payload)) => {
+                    let nonce = pong_payload.nonce();
+                    match in_flight.iter().position(|&(sent_nonce, _)| sent_nonce == nonce) {
+                        Some(index) => {
+                            in_flight.remove(index);
+                            stats.pongs_received += 1;
+                        }
+                        None => stats.mismatched_pongs += 1,
+                    }
+                }
+                Ok(MessageType::Ping(ping_payload)) => sender.send_pong(ping_payload.nonce()).await?,
+                Ok(message) => {
+                    if on_message(message).is_break() {
+                        return Ok(stats);
+                    }
+                }
+                // A quiet peer is fine as long as it answers pings, which the deadline checks
+                Err(MessageReceiveError::Timeout { .. }) => {}
+                Err(e) => return Err(e.into()),
+            },
+            outgoing = outbox.recv() => match outgoing {
+                Some(send) => send(sender).await?,
+                None => return Ok(stats),
+            },
+            _ = ticker.tick() => {
+                let nonce = sender.config().random_nonce();
+                sender.send_ping(nonce).await?;
+                in_flight.push_back((nonce, Instant::now()));
+                stats.pings_sent += 1;
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                let (nonce, sent) = in_flight[0];
+                return Err(KeepaliveError::PeerUnresponsive {
+                    nonce,
+                    waited: sent.elapsed(),
+                });
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum KeepaliveError {
+    PeerUnresponsive { nonce: u64, waited: Duration },
+    Send(MessageSendError),
+    Receive(MessageReceiveError),
+}
+
+impl KeepaliveError {
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::PeerUnresponsive { .. } => DisconnectReason::ReadTimeout,
+            Self::Send(e) => e.disconnect_reason(),
+            Self::Receive(e) => e.disconnect_reason(),
+        }
+    }
+}
+
+impl std::fmt::Display for KeepaliveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PeerUnresponsive { nonce, waited } => write!(
+                f,
+                "peer did not answer ping {nonce:#018x} within {waited:?}",
+            ),
+            Self::Send(e) => e.fmt(f),
+            Self::Receive(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for KeepaliveError {}
+
+impl From<MessageSendError> for KeepaliveError {
+    fn from(value: MessageSendError) -> Self {
+        Self::Send(value)
+    }
+}
+
+impl From<MessageReceiveError> for KeepaliveError {
+    fn from(value: MessageReceiveError) -> Self {
+        Self::Receive(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use crate::{handshake_config::HandshakeConfig, messaging_system::MessagingSystem};
+
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+    const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+    // A sendheaders frame, which nothing here has a reason to send on its own
+    const SENDHEADERS: [u8; 24] = [
+        0xF9, 0xBE, 0xB4, 0xD9, b's', b'e', b'n', b'd', b'h', b'e', b'a', b'd', b'e', b'r', b's',
+        0, 0, 0, 0, 0, 0x5D, 0xF6, 0xE0, 0xE2,
+    ];
+
+    async fn established_pair() -> (MessagingSystem<DuplexStream>, MessagingSystem<DuplexStream>) {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut ours = MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        let mut theirs = MessagingSystem::from_stream(theirs, "192.0.2.2:8333".parse().unwrap());
+
+        let config = HandshakeConfig::default();
+        let (our_outcome, their_outcome) =
+            tokio::join!(ours.handshake(&config), theirs.handshake(&config));
+        our_outcome.unwrap();
+        their_outcome.unwrap();
+        (ours, theirs)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_with_responsive_peer() {
+        let (ours, mut theirs) = established_pair().await;
+
+        // Answers three pings, the first one twice over, then sends something else
+        let peer = tokio::spawn(async move {
+            theirs.read_timeout = Duration::MAX;
+            theirs.send_pong(12345).await.unwrap();
+            for pings in 1..=3 {
+                let Ok(MessageType::Ping(ping_payload)) = theirs.receive_message().await else {
+                    panic!("expected a ping message");
+                };
+                theirs.send_pong(ping_payload.nonce()).await.unwrap();
+                if pings == 1 {
+                    theirs.send_pong(ping_payload.nonce()).await.unwrap();
+                }
+            }
+            let mut stream = theirs.into_inner();
+            stream.write_all(&SENDHEADERS).await.unwrap();
+            stream
+        });
+
+        let (mut sender, mut receiver) = ours.into_split();
+        let started = Instant::now();
+        let stats = keepalive(
+            &mut sender,
+            &mut receiver,
+            INTERVAL,
+            PONG_TIMEOUT,
+            |message| {
+                assert!(matches!(
+                    message,
+                    MessageType::Unknown { command, .. } if command == *b"sendheaders\0",
+                ));
+                ControlFlow::Break(())
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            stats,
+            KeepaliveStats {
+                pings_sent: 3,
+                pongs_received: 3,
+                mismatched_pongs: 2,
+            },
+        );
+        assert_eq!(started.elapsed(), INTERVAL * 3);
+        peer.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive_with_unresponsive_peer() {
+        let (ours, theirs) = established_pair().await;
+
+        // Reads everything and answers nothing
+        let peer = tokio::spawn(async move {
+            let mut theirs = theirs.into_inner();
+            tokio::io::copy(&mut theirs, &mut tokio::io::sink())
+                .await
+                .unwrap();
+        });
+
+        let (mut sender, mut receiver) = ours.into_split();
+        let started = Instant::now();
+        let result = keepalive(
+            &mut sender,
+            &mut receiver,
+            INTERVAL,
+            PONG_TIMEOUT,
+            |message| panic!("unexpected {message:?}"),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(KeepaliveError::PeerUnresponsive { waited, .. }) if waited == PONG_TIMEOUT,
+        ));
+        assert_eq!(started.elapsed(), INTERVAL + PONG_TIMEOUT);
+
+        drop((sender, receiver));
+        peer.await.unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+pub mod addr_payload;
+pub mod capture;
+pub mod clock;
+pub mod codec;
+pub mod command;
+pub mod command_registry;
+pub mod config;
+pub mod connect;
+pub mod connection_state;
+pub mod crawl;
+pub mod decoder;
+pub mod disconnect;
+pub mod exit_code;
+pub mod handshake;
+pub mod handshake_config;
+pub mod header;
+pub mod hexdump;
+pub mod json;
+pub mod keepalive;
+pub mod log;
+pub mod message;
+pub mod message_preparable;
+mod messaging_system;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod network;
+pub mod onion;
+pub mod output;
+pub mod peer_address;
+pub mod peer_list;
+pub mod peer_manager;
+pub mod ping_payload;
+pub mod protocol;
+pub mod recording;
+pub mod seeds;
+pub mod services;
+pub mod session;
+pub mod stats;
+pub mod survey;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod user_agent;
+mod utils;
+pub mod varint;
+pub mod verack_payload;
+pub mod version_payload;
+pub mod wtxidrelay_payload;
+
+pub use command::Command;
+pub use connection_state::{ConnectionState, ProtocolStateError};
+pub use handshake::{HandshakeError, HandshakeOutcome, HandshakeStep, HandshakeTimings};
+pub use handshake_config::HandshakeConfig;
+pub use header::Header;
+pub use message::{
+    parse_message, prepare_message, MessageParseError, MessageType, PrepareMessageError,
+};
+pub use messaging_system::{
+    CloseReport, ExpectError, MessageReceiveError, MessageReceiver, MessageSendError,
+    MessageSender, MessagingSystem, PingError, DEFAULT_CLOSE_GRACE,
+};
+pub use ping_payload::{PingPayload, PongPayload};
+pub use utils::double_sha256_hash;
+pub use verack_payload::VerackPayload;
+pub use version_payload::VersionPayload;
+pub use wtxidrelay_payload::WtxidRelayPayload;
@@ -0,0 +1,266 @@
+use std::{
+    cell::RefCell,
+    marker::PhantomData,
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+// Leveled diagnostics on stderr, for following a connection message by message. Nothing is
+// written until the binary sets a level, and a thread can capture events instead, which is how
+// tests check what was logged
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 5] = [
+        Self::Error,
+        Self::Warn,
+        Self::Info,
+        Self::Debug,
+        Self::Trace,
+    ];
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warn => write!(f, "warn"),
+            Self::Info => write!(f, "info"),
+            Self::Debug => write!(f, "debug"),
+            Self::Trace => write!(f, "trace"),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = LevelParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|level| s.eq_ignore_ascii_case(&level.to_string()))
+            .ok_or_else(|| LevelParseError(s.to_owned()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LevelParseError(String);
+
+impl std::fmt::Display for LevelParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown log level {:?}, expected off, error, warn, info, debug, or trace",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for LevelParseError {}
+
+// The most verbose level to write, `None` for nothing at all, in the style of `RUST_LOG`: a bare
+// level, or comma-separated `target=level` directives of which only those for this crate count
+pub fn parse_filter(s: &str) -> Result<Option<Level>, LevelParseError> {
+    let mut filter = None;
+    for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = match directive.split_once('=') {
+            None => directive,
+            Some((target, level)) if target.trim() == env!("CARGO_CRATE_NAME") => level.trim(),
+            Some(_) => continue,
+        };
+        filter = if level.eq_ignore_ascii_case("off") {
+            None
+        } else {
+            Some(level.parse()?)
+        };
+    }
+    Ok(filter)
+}
+
+// Zero is off, otherwise a `Level` discriminant
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_max_level(level: Option<Level>) {
+    MAX_LEVEL.store(level.map_or(0, |level| level as u8), Ordering::Relaxed);
+}
+
+pub fn max_level() -> Option<Level> {
+    let max = MAX_LEVEL.load(Ordering::Relaxed);
+    Level::ALL.into_iter().find(|&level| level as u8 == max)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub level: Level,
+    // The module the event came from
+    pub target: &'static str,
+    pub message: String,
+}
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<Record>>> = const { RefCell::new(None) };
+}
+
+// Collects every event on this thread, whatever the level, instead of writing it, until dropped
+pub fn capture() -> Capture {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    Capture {
+        _thread: PhantomData,
+    }
+}
+
+#[derive(Debug)]
+pub struct Capture {
+    // Tied to the thread it captures on
+    _thread: PhantomData<*const ()>,
+}
+
+impl Capture {
+    pub fn records(&self) -> Vec<Record> {
+        CAPTURED.with(|captured| captured.borrow().clone().unwrap_or_default())
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        CAPTURED.with(|captured| *captured.borrow_mut() = None);
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+        || CAPTURED.with(|captured| captured.borrow().is_some())
+}
+
+pub fn emit(level: Level, target: &'static str, message: std::fmt::Arguments<'_>) {
+    let record = Record {
+        level,
+        target,
+        message: message.to_string(),
+    };
+    let uncaptured = CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(records) => {
+            records.push(record);
+            None
+        }
+        None => Some(record),
+    });
+    if let Some(record) = uncaptured {
+        if level as u8 <= MAX_LEVEL.load(Ordering::Relaxed) {
+            eprintln!(
+                "{:>5} {}: {}",
+                record.level.to_string().to_uppercase(),
+                record.target,
+                record.message
+            );
+        }
+    }
+}
+
+// An error's message followed by its sources, leaving out those it already spells out
+pub fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(e) = source {
+        let message = e.to_string();
+        if !chain.contains(&message) {
+            chain.push_str(": ");
+            chain.push_str(&message);
+        }
+        source = e.source();
+    }
+    chain
+}
+
+// Formats the message only when something will see it. Exported for the binary, which logs
+// through it too
+#[macro_export]
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        if $crate::log::enabled($crate::log::Level::$level) {
+            $crate::log::emit($crate::log::Level::$level, module_path!(), format_args!($($arg)+));
+        }
+    };
+}
+
+pub use event;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(parse_filter("debug"), Ok(Some(Level::Debug)));
+        assert_eq!(parse_filter("WARN"), Ok(Some(Level::Warn)));
+        assert_eq!(parse_filter("off"), Ok(None));
+        assert_eq!(parse_filter(""), Ok(None));
+        assert_eq!(
+            parse_filter("tokio=trace, bitcoin_handshake=info"),
+            Ok(Some(Level::Info))
+        );
+        assert_eq!(parse_filter("tokio=trace"), Ok(None));
+        assert_eq!(parse_filter("error,bitcoin_handshake=off"), Ok(None));
+        assert_eq!(
+            parse_filter("verbose"),
+            Err(LevelParseError("verbose".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_capture() {
+        event!(Info, "before capturing");
+        let capture = capture();
+        event!(Trace, "captured {}", 1);
+        assert_eq!(
+            capture.records(),
+            [Record {
+                level: Level::Trace,
+                target: "bitcoin_handshake::log::tests",
+                message: "captured 1".to_owned(),
+            }],
+        );
+        drop(capture);
+        assert_eq!(max_level(), None);
+        assert!(!enabled(Level::Error));
+    }
+
+    #[test]
+    fn test_error_chain() {
+        #[derive(Debug)]
+        struct Wrapped(std::io::Error, bool);
+
+        impl std::fmt::Display for Wrapped {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self.1 {
+                    true => write!(f, "could not connect: {}", self.0),
+                    false => write!(f, "could not connect"),
+                }
+            }
+        }
+
+        impl std::error::Error for Wrapped {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let refused = || std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert_eq!(
+            error_chain(&Wrapped(refused(), false)),
+            "could not connect: refused"
+        );
+        assert_eq!(
+            error_chain(&Wrapped(refused(), true)),
+            "could not connect: refused"
+        );
+    }
+}
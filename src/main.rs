@@ -1,192 +1,1822 @@
 use std::{
-    net::{IpAddr, SocketAddr},
-    time::SystemTime,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, UNIX_EPOCH},
 };
 
-use clap::Parser;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use rand::seq::SliceRandom;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    fs::File,
+    io::BufWriter,
+    net::{TcpListener, TcpStream},
+    time::Instant,
 };
+use tokio_util::sync::CancellationToken;
 
-use command::Command;
-use message::{parse_message, prepare_message, MessageParseError, MessageType};
-use verack_payload::VerackPayload;
-use version_payload::VersionPayload;
-
-mod command;
-mod header;
-mod message;
-mod message_preparable;
-mod utils;
-mod verack_payload;
-mod version_payload;
+#[cfg(feature = "metrics")]
+use bitcoin_handshake::metrics::{self, Metrics};
+use bitcoin_handshake::{
+    capture::{decode_capture, summarize, CaptureEntry},
+    command::command_name,
+    config::{self, parse_duration, Config},
+    connect::{ConnectError, HappyEyeballs, RetryPolicy},
+    crawl::{crawl_with_progress, CrawlLimits, CrawlReport, CrawledPeer},
+    exit_code,
+    handshake::{connect_and_handshake, HandshakeError, HandshakeOutcome},
+    log::{self, Level, LevelParseError},
+    message::MessageType,
+    network::Network,
+    output::{failure_json, handshake_json, write_csv, OutputFormat, PeerRow},
+    peer_address::PeerAddress,
+    peer_list::parse_peer_list,
+    protocol::{MIN_PEER_PROTO_VERSION, PROTOCOL_VERSION, RELAY_VERSION},
+    recording::{self, Direction, Recorded, Recorder, Recording},
+    seeds::{self, AddressFamily, DnsResolver, SeedError},
+    services::ServiceFlags,
+    session::{self, SessionEnd, DEFAULT_PING_INTERVAL},
+    stats::{HandshakeTally, SessionStats},
+    survey::{survey, SurveyFailure, SurveyResult},
+    user_agent::{append_comment, default_agent, validate_user_agent, UserAgentError},
+    version_payload::DEFAULT_MAX_CLOCK_SKEW,
+    HandshakeConfig, MessagingSystem, DEFAULT_CLOSE_GRACE,
+};
 
+// Every flag can also come from a BITCOIN_HANDSHAKE_ variable named after it, e.g.
+// BITCOIN_HANDSHAKE_NETWORK=signet or BITCOIN_HANDSHAKE_GETADDR=true. A flag wins over its
+// variable, and both win over the config file
 #[derive(Debug, Parser)]
 struct Args {
-    #[arg(short, long)]
-    ip_address: IpAddr,
-    #[arg(short, long, default_value_t = 8333)]
-    port: u16,
+    // An IP address or hostname, with an optional port. Repeat to try several peers in turn,
+    // stopping at the first successful handshake, or separate them with commas. --ip-address/-i
+    // is deprecated and will be removed in the next release
+    #[arg(
+        short,
+        long,
+        env = "BITCOIN_HANDSHAKE_ADDRESS",
+        value_delimiter = ',',
+        alias = "ip-address",
+        short_alias = 'i'
+    )]
+    address: Vec<PeerAddress>,
+    // Wait for peers to connect here instead, answering their handshakes one at a time
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_LISTEN",
+        conflicts_with_all = ["address", "peers_file", "seed", "use_seeds", "all", "crawl"],
+    )]
+    listen: Option<SocketAddr>,
+    // Decode a file of captured frames, or a --record recording, instead of connecting anywhere
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_PARSE",
+        conflicts_with_all = [
+            "address", "peers_file", "seed", "use_seeds", "all", "crawl", "listen",
+        ],
+    )]
+    parse: Option<PathBuf>,
+    // One ip:port per line, tried after any --address
+    #[arg(long, env = "BITCOIN_HANDSHAKE_PEERS_FILE")]
+    peers_file: Option<PathBuf>,
+    // A DNS seed to take candidates from, tried after any given peers
+    #[arg(long, env = "BITCOIN_HANDSHAKE_SEED")]
+    seed: Option<String>,
+    // Keep only the addresses of one family from whatever the seeds resolve to
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_IPV4_ONLY",
+        conflicts_with = "ipv6_only"
+    )]
+    ipv4_only: bool,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_IPV6_ONLY")]
+    ipv6_only: bool,
+    // When a hostname resolves to both IPv4 and IPv6 addresses, start with IPv6 rather than
+    // whichever came first
+    #[arg(long, env = "BITCOIN_HANDSHAKE_PREFER_IPV6")]
+    prefer_ipv6: bool,
+    // Try a hostname's addresses one at a time, instead of giving the other family a go once
+    // the first has kept us waiting 250ms
+    #[arg(long, env = "BITCOIN_HANDSHAKE_NO_HAPPY_EYEBALLS")]
+    no_happy_eyeballs: bool,
+    // Try the candidates in random order instead
+    #[arg(long, env = "BITCOIN_HANDSHAKE_SHUFFLE")]
+    shuffle: bool,
+    // Handshake with every candidate rather than stopping at the first, and report on each
+    #[arg(long, env = "BITCOIN_HANDSHAKE_ALL")]
+    all: bool,
+    // Handshakes in flight at once with --all or --crawl
+    #[arg(long, env = "BITCOIN_HANDSHAKE_CONCURRENCY", default_value_t = 16)]
+    concurrency: usize,
+    // Starting from the candidates, ask each peer for addresses and handshake with those in
+    // turn, reporting on every peer reached
+    #[arg(long, env = "BITCOIN_HANDSHAKE_CRAWL", conflicts_with = "all")]
+    crawl: bool,
+    // How --all and --crawl report on each peer: text, or csv with one row per peer
+    #[arg(long, env = "BITCOIN_HANDSHAKE_OUTPUT", default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    // Write --output csv here rather than to stdout
+    #[arg(long, env = "BITCOIN_HANDSHAKE_OUTPUT_FILE")]
+    output_file: Option<PathBuf>,
+    // Hops from the candidates that --crawl follows
+    #[arg(long, env = "BITCOIN_HANDSHAKE_MAX_DEPTH", default_value_t = 2)]
+    max_depth: usize,
+    // Peers --crawl connects to in all, candidates included
+    #[arg(long, env = "BITCOIN_HANDSHAKE_MAX_PEERS", default_value_t = 100)]
+    max_peers: usize,
+    // For addresses given without a port, and seeds. Defaults to the selected network's
+    // well-known port
+    #[arg(short, long, env = "BITCOIN_HANDSHAKE_PORT")]
+    port: Option<u16>,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_NETWORK", default_value_t = Network::Mainnet)]
+    network: Network,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_MAGIC", value_parser = Network::parse_magic)]
+    magic: Option<[u8; 4]>,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_WTXIDRELAY")]
+    wtxidrelay: bool,
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_USER_AGENT",
+        default_value_t = default_agent(),
+        value_parser = parse_user_agent,
+    )]
+    user_agent: String,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_USER_AGENT_COMMENT")]
+    user_agent_comment: Option<String>,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_NO_RELAY")]
+    no_relay: bool,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_START_HEIGHT", default_value_t = 0)]
+    start_height: i32,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_PROTOCOL_VERSION", default_value_t = PROTOCOL_VERSION)]
+    protocol_version: i32,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_MIN_VERSION", default_value_t = MIN_PEER_PROTO_VERSION)]
+    min_version: i32,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_REQUIRE_SERVICES", default_value_t = ServiceFlags::NONE)]
+    require_services: ServiceFlags,
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_MAX_CLOCK_SKEW_SECS",
+        default_value_t = DEFAULT_MAX_CLOCK_SKEW.as_secs(),
+    )]
+    max_clock_skew_secs: u64,
+    // Without an IP address, find a peer through the network's DNS seeds instead
+    #[arg(long, env = "BITCOIN_HANDSHAKE_USE_SEEDS")]
+    use_seeds: bool,
+    #[arg(long, env = "BITCOIN_HANDSHAKE_MAX_ATTEMPTS", default_value_t = 10)]
+    max_attempts: usize,
+    // Skip stray bytes up to the next network magic instead of giving up on the connection
+    #[arg(long, env = "BITCOIN_HANDSHAKE_RESYNC")]
+    resync: bool,
+    // Accepts e.g. "10s", "500ms", or a bare number of seconds
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_CONNECT_TIMEOUT",
+        default_value = "10s",
+        value_parser = parse_duration,
+    )]
+    connect_timeout: Duration,
+    // Reconnect this many more times when the peer refuses or resets the connection, or it
+    // times out, waiting twice as long before each attempt as before the last
+    #[arg(long, env = "BITCOIN_HANDSHAKE_RETRIES", default_value_t = 0)]
+    retries: u32,
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_RETRY_BACKOFF",
+        default_value = "500ms",
+        value_parser = parse_duration,
+    )]
+    retry_backoff: Duration,
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_READ_TIMEOUT",
+        default_value = "20s",
+        value_parser = parse_duration,
+    )]
+    read_timeout: Duration,
+    // How long writing one message may take before giving up; defaults to the read timeout
+    #[arg(long, env = "BITCOIN_HANDSHAKE_WRITE_TIMEOUT", value_parser = parse_duration)]
+    write_timeout: Option<Duration>,
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_HANDSHAKE_TIMEOUT",
+        default_value = "60s",
+        value_parser = parse_duration,
+    )]
+    handshake_timeout: Duration,
+    // Pings to time once the handshake is done
+    #[arg(long, env = "BITCOIN_HANDSHAKE_PING", default_value_t = 0)]
+    ping: u32,
+    // Ask the peer for the addresses it knows once the handshake is done, and print them
+    #[arg(long, env = "BITCOIN_HANDSHAKE_GETADDR")]
+    getaddr: bool,
+    // How long to keep collecting addresses with --getaddr, as peers answer in several batches
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_ADDR_WAIT",
+        default_value = "10s",
+        value_parser = parse_duration,
+    )]
+    addr_wait: Duration,
+    // Keep the connection open this long after the handshake, logging what the peer sends
+    #[arg(long, env = "BITCOIN_HANDSHAKE_STAY_CONNECTED", value_parser = parse_duration)]
+    stay_connected: Option<Duration>,
+    // Print the handshake's result as a single JSON object instead of the usual report
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_JSON",
+        conflicts_with_all = [
+            "all", "crawl", "listen", "parse", "ping", "getaddr", "stay_connected",
+        ],
+    )]
+    json: bool,
+    // Handshake with the first peer this many times, each on a fresh connection, then report how
+    // often that worked and how long it took
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_COUNT",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = [
+            "all", "crawl", "listen", "parse", "json", "ping", "getaddr", "stay_connected",
+        ],
+    )]
+    count: Option<u32>,
+    // How long to wait between handshakes with --count
+    #[arg(
+        long,
+        env = "BITCOIN_HANDSHAKE_INTERVAL",
+        default_value = "1s",
+        value_parser = parse_duration,
+    )]
+    interval: Duration,
+    // Capture every byte sent and received, with its direction and time, to this file
+    #[arg(long, env = "BITCOIN_HANDSHAKE_RECORD")]
+    record: Option<PathBuf>,
+    // Dump every frame sent and received to stderr as annotated hex
+    #[arg(long, env = "BITCOIN_HANDSHAKE_TRACE_WIRE")]
+    trace_wire: bool,
+    // Serve Prometheus metrics here while listening or crawling, e.g. 127.0.0.1:9325
+    #[cfg(feature = "metrics")]
+    #[arg(long, env = "BITCOIN_HANDSHAKE_METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    // How much of what goes on to write to stderr: off, error, warn, info, debug, or trace, or
+    // RUST_LOG-style directives. Falls back to RUST_LOG, then to warn
+    #[arg(long, env = "BITCOIN_HANDSHAKE_LOG_LEVEL", value_parser = parse_log_level)]
+    log_level: Option<String>,
+    // A TOML file of defaults for the network, peers, user agent, timeouts, and output format,
+    // which flags and variables override. Without it,
+    // $XDG_CONFIG_HOME/bitcoin-handshake/config.toml is read if it exists
+    #[arg(long, env = "BITCOIN_HANDSHAKE_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+type Connection = MessagingSystem<Recorded<TcpStream>>;
+
+impl Args {
+    fn network(&self) -> Network {
+        match self.magic {
+            Some(magic) => Network::Custom(magic),
+            None => self.network,
+        }
+    }
+
+    // An explicit port always wins, and a custom magic keeps the preset network's port
+    fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.network.default_port())
+    }
+
+    fn write_timeout(&self) -> Duration {
+        self.write_timeout.unwrap_or(self.read_timeout)
+    }
+
+    fn address_family(&self) -> AddressFamily {
+        if self.ipv4_only {
+            AddressFamily::Ipv4
+        } else if self.ipv6_only {
+            AddressFamily::Ipv6
+        } else {
+            AddressFamily::Any
+        }
+    }
+
+    fn happy_eyeballs(&self) -> HappyEyeballs {
+        HappyEyeballs {
+            enabled: !self.no_happy_eyeballs,
+            prefer_ipv6: self.prefer_ipv6,
+            ..HappyEyeballs::default()
+        }
+    }
+
+    // Hostnames are looked up in turn, and skipped with a warning when they don't resolve
+    async fn resolve_addresses(&self) -> Vec<Candidate> {
+        let mut candidates = Vec::new();
+        for address in &self.address {
+            match address.resolve(self.port()).await {
+                Ok(addresses) => candidates.push(Candidate {
+                    name: address.to_string(),
+                    addresses,
+                }),
+                Err(e) => log::event!(Warn, "could not resolve {address}: {e}"),
+            }
+        }
+        candidates
+    }
+
+    // Whether there is a peer to connect to, or something else to do instead
+    fn has_target(&self) -> bool {
+        !self.address.is_empty()
+            || self.peers_file.is_some()
+            || self.seed.is_some()
+            || self.use_seeds
+            || self.listen.is_some()
+            || self.parse.is_some()
+    }
+
+    // The settings a config file can also give, where they were given as flags or variables
+    // rather than left to their defaults
+    fn given_config(&self, matches: &ArgMatches) -> Config {
+        let given = |id| {
+            matches
+                .value_source(id)
+                .is_some_and(|source| source != ValueSource::DefaultValue)
+        };
+        Config {
+            network: given("network").then_some(self.network),
+            peers: given("address").then(|| self.address.clone()),
+            user_agent: given("user_agent").then(|| self.user_agent.clone()),
+            connect_timeout: given("connect_timeout").then_some(self.connect_timeout),
+            read_timeout: given("read_timeout").then_some(self.read_timeout),
+            handshake_timeout: given("handshake_timeout").then_some(self.handshake_timeout),
+            proxy: None,
+            output: given("output").then_some(self.output),
+        }
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        if let Some(network) = config.network {
+            self.network = network;
+        }
+        if let Some(peers) = config.peers {
+            self.address = peers;
+        }
+        if let Some(user_agent) = config.user_agent {
+            self.user_agent = user_agent;
+        }
+        if let Some(timeout) = config.connect_timeout {
+            self.connect_timeout = timeout;
+        }
+        if let Some(timeout) = config.read_timeout {
+            self.read_timeout = timeout;
+        }
+        if let Some(timeout) = config.handshake_timeout {
+            self.handshake_timeout = timeout;
+        }
+        if let Some(output) = config.output {
+            self.output = output;
+        }
+    }
+}
+
+// A peer to try, under every address its name resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Candidate {
+    name: String,
+    addresses: Vec<SocketAddr>,
+}
+
+impl From<SocketAddr> for Candidate {
+    fn from(socket_address: SocketAddr) -> Self {
+        Self {
+            name: socket_address.to_string(),
+            addresses: vec![socket_address],
+        }
+    }
+}
+
+impl std::fmt::Display for Candidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.name.fmt(f)
+    }
+}
+
+fn parse_user_agent(user_agent: &str) -> Result<String, UserAgentError> {
+    validate_user_agent(user_agent)?;
+    Ok(user_agent.to_owned())
+}
+
+fn parse_log_level(s: &str) -> Result<String, LevelParseError> {
+    log::parse_filter(s)?;
+    Ok(s.to_owned())
+}
+
+// Lays the config file, when there is one, under whatever flags were given
+fn load_config(args: &mut Args, matches: &ArgMatches) {
+    let path = match &args.config {
+        Some(path) => path.clone(),
+        None => match config::default_path() {
+            Some(path) if path.is_file() => path,
+            _ => return,
+        },
+    };
+    let parsed = std::fs::read_to_string(&path)
+        .map_err(|e| format!("could not read {}: {e}", path.display()))
+        .and_then(|text| Config::parse(&text).map_err(|e| format!("{}: {e}", path.display())))
+        .unwrap_or_else(|e| {
+            eprintln!("error: {e}");
+            std::process::exit(exit_code::FAILURE);
+        });
+    for unknown_key in &parsed.unknown_keys {
+        eprintln!("warning: {}: {unknown_key}", path.display());
+    }
+    if let Some(proxy) = parsed.config.proxy {
+        eprintln!(
+            "warning: {}: ignoring proxy {proxy}, connecting through a proxy is not supported yet",
+            path.display()
+        );
+    }
+    let given = args.given_config(matches);
+    args.apply_config(parsed.config.merge(given));
+}
+
+fn init_logging(args: &Args) {
+    let filter = match &args.log_level {
+        Some(filter) => log::parse_filter(filter).expect("validated by clap"),
+        None => match std::env::var("RUST_LOG") {
+            Ok(filter) => log::parse_filter(&filter).unwrap_or_else(|e| {
+                eprintln!("warning: ignoring RUST_LOG: {e}");
+                Some(Level::Warn)
+            }),
+            Err(_) => Some(Level::Warn),
+        },
+    };
+    log::set_max_level(filter);
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
+    // clap would exit with 2 on a usage error, which means a failed connection here
+    let usage_error = |e: clap::Error| -> ! {
+        if e.use_stderr() {
+            let _ = e.print();
+            std::process::exit(exit_code::FAILURE);
+        }
+        e.exit()
+    };
+    let matches = Args::command()
+        .try_get_matches()
+        .unwrap_or_else(|e| usage_error(e));
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| usage_error(e));
+    load_config(&mut args, &matches);
+    init_logging(&args);
+    let cancel = cancel_on_interrupt();
+    // Checked only now, as the config file can give the peers
+    if !args.has_target() {
+        eprintln!(
+            "error: nothing to connect to; give --address, --peers-file, --seed, or --use-seeds, \
+             or peers in the config file"
+        );
+        std::process::exit(exit_code::FAILURE);
+    }
 
-    let mut messaging_system =
-        MessagingSystem::try_new(SocketAddr::new(args.ip_address, args.port))
-            .await
-            .expect("IP address and port should point to an available node");
+    let config = handshake_config(&args).unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        std::process::exit(exit_code::FAILURE);
+    });
+    if let Some(path) = &args.parse {
+        let data = std::fs::read(path).unwrap_or_else(|e| {
+            eprintln!("error: could not read {}: {e}", path.display());
+            std::process::exit(exit_code::FAILURE);
+        });
+        let clean = print_capture(args.network(), &data);
+        std::process::exit(if clean {
+            exit_code::SUCCESS
+        } else {
+            exit_code::PROTOCOL_ERROR
+        });
+    }
+    if let Some(listen_address) = args.listen {
+        let recording = start_recording(&args).await;
+        let recorder = recording.as_ref().map(Recording::recorder);
+        // Only an interruption stops listening, once it has started
+        let code = match listen(&args, &config, listen_address, recorder, &cancel).await {
+            Ok(()) => exit_code::INTERRUPTED,
+            Err(e) => {
+                eprintln!("error: {e}");
+                exit_code::FAILURE
+            }
+        };
+        exit(recording, code).await;
+    }
 
-    // Send my version message
-    messaging_system
-        .send_message(Command::Version)
-        .await
-        .expect("should be able to send version message");
+    let mut candidates = args.resolve_addresses().await;
+    if let Some(path) = &args.peers_file {
+        let peers = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| parse_peer_list(&contents).map_err(|e| e.to_string()));
+        match peers {
+            Ok(peers) => candidates.extend(peers.into_iter().map(Candidate::from)),
+            Err(e) => {
+                eprintln!("error: could not read {}: {e}", path.display());
+                std::process::exit(exit_code::FAILURE);
+            }
+        }
+    }
+    if let Some(seed) = &args.seed {
+        match seeds::resolve_seed(&DnsResolver, seed, args.port(), args.address_family()).await {
+            Ok(resolved) => candidates.extend(resolved.into_iter().map(Candidate::from)),
+            Err(e) => {
+                eprintln!("error: {e}");
+                std::process::exit(exit_code::CONNECTION_FAILED);
+            }
+        }
+    }
+    if args.shuffle {
+        candidates.shuffle(&mut rand::thread_rng());
+    }
+    // Seeds are only consulted when no peers were given
+    if candidates.is_empty() && args.use_seeds {
+        let mut resolved = seeds::resolve_candidates(
+            seeds::dns_seeds(args.network()),
+            args.port(),
+            |host| async move { Ok(tokio::net::lookup_host(host).await?.collect()) },
+        )
+        .await;
+        resolved.retain(|socket_address| args.address_family().contains(socket_address));
+        resolved.truncate(args.max_attempts);
+        candidates = resolved.into_iter().map(Candidate::from).collect();
+    }
 
-    // Receive the version message
-    let message = messaging_system
-        .receive_message()
-        .await
-        .expect("should be able to receive message");
-    match message {
-        MessageType::Verack => panic!("unexpectedly received verack message"),
-        MessageType::Version(_) => {}
+    let recording = start_recording(&args).await;
+    let connect_options = ConnectOptions::new(
+        &args,
+        recording.as_ref().map(Recording::recorder),
+        cancel.clone(),
+    );
+    if args.all {
+        // Enough for every connection attempt and the handshake after it, but no more
+        let peer_timeout = connect_options.retry_policy.worst_case() + args.handshake_timeout;
+        let results = survey(
+            candidates,
+            &config,
+            args.concurrency,
+            peer_timeout,
+            |candidate| connect(connect_options.clone(), candidate),
+            &cancel,
+        )
+        .await;
+        let succeeded = match args.output {
+            OutputFormat::Text => print_survey(&results),
+            OutputFormat::Csv => {
+                let rows: Vec<_> = results
+                    .iter()
+                    .map(|result| PeerRow::from_survey(result, args.network()))
+                    .collect();
+                if let Err(e) = write_rows(&args, &rows) {
+                    eprintln!("error: {e}");
+                    exit(recording, exit_code::FAILURE).await;
+                }
+                results
+                    .iter()
+                    .filter(|result| result.outcome.is_ok())
+                    .count()
+            }
+        };
+        exit(
+            recording,
+            if cancel.is_cancelled() {
+                exit_code::INTERRUPTED
+            } else if succeeded > 0 {
+                exit_code::SUCCESS
+            } else {
+                exit_code::CONNECTION_FAILED
+            },
+        )
+        .await;
+    }
+
+    if args.crawl {
+        let limits = CrawlLimits {
+            max_depth: args.max_depth,
+            max_peers: args.max_peers,
+            concurrency: args.concurrency,
+            // Each peer also gets its time to answer getaddr
+            peer_timeout: connect_options.retry_policy.worst_case()
+                + args.handshake_timeout
+                + args.addr_wait,
+            addr_wait: args.addr_wait,
+        };
+        let start = candidates
+            .into_iter()
+            .flat_map(|candidate| candidate.addresses)
+            .collect();
+        let telemetry = match Telemetry::start(&args).await {
+            Ok(telemetry) => telemetry,
+            Err(e) => {
+                eprintln!("error: {e}");
+                exit(recording, exit_code::FAILURE).await;
+            }
+        };
+        let report = crawl_with_progress(
+            start,
+            &config,
+            limits,
+            |address| connect(connect_options.clone(), Candidate::from(address)),
+            |crawled| telemetry.crawled(crawled),
+            &cancel,
+        )
+        .await;
+        match args.output {
+            OutputFormat::Text => print_crawl(&report),
+            OutputFormat::Csv => {
+                let rows: Vec<_> = report
+                    .peers
+                    .iter()
+                    .map(|crawled| PeerRow::from_crawl(crawled, args.network()))
+                    .collect();
+                if let Err(e) = write_rows(&args, &rows) {
+                    eprintln!("error: {e}");
+                    exit(recording, exit_code::FAILURE).await;
+                }
+            }
+        }
+        exit(
+            recording,
+            if report.interrupted {
+                exit_code::INTERRUPTED
+            } else if report.reachable() > 0 {
+                exit_code::SUCCESS
+            } else {
+                exit_code::CONNECTION_FAILED
+            },
+        )
+        .await;
+    }
+
+    if let Some(count) = args.count {
+        let code = repeat_handshakes(&args, &config, candidates, &connect_options, count).await;
+        exit(recording, code).await;
+    }
+
+    if args.json {
+        let code = print_handshake_json(&args, &config, candidates, &connect_options).await;
+        exit(recording, code).await;
+    }
+
+    let Handshaken {
+        candidate,
+        messaging_system,
+        outcome,
+        ..
+    } = match handshake_candidates(candidates, &config, &connect_options).await {
+        Ok(handshaken) => handshaken,
+        Err(e) => {
+            eprintln!("error: {e} (exit code {})", e.exit_code());
+            exit(recording, e.exit_code()).await;
+        }
     };
 
-    // Receive the verack message
-    let message = messaging_system
-        .receive_message()
-        .await
-        .expect("should be able to receive message");
-    match message {
-        MessageType::Verack => {}
-        MessageType::Version(_) => panic!("unexpectedly received version message"),
+    let socket_address = messaging_system.remote_addr();
+    if candidate.name == socket_address.to_string() {
+        println!(
+            "successful handshake with {socket_address} ({})",
+            outcome.timings
+        );
+    } else {
+        println!(
+            "successful handshake with {candidate} ({socket_address}; {})",
+            outcome.timings
+        );
+    }
+    let code = after_handshake(&args, &Telemetry::default(), messaging_system, outcome).await;
+    exit(recording, code).await;
+}
+
+// Cancels the token on Ctrl-C, or SIGTERM on unix, so that whatever is running can wrap up and
+// report what it finished. A second signal exits straight away
+fn cancel_on_interrupt() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let cancelling = cancel.clone();
+    tokio::spawn(async move {
+        interrupted().await;
+        eprintln!("interrupted, finishing up; interrupt again to exit now");
+        cancelling.cancel();
+        interrupted().await;
+        std::process::exit(exit_code::INTERRUPTED);
+    });
+    cancel
+}
+
+async fn interrupted() {
+    // Without a handler there's nothing to wait for, rather than something to act on
+    let ctrl_c = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
     };
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = ctrl_c => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    ctrl_c.await
+}
+
+async fn start_recording(args: &Args) -> Option<Recording<BufWriter<File>>> {
+    let path = args.record.as_ref()?;
+    match Recording::create(path).await {
+        Ok(recording) => Some(recording),
+        Err(e) => {
+            eprintln!("error: could not create {}: {e}", path.display());
+            std::process::exit(exit_code::FAILURE);
+        }
+    }
+}
 
-    // Send the verack message
-    messaging_system
-        .send_message(Command::Verack)
+// Writes out whatever the recording still holds, so it is complete however we exit
+async fn finish_recording(recording: Option<Recording<BufWriter<File>>>) {
+    if let Some(recording) = recording {
+        if let Err(e) = recording.finish().await {
+            eprintln!("warning: could not write the recording: {e}");
+        }
+    }
+}
+
+async fn exit(recording: Option<Recording<BufWriter<File>>>, code: i32) -> ! {
+    finish_recording(recording).await;
+    std::process::exit(code)
+}
+
+// Accepts peers one at a time, answering their handshakes, until interrupted
+async fn listen(
+    args: &Args,
+    config: &HandshakeConfig,
+    listen_address: SocketAddr,
+    recorder: Option<Recorder>,
+    cancel: &CancellationToken,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(listen_address)
         .await
-        .expect("should be able to send verack message");
+        .map_err(|e| format!("could not listen on {listen_address}: {e}"))?;
+    println!(
+        "listening on {}",
+        listener.local_addr().unwrap_or(listen_address)
+    );
+    let telemetry = Telemetry::start(args).await?;
 
-    println!("successful handshake");
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    eprintln!("warning: could not accept a connection: {e}");
+                    continue;
+                }
+            },
+            _ = cancel.cancelled() => return Ok(()),
+        };
+        let mut messaging_system = match MessagingSystem::try_from_accepted(stream) {
+            Ok(messaging_system) => messaging_system.record(recorder.clone()),
+            Err(e) => {
+                eprintln!("warning: could not set up an accepted connection: {e}");
+                continue;
+            }
+        };
+        messaging_system.resync = args.resync;
+        messaging_system.trace_wire = args.trace_wire;
+        messaging_system.read_timeout = args.read_timeout;
+        messaging_system.write_timeout = args.write_timeout();
+        messaging_system.cancel = cancel.clone();
+
+        let socket_address = messaging_system.remote_addr();
+        println!("accepted connection from {socket_address}");
+        telemetry.connection_opened();
+        match messaging_system.accept_handshake(config).await {
+            Ok(outcome) => {
+                println!(
+                    "successful handshake with {socket_address} ({})",
+                    outcome.timings
+                );
+                telemetry.handshake(Ok(outcome.timings.total));
+                // One peer's trouble doesn't stop us listening for the next
+                let _ = after_handshake(args, &telemetry, messaging_system, outcome).await;
+            }
+            Err(e) => {
+                telemetry.handshake(Err(e.kind()));
+                telemetry.session(&messaging_system.stats());
+                let _ = messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+            }
+        }
+        telemetry.connection_closed();
+    }
 }
 
-pub struct MessagingSystem {
-    stream: tokio::net::TcpStream,
-    data: Vec<u8>,
-    buf: [u8; 4096],
-    socket_address: SocketAddr,
+struct Handshaken {
+    candidate: Candidate,
+    messaging_system: Connection,
+    outcome: HandshakeOutcome,
+    // How long connecting took before the handshake started
+    connect_time: Duration,
 }
 
-impl MessagingSystem {
-    pub async fn try_new(socket_address: SocketAddr) -> std::io::Result<Self> {
-        let stream = TcpStream::connect(&socket_address).await?;
+#[derive(Debug)]
+enum CandidatesError {
+    NoCandidates,
+    // The last candidate's failure, which decides how we exit
+    Failed(Candidate, HandshakeError),
+}
 
-        Ok(Self {
-            stream,
-            data: Vec::new(),
-            buf: [0; 4096],
-            socket_address,
-        })
+impl CandidatesError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Self::NoCandidates => exit_code::CONNECTION_FAILED,
+            Self::Failed(_, e) => e.exit_code(),
+        }
     }
+}
 
-    pub async fn send_message(&mut self, command: Command) -> Result<(), MessageSendError> {
-        let message_packet = match command {
-            Command::Verack => prepare_message(VerackPayload)?,
-            Command::Version => prepare_message(VersionPayload::create(
-                SystemTime::now(),
-                self.socket_address.ip(),
-                self.socket_address.port(),
-            ))?,
-        };
+impl std::fmt::Display for CandidatesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoCandidates => SeedError::NoCandidates.fmt(f),
+            // The failure itself was already reported as it happened
+            Self::Failed(candidate, e) => {
+                write!(f, "{} with {candidate}", exit_code::describe(e.exit_code()))
+            }
+        }
+    }
+}
+
+// Tries each candidate in turn like `handshake_any`, but keeps the last failure so its category
+// can be reported
+async fn handshake_candidates(
+    candidates: Vec<Candidate>,
+    config: &HandshakeConfig,
+    connect_options: &ConnectOptions,
+) -> Result<Handshaken, CandidatesError> {
+    let mut last_failure = CandidatesError::NoCandidates;
+    for candidate in candidates {
+        let started = Instant::now();
+        let connecting = connect(connect_options.clone(), candidate.clone());
+        match connect_and_handshake(connecting, config).await {
+            Ok((messaging_system, outcome)) => {
+                return Ok(Handshaken {
+                    candidate,
+                    messaging_system,
+                    connect_time: outcome
+                        .timings
+                        .connect
+                        .unwrap_or_else(|| started.elapsed().saturating_sub(outcome.timings.total)),
+                    outcome,
+                })
+            }
+            Err(e) => {
+                // A failed handshake logs itself, but a connection that never opened doesn't
+                if let HandshakeError::Connect(e) = &e {
+                    log::event!(Warn, "could not connect to {candidate}: {e}");
+                }
+                let interrupted = e.is_interrupted();
+                last_failure = CandidatesError::Failed(candidate, e);
+                if interrupted {
+                    break;
+                }
+            }
+        }
+    }
+    Err(last_failure)
+}
 
-        Ok(self.stream.write_all(&message_packet).await?)
+// Prints the one JSON object --json promises, and returns the exit code
+async fn print_handshake_json(
+    args: &Args,
+    config: &HandshakeConfig,
+    candidates: Vec<Candidate>,
+    connect_options: &ConnectOptions,
+) -> i32 {
+    match handshake_candidates(candidates, config, connect_options).await {
+        Ok(handshaken) => {
+            let peer = handshaken.messaging_system.remote_addr();
+            println!(
+                "{}",
+                handshake_json(
+                    &peer,
+                    args.network(),
+                    &handshaken.outcome,
+                    handshaken.connect_time,
+                )
+            );
+            let _ = handshaken.messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+            exit_code::SUCCESS
+        }
+        Err(CandidatesError::Failed(candidate, e)) => {
+            println!("{}", failure_json(&candidate, args.network(), &e));
+            e.exit_code()
+        }
+        Err(e) => {
+            eprintln!("error: {e} (exit code {})", e.exit_code());
+            e.exit_code()
+        }
     }
+}
 
-    pub async fn receive_message(&mut self) -> Result<MessageType, MessageReceiveError> {
-        'receiving: loop {
-            match parse_message(&self.data) {
-                Ok((message, bytes_read)) => {
-                    self.data = self.data.split_off(bytes_read);
-                    return Ok(message);
+// Handshakes with the first candidate `count` times, then prints the tally of every attempt that
+// finished. Returns the exit code: success if any handshake was, else the last failure's
+async fn repeat_handshakes(
+    args: &Args,
+    config: &HandshakeConfig,
+    candidates: Vec<Candidate>,
+    connect_options: &ConnectOptions,
+    count: u32,
+) -> i32 {
+    let Some(candidate) = candidates.into_iter().next() else {
+        let e = CandidatesError::NoCandidates;
+        eprintln!("error: {e} (exit code {})", e.exit_code());
+        return e.exit_code();
+    };
+    let cancel = &connect_options.cancel;
+    let mut tally = HandshakeTally::default();
+    let mut last_failure = exit_code::CONNECTION_FAILED;
+    for attempt in 1..=count {
+        if attempt > 1 {
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tokio::time::sleep(args.interval) => {}
+            }
+        }
+        match handshake_candidates(vec![candidate.clone()], config, connect_options).await {
+            Ok(handshaken) => {
+                println!(
+                    "handshake {attempt}/{count} with {candidate}: connected in {:.1?}, \
+                     handshake in {:.1?}",
+                    handshaken.connect_time, handshaken.outcome.timings.total
+                );
+                tally.record_success(handshaken.connect_time, handshaken.outcome.timings.total);
+                let _ = handshaken.messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+            }
+            // An interrupted attempt says nothing about the peer
+            Err(CandidatesError::Failed(_, e)) if e.is_interrupted() => break,
+            Err(e) => {
+                last_failure = e.exit_code();
+                if let CandidatesError::Failed(_, e) = &e {
+                    tally.record_failure(e.kind());
                 }
-                Err(MessageParseError::UnknownMessageType(bytes_read)) => {
-                    let bytes_read = bytes_read as usize;
-                    self.data = self.data.split_off(bytes_read);
-                    return Err(MessageReceiveError::UnknownMessage);
+            }
+        }
+    }
+
+    println!("{tally}");
+    if cancel.is_cancelled() {
+        exit_code::INTERRUPTED
+    } else if tally.successes() > 0 {
+        exit_code::SUCCESS
+    } else {
+        last_failure
+    }
+}
+
+// Reports on the peer, then pings it and stays connected as asked before hanging up. Returns the
+// exit code, which only a failed session changes.
+async fn after_handshake(
+    args: &Args,
+    telemetry: &Telemetry,
+    mut messaging_system: Connection,
+    outcome: HandshakeOutcome,
+) -> i32 {
+    let socket_address = messaging_system.remote_addr();
+    if messaging_system.skipped_bytes() > 0 {
+        eprintln!(
+            "warning: skipped {} stray byte(s) while resynchronizing",
+            messaging_system.skipped_bytes(),
+        );
+    }
+    let peer_version = &outcome.peer_version;
+    println!(
+        "protocol version: ours {}, theirs {}, negotiated {}",
+        args.protocol_version,
+        peer_version.version(),
+        outcome.negotiated_version,
+    );
+    println!(
+        "peer {}: version {}, services {}, user agent {:?}, start height {}, relay {}",
+        socket_address,
+        peer_version.version(),
+        peer_version.services(),
+        String::from_utf8_lossy(peer_version.user_agent_bytes()),
+        peer_version.start_height(),
+        peer_version.relay().unwrap_or(true),
+    );
+    for message in &outcome.other_messages {
+        match message {
+            MessageType::Unknown { command, payload } => log::event!(
+                Info,
+                "skipped unknown message {:?} ({} byte payload)",
+                command_name(command),
+                payload.len(),
+            ),
+            MessageType::Ping(ping_payload) => {
+                log::event!(
+                    Info,
+                    "answered ping with nonce {:#018x}",
+                    ping_payload.nonce()
+                )
+            }
+            message => log::event!(
+                Info,
+                "skipped {} message",
+                command_name(&message.command_raw())
+            ),
+        }
+    }
+
+    match outcome.clock_skew {
+        Some(clock_skew) => {
+            println!("clock skew: {clock_skew:+} second(s)");
+            if clock_skew.unsigned_abs() > args.max_clock_skew_secs {
+                eprintln!(
+                    "warning: peer clock differs from ours by more than {} second(s)",
+                    args.max_clock_skew_secs,
+                );
+            }
+        }
+        None => println!("clock skew: unknown"),
+    }
+
+    // Core pings right after the handshake too, and should get its answer while we wait for ours
+    messaging_system.auto_pong = true;
+    let mut round_trips = Vec::new();
+    for _ in 0..args.ping {
+        match messaging_system.measure_ping().await {
+            Ok(round_trip) => {
+                println!("pong received in {round_trip:?}");
+                round_trips.push(round_trip);
+            }
+            Err(e) => {
+                log::event!(Warn, "ping failed: {e}");
+                break;
+            }
+        }
+    }
+    if let (Some(min), Some(max)) = (round_trips.iter().min(), round_trips.iter().max()) {
+        let avg = round_trips.iter().sum::<Duration>() / round_trips.len() as u32;
+        println!("ping round trip: min {min:?}, avg {avg:?}, max {max:?}");
+    }
+
+    if args.getaddr {
+        match session::collect_addresses(&mut messaging_system, args.addr_wait).await {
+            Ok(addresses) => {
+                println!("received {} address(es)", addresses.len());
+                for address in &addresses {
+                    let last_seen = address
+                        .last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    println!("{address} {} {last_seen}", address.services);
                 }
-                Err(MessageParseError::NotEnoughData) => {
-                    let bytes_read = self.stream.read(&mut self.buf).await?;
-                    self.data.extend(&self.buf[..bytes_read]);
-                    continue 'receiving;
+            }
+            Err(e) => log::event!(Warn, "could not collect addresses: {e}"),
+        }
+    }
+
+    let mut code = exit_code::SUCCESS;
+    if let Some(duration) = args.stay_connected {
+        // The session answers pings itself, and logs them like everything else
+        messaging_system.auto_pong = false;
+        let cancel = messaging_system.cancel.clone();
+        let session = session::stay_connected(
+            &mut messaging_system,
+            duration,
+            DEFAULT_PING_INTERVAL,
+            cancel.cancelled(),
+            |parsed| {
+                println!(
+                    "received {} ({} byte payload)",
+                    command_name(&parsed.header().command_raw()),
+                    parsed.header().payload_size(),
+                )
+            },
+        )
+        .await;
+        match session {
+            Ok(summary) => {
+                let end = match summary.end {
+                    SessionEnd::Deadline => "",
+                    SessionEnd::Interrupted => ", interrupted",
+                };
+                println!(
+                    "stayed connected for {:?}{end}, sent {} ping(s)",
+                    summary.elapsed, summary.pings_sent,
+                );
+                for (command, count) in &summary.received {
+                    println!("  {command}: {count}");
                 }
-                Err(e @ MessageParseError::MissingMagicNumber)
-                | Err(e @ MessageParseError::IncorrectChecksum)
-                | Err(e @ MessageParseError::MalformedData) => return Err(e.into()),
-            };
+            }
+            Err(e) => {
+                eprintln!("error: session ended early: {e}");
+                code = e.exit_code();
+            }
         }
     }
+
+    if messaging_system.cancel.is_cancelled() {
+        code = exit_code::INTERRUPTED;
+    }
+    let stats = messaging_system.stats();
+    if args.getaddr || args.stay_connected.is_some() {
+        println!("{stats}");
+    }
+    telemetry.session(&stats);
+
+    match messaging_system.close(DEFAULT_CLOSE_GRACE).await {
+        Ok(report) => {
+            if report.buffered > 0 || report.deferred > 0 {
+                eprintln!(
+                    "warning: disconnected with {} unread message(s) and {} byte(s) of a partial message",
+                    report.deferred, report.buffered,
+                );
+            }
+        }
+        Err(e) => eprintln!("warning: could not close the connection cleanly: {e}"),
+    }
+    code
 }
 
-#[derive(Debug)]
-pub enum MessageSendError {
-    Creation(binrw::Error),
-    Io(std::io::Error),
+// Counters for --metrics-addr, which record nothing unless it was given
+#[derive(Debug, Default)]
+struct Telemetry {
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
 }
 
-impl std::fmt::Display for MessageSendError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Creation(e) => e.fmt(f),
-            Self::Io(e) => e.fmt(f),
+#[cfg(feature = "metrics")]
+impl Telemetry {
+    async fn start(args: &Args) -> Result<Self, String> {
+        let Some(address) = args.metrics_addr else {
+            return Ok(Self::default());
+        };
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(|e| format!("could not serve metrics on {address}: {e}"))?;
+        println!(
+            "serving metrics on http://{}/metrics",
+            listener.local_addr().unwrap_or(address)
+        );
+        let metrics = Arc::new(Metrics::new());
+        tokio::spawn(metrics::serve(listener, metrics.clone()));
+        Ok(Self {
+            metrics: Some(metrics),
+        })
+    }
+
+    fn handshake(&self, result: Result<Duration, &'static str>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_handshake(result);
+        }
+    }
+
+    fn connection_opened(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_opened();
+        }
+    }
+
+    fn connection_closed(&self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.connection_closed();
+        }
+    }
+
+    fn session(&self, stats: &SessionStats) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_session(stats);
         }
     }
 }
 
-impl std::error::Error for MessageSendError {}
+#[cfg(not(feature = "metrics"))]
+impl Telemetry {
+    async fn start(_: &Args) -> Result<Self, String> {
+        Ok(Self::default())
+    }
 
-impl From<binrw::Error> for MessageSendError {
-    fn from(value: binrw::Error) -> Self {
-        Self::Creation(value)
+    fn handshake(&self, _: Result<Duration, &'static str>) {}
+
+    fn connection_opened(&self) {}
+
+    fn connection_closed(&self) {}
+
+    fn session(&self, _: &SessionStats) {}
+}
+
+impl Telemetry {
+    fn crawled(&self, crawled: &CrawledPeer) {
+        match &crawled.outcome {
+            Ok(summary) => {
+                self.handshake(Ok(summary.peer.latency));
+                self.session(&summary.stats);
+            }
+            Err(SurveyFailure::Handshake(e)) => self.handshake(Err(e.kind())),
+            Err(SurveyFailure::TimedOut(_)) => self.handshake(Err("timeout")),
+            Err(SurveyFailure::Panicked) => self.handshake(Err("panicked")),
+            // Left out of the crawl's report, so left out of the counts too
+            Err(SurveyFailure::Interrupted) => {}
+        }
     }
 }
 
-impl From<std::io::Error> for MessageSendError {
-    fn from(value: std::io::Error) -> Self {
-        Self::Io(value)
+// What every connection needs from the arguments, cheap to hand to each concurrent attempt
+#[derive(Debug, Clone)]
+struct ConnectOptions {
+    retry_policy: RetryPolicy,
+    happy_eyeballs: HappyEyeballs,
+    resync: bool,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    trace_wire: bool,
+    recorder: Option<Recorder>,
+    cancel: CancellationToken,
+}
+
+impl ConnectOptions {
+    fn new(args: &Args, recorder: Option<Recorder>, cancel: CancellationToken) -> Self {
+        Self {
+            retry_policy: RetryPolicy {
+                retries: args.retries,
+                initial_backoff: args.retry_backoff,
+                connect_timeout: args.connect_timeout,
+                ..RetryPolicy::default()
+            },
+            happy_eyeballs: args.happy_eyeballs(),
+            resync: args.resync,
+            read_timeout: args.read_timeout,
+            write_timeout: args.write_timeout(),
+            trace_wire: args.trace_wire,
+            recorder,
+            cancel,
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum MessageReceiveError {
-    Parsing(MessageParseError),
-    UnknownMessage,
-    Io(std::io::Error),
+async fn connect(
+    options: ConnectOptions,
+    candidate: Candidate,
+) -> Result<Connection, ConnectError> {
+    let connecting = MessagingSystem::try_new_to_any(
+        &candidate.addresses,
+        &options.retry_policy,
+        &options.happy_eyeballs,
+    );
+    let mut messaging_system = tokio::select! {
+        connected = connecting => connected?.record(options.recorder),
+        _ = options.cancel.cancelled() => return Err(ConnectError::Interrupted),
+    };
+    messaging_system.resync = options.resync;
+    messaging_system.read_timeout = options.read_timeout;
+    messaging_system.write_timeout = options.write_timeout;
+    messaging_system.trace_wire = options.trace_wire;
+    messaging_system.cancel = options.cancel;
+    Ok(messaging_system)
 }
 
-impl std::fmt::Display for MessageReceiveError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Parsing(e) => e.fmt(f),
-            Self::UnknownMessage => write!(f, "unknown message"),
-            Self::Io(e) => e.fmt(f),
+// Prints one row per peer, in the order given, and returns how many handshakes succeeded
+fn print_survey(results: &[SurveyResult<Candidate>]) -> usize {
+    println!(
+        "{:<47} {:<7} {:>10} {:>8} {:>9}  {:<24} user agent",
+        "peer", "result", "latency", "version", "height", "services",
+    );
+    let mut succeeded = 0;
+    for result in results {
+        match &result.outcome {
+            Ok(summary) => {
+                succeeded += 1;
+                println!(
+                    "{:<47} {:<7} {:>10} {:>8} {:>9}  {:<24} {:?}",
+                    result.candidate,
+                    "ok",
+                    format!("{:.1?}", summary.latency),
+                    summary.version,
+                    summary.start_height,
+                    summary.services.to_string(),
+                    summary.user_agent,
+                );
+            }
+            Err(e) => println!("{:<47} {:<7} {e}", result.candidate, "failed"),
         }
     }
+    println!("{succeeded} of {} handshake(s) succeeded", results.len());
+    succeeded
 }
 
-impl std::error::Error for MessageReceiveError {}
+fn write_rows(args: &Args, rows: &[PeerRow]) -> Result<(), String> {
+    match &args.output_file {
+        Some(path) => std::fs::File::create(path)
+            .and_then(|file| write_csv(std::io::BufWriter::new(file), rows))
+            .map_err(|e| format!("could not write {}: {e}", path.display())),
+        None => write_csv(std::io::stdout().lock(), rows).map_err(|e| e.to_string()),
+    }
+}
 
-impl From<MessageParseError> for MessageReceiveError {
-    fn from(value: MessageParseError) -> Self {
-        Self::Parsing(value)
+// Prints one row per peer, in the order they were connected to, then the totals
+fn print_crawl(report: &CrawlReport) {
+    println!(
+        "{:<47} {:>5} {:<11} {:>9} {:>9}  {:<24} user agent",
+        "peer", "depth", "result", "addresses", "height", "services",
+    );
+    for crawled in &report.peers {
+        match &crawled.outcome {
+            Ok(summary) => {
+                let addresses = match &summary.addresses {
+                    Ok(addresses) => addresses.len().to_string(),
+                    Err(_) => "-".to_owned(),
+                };
+                println!(
+                    "{:<47} {:>5} {:<11} {:>9} {:>9}  {:<24} {:?}",
+                    crawled.address.to_string(),
+                    crawled.depth,
+                    "reachable",
+                    addresses,
+                    summary.peer.start_height,
+                    summary.peer.services.to_string(),
+                    summary.peer.user_agent,
+                );
+                if let Err(e) = &summary.addresses {
+                    log::event!(Warn, "{}: no addresses: {e}", crawled.address);
+                }
+            }
+            Err(e) => println!(
+                "{:<47} {:>5} {:<11} {e}",
+                crawled.address.to_string(),
+                crawled.depth,
+                "unreachable",
+            ),
+        }
     }
+    println!(
+        "{} of {} peer(s) reachable, {} address(es) discovered, {} left unvisited{}",
+        report.reachable(),
+        report.peers.len(),
+        report.discovered,
+        report.unvisited,
+        if report.interrupted {
+            " (interrupted)"
+        } else {
+            ""
+        },
+    );
+    println!("{}", report.stats());
 }
 
-impl From<std::io::Error> for MessageReceiveError {
-    fn from(value: std::io::Error) -> Self {
-        Self::Io(value)
+// Lists every frame in a capture, or in each direction of a recording, and returns whether all
+// of them decoded
+fn print_capture(network: Network, data: &[u8]) -> bool {
+    let records = match data.first() {
+        Some(b'<' | b'>') => recording::read_records(data).ok(),
+        _ => None,
+    };
+    let Some(records) = records else {
+        return print_frames(network, data);
+    };
+
+    let mut clean = true;
+    for (direction, heading) in [(Direction::Sent, "sent"), (Direction::Received, "received")] {
+        println!("{heading}:");
+        clean &= print_frames(network, &recording::stream_bytes(&records, direction));
+    }
+    clean
+}
+
+fn print_frames(network: Network, data: &[u8]) -> bool {
+    let mut clean = true;
+    for entry in decode_capture(network, data) {
+        match entry {
+            CaptureEntry::Frame(frame) => {
+                let description = match &frame.message {
+                    Ok(message) => summarize(message).unwrap_or_default(),
+                    Err(e) => {
+                        clean = false;
+                        format!("not decoded: {e}")
+                    }
+                };
+                let line = format!(
+                    "{:>8}  {:<12} {:>8}  checksum {:<3}  {description}",
+                    frame.offset,
+                    command_name(&frame.header.command_raw()),
+                    frame.header.payload_size(),
+                    if frame.checksum_valid { "ok" } else { "bad" },
+                );
+                println!("{}", line.trim_end());
+            }
+            CaptureEntry::LostSync {
+                offset,
+                skipped,
+                reason,
+            } => {
+                clean = false;
+                println!("{offset:>8}  lost sync ({reason}), skipped {skipped} byte(s)");
+            }
+            CaptureEntry::Truncated { offset, available } => {
+                clean = false;
+                println!("{offset:>8}  truncated frame, only {available} byte(s) left");
+            }
+        }
+    }
+    clean
+}
+
+fn handshake_config(args: &Args) -> Result<HandshakeConfig, UserAgentError> {
+    let user_agent = match &args.user_agent_comment {
+        Some(comment) => append_comment(&args.user_agent, comment)?,
+        None => args.user_agent.clone(),
+    };
+    let mut config = HandshakeConfig::default()
+        .network(args.network())
+        .wtxidrelay(args.wtxidrelay)
+        .user_agent(&user_agent)
+        .start_height(args.start_height)
+        .protocol_version(args.protocol_version)
+        .min_peer_version(args.min_version)
+        .required_services(args.require_services)
+        .handshake_timeout(args.handshake_timeout);
+    if args.protocol_version >= RELAY_VERSION {
+        config = config.relay(Some(!args.no_relay));
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Like `Args::command`, but blind to any BITCOIN_HANDSHAKE_* variables exported where the tests
+    // run; tests/cli.rs covers those
+    fn command() -> clap::Command {
+        Args::command().mut_args(|arg| arg.env(None))
+    }
+
+    fn try_parse_from<I, T>(args: I) -> Result<Args, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        Args::from_arg_matches(&command().try_get_matches_from(args)?)
+    }
+
+    fn parse_from<I, T>(args: I) -> Args
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        try_parse_from(args).unwrap()
+    }
+
+    #[test]
+    fn test_port_defaults_to_network() {
+        let args = parse_from(["bitcoin-handshake", "--ip-address", "127.0.0.1"]);
+        assert_eq!(args.port(), 8333);
+
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--ip-address",
+            "127.0.0.1",
+            "--network",
+            "testnet3",
+        ]);
+        assert_eq!(args.port(), 18333);
+
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--ip-address",
+            "127.0.0.1",
+            "--network",
+            "signet",
+            "--magic",
+            "deadbeef",
+        ]);
+        assert_eq!(args.network(), Network::Custom([0xDE, 0xAD, 0xBE, 0xEF]));
+        assert_eq!(args.port(), 38333);
+    }
+
+    #[tokio::test]
+    async fn test_explicit_port_overrides_network_default() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--ip-address",
+            "127.0.0.1",
+            "--network",
+            "regtest",
+            "--port",
+            "8333",
+        ]);
+        assert_eq!(args.port(), 8333);
+        assert_eq!(
+            args.resolve_addresses().await,
+            [Candidate {
+                name: "127.0.0.1".to_owned(),
+                addresses: vec!["127.0.0.1:8333".parse().unwrap()],
+            }],
+        );
+
+        // A port given with the address beats both
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1:18444",
+            "--port",
+            "8333",
+        ]);
+        assert_eq!(
+            args.resolve_addresses().await,
+            [Candidate::from(
+                "127.0.0.1:18444".parse::<SocketAddr>().unwrap()
+            )],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_repeated_addresses() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "-a",
+            "[::1]:8333",
+            "-i",
+            "::1",
+            "--port",
+            "18444",
+        ]);
+        let addresses: Vec<_> = args
+            .resolve_addresses()
+            .await
+            .into_iter()
+            .flat_map(|candidate| candidate.addresses)
+            .collect();
+        assert_eq!(
+            addresses,
+            [
+                "127.0.0.1:18444".parse::<SocketAddr>().unwrap(),
+                "[::1]:8333".parse().unwrap(),
+                "[::1]:18444".parse().unwrap(),
+            ],
+        );
+
+        let args = parse_from(["bitcoin-handshake", "--address", "node.example.com:8333"]);
+        assert_eq!(
+            args.address,
+            [PeerAddress::Host {
+                host: "node.example.com".to_owned(),
+                port: Some(8333),
+            }],
+        );
+        assert!(try_parse_from(["bitcoin-handshake", "--address", "[::1"]).is_err());
+
+        let args = parse_from(["bitcoin-handshake", "--peers-file", "peers.txt"]);
+        assert!(args.resolve_addresses().await.is_empty());
+        assert!(args.has_target());
+        assert!(!parse_from(["bitcoin-handshake"]).has_target());
+    }
+
+    #[test]
+    fn test_config_under_flags() {
+        let matches = command().get_matches_from([
+            "bitcoin-handshake",
+            "--network",
+            "signet",
+            "--read-timeout",
+            "5s",
+        ]);
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let file = Config::parse(
+            "network = \"testnet3\"\npeers = [\"192.0.2.1\"]\n\n[timeouts]\nconnect = \"3s\"\nread = \"30s\"\n",
+        )
+        .unwrap()
+        .config;
+        args.apply_config(file.merge(args.given_config(&matches)));
+
+        assert_eq!(args.network, Network::Signet);
+        assert_eq!(args.address, ["192.0.2.1".parse::<PeerAddress>().unwrap()]);
+        assert_eq!(args.connect_timeout, Duration::from_secs(3));
+        assert_eq!(args.read_timeout, Duration::from_secs(5));
+        assert_eq!(args.write_timeout(), Duration::from_secs(5));
+        assert_eq!(args.handshake_timeout, Duration::from_secs(60));
+        assert_eq!(args.user_agent, default_agent());
+        assert!(args.has_target());
+    }
+
+    #[test]
+    fn test_seed_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--seed",
+            "seed.bitcoin.sipa.be",
+            "--ipv6-only",
+        ]);
+        assert_eq!(args.seed.as_deref(), Some("seed.bitcoin.sipa.be"));
+        assert_eq!(args.address_family(), AddressFamily::Ipv6);
+
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--seed",
+            "seed.bitcoin.sipa.be",
+            "--ipv4-only",
+            "--ipv6-only",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_happy_eyeballs_flags() {
+        let args = parse_from(["bitcoin-handshake", "--address", "node.example.com"]);
+        assert_eq!(args.happy_eyeballs(), HappyEyeballs::default());
+
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "node.example.com",
+            "--prefer-ipv6",
+            "--no-happy-eyeballs",
+        ]);
+        let happy_eyeballs = args.happy_eyeballs();
+        assert!(happy_eyeballs.prefer_ipv6);
+        assert!(!happy_eyeballs.enabled);
+    }
+
+    #[test]
+    fn test_listen_flag() {
+        let args = parse_from(["bitcoin-handshake", "--listen", "0.0.0.0:8333"]);
+        assert_eq!(args.listen, Some("0.0.0.0:8333".parse().unwrap()));
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--listen",
+            "0.0.0.0:8333",
+            "--address",
+            "127.0.0.1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_flag() {
+        let args = parse_from(["bitcoin-handshake", "--parse", "session.bin"]);
+        assert_eq!(args.parse, Some(PathBuf::from("session.bin")));
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--parse",
+            "session.bin",
+            "--address",
+            "127.0.0.1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_retry_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--ip-address",
+            "127.0.0.1",
+            "--retries",
+            "3",
+            "--retry-backoff",
+            "250ms",
+        ]);
+        assert_eq!(args.retries, 3);
+        assert_eq!(args.retry_backoff, Duration::from_millis(250));
+
+        let args = parse_from(["bitcoin-handshake", "--ip-address", "127.0.0.1"]);
+        assert_eq!(args.retries, 0);
+    }
+
+    #[test]
+    fn test_crawl_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--crawl",
+            "--max-depth",
+            "1",
+            "--max-peers",
+            "20",
+        ]);
+        assert!(args.crawl);
+        assert_eq!(args.max_depth, 1);
+        assert_eq!(args.max_peers, 20);
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--crawl",
+            "--all",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_output_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--all",
+            "--output",
+            "csv",
+            "--output-file",
+            "peers.csv",
+        ]);
+        assert_eq!(args.output, OutputFormat::Csv);
+        assert_eq!(args.output_file, Some(PathBuf::from("peers.csv")));
+
+        let args = parse_from(["bitcoin-handshake", "--address", "127.0.0.1"]);
+        assert_eq!(args.output, OutputFormat::Text);
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--output",
+            "xml",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_json_flag() {
+        let args = parse_from(["bitcoin-handshake", "--address", "127.0.0.1", "--json"]);
+        assert!(args.json);
+        assert!(!parse_from(["bitcoin-handshake", "--address", "127.0.0.1"]).json);
+        assert!(try_parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--json",
+            "--ping",
+            "3",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_count_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--count",
+            "20",
+            "--interval",
+            "5s",
+        ]);
+        assert_eq!(args.count, Some(20));
+        assert_eq!(args.interval, Duration::from_secs(5));
+
+        let args = parse_from(["bitcoin-handshake", "--address", "127.0.0.1"]);
+        assert_eq!(args.count, None);
+        assert_eq!(args.interval, Duration::from_secs(1));
+
+        for conflicting in [&["--count", "0"][..], &["--count", "2", "--json"]] {
+            assert!(try_parse_from(
+                [
+                    &["bitcoin-handshake", "--address", "127.0.0.1"][..],
+                    conflicting
+                ]
+                .concat()
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_getaddr_flags() {
+        let args = parse_from([
+            "bitcoin-handshake",
+            "--address",
+            "127.0.0.1",
+            "--getaddr",
+            "--addr-wait",
+            "30s",
+        ]);
+        assert!(args.getaddr);
+        assert_eq!(args.addr_wait, Duration::from_secs(30));
+
+        let args = parse_from(["bitcoin-handshake", "--address", "127.0.0.1"]);
+        assert!(!args.getaddr);
+        assert_eq!(args.addr_wait, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        for invalid in ["", "0", "0ms", "-1s", "ten", "inf"] {
+            assert!(parse_duration(invalid).is_err(), "{invalid:?}");
+        }
     }
 }
@@ -1,117 +1,544 @@
-use std::io::Cursor;
+use std::io::{Cursor, IoSlice};
 
 use binrw::{meta::WriteEndian, BinRead, BinWrite};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 
 use crate::{
-    command::Command,
-    header::{ChecksumError, Header},
+    addr_payload::{AddrPayload, AddrV2Payload},
+    command::{command_name, requires_empty_payload, Command, CommandError},
+    command_registry::{CommandRegistry, CustomPayload},
+    disconnect::{DisconnectReason, Violation},
+    header::{checksum_hex, ChecksumError, Header, HeaderCreateError},
+    log::event,
     message_preparable::MessagePreparable,
+    network::Network,
+    ping_payload::{PingPayload, PongPayload},
+    protocol::{MAX_MESSAGE_SIZE, MAX_SIZE},
     version_payload::VersionPayload,
 };
 
 #[derive(Debug)]
 pub enum MessageType {
+    Addr(AddrPayload),
+    AddrV2(AddrV2Payload),
+    Alert(Vec<u8>),
+    GetAddr,
+    Ping(PingPayload),
+    Pong(PongPayload),
     Verack,
     Version(VersionPayload),
+    WtxidRelay,
+    Custom {
+        command: [u8; 12],
+        payload: CustomPayload,
+    },
+    // A well-formed frame whose command we don't implement, delivered intact
+    Unknown {
+        command: [u8; 12],
+        payload: Vec<u8>,
+    },
 }
 
-pub fn prepare_message<P>(payload: P) -> Result<Vec<u8>, binrw::error::Error>
+impl MessageType {
+    // The command field the message arrived with
+    pub fn command_raw(&self) -> [u8; 12] {
+        match self {
+            Self::Addr(_) => Command::Addr.into(),
+            Self::AddrV2(_) => Command::AddrV2.into(),
+            Self::Alert(_) => Command::Alert.into(),
+            Self::GetAddr => Command::GetAddr.into(),
+            Self::Ping(_) => Command::Ping.into(),
+            Self::Pong(_) => Command::Pong.into(),
+            Self::Verack => Command::Verack.into(),
+            Self::Version(_) => Command::Version.into(),
+            Self::WtxidRelay => Command::WtxidRelay.into(),
+            Self::Custom { command, .. } | Self::Unknown { command, .. } => *command,
+        }
+    }
+}
+
+// A payload paired with the network it is bound for, ready to be framed for the wire
+#[derive(Debug)]
+pub struct Message<P> {
+    network: Network,
+    payload: P,
+}
+
+impl<P> Message<P>
 where
     P: MessagePreparable,
     P: BinWrite + WriteEndian,
     for<'a> <P as BinWrite>::Args<'a>: Default,
 {
-    let buf = vec![0u8; Header::HEADER_BYTE_SIZE];
-    let mut cursor = Cursor::new(buf);
-    cursor.set_position(Header::HEADER_BYTE_SIZE as u64);
+    pub fn new(network: Network, payload: P) -> Self {
+        Self { network, payload }
+    }
 
-    payload.write(&mut cursor)?;
+    pub fn command(&self) -> Command {
+        P::COMMAND_TYPE
+    }
+
+    pub fn payload(&self) -> &P {
+        &self.payload
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PrepareMessageError> {
+        let mut buf = Vec::with_capacity(Header::HEADER_BYTE_SIZE + self.payload.size_hint());
+        buf.resize(Header::HEADER_BYTE_SIZE, 0);
+        let mut cursor = Cursor::new(buf);
+        cursor.set_position(Header::HEADER_BYTE_SIZE as u64);
+
+        self.payload.write(&mut cursor)?;
+
+        let buf = cursor.into_inner();
+        let header = Header::create(
+            self.network,
+            P::COMMAND_TYPE,
+            &buf[Header::HEADER_BYTE_SIZE..],
+        )?;
+
+        let mut cursor = Cursor::new(buf);
+        header.write(&mut cursor)?;
+        // Anything else would have overwritten the payload or left a gap before it
+        if cursor.position() != Header::HEADER_BYTE_SIZE as u64 {
+            return Err(PrepareMessageError::HeaderSize(cursor.position()));
+        }
+
+        Ok(cursor.into_inner())
+    }
+}
 
-    let buf = cursor.into_inner();
-    let header = Header::create(P::COMMAND_TYPE, &buf[Header::HEADER_BYTE_SIZE..]);
+pub fn prepare_message<P>(network: Network, payload: P) -> Result<Vec<u8>, PrepareMessageError>
+where
+    P: MessagePreparable,
+    P: BinWrite + WriteEndian,
+    for<'a> <P as BinWrite>::Args<'a>: Default,
+{
+    Message::new(network, payload).to_bytes()
+}
+
+// Like `prepare_message`, but writes the header and payload straight out instead of combining
+// them, returning the payload's length
+pub async fn prepare_message_into<P, W>(
+    network: Network,
+    payload: P,
+    writer: &mut W,
+) -> Result<usize, PrepareMessageError>
+where
+    P: MessagePreparable,
+    P: BinWrite + WriteEndian,
+    for<'a> <P as BinWrite>::Args<'a>: Default,
+    W: AsyncWrite + Unpin,
+{
+    let (header, payload) = frame_parts(network, payload)?;
+    write_frame(writer, &header, &payload, &mut 0).await?;
+    Ok(payload.len())
+}
 
-    let mut cursor = Cursor::new(buf);
+// The header and serialized payload of a frame, before either is written anywhere
+pub(crate) fn frame_parts<P>(
+    network: Network,
+    payload: P,
+) -> Result<(Header, Vec<u8>), PrepareMessageError>
+where
+    P: MessagePreparable,
+    P: BinWrite + WriteEndian,
+    for<'a> <P as BinWrite>::Args<'a>: Default,
+{
+    // The payload has to be serialized up front anyway, since the header carries its checksum
+    let mut cursor = Cursor::new(Vec::with_capacity(payload.size_hint()));
+    payload.write(&mut cursor)?;
+    let payload = cursor.into_inner();
+    let header = Header::create(network, P::COMMAND_TYPE, &payload)?;
+    Ok((header, payload))
+}
+
+// Keeps `written` up to date as bytes go out, so a caller that gives up partway knows how far it got
+pub(crate) async fn write_frame<W>(
+    writer: &mut W,
+    header: &Header,
+    payload: &[u8],
+    written: &mut usize,
+) -> Result<(), PrepareMessageError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut cursor = Cursor::new([0u8; Header::HEADER_BYTE_SIZE]);
     header.write(&mut cursor)?;
-    assert_eq!(cursor.position(), Header::HEADER_BYTE_SIZE as u64);
+    if cursor.position() != Header::HEADER_BYTE_SIZE as u64 {
+        return Err(PrepareMessageError::HeaderSize(cursor.position()));
+    }
 
-    Ok(cursor.into_inner())
+    let mut bufs = [IoSlice::new(cursor.get_ref()), IoSlice::new(payload)];
+    write_all_vectored(writer, &mut bufs, written).await?;
+    Ok(())
 }
 
-pub fn parse_message(data: &[u8]) -> Result<(MessageType, usize), MessageParseError> {
-    if data.len() < Header::HEADER_BYTE_SIZE {
-        return Err(MessageParseError::NotEnoughData);
+// Hands the header and payload to the transport together, without first copying them into one
+// buffer, and writes them one after the other when the transport can't take both at once
+async fn write_all_vectored<W>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+    total: &mut usize,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while !bufs.is_empty() {
+        let written = if writer.is_write_vectored() {
+            writer.write_vectored(bufs).await?
+        } else {
+            writer.write(&bufs[0]).await?
+        };
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        *total += written;
+        IoSlice::advance_slices(&mut bufs, written);
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct ParsedMessage {
+    header: Header,
+    message: MessageType,
+}
+
+impl ParsedMessage {
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    pub fn message(&self) -> &MessageType {
+        &self.message
     }
 
-    let mut cursor = Cursor::new(data);
+    pub fn into_message(self) -> MessageType {
+        self.message
+    }
 
+    pub fn into_parts(self) -> (Header, MessageType) {
+        (self.header, self.message)
+    }
+
+    pub(crate) fn from_parts(header: Header, message: MessageType) -> Self {
+        Self { header, message }
+    }
+}
+
+pub fn parse_message(
+    network: Network,
+    data: &[u8],
+) -> Result<(MessageType, usize), MessageParseError> {
+    parse_message_with_header(network, data)
+        .map(|(parsed, bytes_read)| (parsed.into_message(), bytes_read))
+}
+
+// Like `parse_message`, but keeps the header for callers that want its raw fields
+pub fn parse_message_with_header(
+    network: Network,
+    data: &[u8],
+) -> Result<(ParsedMessage, usize), MessageParseError> {
+    parse_message_with_options(network, &ParseOptions::default(), data)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions<'a> {
+    // Commands we don't know are offered to the registry before being reported as unknown
+    pub registry: Option<&'a CommandRegistry>,
+    // Larger payloads are refused as soon as the header is read, capped at `MAX_SIZE`
+    pub max_message_size: u32,
+    // Set when the caller already validated the checksum while receiving the payload
+    pub checksum_verified: bool,
+}
+
+impl Default for ParseOptions<'_> {
+    fn default() -> Self {
+        Self {
+            registry: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            checksum_verified: false,
+        }
+    }
+}
+
+pub fn parse_message_with_options(
+    network: Network,
+    options: &ParseOptions,
+    data: &[u8],
+) -> Result<(ParsedMessage, usize), MessageParseError> {
     // Read the header first
-    let header = Header::read(&mut cursor)?;
+    let peeked = Header::peek(data)?;
+    let header = peeked.header();
+    if header.magic() != network.magic() {
+        return Err(MessageParseError::MissingMagicNumber);
+    }
+
+    // Refuse oversized payloads before waiting for (and buffering) any of their bytes
+    if header.payload_size() > options.max_message_size.min(MAX_SIZE) {
+        return Err(MessageParseError::OversizedPayload {
+            command: header.command_raw(),
+            length: header.payload_size(),
+        });
+    }
+
+    // Messages like verack carry nothing, so a declared payload means the frame is bogus
+    if header.payload_size() != 0 && requires_empty_payload(&header.command_raw()) {
+        return Err(MessageParseError::MalformedData(None));
+    }
 
     // Ensure that the payload checksum is valid before even trying to parse the payload
-    header.validate_checksum(&data[(cursor.position() as usize)..])?;
+    if !options.checksum_verified {
+        header
+            .validate_checksum(&data[Header::HEADER_BYTE_SIZE..])
+            .inspect_err(|e| warn_checksum(header, e))?;
+    } else if data.len() < peeked.total_frame_len() {
+        return Err(MessageParseError::NotEnoughData);
+    }
+
+    // Payload parsers only ever see this frame's bytes, never the start of the next one
+    let payload = &data[Header::HEADER_BYTE_SIZE..peeked.total_frame_len()];
 
     // Introspect on the header type to determine which parsing should be applied
     let message = match header.command_type() {
+        Ok(Command::Addr) => {
+            let mut cursor = Cursor::new(payload);
+            let addr_payload = AddrPayload::read(&mut cursor)?;
+            warn_unconsumed(header, payload.len() - cursor.position() as usize);
+            MessageType::Addr(addr_payload)
+        }
+        Ok(Command::AddrV2) => {
+            let mut cursor = Cursor::new(payload);
+            let addrv2_payload = AddrV2Payload::read(&mut cursor)?;
+            warn_unconsumed(header, payload.len() - cursor.position() as usize);
+            MessageType::AddrV2(addrv2_payload)
+        }
+        // Alerts are deprecated, so keep the payload opaque
+        Ok(Command::Alert) => MessageType::Alert(payload.to_vec()),
+        Ok(Command::GetAddr) => MessageType::GetAddr,
+        Ok(Command::Ping) => {
+            let mut cursor = Cursor::new(payload);
+            let ping_payload = PingPayload::read(&mut cursor)?;
+            warn_unconsumed(header, payload.len() - cursor.position() as usize);
+            MessageType::Ping(ping_payload)
+        }
+        Ok(Command::Pong) => {
+            let mut cursor = Cursor::new(payload);
+            let pong_payload = PongPayload::read(&mut cursor)?;
+            warn_unconsumed(header, payload.len() - cursor.position() as usize);
+            MessageType::Pong(pong_payload)
+        }
         Ok(Command::Verack) => MessageType::Verack,
         Ok(Command::Version) => {
+            let mut cursor = Cursor::new(payload);
             let version_payload = VersionPayload::read(&mut cursor)?;
+            warn_unconsumed(header, payload.len() - cursor.position() as usize);
             MessageType::Version(version_payload)
         }
-        Err(_) => return Err(MessageParseError::UnknownMessageType(header.payload_size())),
+        Ok(Command::WtxidRelay) => MessageType::WtxidRelay,
+        Err(CommandError::MalformedCommand) => return Err(MessageParseError::MalformedData(None)),
+        Err(CommandError::UnknownCommand) => {
+            let command = header.command_raw();
+            match options
+                .registry
+                .and_then(|registry| registry.parse(&command, payload))
+            {
+                Some(result) => MessageType::Custom {
+                    command,
+                    payload: result?,
+                },
+                None => MessageType::Unknown {
+                    command,
+                    payload: payload.to_vec(),
+                },
+            }
+        }
+    };
+    let frame_len = peeked.total_frame_len();
+    let parsed = ParsedMessage {
+        header: peeked.into_header(),
+        message,
     };
-    let bytes_read = cursor.position() as usize;
-    Ok((message, bytes_read))
+    Ok((parsed, frame_len))
+}
+
+// Newer peers may append fields we don't know about yet, so leftovers are only worth a warning
+fn warn_unconsumed(header: &Header, unconsumed: usize) {
+    if unconsumed > 0 {
+        event!(
+            Warn,
+            "ignoring {unconsumed} trailing byte(s) in {:?} payload",
+            command_name(&header.command_raw()),
+        );
+    }
+}
+
+pub(crate) fn warn_checksum(header: &Header, error: &ChecksumError) {
+    event!(
+        Warn,
+        "{:?} frame failed its checksum: {error}",
+        command_name(&header.command_raw()),
+    );
+}
+
+// Counts the leading bytes that can't start a frame, up to the next occurrence of the network magic
+pub fn skip_to_magic(network: Network, data: &[u8]) -> usize {
+    let magic = network.magic();
+    data.windows(magic.len())
+        .position(|window| window == magic)
+        .unwrap_or_else(|| {
+            // Keep a trailing partial magic, since the rest of it may still arrive
+            let partial = (1..magic.len())
+                .rev()
+                .find(|&len| data.ends_with(&magic[..len]))
+                .unwrap_or(0);
+            data.len() - partial
+        })
+}
+
+#[derive(Debug)]
+pub enum PrepareMessageError {
+    Serialization(binrw::Error),
+    Header(HeaderCreateError),
+    HeaderSize(u64),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for PrepareMessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Serialization(e) => e.fmt(f),
+            Self::Header(e) => e.fmt(f),
+            Self::HeaderSize(size) => write!(
+                f,
+                "wrote a {size} byte header instead of {}",
+                Header::HEADER_BYTE_SIZE,
+            ),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PrepareMessageError {}
+
+impl From<binrw::Error> for PrepareMessageError {
+    fn from(e: binrw::Error) -> Self {
+        Self::Serialization(e)
+    }
+}
+
+impl From<std::io::Error> for PrepareMessageError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<HeaderCreateError> for PrepareMessageError {
+    fn from(e: HeaderCreateError) -> Self {
+        Self::Header(e)
+    }
 }
 
 #[derive(Debug)]
 pub enum MessageParseError {
     NotEnoughData,
     MissingMagicNumber,
-    IncorrectChecksum,
-    MalformedData,
-    UnknownMessageType(u32),
+    IncorrectChecksum { expected: u32, computed: u32 },
+    // Carries the binrw error when that is what failed, for which field and where
+    MalformedData(Option<Box<binrw::Error>>),
+    OversizedPayload { command: [u8; 12], length: u32 },
+}
+
+impl MessageParseError {
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            // Only surfaces from a stream when it ended partway through a frame
+            Self::NotEnoughData => DisconnectReason::PeerClosed,
+            Self::MissingMagicNumber => DisconnectReason::ProtocolViolation(Violation::BadMagic),
+            Self::IncorrectChecksum { .. } => DisconnectReason::ChecksumMismatch,
+            Self::MalformedData(_) => {
+                DisconnectReason::ProtocolViolation(Violation::MalformedPayload)
+            }
+            Self::OversizedPayload { .. } => DisconnectReason::OversizedMessage,
+        }
+    }
 }
 
 impl std::fmt::Display for MessageParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match *self {
+        match self {
             Self::NotEnoughData => write!(f, "not enough data"),
             Self::MissingMagicNumber => write!(f, "missing magic number"),
-            Self::IncorrectChecksum => write!(f, "incorrect payload checksum"),
-            Self::MalformedData => write!(f, "malformed data"),
-            Self::UnknownMessageType(_) => write!(f, "unknown or unimplemented message type"),
+            Self::IncorrectChecksum { expected, computed } => write!(
+                f,
+                "incorrect payload checksum (expected {}, computed {})",
+                checksum_hex(*expected),
+                checksum_hex(*computed),
+            ),
+            Self::MalformedData(e) => match e.as_deref().and_then(error_position) {
+                Some(pos) => write!(f, "malformed data at payload offset {pos}"),
+                None => write!(f, "malformed data"),
+            },
+            Self::OversizedPayload { command, length } => write!(
+                f,
+                "message type {:?} declares an oversized {length} byte payload",
+                command_name(command),
+            ),
         }
     }
 }
 
-impl std::error::Error for MessageParseError {}
+impl std::error::Error for MessageParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::MalformedData(Some(e)) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<binrw::Error> for MessageParseError {
     fn from(e: binrw::Error) -> Self {
         match e {
             binrw::Error::BadMagic { .. } => Self::MissingMagicNumber,
-            _ => Self::MalformedData,
+            e => Self::MalformedData(Some(Box::new(e))),
         }
     }
 }
 
+fn error_position(e: &binrw::Error) -> Option<u64> {
+    match e.root_cause() {
+        binrw::Error::BadMagic { pos, .. }
+        | binrw::Error::AssertFail { pos, .. }
+        | binrw::Error::Custom { pos, .. }
+        | binrw::Error::NoVariantMatch { pos }
+        | binrw::Error::EnumErrors { pos, .. } => Some(*pos),
+        _ => None,
+    }
+}
+
 impl From<ChecksumError> for MessageParseError {
     fn from(e: ChecksumError) -> Self {
         match e {
             ChecksumError::InsufficientPayload(_, _) => Self::NotEnoughData,
-            ChecksumError::IncorrectChecksum => Self::IncorrectChecksum,
+            ChecksumError::IncorrectChecksum { expected, computed } => {
+                Self::IncorrectChecksum { expected, computed }
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        net::IpAddr,
-        time::{Duration, SystemTime},
-    };
+    use std::time::{Duration, SystemTime};
 
-    use crate::verack_payload::VerackPayload;
+    use crate::{
+        protocol::{ADDR_FROM_VERSION, PROTOCOL_VERSION},
+        utils::double_sha256_hash,
+        verack_payload::VerackPayload,
+        wtxidrelay_payload::WtxidRelayPayload,
+    };
 
     use super::*;
 
@@ -119,7 +546,7 @@ mod tests {
     fn test_prepare_verack_message() {
         let verack_payload = VerackPayload;
 
-        let verack_message = prepare_message(verack_payload).unwrap();
+        let verack_message = prepare_message(Network::Mainnet, verack_payload).unwrap();
         assert_eq!(
             verack_message,
             hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap(),
@@ -128,22 +555,266 @@ mod tests {
 
     #[test]
     fn test_prepare_version_message() {
+        // The builder defaults advertise our BIP 14 user agent
         let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1640961477);
-        let version_payload =
-            VersionPayload::create(timestamp, "46.19.137.74".parse::<IpAddr>().unwrap(), 8333);
+        let version_payload = VersionPayload::builder()
+            .timestamp(timestamp)
+            .addr_recv("46.19.137.74:8333".parse().unwrap())
+            .build()
+            .unwrap();
 
-        let version_message = prepare_message(version_payload).unwrap();
+        let version_message = prepare_message(Network::Mainnet, version_payload).unwrap();
         assert_eq!(
             version_message,
-            hex::decode("F9BEB4D976657273696F6E0000000000550000002C2F86F37E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap(),
+            hex::decode("F9BEB4D976657273696F6E00000000006E00000021436C847E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D0000000000000000192F626974636F696E2D68616E647368616B653A302E312E302F00000000").unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_prepare_wtxidrelay_message() {
+        let wtxidrelay_payload = WtxidRelayPayload;
+
+        let wtxidrelay_message = prepare_message(Network::Mainnet, wtxidrelay_payload).unwrap();
+        assert_eq!(
+            wtxidrelay_message,
+            hex::decode("F9BEB4D9777478696472656C61790000000000005DF6E0E2").unwrap(),
         );
     }
 
+    #[test]
+    fn test_prepare_version_message_with_user_agent() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1640961477);
+        let user_agent = "/Satoshi:0.7.2/";
+        let version_payload = VersionPayload::builder()
+            .timestamp(timestamp)
+            .addr_recv("46.19.137.74:8333".parse().unwrap())
+            .user_agent(user_agent)
+            .build()
+            .unwrap();
+
+        let version_message = prepare_message(Network::Mainnet, version_payload).unwrap();
+        let payload = &version_message[Header::HEADER_BYTE_SIZE..];
+
+        // The empty agent payload is 0x55 bytes, so the agent adds exactly its own length
+        assert_eq!(payload.len(), 0x55 + user_agent.len());
+        assert!(payload
+            .windows(user_agent.len() + 1)
+            .any(|window| window[0] == user_agent.len() as u8
+                && &window[1..] == user_agent.as_bytes()));
+
+        let header = Header::read(&mut Cursor::new(&version_message)).unwrap();
+        assert_eq!(header.payload_size() as usize, payload.len());
+        assert!(matches!(header.validate_checksum(payload), Ok(())));
+    }
+
+    #[test]
+    fn test_prepare_version_message_with_relay() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1640961477);
+        let create_payload = |relay| {
+            VersionPayload::builder()
+                .timestamp(timestamp)
+                .addr_recv("46.19.137.74:8333".parse().unwrap())
+                .relay(relay)
+                .build()
+                .unwrap()
+        };
+
+        let without_relay = prepare_message(Network::Mainnet, create_payload(None)).unwrap();
+
+        for relay in [false, true] {
+            let version_message =
+                prepare_message(Network::Mainnet, create_payload(Some(relay))).unwrap();
+            assert_eq!(version_message.len(), without_relay.len() + 1);
+
+            let payload = &version_message[Header::HEADER_BYTE_SIZE..];
+            assert_eq!(payload.last(), Some(&(relay as u8)));
+
+            let header = Header::read(&mut Cursor::new(&version_message)).unwrap();
+            assert_eq!(header.payload_size() as usize, payload.len());
+            assert!(matches!(header.validate_checksum(payload), Ok(())));
+
+            let (message, bytes_read) = parse_message(Network::Mainnet, &version_message).unwrap();
+            assert_eq!(bytes_read, version_message.len());
+            match message {
+                MessageType::Version(version_payload) => {
+                    assert_eq!(version_payload.relay(), Some(relay));
+                }
+                _ => panic!("expected a version message"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prepare_message_into_matches_prepare_message() {
+        let mut written = Vec::new();
+        prepare_message_into(Network::Mainnet, VerackPayload, &mut written)
+            .await
+            .unwrap();
+        assert_eq!(
+            written,
+            prepare_message(Network::Mainnet, VerackPayload).unwrap()
+        );
+
+        let create_payload = || {
+            VersionPayload::builder()
+                .timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(1640961477))
+                .nonce(42)
+                .relay(Some(true))
+                .build()
+                .unwrap()
+        };
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        let writing = tokio::spawn(async move {
+            prepare_message_into(Network::Mainnet, create_payload(), &mut writer)
+                .await
+                .unwrap();
+        });
+        let mut received = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut received)
+            .await
+            .unwrap();
+        writing.await.unwrap();
+        assert_eq!(
+            received,
+            prepare_message(Network::Mainnet, create_payload()).unwrap()
+        );
+    }
+
+    // Records every write call, optionally accepting at most `max_write` bytes per call
+    struct RecordingWriter {
+        vectored: bool,
+        max_write: usize,
+        calls: Vec<Vec<u8>>,
+    }
+
+    impl AsyncWrite for RecordingWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let len = buf.len().min(self.max_write);
+            self.calls.push(buf[..len].to_vec());
+            std::task::Poll::Ready(Ok(len))
+        }
+
+        fn poll_write_vectored(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let mut call: Vec<u8> = bufs.iter().flat_map(|buf| buf.iter().copied()).collect();
+            call.truncate(self.max_write);
+            let len = call.len();
+            self.calls.push(call);
+            std::task::Poll::Ready(Ok(len))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            self.vectored
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prepare_message_into_vectored_writes() {
+        let create_payload = || VersionPayload::builder().nonce(42).build().unwrap();
+        let expected = prepare_message(Network::Mainnet, create_payload()).unwrap();
+
+        for (vectored, max_write, calls) in [
+            (true, usize::MAX, 1),
+            (true, 10, expected.len().div_ceil(10)),
+            (false, usize::MAX, 2),
+        ] {
+            let mut writer = RecordingWriter {
+                vectored,
+                max_write,
+                calls: Vec::new(),
+            };
+            prepare_message_into(Network::Mainnet, create_payload(), &mut writer)
+                .await
+                .unwrap();
+            assert_eq!(writer.calls.len(), calls);
+            assert_eq!(writer.calls.concat(), expected);
+        }
+    }
+
+    #[test]
+    fn test_message_accessors() {
+        let message = Message::new(Network::Mainnet, WtxidRelayPayload);
+        assert_eq!(message.command(), Command::WtxidRelay);
+        assert!(matches!(message.payload(), WtxidRelayPayload));
+        assert_eq!(
+            message.to_bytes().unwrap(),
+            prepare_message(Network::Mainnet, WtxidRelayPayload).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_size_hint_is_exact() {
+        let verack_message = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        assert_eq!(
+            verack_message.len(),
+            Header::HEADER_BYTE_SIZE + VerackPayload.size_hint()
+        );
+
+        for (version, relay) in [
+            (PROTOCOL_VERSION, None),
+            (PROTOCOL_VERSION, Some(true)),
+            (ADDR_FROM_VERSION - 1, None),
+        ] {
+            let create_payload = || {
+                VersionPayload::builder()
+                    .version(version)
+                    .user_agent("/Satoshi:0.7.2/")
+                    .relay(relay)
+                    .build()
+                    .unwrap()
+            };
+            let size_hint = create_payload().size_hint();
+            let version_message = prepare_message(Network::Mainnet, create_payload()).unwrap();
+            assert_eq!(version_message.len(), Header::HEADER_BYTE_SIZE + size_hint);
+        }
+    }
+
+    #[test]
+    fn test_prepare_with_underestimated_size_hint() {
+        #[binrw::binrw]
+        #[brw(little)]
+        struct Underestimated([u8; 64]);
+
+        impl MessagePreparable for Underestimated {
+            const COMMAND_TYPE: Command = Command::Alert;
+
+            fn size_hint(&self) -> usize {
+                1
+            }
+        }
+
+        let message = prepare_message(Network::Mainnet, Underestimated([0xA5; 64])).unwrap();
+        let (message, bytes_read) = parse_message(Network::Mainnet, &message).unwrap();
+        assert_eq!(bytes_read, Header::HEADER_BYTE_SIZE + 64);
+        assert!(matches!(message, MessageType::Alert(alert) if alert == [0xA5; 64]));
+    }
+
     #[test]
     fn test_parse_verack_message() {
         let raw_binary = hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap();
 
-        let (message, bytes_read) = parse_message(&raw_binary).unwrap();
+        let (message, bytes_read) = parse_message(Network::Mainnet, &raw_binary).unwrap();
         assert!(matches!(message, MessageType::Verack));
         assert_eq!(raw_binary.len(), bytes_read);
     }
@@ -152,8 +823,390 @@ mod tests {
     fn test_parse_version_message() {
         let raw_binary = hex::decode("F9BEB4D976657273696F6E0000000000550000002C2F86F37E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap();
 
-        let (message, bytes_read) = parse_message(&raw_binary).unwrap();
+        let (message, bytes_read) = parse_message(Network::Mainnet, &raw_binary).unwrap();
         assert!(matches!(message, MessageType::Version(_)));
         assert_eq!(raw_binary.len(), bytes_read);
     }
+
+    #[test]
+    fn test_parse_message_with_header() {
+        let raw_binary = hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap();
+
+        let (parsed, bytes_read) =
+            parse_message_with_header(Network::Mainnet, &raw_binary).unwrap();
+        assert_eq!(bytes_read, raw_binary.len());
+        assert!(matches!(parsed.message(), MessageType::Verack));
+        assert_eq!(&parsed.header().command_raw(), b"verack\0\0\0\0\0\0");
+        assert_eq!(parsed.header().payload_size(), 0);
+        assert_eq!(parsed.header().checksum(), 0xE2E0F65D);
+
+        let raw_binary = hex::decode("F9BEB4D976657273696F6E0000000000550000002C2F86F37E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap();
+
+        let (parsed, bytes_read) =
+            parse_message_with_header(Network::Mainnet, &raw_binary).unwrap();
+        assert_eq!(bytes_read, raw_binary.len());
+        assert!(matches!(parsed.message(), MessageType::Version(_)));
+        assert_eq!(&parsed.header().command_raw(), b"version\0\0\0\0\0");
+        assert_eq!(parsed.header().payload_size(), 0x55);
+        assert_eq!(parsed.header().checksum(), 0xF3862F2C);
+    }
+
+    #[test]
+    fn test_parse_ping_and_pong() {
+        let frame =
+            prepare_message(Network::Mainnet, PingPayload::new(0x0102030405060708)).unwrap();
+        assert_eq!(&frame[24..], &[8, 7, 6, 5, 4, 3, 2, 1]);
+        let (message, bytes_read) = parse_message(Network::Mainnet, &frame).unwrap();
+        assert_eq!(bytes_read, 32);
+        assert!(matches!(message, MessageType::Ping(ping) if ping.nonce() == 0x0102030405060708));
+
+        let frame = prepare_message(Network::Mainnet, PingPayload::new(9).pong()).unwrap();
+        let (message, _) = parse_message(Network::Mainnet, &frame).unwrap();
+        assert!(matches!(message, MessageType::Pong(pong) if pong.nonce() == 9));
+
+        // Pings from before BIP 31 carried no nonce, and nothing still speaking the protocol sends them
+        let mut frame = Network::Mainnet.magic().to_vec();
+        frame.extend(b"ping\0\0\0\0\0\0\0\0");
+        frame.extend(0u32.to_le_bytes());
+        frame.extend(&double_sha256_hash(&[])[..4]);
+        assert!(matches!(
+            parse_message(Network::Mainnet, &frame),
+            Err(MessageParseError::MalformedData(_)),
+        ));
+    }
+
+    #[test]
+    fn test_parse_wtxidrelay_interleaved_before_verack() {
+        let mut transcript = hex::decode("F9BEB4D976657273696F6E0000000000550000002C2F86F37E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap();
+        transcript.extend(prepare_message(Network::Mainnet, WtxidRelayPayload).unwrap());
+        transcript.extend(prepare_message(Network::Mainnet, VerackPayload).unwrap());
+
+        let mut offset = 0;
+
+        let (message, bytes_read) = parse_message(Network::Mainnet, &transcript[offset..]).unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+        offset += bytes_read;
+
+        let (message, bytes_read) = parse_message(Network::Mainnet, &transcript[offset..]).unwrap();
+        assert!(matches!(message, MessageType::WtxidRelay));
+        offset += bytes_read;
+
+        let (message, bytes_read) = parse_message(Network::Mainnet, &transcript[offset..]).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        offset += bytes_read;
+
+        assert_eq!(offset, transcript.len());
+    }
+
+    #[test]
+    fn test_parse_alert_message_followed_by_verack() {
+        let alert_payload = hex::decode("0102030405060708090A").unwrap();
+        let mut raw_binary = hex::decode("F9BEB4D9616C657274000000000000000A000000").unwrap();
+        raw_binary.extend(&double_sha256_hash(&alert_payload)[..4]);
+        raw_binary.extend(&alert_payload);
+        raw_binary.extend(hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap());
+
+        let (message, bytes_read) = parse_message(Network::Mainnet, &raw_binary).unwrap();
+        assert!(matches!(&message, MessageType::Alert(payload) if *payload == alert_payload));
+        assert_eq!(bytes_read, Header::HEADER_BYTE_SIZE + alert_payload.len());
+
+        let (message, verack_bytes_read) =
+            parse_message(Network::Mainnet, &raw_binary[bytes_read..]).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(raw_binary.len(), bytes_read + verack_bytes_read);
+    }
+
+    #[test]
+    fn test_prepare_parse_testnet_verack_message() {
+        let verack_message = prepare_message(Network::Testnet3, VerackPayload).unwrap();
+        assert_eq!(
+            verack_message,
+            hex::decode("0B11090776657261636B000000000000000000005DF6E0E2").unwrap(),
+        );
+
+        let (message, bytes_read) = parse_message(Network::Testnet3, &verack_message).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(bytes_read, verack_message.len());
+    }
+
+    #[test]
+    fn test_parse_reports_incorrect_checksum() {
+        let raw_binary = hex::decode("F9BEB4D976657261636B00000000000000000000DEADBEEF").unwrap();
+
+        let error = parse_message(Network::Mainnet, &raw_binary).unwrap_err();
+        assert!(matches!(
+            error,
+            MessageParseError::IncorrectChecksum {
+                expected: 0xEFBEADDE,
+                computed: 0xE2E0F65D,
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "incorrect payload checksum (expected deadbeef, computed 5df6e0e2)",
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_other_network_magic() {
+        let raw_binary = hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap();
+
+        assert!(matches!(
+            parse_message(Network::Testnet3, &raw_binary),
+            Err(MessageParseError::MissingMagicNumber),
+        ));
+    }
+
+    #[test]
+    fn test_prepare_parse_custom_magic_verack_message() {
+        let network = Network::Custom(Network::parse_magic("FABFB5DA").unwrap());
+
+        let verack_message = prepare_message(network, VerackPayload).unwrap();
+        assert_eq!(
+            verack_message,
+            hex::decode("FABFB5DA76657261636B000000000000000000005DF6E0E2").unwrap(),
+        );
+
+        let (message, bytes_read) = parse_message(network, &verack_message).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(bytes_read, verack_message.len());
+
+        // The custom magic is indistinguishable on the wire from the matching preset
+        assert!(parse_message(Network::Regtest, &verack_message).is_ok());
+    }
+
+    #[test]
+    fn test_parse_malformed_command_padding() {
+        let frame = |command: &[u8; 12]| {
+            let mut raw_binary = Network::Mainnet.magic().to_vec();
+            raw_binary.extend(command);
+            raw_binary.extend(hex::decode("000000005DF6E0E2").unwrap());
+            raw_binary
+        };
+
+        assert!(matches!(
+            parse_message(Network::Mainnet, &frame(b"sendtxrcncl\0")),
+            Ok((MessageType::Unknown { .. }, _)),
+        ));
+
+        for command in [
+            b"version\0\0\0\0X",
+            b"ver\0sion\0\0\0\0",
+            b"bad\xFF\0\0\0\0\0\0\0\0",
+        ] {
+            assert!(matches!(
+                parse_message(Network::Mainnet, &frame(command)),
+                Err(MessageParseError::MalformedData(_)),
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_registered_custom_message() {
+        let mut registry = CommandRegistry::new();
+        registry
+            .register("myext", |payload: &[u8]| Ok(payload.len()))
+            .unwrap();
+
+        let payload = [1, 2, 3];
+        let mut raw_binary = Network::Mainnet.magic().to_vec();
+        raw_binary.extend(b"myext\0\0\0\0\0\0\0");
+        raw_binary.extend((payload.len() as u32).to_le_bytes());
+        raw_binary.extend(&double_sha256_hash(&payload)[..4]);
+        raw_binary.extend(payload);
+
+        let options = ParseOptions {
+            registry: Some(&registry),
+            ..Default::default()
+        };
+        let (parsed, bytes_read) =
+            parse_message_with_options(Network::Mainnet, &options, &raw_binary).unwrap();
+        assert_eq!(bytes_read, raw_binary.len());
+        match parsed.into_message() {
+            MessageType::Custom { command, payload } => {
+                assert_eq!(&command, b"myext\0\0\0\0\0\0\0");
+                assert_eq!(payload.downcast_ref::<usize>(), Some(&3));
+            }
+            _ => panic!("expected a custom message"),
+        }
+
+        // Without the registry the command is still unknown
+        assert!(matches!(
+            parse_message(Network::Mainnet, &raw_binary),
+            Ok((MessageType::Unknown { .. }, _)),
+        ));
+    }
+
+    #[test]
+    fn test_parse_rejects_oversized_payload() {
+        // Only the header is present, the declared 8 byte payload never needs to arrive
+        let raw_binary = hex::decode("F9BEB4D976657273696F6E00000000000800000000000000").unwrap();
+
+        let options = ParseOptions {
+            max_message_size: 7,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_message_with_options(Network::Mainnet, &options, &raw_binary),
+            Err(MessageParseError::OversizedPayload { length: 8, .. }),
+        ));
+
+        let options = ParseOptions {
+            max_message_size: 8,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_message_with_options(Network::Mainnet, &options, &raw_binary),
+            Err(MessageParseError::NotEnoughData),
+        ));
+
+        // Raising the limit can't lift it past the absolute ceiling
+        let raw_binary = hex::decode("F9BEB4D976657273696F6E00000000000100000200000000").unwrap();
+        let options = ParseOptions {
+            max_message_size: u32::MAX,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_message_with_options(Network::Mainnet, &options, &raw_binary),
+            Err(MessageParseError::OversizedPayload { length, .. }) if length == MAX_SIZE + 1,
+        ));
+    }
+
+    #[test]
+    fn test_skip_to_magic_before_verack() {
+        let mut raw_binary = hex::decode("0102030405").unwrap();
+        raw_binary.extend(hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap());
+
+        let skipped = skip_to_magic(Network::Mainnet, &raw_binary);
+        assert_eq!(skipped, 5);
+
+        let (message, bytes_read) =
+            parse_message(Network::Mainnet, &raw_binary[skipped..]).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(skipped + bytes_read, raw_binary.len());
+    }
+
+    #[test]
+    fn test_skip_to_magic_past_partial_false_magic() {
+        let mut raw_binary = hex::decode("00F9BEB400F9").unwrap();
+        raw_binary.extend(hex::decode("F9BEB4D976657261636B000000000000000000005DF6E0E2").unwrap());
+
+        assert_eq!(skip_to_magic(Network::Mainnet, &raw_binary), 6);
+    }
+
+    #[test]
+    fn test_skip_to_magic_without_magic() {
+        let raw_binary = hex::decode("0102030405060708").unwrap();
+        assert_eq!(
+            skip_to_magic(Network::Mainnet, &raw_binary),
+            raw_binary.len()
+        );
+
+        // A magic cut off at the end of the buffer is kept for when the rest arrives
+        let raw_binary = hex::decode("01020304F9BEB4").unwrap();
+        assert_eq!(skip_to_magic(Network::Mainnet, &raw_binary), 4);
+
+        assert_eq!(skip_to_magic(Network::Mainnet, &[]), 0);
+    }
+
+    #[test]
+    fn test_parse_version_bounded_by_declared_length() {
+        // A magic starting with 0x01 would read as a relay flag if the parser overran the payload
+        let network = Network::Custom([0x01, 0x02, 0x03, 0x04]);
+        let version_payload = VersionPayload::builder().build().unwrap();
+        assert_eq!(version_payload.relay(), None);
+
+        let mut raw_binary = prepare_message(network, version_payload).unwrap();
+        let version_len = raw_binary.len();
+        raw_binary.extend(prepare_message(network, VerackPayload).unwrap());
+
+        let (message, bytes_read) = parse_message(network, &raw_binary).unwrap();
+        assert_eq!(bytes_read, version_len);
+        match message {
+            MessageType::Version(version_payload) => assert_eq!(version_payload.relay(), None),
+            _ => panic!("expected a version message"),
+        }
+
+        let (message, bytes_read) = parse_message(network, &raw_binary[version_len..]).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(version_len + bytes_read, raw_binary.len());
+    }
+
+    #[test]
+    fn test_parse_version_with_trailing_bytes() {
+        let mut payload = hex::decode("7E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap();
+        payload.extend([0xAA, 0xBB]);
+
+        let mut raw_binary = hex::decode("F9BEB4D976657273696F6E0000000000").unwrap();
+        raw_binary.extend((payload.len() as u32).to_le_bytes());
+        raw_binary.extend(&double_sha256_hash(&payload)[..4]);
+        raw_binary.extend(&payload);
+
+        // The unknown extra fields are skipped along with the rest of the frame
+        let (message, bytes_read) = parse_message(Network::Mainnet, &raw_binary).unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+        assert_eq!(bytes_read, raw_binary.len());
+    }
+
+    #[test]
+    fn test_parse_rejects_verack_with_payload() {
+        let payload = [0u8; 100];
+        let mut raw_binary = hex::decode("F9BEB4D976657261636B000000000000").unwrap();
+        raw_binary.extend((payload.len() as u32).to_le_bytes());
+        raw_binary.extend(&double_sha256_hash(&payload)[..4]);
+        raw_binary.extend(payload);
+        let verack_len = raw_binary.len();
+        raw_binary.extend(hex::decode("F9BEB4D976657273696F6E0000000000550000002C2F86F37E1101000000000000000000C515CF6100000000000000000000000000000000000000000000FFFF2E13894A208D000000000000000000000000000000000000FFFF7F000001208D00000000000000000000000000").unwrap());
+
+        assert!(matches!(
+            parse_message(Network::Mainnet, &raw_binary),
+            Err(MessageParseError::MalformedData(_)),
+        ));
+
+        // The version frame still starts right after the declared verack payload
+        let (message, bytes_read) =
+            parse_message(Network::Mainnet, &raw_binary[verack_len..]).unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+        assert_eq!(verack_len + bytes_read, raw_binary.len());
+
+        // The same goes for empty-payload commands we don't otherwise handle
+        let mut raw_binary = hex::decode("F9BEB4D9676574616464720000000000").unwrap();
+        raw_binary.extend(1u32.to_le_bytes());
+        raw_binary.extend(&double_sha256_hash(&[0])[..4]);
+        raw_binary.push(0);
+        assert!(matches!(
+            parse_message(Network::Mainnet, &raw_binary),
+            Err(MessageParseError::MalformedData(_)),
+        ));
+    }
+
+    #[test]
+    fn test_parse_unknown_message_followed_by_verack() {
+        // sendcmpct: announce=false, version=2
+        let payload = hex::decode("000200000000000000").unwrap();
+        let mut raw_binary = hex::decode("F9BEB4D973656E64636D706374000000").unwrap();
+        raw_binary.extend((payload.len() as u32).to_le_bytes());
+        raw_binary.extend(&double_sha256_hash(&payload)[..4]);
+        raw_binary.extend(&payload);
+        let sendcmpct_len = raw_binary.len();
+        raw_binary.extend(prepare_message(Network::Mainnet, VerackPayload).unwrap());
+
+        let (message, bytes_read) = parse_message(Network::Mainnet, &raw_binary).unwrap();
+        assert_eq!(bytes_read, sendcmpct_len);
+        match message {
+            MessageType::Unknown {
+                command,
+                payload: unknown_payload,
+            } => {
+                assert_eq!(command_name(&command), "sendcmpct");
+                assert_eq!(unknown_payload, payload);
+            }
+            _ => panic!("expected an unknown message"),
+        }
+
+        let (message, bytes_read) =
+            parse_message(Network::Mainnet, &raw_binary[sendcmpct_len..]).unwrap();
+        assert!(matches!(message, MessageType::Verack));
+        assert_eq!(sendcmpct_len + bytes_read, raw_binary.len());
+    }
 }
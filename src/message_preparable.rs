@@ -2,4 +2,10 @@ use crate::command::Command;
 
 pub trait MessagePreparable {
     const COMMAND_TYPE: Command;
+
+    // Serialized payload length, used to size the message buffer up front; an underestimate
+    // only costs a reallocation
+    fn size_hint(&self) -> usize {
+        0
+    }
 }
@@ -0,0 +1,2270 @@
+use std::{collections::VecDeque, net::SocketAddr, ops::ControlFlow, time::Duration};
+
+use binrw::{meta::WriteEndian, BinWrite};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    addr_payload::GetAddrPayload,
+    command::{command_name, Command},
+    command_registry::CommandRegistry,
+    connect::{self, ConnectError, HappyEyeballs, RetryPolicy, DEFAULT_CONNECT_TIMEOUT},
+    connection_state::{ConnectionState, HandshakeProgress, ProtocolStateError},
+    decoder::{MessageDecoder, DEFAULT_MAX_BUFFER_BYTES},
+    disconnect::{DisconnectReason, Rejection, Violation},
+    exit_code,
+    handshake_config::HandshakeConfig,
+    hexdump::format_frame,
+    log::event,
+    message::{
+        frame_parts, write_frame, MessageParseError, MessageType, ParsedMessage,
+        PrepareMessageError,
+    },
+    message_preparable::MessagePreparable,
+    network::Network,
+    ping_payload::{PingPayload, PongPayload},
+    protocol::MAX_MESSAGE_SIZE,
+    recording::{Direction, Recorded, Recorder},
+    services::ServiceFlags,
+    stats::SessionStats,
+    verack_payload::VerackPayload,
+    version_payload::{VersionPayload, VersionPayloadBuildError},
+    wtxidrelay_payload::WtxidRelayPayload,
+};
+
+pub struct MessagingSystem<T = TcpStream> {
+    stream: T,
+    decoder: MessageDecoder,
+    // Messages that arrived while we were waiting for something else, delivered before reading more
+    deferred: VecDeque<ParsedMessage>,
+    remote_addr: SocketAddr,
+    // Only known when we dialed the connection ourselves
+    local_address: Option<SocketAddr>,
+    // How long dialing took, retries included; likewise only known when we dialed
+    connect_time: Option<Duration>,
+    config: HandshakeConfig,
+    progress: HandshakeProgress,
+    nonce: u64,
+    // What was sent; the decoder counts what was received
+    sent_stats: SessionStats,
+    // Why the connection would end, as of the last send, receive, or handshake to fail
+    disconnect: Option<DisconnectReason>,
+    pub max_message_size: u32,
+    // Caps how much may sit in the receive buffer, however slowly a frame trickles in
+    pub max_buffer_bytes: usize,
+    pub resync: bool,
+    // How long a single read may go without any bytes arriving
+    pub read_timeout: Duration,
+    // How long writing one message, or flushing before a shutdown, may take in all; a peer that
+    // stops reading would otherwise hang us once the socket buffers fill
+    pub write_timeout: Duration,
+    // Dump every complete frame sent and received to stderr as annotated hex
+    pub trace_wire: bool,
+    // Answer pings as they arrive instead of handing them to the caller, so a long-lived session
+    // isn't dropped by the peer's ping timeout
+    pub auto_pong: bool,
+    // Once cancelled, waiting on the peer fails with `MessageReceiveError::Interrupted`, so
+    // whatever loop is reading winds down and the connection can be closed properly
+    pub cancel: CancellationToken,
+}
+
+// Long enough for a slow peer, short enough to give up on services that never speak
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub const DEFAULT_WRITE_TIMEOUT: Duration = DEFAULT_READ_TIMEOUT;
+
+// Time for the peer to notice our shutdown and hang up in turn
+pub const DEFAULT_CLOSE_GRACE: Duration = Duration::from_millis(500);
+
+// What was left over when the connection was closed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CloseReport {
+    // Bytes of a partial frame stranded in the receive buffer
+    pub buffered: usize,
+    // Whole messages received but never handed to the caller
+    pub deferred: usize,
+    // Bytes the peer still sent after our shutdown, discarded while waiting for it to hang up
+    pub drained: usize,
+    pub reason: DisconnectReason,
+}
+
+impl MessagingSystem<TcpStream> {
+    pub async fn try_new(socket_address: SocketAddr) -> Result<Self, ConnectError> {
+        Self::try_new_with_timeout(socket_address, DEFAULT_CONNECT_TIMEOUT).await
+    }
+
+    pub async fn try_new_with_timeout(
+        socket_address: SocketAddr,
+        connect_timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        Self::connect(socket_address, connect_timeout, None).await
+    }
+
+    pub async fn try_new_with_registry(
+        socket_address: SocketAddr,
+        registry: Option<CommandRegistry>,
+    ) -> Result<Self, ConnectError> {
+        Self::connect(socket_address, DEFAULT_CONNECT_TIMEOUT, registry).await
+    }
+
+    // Retries refused and timed out connections as `policy` allows
+    pub async fn try_new_with_retry(
+        socket_address: SocketAddr,
+        policy: &RetryPolicy,
+    ) -> Result<Self, ConnectError> {
+        let started = Instant::now();
+        let stream = connect::connect_with_retry(socket_address, policy).await?;
+        Self::from_tcp_stream(stream, socket_address, None, Some(started.elapsed()))
+    }
+
+    // Connects to whichever of the addresses answers first, as `eyeballs` directs, retrying as
+    // `policy` allows
+    pub async fn try_new_to_any(
+        addresses: &[SocketAddr],
+        policy: &RetryPolicy,
+        eyeballs: &HappyEyeballs,
+    ) -> Result<Self, ConnectError> {
+        let started = Instant::now();
+        let (socket_address, stream) =
+            connect::connect_any_with_retry(addresses, policy, eyeballs).await?;
+        Self::from_tcp_stream(stream, socket_address, None, Some(started.elapsed()))
+    }
+
+    async fn connect(
+        socket_address: SocketAddr,
+        connect_timeout: Duration,
+        registry: Option<CommandRegistry>,
+    ) -> Result<Self, ConnectError> {
+        let started = Instant::now();
+        let stream = connect::connect(socket_address, connect_timeout).await?;
+        Self::from_tcp_stream(stream, socket_address, registry, Some(started.elapsed()))
+    }
+
+    // Wraps a connection a listener accepted, addressed to the peer that opened it
+    pub fn try_from_accepted(stream: TcpStream) -> Result<Self, ConnectError> {
+        let socket_address = stream.peer_addr()?;
+        Self::from_tcp_stream(stream, socket_address, None, None)
+    }
+
+    fn from_tcp_stream(
+        stream: TcpStream,
+        socket_address: SocketAddr,
+        registry: Option<CommandRegistry>,
+        connect_time: Option<Duration>,
+    ) -> Result<Self, ConnectError> {
+        let local_address = stream.local_addr()?;
+
+        let mut messaging_system =
+            Self::from_stream_with_registry(stream, socket_address, registry);
+        messaging_system.local_address = Some(local_address);
+        messaging_system.connect_time = connect_time;
+        Ok(messaging_system)
+    }
+}
+
+impl<T> MessagingSystem<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    // The remote address can't be derived from an arbitrary transport, but the version message
+    // needs it
+    pub fn from_stream(stream: T, remote_addr: SocketAddr) -> Self {
+        Self::from_stream_with_registry(stream, remote_addr, None)
+    }
+
+    pub fn from_stream_with_registry(
+        stream: T,
+        remote_addr: SocketAddr,
+        registry: Option<CommandRegistry>,
+    ) -> Self {
+        let config = HandshakeConfig::default();
+        Self {
+            stream,
+            decoder: MessageDecoder::new(Network::Mainnet, registry),
+            deferred: VecDeque::new(),
+            remote_addr,
+            local_address: None,
+            connect_time: None,
+            nonce: config.random_nonce(),
+            config,
+            progress: HandshakeProgress::default(),
+            sent_stats: SessionStats::default(),
+            disconnect: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+            resync: false,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            trace_wire: false,
+            auto_pong: false,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    // Hands back the transport, dropping anything still buffered
+    pub fn into_inner(self) -> T {
+        self.stream
+    }
+
+    // Records every byte sent and received from here on, leaving the rest of the connection as
+    // it was. Without a recorder the bytes pass straight through, so callers can use the same
+    // connection type either way
+    pub fn record(self, recorder: Option<Recorder>) -> MessagingSystem<Recorded<T>> {
+        MessagingSystem {
+            stream: Recorded::new(self.stream, recorder),
+            decoder: self.decoder,
+            deferred: self.deferred,
+            remote_addr: self.remote_addr,
+            local_address: self.local_address,
+            connect_time: self.connect_time,
+            config: self.config,
+            progress: self.progress,
+            nonce: self.nonce,
+            sent_stats: self.sent_stats,
+            disconnect: self.disconnect,
+            max_message_size: self.max_message_size,
+            max_buffer_bytes: self.max_buffer_bytes,
+            resync: self.resync,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            trace_wire: self.trace_wire,
+            auto_pong: self.auto_pong,
+            cancel: self.cancel,
+        }
+    }
+
+    // Flushes whatever is still queued and shuts down our side, leaving reads open. Gives up with
+    // `TimedOut` after the write timeout
+    pub(crate) async fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        let shutting_down = async {
+            self.stream.flush().await?;
+            self.stream.shutdown().await
+        };
+        tokio::time::timeout(self.write_timeout, shutting_down)
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+    }
+
+    // Flushes and shuts down our side, then discards what the peer sends for up to `grace`
+    // while it hangs up too; a zero grace skips the wait
+    pub async fn close(mut self, grace: Duration) -> Result<CloseReport, std::io::Error> {
+        self.shutdown().await?;
+
+        let mut drained = 0;
+        if !grace.is_zero() {
+            let draining = async {
+                let mut scratch = [0; 4096];
+                while let Ok(bytes_read @ 1..) = self.stream.read(&mut scratch).await {
+                    drained += bytes_read;
+                }
+            };
+            let _ = tokio::time::timeout(grace, draining).await;
+        }
+
+        let reason = self.disconnect.unwrap_or(DisconnectReason::Completed);
+        event!(Debug, "closed connection to {}: {reason}", self.remote_addr);
+        Ok(CloseReport {
+            buffered: self.decoder.buffered_len(),
+            deferred: self.deferred.len(),
+            drained,
+            reason,
+        })
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn connect_time(&self) -> Option<Duration> {
+        self.connect_time
+    }
+
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    // Set by a failed send, receive, or handshake, and cleared by the next send or receive to
+    // succeed
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect
+    }
+
+    pub(crate) fn note_disconnect(&mut self, reason: DisconnectReason) {
+        self.disconnect = Some(reason);
+    }
+
+    // Until our version is out, this also draws a fresh nonce for it from the config
+    pub fn set_config(&mut self, config: HandshakeConfig) {
+        if !self.progress.version_sent() {
+            self.nonce = config.random_nonce();
+        }
+        self.config = config;
+    }
+
+    // Stray bytes discarded while resynchronizing on the network magic
+    pub fn skipped_bytes(&self) -> usize {
+        self.decoder.skipped_bytes()
+    }
+
+    // Everything sent and received so far
+    pub fn stats(&self) -> SessionStats {
+        let mut stats = self.decoder.stats().clone();
+        stats += &self.sent_stats;
+        stats
+    }
+
+    pub fn peer_version(&self) -> Option<i32> {
+        self.progress.peer_version
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.progress.state()
+    }
+
+    // Both sides speak the lower of the two advertised versions once versions are exchanged
+    pub fn negotiated_version(&self) -> Option<i32> {
+        self.progress
+            .peer_version
+            .map(|peer_version| peer_version.min(self.config.protocol_version))
+    }
+
+    pub fn should_send_wtxidrelay(&self) -> bool {
+        self.negotiated_version().is_some_and(|negotiated_version| {
+            self.config.should_send_wtxidrelay(negotiated_version)
+        })
+    }
+
+    // The version message we would send right now; `send_payload` takes a customized one too
+    pub fn version_payload(&self) -> Result<VersionPayload, VersionPayloadBuildError> {
+        self.config.version_payload(
+            self.remote_addr,
+            self.local_address,
+            self.nonce,
+            self.config.now(),
+        )
+    }
+
+    pub async fn send_message(&mut self, command: Command) -> Result<(), MessageSendError> {
+        match command {
+            Command::Verack => self.send_payload(VerackPayload).await,
+            Command::Version => self.send_payload(self.version_payload()?).await,
+            Command::WtxidRelay => self.send_payload(WtxidRelayPayload).await,
+            Command::GetAddr => self.send_payload(GetAddrPayload).await,
+            // Pings and pongs need a nonce, and addresses need a list, which a bare command
+            // can't carry
+            Command::Addr | Command::AddrV2 | Command::Alert | Command::Ping | Command::Pong => {
+                Err(MessageSendError::UnsupportedCommand(command))
+            }
+        }
+    }
+
+    // Frames and writes any payload, refusing with `MessageSendError::State` anything the
+    // handshake so far doesn't allow yet
+    pub async fn send_payload<P>(&mut self, payload: P) -> Result<(), MessageSendError>
+    where
+        P: MessagePreparable + BinWrite + WriteEndian,
+        for<'a> <P as BinWrite>::Args<'a>: Default,
+    {
+        let result = send_checked(
+            &mut self.stream,
+            self.remote_addr,
+            self.config.network,
+            &mut self.progress,
+            &mut self.sent_stats,
+            SendLimits {
+                write_timeout: self.write_timeout,
+                trace_wire: self.trace_wire,
+            },
+            payload,
+        )
+        .await;
+        self.disconnect = result
+            .as_ref()
+            .err()
+            .map(MessageSendError::disconnect_reason);
+        result
+    }
+
+    pub async fn send_ping(&mut self, nonce: u64) -> Result<(), MessageSendError> {
+        self.send_payload(PingPayload::new(nonce)).await
+    }
+
+    // Answers a peer's ping, echoing the nonce it chose
+    pub async fn send_pong(&mut self, nonce: u64) -> Result<(), MessageSendError> {
+        self.send_payload(PongPayload::new(nonce)).await
+    }
+
+    pub async fn receive_message(&mut self) -> Result<MessageType, MessageReceiveError> {
+        Ok(self.receive_parsed_message().await?.into_message())
+    }
+
+    // Like `receive_message`, but keeps the header for logging or stricter checks
+    pub async fn receive_parsed_message(&mut self) -> Result<ParsedMessage, MessageReceiveError> {
+        match self.deferred.pop_front() {
+            Some(parsed) => Ok(parsed),
+            None => self.read_parsed_message().await,
+        }
+    }
+
+    // Reads until `filter` breaks on a message, keeping the ones it hands back with `Continue`
+    // for later `receive_message` calls. Pings are answered along the way when `auto_pong` is set
+    pub async fn receive_expecting<F, U>(
+        &mut self,
+        mut filter: F,
+        timeout: Duration,
+    ) -> Result<U, ExpectError>
+    where
+        F: FnMut(MessageType) -> ControlFlow<U, MessageType>,
+    {
+        let mut seen = Vec::new();
+        // What an earlier call set aside may be just what this one is waiting for
+        let mut pending = std::mem::take(&mut self.deferred);
+        while let Some(parsed) = pending.pop_front() {
+            let (header, message) = parsed.into_parts();
+            match filter(message) {
+                ControlFlow::Break(expected) => {
+                    self.deferred.append(&mut pending);
+                    return Ok(expected);
+                }
+                ControlFlow::Continue(message) => {
+                    seen.push(command_name(&header.command_raw()));
+                    self.deferred
+                        .push_back(ParsedMessage::from_parts(header, message));
+                }
+            }
+        }
+        let waiting = async {
+            loop {
+                let (header, message) = self.read_parsed_message().await?.into_parts();
+                match filter(message) {
+                    ControlFlow::Break(expected) => return Ok(expected),
+                    ControlFlow::Continue(message) => {
+                        seen.push(command_name(&header.command_raw()));
+                        self.deferred
+                            .push_back(ParsedMessage::from_parts(header, message));
+                    }
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, waiting).await {
+            Ok(result) => result,
+            Err(_) => Err(ExpectError::Timeout {
+                waited: timeout,
+                seen,
+            }),
+        }
+    }
+
+    pub async fn wait_for_version(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<VersionPayload, ExpectError> {
+        self.receive_expecting(
+            |message| match message {
+                MessageType::Version(version_payload) => ControlFlow::Break(version_payload),
+                message => ControlFlow::Continue(message),
+            },
+            timeout,
+        )
+        .await
+    }
+
+    pub async fn wait_for_verack(&mut self, timeout: Duration) -> Result<(), ExpectError> {
+        self.receive_expecting(
+            |message| match message {
+                MessageType::Verack => ControlFlow::Break(()),
+                message => ControlFlow::Continue(message),
+            },
+            timeout,
+        )
+        .await
+    }
+
+    pub async fn wait_for_pong(
+        &mut self,
+        nonce: u64,
+        timeout: Duration,
+    ) -> Result<(), ExpectError> {
+        self.receive_expecting(
+            |message| match message {
+                MessageType::Pong(pong_payload) if pong_payload.nonce() == nonce => {
+                    ControlFlow::Break(())
+                }
+                message => ControlFlow::Continue(message),
+            },
+            timeout,
+        )
+        .await
+    }
+
+    // Sends a ping and waits for the pong echoing its nonce, keeping whatever else arrives in
+    // the meantime for later `receive_message` calls
+    pub async fn measure_ping(&mut self) -> Result<Duration, PingError> {
+        let nonce = self.config.random_nonce();
+        self.send_ping(nonce).await?;
+        let started = Instant::now();
+
+        match self.wait_for_pong(nonce, self.read_timeout).await {
+            Ok(()) => Ok(started.elapsed()),
+            Err(ExpectError::Receive(e)) => Err(e.into()),
+            Err(ExpectError::Timeout { waited, .. }) => Err(PingError::NoPong(waited)),
+        }
+    }
+
+    async fn read_parsed_message(&mut self) -> Result<ParsedMessage, MessageReceiveError> {
+        let result = self.read_answering_pings().await;
+        self.disconnect = result
+            .as_ref()
+            .err()
+            .map(MessageReceiveError::disconnect_reason);
+        result
+    }
+
+    async fn read_answering_pings(&mut self) -> Result<ParsedMessage, MessageReceiveError> {
+        loop {
+            let limits = ReceiveLimits {
+                max_message_size: self.max_message_size,
+                max_buffer_bytes: self.max_buffer_bytes,
+                resync: self.resync,
+                read_timeout: self.read_timeout,
+                trace_wire: self.trace_wire,
+                cancel: &self.cancel,
+            };
+            let parsed = receive_parsed(
+                &mut self.stream,
+                self.remote_addr,
+                &mut self.decoder,
+                &self.config,
+                self.nonce,
+                &mut self.progress,
+                limits,
+            )
+            .await?;
+            match parsed.message() {
+                MessageType::Ping(ping_payload) if self.auto_pong => self
+                    .send_pong(ping_payload.nonce())
+                    .await
+                    .map_err(MessageReceiveError::PongFailed)?,
+                _ => return Ok(parsed),
+            }
+        }
+    }
+
+    // Separates sending from receiving so each can run in its own task, like
+    // `TcpStream::into_split`; anything already buffered goes to the receiver
+    pub fn into_split(self) -> (MessageSender<WriteHalf<T>>, MessageReceiver<ReadHalf<T>>) {
+        let (read_half, write_half) = tokio::io::split(self.stream);
+        let sender = MessageSender {
+            stream: write_half,
+            remote_addr: self.remote_addr,
+            local_address: self.local_address,
+            config: self.config.clone(),
+            progress: self.progress,
+            nonce: self.nonce,
+            sent_stats: self.sent_stats,
+            write_timeout: self.write_timeout,
+            trace_wire: self.trace_wire,
+        };
+        let receiver = MessageReceiver {
+            stream: read_half,
+            remote_addr: self.remote_addr,
+            decoder: self.decoder,
+            deferred: self.deferred,
+            config: self.config,
+            progress: self.progress,
+            nonce: self.nonce,
+            max_message_size: self.max_message_size,
+            max_buffer_bytes: self.max_buffer_bytes,
+            resync: self.resync,
+            read_timeout: self.read_timeout,
+            trace_wire: self.trace_wire,
+            cancel: self.cancel,
+        };
+        (sender, receiver)
+    }
+}
+
+// The write half of a split `MessagingSystem`. The halves stop sharing handshake progress once
+// split, so split after the handshake
+pub struct MessageSender<W> {
+    stream: W,
+    remote_addr: SocketAddr,
+    local_address: Option<SocketAddr>,
+    config: HandshakeConfig,
+    progress: HandshakeProgress,
+    nonce: u64,
+    sent_stats: SessionStats,
+    pub write_timeout: Duration,
+    pub trace_wire: bool,
+}
+
+impl<W> MessageSender<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    // Only what was sent, before and after the split
+    pub fn stats(&self) -> &SessionStats {
+        &self.sent_stats
+    }
+
+    pub fn version_payload(&self) -> Result<VersionPayload, VersionPayloadBuildError> {
+        self.config.version_payload(
+            self.remote_addr,
+            self.local_address,
+            self.nonce,
+            self.config.now(),
+        )
+    }
+
+    pub async fn send_message(&mut self, command: Command) -> Result<(), MessageSendError> {
+        match command {
+            Command::Verack => self.send_payload(VerackPayload).await,
+            Command::Version => self.send_payload(self.version_payload()?).await,
+            Command::WtxidRelay => self.send_payload(WtxidRelayPayload).await,
+            Command::GetAddr => self.send_payload(GetAddrPayload).await,
+            Command::Addr | Command::AddrV2 | Command::Alert | Command::Ping | Command::Pong => {
+                Err(MessageSendError::UnsupportedCommand(command))
+            }
+        }
+    }
+
+    pub async fn send_payload<P>(&mut self, payload: P) -> Result<(), MessageSendError>
+    where
+        P: MessagePreparable + BinWrite + WriteEndian,
+        for<'a> <P as BinWrite>::Args<'a>: Default,
+    {
+        send_checked(
+            &mut self.stream,
+            self.remote_addr,
+            self.config.network,
+            &mut self.progress,
+            &mut self.sent_stats,
+            SendLimits {
+                write_timeout: self.write_timeout,
+                trace_wire: self.trace_wire,
+            },
+            payload,
+        )
+        .await
+    }
+
+    pub async fn send_ping(&mut self, nonce: u64) -> Result<(), MessageSendError> {
+        self.send_payload(PingPayload::new(nonce)).await
+    }
+
+    pub async fn send_pong(&mut self, nonce: u64) -> Result<(), MessageSendError> {
+        self.send_payload(PongPayload::new(nonce)).await
+    }
+}
+
+// The read half of a split `MessagingSystem`, carrying on with its buffer and limits
+pub struct MessageReceiver<R> {
+    stream: R,
+    remote_addr: SocketAddr,
+    decoder: MessageDecoder,
+    deferred: VecDeque<ParsedMessage>,
+    config: HandshakeConfig,
+    progress: HandshakeProgress,
+    nonce: u64,
+    pub max_message_size: u32,
+    pub max_buffer_bytes: usize,
+    pub resync: bool,
+    pub read_timeout: Duration,
+    pub trace_wire: bool,
+    pub cancel: CancellationToken,
+}
+
+impl<R> MessageReceiver<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    pub fn skipped_bytes(&self) -> usize {
+        self.decoder.skipped_bytes()
+    }
+
+    // Only what was received, before and after the split
+    pub fn stats(&self) -> &SessionStats {
+        self.decoder.stats()
+    }
+
+    pub fn peer_version(&self) -> Option<i32> {
+        self.progress.peer_version
+    }
+
+    pub async fn receive_message(&mut self) -> Result<MessageType, MessageReceiveError> {
+        Ok(self.receive_parsed_message().await?.into_message())
+    }
+
+    pub async fn receive_parsed_message(&mut self) -> Result<ParsedMessage, MessageReceiveError> {
+        if let Some(parsed) = self.deferred.pop_front() {
+            return Ok(parsed);
+        }
+        let limits = ReceiveLimits {
+            max_message_size: self.max_message_size,
+            max_buffer_bytes: self.max_buffer_bytes,
+            resync: self.resync,
+            read_timeout: self.read_timeout,
+            trace_wire: self.trace_wire,
+            cancel: &self.cancel,
+        };
+        receive_parsed(
+            &mut self.stream,
+            self.remote_addr,
+            &mut self.decoder,
+            &self.config,
+            self.nonce,
+            &mut self.progress,
+            limits,
+        )
+        .await
+    }
+}
+
+async fn send_checked<W, P>(
+    stream: &mut W,
+    remote_addr: SocketAddr,
+    network: Network,
+    progress: &mut HandshakeProgress,
+    stats: &mut SessionStats,
+    limits: SendLimits,
+    payload: P,
+) -> Result<(), MessageSendError>
+where
+    W: AsyncWrite + Unpin,
+    P: MessagePreparable + BinWrite + WriteEndian,
+    for<'a> <P as BinWrite>::Args<'a>: Default,
+{
+    progress.check_send(P::COMMAND_TYPE)?;
+    let (header, payload) = frame_parts(network, payload)?;
+    if limits.trace_wire {
+        eprint!("{}", format_frame(Direction::Sent, &header, &payload));
+    }
+    let mut written = 0;
+    let writing = write_frame(stream, &header, &payload, &mut written);
+    if let Ok(result) = tokio::time::timeout(limits.write_timeout, writing).await {
+        result?;
+    } else {
+        return Err(MessageSendError::Timeout {
+            command: P::COMMAND_TYPE,
+            written,
+        });
+    }
+    stats.record_sent(P::COMMAND_TYPE.as_str(), payload.len());
+    event!(
+        Debug,
+        "sent {} to {remote_addr} ({} byte payload)",
+        P::COMMAND_TYPE,
+        payload.len(),
+    );
+    progress.record_sent(P::COMMAND_TYPE);
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SendLimits {
+    write_timeout: Duration,
+    trace_wire: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReceiveLimits<'a> {
+    max_message_size: u32,
+    max_buffer_bytes: usize,
+    resync: bool,
+    read_timeout: Duration,
+    trace_wire: bool,
+    cancel: &'a CancellationToken,
+}
+
+async fn receive_parsed<R>(
+    stream: &mut R,
+    remote_addr: SocketAddr,
+    decoder: &mut MessageDecoder,
+    config: &HandshakeConfig,
+    nonce: u64,
+    progress: &mut HandshakeProgress,
+    limits: ReceiveLimits<'_>,
+) -> Result<ParsedMessage, MessageReceiveError>
+where
+    R: AsyncRead + Unpin,
+{
+    decoder.network = config.network;
+    decoder.max_message_size = limits.max_message_size;
+    decoder.resync = limits.resync;
+    decoder.trace_wire = limits.trace_wire;
+
+    loop {
+        match decoder.next_message() {
+            Ok(Some(parsed)) => {
+                if let MessageType::Version(version_payload) = parsed.message() {
+                    // A peer echoing our own nonce back means we connected to ourselves
+                    if version_payload.nonce() == nonce {
+                        return Err(MessageReceiveError::ConnectedToSelf);
+                    }
+                    if version_payload.version() < config.min_peer_version {
+                        return Err(MessageReceiveError::ObsoletePeer {
+                            their_version: version_payload.version(),
+                            min: config.min_peer_version,
+                        });
+                    }
+                    if !version_payload
+                        .services()
+                        .contains(config.required_services)
+                    {
+                        return Err(MessageReceiveError::MissingServices(
+                            config.required_services & !version_payload.services(),
+                        ));
+                    }
+                }
+                let command = command_name(&parsed.header().command_raw());
+                event!(
+                    Debug,
+                    "received {command} from {remote_addr} ({} byte payload)",
+                    parsed.header().payload_size()
+                );
+                if let MessageType::Unknown { .. } = parsed.message() {
+                    event!(Warn, "{remote_addr} sent unknown message {command:?}");
+                }
+                progress.record_received(parsed.message());
+                return Ok(parsed);
+            }
+            Ok(None) => {
+                // Refuse up front rather than buffering a frame that could never fit
+                let size = decoder.buffered_len() + decoder.missing_bytes();
+                if size > limits.max_buffer_bytes {
+                    return Err(MessageReceiveError::BufferLimitExceeded {
+                        limit: limits.max_buffer_bytes,
+                        size,
+                    });
+                }
+                let reading = tokio::time::timeout(limits.read_timeout, decoder.read_from(stream));
+                // Reading is cancel safe, so giving up on it loses nothing already received
+                let read = tokio::select! {
+                    biased;
+                    _ = limits.cancel.cancelled() => return Err(MessageReceiveError::Interrupted),
+                    read = reading => read,
+                };
+                let Ok(bytes_read) = read else {
+                    return Err(MessageReceiveError::Timeout {
+                        waited: limits.read_timeout,
+                        buffered: decoder.buffered_len(),
+                    });
+                };
+                let bytes_read = bytes_read?;
+                if bytes_read == 0 {
+                    return Err(MessageReceiveError::ConnectionClosed {
+                        buffered: decoder.buffered_len(),
+                    });
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageSendError {
+    Creation(PrepareMessageError),
+    State(ProtocolStateError),
+    InvalidVersionPayload(VersionPayloadBuildError),
+    UnsupportedCommand(Command),
+    // The peer stopped taking bytes partway through the message, after `written` of them
+    Timeout { command: Command, written: usize },
+    Io(std::io::Error),
+}
+
+impl MessageSendError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Timeout { .. } | Self::Io(_) => exit_code::SESSION_IO,
+            // Everything else is a message we shouldn't be sending at all
+            _ => exit_code::PROTOCOL_ERROR,
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Timeout { .. } => DisconnectReason::WriteTimeout,
+            Self::Io(e) => DisconnectReason::Io(e.kind()),
+            Self::Creation(_)
+            | Self::State(_)
+            | Self::InvalidVersionPayload(_)
+            | Self::UnsupportedCommand(_) => {
+                DisconnectReason::ProtocolViolation(Violation::InvalidOutgoing)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for MessageSendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Creation(e) => e.fmt(f),
+            Self::State(e) => e.fmt(f),
+            Self::InvalidVersionPayload(e) => e.fmt(f),
+            Self::UnsupportedCommand(command) => {
+                write!(f, "sending {command} messages is not supported")
+            }
+            Self::Timeout { command, written } => write!(
+                f,
+                "timed out sending {command} message after {written} byte(s) went out",
+            ),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MessageSendError {}
+
+impl From<PrepareMessageError> for MessageSendError {
+    fn from(value: PrepareMessageError) -> Self {
+        match value {
+            PrepareMessageError::Io(e) => Self::Io(e),
+            value => Self::Creation(value),
+        }
+    }
+}
+
+impl From<ProtocolStateError> for MessageSendError {
+    fn from(value: ProtocolStateError) -> Self {
+        Self::State(value)
+    }
+}
+
+impl From<VersionPayloadBuildError> for MessageSendError {
+    fn from(value: VersionPayloadBuildError) -> Self {
+        Self::InvalidVersionPayload(value)
+    }
+}
+
+impl From<std::io::Error> for MessageSendError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum ExpectError {
+    Receive(MessageReceiveError),
+    // Names the commands that arrived instead, in order
+    Timeout { waited: Duration, seen: Vec<String> },
+}
+
+impl std::fmt::Display for ExpectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Receive(e) => e.fmt(f),
+            Self::Timeout { waited, seen } if seen.is_empty() => {
+                write!(f, "nothing received within {waited:?}")
+            }
+            Self::Timeout { waited, seen } => write!(
+                f,
+                "expected message not received within {waited:?}, got {} instead",
+                seen.join(", "),
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ExpectError {}
+
+impl From<MessageReceiveError> for ExpectError {
+    fn from(value: MessageReceiveError) -> Self {
+        Self::Receive(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum PingError {
+    Send(MessageSendError),
+    Receive(MessageReceiveError),
+    NoPong(Duration),
+}
+
+impl std::fmt::Display for PingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Send(e) => e.fmt(f),
+            Self::Receive(e) => e.fmt(f),
+            Self::NoPong(waited) => write!(f, "no pong received within {waited:?}"),
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+impl From<MessageSendError> for PingError {
+    fn from(value: MessageSendError) -> Self {
+        Self::Send(value)
+    }
+}
+
+impl From<MessageReceiveError> for PingError {
+    fn from(value: MessageReceiveError) -> Self {
+        Self::Receive(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum MessageReceiveError {
+    Parsing(MessageParseError),
+    ConnectedToSelf,
+    ObsoletePeer { their_version: i32, min: i32 },
+    MissingServices(ServiceFlags),
+    BufferLimitExceeded { limit: usize, size: usize },
+    // The peer hung up, stranding any partial frame still in the buffer
+    ConnectionClosed { buffered: usize },
+    Timeout { waited: Duration, buffered: usize },
+    // The `cancel` token was cancelled while waiting on the peer
+    Interrupted,
+    PongFailed(MessageSendError),
+    Io(std::io::Error),
+}
+
+impl MessageReceiveError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Parsing(_) | Self::BufferLimitExceeded { .. } => exit_code::PROTOCOL_ERROR,
+            Self::ConnectedToSelf | Self::ObsoletePeer { .. } | Self::MissingServices(_) => {
+                exit_code::PEER_REJECTED
+            }
+            // An idle peer has sent nothing wrong, so this is like a write timing out
+            Self::ConnectionClosed { .. } | Self::Timeout { .. } | Self::Io(_) => {
+                exit_code::SESSION_IO
+            }
+            Self::Interrupted => exit_code::INTERRUPTED,
+            Self::PongFailed(e) => e.exit_code(),
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Parsing(e) => e.disconnect_reason(),
+            Self::ConnectedToSelf => DisconnectReason::Rejected(Rejection::ConnectedToSelf),
+            Self::ObsoletePeer { .. } => DisconnectReason::Rejected(Rejection::ObsoletePeer),
+            Self::MissingServices(_) => DisconnectReason::Rejected(Rejection::MissingServices),
+            Self::BufferLimitExceeded { .. } => DisconnectReason::OversizedMessage,
+            Self::ConnectionClosed { .. } => DisconnectReason::PeerClosed,
+            Self::Timeout { .. } => DisconnectReason::ReadTimeout,
+            Self::Interrupted => DisconnectReason::Cancelled,
+            Self::PongFailed(e) => e.disconnect_reason(),
+            Self::Io(e) => DisconnectReason::Io(e.kind()),
+        }
+    }
+}
+
+impl std::fmt::Display for MessageReceiveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parsing(e) => e.fmt(f),
+            Self::ConnectedToSelf => write!(f, "connected to self"),
+            Self::ObsoletePeer { their_version, min } => write!(
+                f,
+                "peer protocol version {their_version} is older than the minimum of {min}",
+            ),
+            Self::MissingServices(missing) => {
+                write!(f, "peer does not advertise required services {missing}")
+            }
+            Self::BufferLimitExceeded { limit, size } => write!(
+                f,
+                "receive buffer would grow to {size} bytes, over the limit of {limit}",
+            ),
+            Self::ConnectionClosed { buffered: 0 } => write!(f, "connection closed by peer"),
+            Self::Timeout { waited, buffered } => write!(
+                f,
+                "nothing received for {waited:?} ({buffered} byte(s) of a partial message buffered)",
+            ),
+            Self::ConnectionClosed { buffered } => write!(
+                f,
+                "connection closed by peer with {buffered} byte(s) of a partial message buffered",
+            ),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::PongFailed(e) => write!(f, "could not answer ping: {e}"),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for MessageReceiveError {
+    // The wrapped errors are displayed as-is, so skip straight to their sources
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Parsing(e) => e.source(),
+            Self::PongFailed(e) => Some(e),
+            Self::Io(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<MessageParseError> for MessageReceiveError {
+    fn from(value: MessageParseError) -> Self {
+        Self::Parsing(value)
+    }
+}
+
+impl From<std::io::Error> for MessageReceiveError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        net::TcpListener,
+    };
+
+    use crate::{
+        command::command_name,
+        header,
+        message::{self, prepare_message},
+        protocol::{MIN_PEER_PROTO_VERSION, PROTOCOL_VERSION},
+        testing::MockClock,
+        utils,
+        version_payload::VersionPayload,
+    };
+
+    use super::*;
+
+    // Both ends of an in-memory connection, already past the handshake
+    async fn established_pair() -> (MessagingSystem<DuplexStream>, MessagingSystem<DuplexStream>) {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let mut ours = MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        let mut theirs = MessagingSystem::from_stream(theirs, "192.0.2.2:8333".parse().unwrap());
+
+        let config = HandshakeConfig::default();
+        let (our_outcome, their_outcome) =
+            tokio::join!(ours.handshake(&config), theirs.handshake(&config));
+        our_outcome.unwrap();
+        their_outcome.unwrap();
+        (ours, theirs)
+    }
+
+    #[tokio::test]
+    async fn test_send_before_handshake_is_rejected() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let remote_addr = "192.0.2.1:8333".parse().unwrap();
+        let mut messaging_system = MessagingSystem::from_stream(ours, remote_addr);
+        let mut theirs = MessagingSystem::from_stream(theirs, remote_addr);
+
+        assert!(matches!(
+            messaging_system.send_message(Command::Verack).await,
+            Err(MessageSendError::State(
+                ProtocolStateError::VersionNotReceived(Command::Verack)
+            )),
+        ));
+        messaging_system
+            .send_message(Command::Version)
+            .await
+            .unwrap();
+        assert_eq!(messaging_system.state(), ConnectionState::VersionSent);
+        assert!(matches!(
+            messaging_system.send_message(Command::Version).await,
+            Err(MessageSendError::State(
+                ProtocolStateError::DuplicateVersion
+            )),
+        ));
+        assert!(matches!(
+            messaging_system.send_ping(1).await,
+            Err(MessageSendError::State(
+                ProtocolStateError::HandshakeIncomplete(Command::Ping)
+            )),
+        ));
+
+        // Nothing refused ever reached the wire
+        drop(messaging_system);
+        assert!(matches!(
+            theirs.receive_message().await,
+            Ok(MessageType::Version(_)),
+        ));
+        assert!(matches!(
+            theirs.receive_message().await,
+            Err(MessageReceiveError::ConnectionClosed { buffered: 0 }),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_payload_with_custom_nonce() {
+        let (mut messaging_system, theirs) = established_pair().await;
+        messaging_system
+            .send_payload(PingPayload::new(0x0123_4567_89AB_CDEF))
+            .await
+            .unwrap();
+
+        let mut frame = [0; 32];
+        theirs.into_inner().read_exact(&mut frame).await.unwrap();
+        let payload = [0xEF, 0xCD, 0xAB, 0x89, 0x67, 0x45, 0x23, 0x01];
+        assert_eq!(&frame[..4], &Network::Mainnet.magic());
+        assert_eq!(&frame[4..16], b"ping\0\0\0\0\0\0\0\0");
+        assert_eq!(&frame[16..20], &8u32.to_le_bytes());
+        assert_eq!(&frame[20..24], &utils::double_sha256_hash(&payload)[..4]);
+        assert_eq!(&frame[24..], &payload);
+    }
+
+    #[tokio::test]
+    async fn test_send_customized_version_payload() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let remote_addr = "192.0.2.1:8333".parse().unwrap();
+        let mut messaging_system = MessagingSystem::from_stream(ours, remote_addr);
+        let mut theirs = MessagingSystem::from_stream(theirs, remote_addr);
+
+        let version_payload = messaging_system.version_payload().unwrap();
+        assert_eq!(version_payload.addr_recv().socket_address(), remote_addr);
+        let customized = VersionPayload::builder()
+            .nonce(version_payload.nonce())
+            .user_agent("/custom:0.1/")
+            .build()
+            .unwrap();
+        messaging_system.send_payload(customized).await.unwrap();
+        assert_eq!(messaging_system.state(), ConnectionState::VersionSent);
+
+        assert!(matches!(
+            theirs.receive_message().await,
+            Ok(MessageType::Version(version_payload))
+                if version_payload.user_agent().unwrap() == "/custom:0.1/",
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_established_pair() {
+        let (ours, theirs) = established_pair().await;
+        assert_eq!(ours.state(), ConnectionState::HandshakeComplete);
+        assert_eq!(theirs.state(), ConnectionState::HandshakeComplete);
+    }
+
+    #[tokio::test]
+    async fn test_detect_connection_to_self() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_address = listener.local_addr().unwrap();
+
+        // The remote half reflects every byte straight back, exactly as if we had dialed ourselves,
+        // and keeps a copy
+        let reflector = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut reflected: Vec<u8> = Vec::new();
+            let mut chunk = [0; 1024];
+            while let Ok(bytes_read @ 1..) = stream.read(&mut chunk).await {
+                reflected.extend(&chunk[..bytes_read]);
+                if stream.write_all(&chunk[..bytes_read]).await.is_err() {
+                    break;
+                }
+            }
+            reflected
+        });
+
+        let mut messaging_system = MessagingSystem::try_new(local_address).await.unwrap();
+        messaging_system.set_config(HandshakeConfig::default().rng(StdRng::seed_from_u64(7)));
+        messaging_system
+            .send_message(Command::Version)
+            .await
+            .unwrap();
+
+        let result = messaging_system.receive_message().await;
+        assert!(matches!(result, Err(MessageReceiveError::ConnectedToSelf)));
+
+        drop(messaging_system);
+        let reflected = reflector.await.unwrap();
+        // After the header, the version, services, timestamp, and both addresses
+        let nonce = StdRng::seed_from_u64(7).next_u64();
+        assert_eq!(reflected[96..104], nonce.to_le_bytes());
+    }
+
+    // Connects to a local peer that writes the given frames and then hangs up
+    async fn connect_to_scripted_peer(
+        frames: Vec<Vec<u8>>,
+    ) -> (MessagingSystem, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_address = listener.local_addr().unwrap();
+
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            for frame in frames {
+                stream.write_all(&frame).await.unwrap();
+            }
+        });
+
+        let messaging_system = MessagingSystem::try_new(local_address).await.unwrap();
+        (messaging_system, peer)
+    }
+
+    fn peer_version_frame(version: i32, nonce: u64) -> Vec<u8> {
+        peer_version_frame_with_services(version, nonce, ServiceFlags::NONE)
+    }
+
+    fn peer_version_frame_with_services(
+        version: i32,
+        nonce: u64,
+        services: ServiceFlags,
+    ) -> Vec<u8> {
+        prepare_message(
+            Network::Mainnet,
+            VersionPayload::builder()
+                .version(version)
+                .nonce(nonce)
+                .services(services)
+                .build()
+                .unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_accept_peer_with_different_nonce() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame(PROTOCOL_VERSION, 1)]).await;
+        messaging_system.nonce = 2;
+
+        let message = messaging_system.receive_message().await.unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_version_with_older_peer() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame(60002, 1)]).await;
+        messaging_system.nonce = 2;
+        assert_eq!(messaging_system.negotiated_version(), None);
+
+        messaging_system.receive_message().await.unwrap();
+        assert_eq!(messaging_system.peer_version(), Some(60002));
+        assert_eq!(messaging_system.negotiated_version(), Some(60002));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_version_with_newer_peer() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame(70020, 1)]).await;
+        messaging_system.nonce = 2;
+        messaging_system.set_config(HandshakeConfig::default().wtxidrelay(true));
+
+        messaging_system.receive_message().await.unwrap();
+        assert_eq!(messaging_system.peer_version(), Some(70020));
+        assert_eq!(
+            messaging_system.negotiated_version(),
+            Some(PROTOCOL_VERSION)
+        );
+        assert!(!messaging_system.should_send_wtxidrelay());
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_peer_at_minimum_version() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame(MIN_PEER_PROTO_VERSION, 1)]).await;
+        messaging_system.nonce = 2;
+
+        let message = messaging_system.receive_message().await.unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_peer_below_minimum_version() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame(MIN_PEER_PROTO_VERSION - 1, 1)]).await;
+        messaging_system.nonce = 2;
+
+        let result = messaging_system.receive_message().await;
+        assert!(matches!(
+            result,
+            Err(MessageReceiveError::ObsoletePeer {
+                their_version: 31799,
+                min: MIN_PEER_PROTO_VERSION,
+            }),
+        ));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_require_services() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame_with_services(
+                PROTOCOL_VERSION,
+                1,
+                ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS | ServiceFlags::NODE_BLOOM,
+            )])
+            .await;
+        messaging_system.nonce = 2;
+        messaging_system.set_config(
+            HandshakeConfig::default()
+                .required_services(ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS),
+        );
+
+        let message = messaging_system.receive_message().await.unwrap();
+        assert!(matches!(message, MessageType::Version(_)));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_peer_missing_required_services() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![peer_version_frame_with_services(
+                PROTOCOL_VERSION,
+                1,
+                ServiceFlags::NODE_NETWORK_LIMITED,
+            )])
+            .await;
+        messaging_system.nonce = 2;
+        messaging_system.set_config(
+            HandshakeConfig::default()
+                .required_services(ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS),
+        );
+
+        let error = messaging_system.receive_message().await.unwrap_err();
+        assert!(matches!(error, MessageReceiveError::MissingServices(_)));
+        assert_eq!(
+            error.to_string(),
+            "peer does not advertise required services NETWORK|WITNESS",
+        );
+
+        peer.await.unwrap();
+    }
+
+    // Frames an arbitrary payload under a raw command name, bypassing `Command`
+    fn raw_frame(command: &[u8; 12], payload: &[u8]) -> Vec<u8> {
+        let mut frame = Network::Mainnet.magic().to_vec();
+        frame.extend(command);
+        frame.extend((payload.len() as u32).to_le_bytes());
+        frame.extend(&utils::double_sha256_hash(payload)[..4]);
+        frame.extend(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_is_delivered() {
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![
+            raw_frame(b"sendtxrcncl\0", &[0; 12]),
+            prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        ])
+        .await;
+
+        match messaging_system.receive_message().await.unwrap() {
+            MessageType::Unknown { command, payload } => {
+                assert_eq!(command_name(&command), "sendtxrcncl");
+                assert_eq!(payload, [0; 12]);
+            }
+            message => panic!("expected an unknown message, got {message:?}"),
+        }
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Verack)
+        ));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_registered_custom_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_address = listener.local_addr().unwrap();
+
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let frame = raw_frame(b"myext\0\0\0\0\0\0\0", &42u32.to_le_bytes());
+            stream.write_all(&frame).await.unwrap();
+        });
+
+        let mut registry = CommandRegistry::new();
+        registry
+            .register("myext", |payload: &[u8]| {
+                let payload = payload
+                    .try_into()
+                    .map_err(|_| MessageParseError::MalformedData(None))?;
+                Ok(u32::from_le_bytes(payload))
+            })
+            .unwrap();
+        let mut messaging_system =
+            MessagingSystem::try_new_with_registry(local_address, Some(registry))
+                .await
+                .unwrap();
+
+        match messaging_system.receive_message().await.unwrap() {
+            MessageType::Custom { command, payload } => {
+                assert_eq!(command_name(&command), "myext");
+                assert_eq!(payload.downcast_ref::<u32>(), Some(&42));
+            }
+            message => panic!("expected a custom message, got {message:?}"),
+        }
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reject_oversized_payload_without_buffering() {
+        // A bare header declaring a 1 GiB payload, whose body will never arrive
+        let mut header = Network::Mainnet.magic().to_vec();
+        header.extend(b"version\0\0\0\0\0");
+        header.extend((1u32 << 30).to_le_bytes());
+        header.extend([0; 4]);
+
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![header]).await;
+
+        let result = messaging_system.receive_message().await;
+        assert!(matches!(
+            result,
+            Err(MessageReceiveError::Parsing(
+                MessageParseError::OversizedPayload { length, .. }
+            )) if length == 1 << 30,
+        ));
+        assert_eq!(
+            messaging_system.decoder.buffered_len(),
+            header::Header::HEADER_BYTE_SIZE
+        );
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resync_skips_garbage() {
+        let garbage = hex::decode("00F9BEB40102").unwrap();
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![garbage.clone(), verack.clone(), garbage, verack]).await;
+
+        // Without resync the stray bytes are fatal
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Err(MessageReceiveError::Parsing(
+                MessageParseError::MissingMagicNumber
+            )),
+        ));
+
+        messaging_system.resync = true;
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Verack),
+        ));
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Verack),
+        ));
+        assert_eq!(messaging_system.skipped_bytes(), 12);
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incremental_checksum_on_large_payload() {
+        let payload: Vec<u8> = (0..MAX_MESSAGE_SIZE).map(|i| (i % 251) as u8).collect();
+        let frame = raw_frame(b"alert\0\0\0\0\0\0\0", &payload);
+
+        let (message, _) = message::parse_message(Network::Mainnet, &frame).unwrap();
+        assert!(matches!(&message, MessageType::Alert(alert) if *alert == payload));
+
+        let mut corrupted = frame.clone();
+        *corrupted.last_mut().unwrap() ^= 0xFF;
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![frame, corrupted]).await;
+
+        let message = messaging_system.receive_message().await.unwrap();
+        assert!(matches!(&message, MessageType::Alert(alert) if *alert == payload));
+
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Err(MessageReceiveError::Parsing(
+                MessageParseError::IncorrectChecksum { .. }
+            )),
+        ));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_many_back_to_back_frames() {
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![verack.repeat(1_000)]).await;
+
+        for _ in 0..1_000 {
+            assert!(matches!(
+                messaging_system.receive_message().await,
+                Ok(MessageType::Verack)
+            ));
+        }
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_parsed_message_keeps_header() {
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![raw_frame(b"sendtxrcncl\0", &[0; 12])]).await;
+
+        let parsed = messaging_system.receive_parsed_message().await.unwrap();
+        assert_eq!(command_name(&parsed.header().command_raw()), "sendtxrcncl");
+        assert_eq!(parsed.header().payload_size(), 12);
+        assert!(matches!(parsed.message(), MessageType::Unknown { .. }));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_buffer_limit() {
+        let version = peer_version_frame(PROTOCOL_VERSION, 1);
+
+        // A frame that exactly fills the buffer is fine
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![version.clone()]).await;
+        messaging_system.max_buffer_bytes = version.len();
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Version(_)),
+        ));
+        peer.await.unwrap();
+
+        // One byte less and it is refused before the payload is read
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![version.clone()]).await;
+        messaging_system.max_buffer_bytes = version.len() - 1;
+        let result = messaging_system.receive_message().await;
+        assert!(matches!(
+            result,
+            Err(MessageReceiveError::BufferLimitExceeded { limit, size })
+                if limit == version.len() - 1 && size == version.len(),
+        ));
+        assert_eq!(
+            messaging_system.decoder.buffered_len(),
+            header::Header::HEADER_BYTE_SIZE
+        );
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message_split_across_reads() {
+        let sendheaders = raw_frame(b"sendheaders\0", &[]);
+        let (mut messaging_system, peer) = connect_to_scripted_peer(vec![
+            sendheaders[..10].to_vec(),
+            sendheaders[10..].to_vec(),
+            prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        ])
+        .await;
+
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Unknown { command, payload })
+                if command_name(&command) == "sendheaders" && payload.is_empty(),
+        ));
+        // The whole frame was consumed, so the next one starts on its magic
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Verack),
+        ));
+
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_closed_mid_header() {
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        let (mut messaging_system, peer) =
+            connect_to_scripted_peer(vec![verack[..12].to_vec()]).await;
+        peer.await.unwrap();
+
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Err(MessageReceiveError::ConnectionClosed { buffered: 12 }),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_duplex() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = MessagingSystem::from_stream(client, "192.0.2.1:8333".parse().unwrap());
+        let mut server = MessagingSystem::from_stream(server, "192.0.2.2:8333".parse().unwrap());
+
+        client.send_message(Command::Version).await.unwrap();
+        match server.receive_message().await.unwrap() {
+            MessageType::Version(version_payload) => {
+                assert_eq!(
+                    version_payload.addr_recv().socket_address(),
+                    client.remote_addr()
+                );
+            }
+            message => panic!("expected a version message, got {message:?}"),
+        }
+        server.send_message(Command::Version).await.unwrap();
+        server.send_message(Command::Verack).await.unwrap();
+
+        assert!(matches!(
+            client.receive_message().await,
+            Ok(MessageType::Version(_)),
+        ));
+        assert!(matches!(
+            client.receive_message().await,
+            Ok(MessageType::Verack)
+        ));
+        client.send_message(Command::Verack).await.unwrap();
+        assert!(matches!(
+            server.receive_message().await,
+            Ok(MessageType::Verack)
+        ));
+
+        assert_eq!(client.negotiated_version(), Some(PROTOCOL_VERSION));
+        assert_eq!(server.negotiated_version(), Some(PROTOCOL_VERSION));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_after_half_a_header() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.read_timeout = Duration::from_millis(100);
+
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        theirs.write_all(&verack[..12]).await.unwrap();
+
+        // The peer stays connected but never sends the rest
+        let started = std::time::Instant::now();
+        let result = messaging_system.receive_message().await;
+        assert!(matches!(
+            result,
+            Err(MessageReceiveError::Timeout { waited, buffered: 12 })
+                if waited == Duration::from_millis(100),
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout_spares_slow_but_steady_peer() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.read_timeout = Duration::from_millis(200);
+
+        let verack = prepare_message(Network::Mainnet, VerackPayload).unwrap();
+        let peer = tokio::spawn(async move {
+            // Takes well over the timeout in total, but never stalls for a whole window
+            for byte in verack {
+                theirs.write_all(&[byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            theirs
+        });
+
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Verack),
+        ));
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_split_halves_run_concurrently() {
+        const PINGS: u64 = 100;
+
+        let (ours, mut theirs) = established_pair().await;
+
+        // Answers every ping with a pong until we hang up
+        let peer = tokio::spawn(async move {
+            while let Ok(message) = theirs.receive_message().await {
+                if let MessageType::Ping(ping_payload) = message {
+                    theirs.send_pong(ping_payload.nonce()).await.unwrap();
+                }
+            }
+        });
+
+        let (mut sender, mut receiver) = ours.into_split();
+        let pinging = tokio::spawn(async move {
+            for nonce in 0..PINGS {
+                sender.send_ping(nonce).await.unwrap();
+            }
+            sender
+        });
+        let ponged = tokio::spawn(async move {
+            for nonce in 0..PINGS {
+                let message = receiver.receive_message().await.unwrap();
+                assert!(matches!(message, MessageType::Pong(pong) if pong.nonce() == nonce));
+            }
+            receiver
+        });
+
+        let sender = pinging.await.unwrap();
+        let receiver = ponged.await.unwrap();
+        drop((sender, receiver));
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auto_pong() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let remote_addr = "192.0.2.1:8333".parse().unwrap();
+
+        let peer = tokio::spawn(async move {
+            let mut theirs = theirs;
+            theirs
+                .write_all(&prepare_message(Network::Mainnet, PingPayload::new(7)).unwrap())
+                .await
+                .unwrap();
+            theirs
+                .write_all(&peer_version_frame(PROTOCOL_VERSION, 2))
+                .await
+                .unwrap();
+            theirs
+                .write_all(&prepare_message(Network::Mainnet, PingPayload::new(8)).unwrap())
+                .await
+                .unwrap();
+            theirs.shutdown().await.unwrap();
+
+            let mut theirs = MessagingSystem::from_stream(theirs, remote_addr);
+            let mut nonces = Vec::new();
+            while let Ok(message) = theirs.receive_message().await {
+                match message {
+                    MessageType::Pong(pong_payload) => nonces.push(pong_payload.nonce()),
+                    message => panic!("expected a pong message, got {message:?}"),
+                }
+            }
+            nonces
+        });
+
+        let mut messaging_system = MessagingSystem::from_stream(ours, remote_addr);
+        messaging_system.auto_pong = true;
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Version(version_payload)) if version_payload.nonce() == 2,
+        ));
+        // The trailing ping is answered while waiting for a message that never comes
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Err(MessageReceiveError::ConnectionClosed { buffered: 0 }),
+        ));
+
+        drop(messaging_system);
+        assert_eq!(peer.await.unwrap(), [7, 8]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_measure_ping() {
+        let (mut messaging_system, mut theirs) = established_pair().await;
+
+        let peer = tokio::spawn(async move {
+            let Ok(MessageType::Ping(ping_payload)) = theirs.receive_message().await else {
+                panic!("expected a ping message");
+            };
+            theirs.send_ping(3).await.unwrap();
+            theirs.send_pong(!ping_payload.nonce()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            theirs.send_pong(ping_payload.nonce()).await.unwrap();
+            theirs
+        });
+
+        let rtt = messaging_system.measure_ping().await.unwrap();
+        assert_eq!(rtt, Duration::from_millis(250));
+
+        // What arrived in between is still delivered, in order
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Ping(ping_payload)) if ping_payload.nonce() == 3,
+        ));
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Pong(_)),
+        ));
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_measure_ping_matches_seeded_nonce() {
+        let (mut messaging_system, theirs) = established_pair().await;
+        let config = messaging_system.config().clone();
+        messaging_system.set_config(config.rng(StdRng::seed_from_u64(7)));
+        let nonce = StdRng::seed_from_u64(7).next_u64();
+
+        // Checks the ping's bytes, then echoes the wrong nonce before the right one
+        let peer = tokio::spawn(async move {
+            let mut theirs = theirs.into_inner();
+            let mut ping = [0; 24 + 8];
+            theirs.read_exact(&mut ping).await.unwrap();
+            assert_eq!(ping[4..16], *b"ping\0\0\0\0\0\0\0\0");
+            assert_eq!(ping[24..], nonce.to_le_bytes());
+            for echoed in [nonce.wrapping_add(1), nonce] {
+                let pong = prepare_message(Network::Mainnet, PongPayload::new(echoed)).unwrap();
+                theirs.write_all(&pong).await.unwrap();
+            }
+            theirs
+        });
+
+        messaging_system.measure_ping().await.unwrap();
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Pong(pong_payload)) if pong_payload.nonce() == nonce.wrapping_add(1),
+        ));
+        drop(peer.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_measure_ping_without_matching_pong() {
+        let (mut messaging_system, mut theirs) = established_pair().await;
+
+        // Keeps talking, so no single read times out, but never echoes the right nonce
+        let peer = tokio::spawn(async move {
+            while theirs.send_pong(0).await.is_ok() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        messaging_system.read_timeout = Duration::from_millis(4500);
+        let started = Instant::now();
+        assert!(matches!(
+            messaging_system.measure_ping().await,
+            Err(PingError::NoPong(waited)) if waited == Duration::from_millis(4500),
+        ));
+        assert_eq!(started.elapsed(), Duration::from_millis(4500));
+
+        drop(messaging_system);
+        peer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receive_expecting_skips_to_match() {
+        let (mut messaging_system, theirs) = established_pair().await;
+        let mut theirs = theirs.into_inner();
+        theirs
+            .write_all(&raw_frame(b"sendheaders\0", &[]))
+            .await
+            .unwrap();
+        theirs
+            .write_all(&raw_frame(b"ping\0\0\0\0\0\0\0\0", &7u64.to_le_bytes()))
+            .await
+            .unwrap();
+        theirs
+            .write_all(&raw_frame(b"pong\0\0\0\0\0\0\0\0", &42u64.to_le_bytes()))
+            .await
+            .unwrap();
+
+        messaging_system.auto_pong = true;
+        messaging_system
+            .wait_for_pong(42, Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        // The ping was answered, and whatever else came first is still there to be read
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Unknown { command, .. }) if command == *b"sendheaders\0",
+        ));
+        let mut theirs = MessagingSystem::from_stream(theirs, "192.0.2.1:8333".parse().unwrap());
+        assert!(matches!(
+            theirs.receive_message().await,
+            Ok(MessageType::Pong(pong_payload)) if pong_payload.nonce() == 7,
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_receive_expecting_finds_deferred_message() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system
+            .send_message(Command::Version)
+            .await
+            .unwrap();
+        theirs
+            .write_all(&prepare_message(Network::Mainnet, VerackPayload).unwrap())
+            .await
+            .unwrap();
+        let version = VersionPayload::builder().nonce(2).build().unwrap();
+        theirs
+            .write_all(&prepare_message(Network::Mainnet, version).unwrap())
+            .await
+            .unwrap();
+        theirs
+            .write_all(&raw_frame(b"sendheaders\0", &[]))
+            .await
+            .unwrap();
+
+        // Waiting for the version sets the verack aside, where waiting for it then finds it
+        let version = messaging_system
+            .wait_for_version(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(version.nonce(), 2);
+        messaging_system
+            .wait_for_verack(Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Ok(MessageType::Unknown { command, .. }) if command == *b"sendheaders\0",
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_receive_expecting_names_what_arrived_instead() {
+        let (mut messaging_system, theirs) = established_pair().await;
+        let mut theirs = theirs.into_inner();
+        theirs
+            .write_all(&raw_frame(b"sendheaders\0", &[]))
+            .await
+            .unwrap();
+        theirs
+            .write_all(&raw_frame(b"pong\0\0\0\0\0\0\0\0", &1u64.to_le_bytes()))
+            .await
+            .unwrap();
+        theirs
+            .write_all(&raw_frame(b"feefilter\0\0\0", &1000u64.to_le_bytes()))
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let Err(e) = messaging_system
+            .wait_for_verack(Duration::from_secs(10))
+            .await
+        else {
+            panic!("expected no verack");
+        };
+        assert_eq!(started.elapsed(), Duration::from_secs(10));
+        assert!(matches!(
+            &e,
+            ExpectError::Timeout { waited, seen }
+                if *waited == Duration::from_secs(10)
+                    && *seen == ["sendheaders", "pong", "feefilter"],
+        ));
+        assert_eq!(
+            e.to_string(),
+            "expected message not received within 10s, got sendheaders, pong, feefilter instead",
+        );
+
+        drop(theirs);
+    }
+
+    #[tokio::test]
+    async fn test_close_flushes_before_shutting_down() {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let remote_addr = "192.0.2.1:8333".parse().unwrap();
+        let mut messaging_system =
+            MessagingSystem::from_stream(tokio::io::BufStream::new(ours), remote_addr);
+        let mut theirs = MessagingSystem::from_stream(theirs, remote_addr);
+        theirs.read_timeout = Duration::from_millis(50);
+
+        messaging_system
+            .send_message(Command::Version)
+            .await
+            .unwrap();
+        messaging_system.send_pong(1).await.unwrap();
+        // Both still sit in our write buffer
+        assert!(matches!(
+            theirs.receive_message().await,
+            Err(MessageReceiveError::Timeout { buffered: 0, .. }),
+        ));
+
+        let report = messaging_system.close(Duration::ZERO).await.unwrap();
+        assert_eq!(report, CloseReport::default());
+
+        assert!(matches!(
+            theirs.receive_message().await,
+            Ok(MessageType::Version(_)),
+        ));
+        assert!(matches!(
+            theirs.receive_message().await,
+            Ok(MessageType::Pong(_)),
+        ));
+        assert!(matches!(
+            theirs.receive_message().await,
+            Err(MessageReceiveError::ConnectionClosed { buffered: 0 }),
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_close_reports_leftovers() {
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+
+        // Starts a frame it never finishes, then answers our shutdown with a parting shot
+        let peer = tokio::spawn(async move {
+            theirs
+                .write_all(&prepare_message(Network::Mainnet, VerackPayload).unwrap()[..12])
+                .await
+                .unwrap();
+            tokio::io::copy(&mut theirs, &mut tokio::io::sink())
+                .await
+                .unwrap();
+            theirs.write_all(&[0; 30]).await.unwrap();
+        });
+
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.read_timeout = Duration::from_millis(50);
+        assert!(matches!(
+            messaging_system.receive_message().await,
+            Err(MessageReceiveError::Timeout { buffered: 12, .. }),
+        ));
+
+        let report = messaging_system
+            .close(Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            CloseReport {
+                buffered: 12,
+                deferred: 0,
+                drained: 30,
+                reason: DisconnectReason::ReadTimeout,
+            },
+        );
+        peer.await.unwrap();
+    }
+
+    // Takes the first `capacity` bytes written, then never anything more, nor reads or flushes
+    struct StalledWriter {
+        capacity: usize,
+    }
+
+    impl AsyncRead for StalledWriter {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for StalledWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            match buf.len().min(self.capacity) {
+                0 => std::task::Poll::Pending,
+                accepted => {
+                    self.capacity -= accepted;
+                    std::task::Poll::Ready(Ok(accepted))
+                }
+            }
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_write_timeout_reports_bytes_written() {
+        let stalled = StalledWriter { capacity: 30 };
+        let mut messaging_system =
+            MessagingSystem::from_stream(stalled, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.write_timeout = Duration::from_secs(5);
+
+        let started = tokio::time::Instant::now();
+        match messaging_system.send_message(Command::Version).await {
+            Err(e @ MessageSendError::Timeout { .. }) => {
+                assert!(matches!(
+                    e,
+                    MessageSendError::Timeout {
+                        command: Command::Version,
+                        written: 30,
+                    }
+                ));
+                assert_eq!(e.exit_code(), exit_code::SESSION_IO);
+                assert_eq!(
+                    e.to_string(),
+                    "timed out sending version message after 30 byte(s) went out"
+                );
+            }
+            other => panic!("expected a timeout, got {other:?}"),
+        }
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+        assert_eq!(messaging_system.stats().sent.messages, 0);
+
+        let (mut sender, _) = messaging_system.into_split();
+        sender.write_timeout = Duration::from_secs(1);
+        assert!(matches!(
+            sender.send_message(Command::Version).await,
+            Err(MessageSendError::Timeout { written: 0, .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_gives_up_on_stalled_flush() {
+        let mut messaging_system = MessagingSystem::from_stream(
+            StalledWriter { capacity: 0 },
+            "192.0.2.1:8333".parse().unwrap(),
+        );
+        messaging_system.write_timeout = Duration::from_secs(5);
+
+        let started = tokio::time::Instant::now();
+        let e = messaging_system
+            .close(DEFAULT_CLOSE_GRACE)
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_version_timestamp_from_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let (ours, mut theirs) = tokio::io::duplex(64 * 1024);
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.set_config(HandshakeConfig::default().clock(clock.clone()));
+
+        clock.advance(Duration::from_secs(5));
+        messaging_system
+            .send_message(Command::Version)
+            .await
+            .unwrap();
+        let mut frame = [0; 24 + 44];
+        theirs.read_exact(&mut frame).await.unwrap();
+        // After the header, the version and services
+        assert_eq!(frame[36..44], 1_700_000_005i64.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_clock_before_epoch_is_an_error() {
+        let (ours, _theirs) = tokio::io::duplex(64 * 1024);
+        let mut messaging_system =
+            MessagingSystem::from_stream(ours, "192.0.2.1:8333".parse().unwrap());
+        messaging_system.set_config(
+            HandshakeConfig::default().clock(MockClock::new(UNIX_EPOCH - Duration::from_secs(1))),
+        );
+        assert!(matches!(
+            messaging_system.send_message(Command::Version).await,
+            Err(MessageSendError::InvalidVersionPayload(
+                VersionPayloadBuildError::TimestampOutOfRange
+            ))
+        ));
+    }
+}
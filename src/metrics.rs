@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::stats::SessionStats;
+
+// Counters for long-running modes, served in the Prometheus text format for scraping
+
+// Upper bounds in seconds, from a LAN peer up to the default handshake timeout
+const DURATION_BUCKETS: [f64; 12] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+// More than any scraper sends, so a client that never finishes its request can't grow the buffer
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    registry: Mutex<Registry>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    handshake_attempts: u64,
+    handshake_successes: u64,
+    // By `HandshakeError::kind`, or whatever else ended the attempt
+    handshake_failures: BTreeMap<&'static str, u64>,
+    active_connections: i64,
+    // By direction, then command
+    messages: BTreeMap<(&'static str, String), u64>,
+    bytes: BTreeMap<(&'static str, String), u64>,
+    // Not cumulative; each count is for its own bucket, with the last for anything slower
+    duration_buckets: [u64; DURATION_BUCKETS.len() + 1],
+    duration_sum: f64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Counts an attempt, with how long it took when it succeeded and why when it didn't
+    pub fn record_handshake(&self, result: Result<Duration, &'static str>) {
+        let mut registry = self.registry.lock().expect("metrics lock poisoned");
+        registry.handshake_attempts += 1;
+        match result {
+            Ok(elapsed) => {
+                registry.handshake_successes += 1;
+                let seconds = elapsed.as_secs_f64();
+                let bucket = DURATION_BUCKETS
+                    .iter()
+                    .position(|&bound| seconds <= bound)
+                    .unwrap_or(DURATION_BUCKETS.len());
+                registry.duration_buckets[bucket] += 1;
+                registry.duration_sum += seconds;
+            }
+            Err(reason) => *registry.handshake_failures.entry(reason).or_default() += 1,
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.registry
+            .lock()
+            .expect("metrics lock poisoned")
+            .active_connections += 1;
+    }
+
+    pub fn connection_closed(&self) {
+        self.registry
+            .lock()
+            .expect("metrics lock poisoned")
+            .active_connections -= 1;
+    }
+
+    // Adds a finished session's traffic to the totals
+    pub fn record_session(&self, stats: &SessionStats) {
+        let mut registry = self.registry.lock().expect("metrics lock poisoned");
+        for (direction, traffic) in [("received", &stats.received), ("sent", &stats.sent)] {
+            for (command, counted) in &traffic.commands {
+                let key = (direction, command.clone());
+                *registry.messages.entry(key.clone()).or_default() += counted.messages as u64;
+                *registry.bytes.entry(key).or_default() += counted.bytes as u64;
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().expect("metrics lock poisoned");
+        let mut text = String::new();
+
+        write_header(
+            &mut text,
+            "handshake_attempts_total",
+            "counter",
+            "Handshakes attempted",
+        );
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_handshake_attempts_total {}",
+            registry.handshake_attempts
+        );
+        write_header(
+            &mut text,
+            "handshake_successes_total",
+            "counter",
+            "Handshakes completed",
+        );
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_handshake_successes_total {}",
+            registry.handshake_successes
+        );
+        write_header(
+            &mut text,
+            "handshake_failures_total",
+            "counter",
+            "Handshakes failed, by reason",
+        );
+        for (reason, count) in &registry.handshake_failures {
+            let _ = writeln!(
+                text,
+                "bitcoin_handshake_handshake_failures_total{{reason=\"{}\"}} {count}",
+                escape_label(reason)
+            );
+        }
+
+        write_header(
+            &mut text,
+            "active_connections",
+            "gauge",
+            "Connections currently open",
+        );
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_active_connections {}",
+            registry.active_connections
+        );
+
+        for (name, help, values) in [
+            (
+                "messages_total",
+                "Messages by direction and command",
+                &registry.messages,
+            ),
+            (
+                "bytes_total",
+                "Bytes of whole frames by direction and command",
+                &registry.bytes,
+            ),
+        ] {
+            write_header(&mut text, name, "counter", help);
+            for ((direction, command), count) in values {
+                let _ = writeln!(
+                    text,
+                    "bitcoin_handshake_{name}{{direction=\"{direction}\",command=\"{}\"}} {count}",
+                    escape_label(command)
+                );
+            }
+        }
+
+        write_header(
+            &mut text,
+            "handshake_duration_seconds",
+            "histogram",
+            "Time taken by successful handshakes",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(&registry.duration_buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                text,
+                "bitcoin_handshake_handshake_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_handshake_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            registry.handshake_successes
+        );
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_handshake_duration_seconds_sum {}",
+            registry.duration_sum
+        );
+        let _ = writeln!(
+            text,
+            "bitcoin_handshake_handshake_duration_seconds_count {}",
+            registry.handshake_successes
+        );
+        text
+    }
+}
+
+fn write_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP bitcoin_handshake_{name} {help}");
+    let _ = writeln!(text, "# TYPE bitcoin_handshake_{name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Answers every request on the listener with the metrics, until accepting fails
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // A scraper that hangs up early is its own problem
+            let _ = respond(stream, &metrics).await;
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        let mut chunk = [0; 1024];
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend(&chunk[..read]);
+    }
+
+    let request_line = request.split(|&byte| byte == b'\r').next().unwrap_or(&[]);
+    let response = match request_line.strip_prefix(b"GET ") {
+        Some(rest) if rest.starts_with(b"/metrics ") || rest.starts_with(b"/ ") => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record_handshake(Ok(Duration::from_millis(80)));
+        metrics.record_handshake(Ok(Duration::from_secs(90)));
+        metrics.record_handshake(Err("timeout"));
+        metrics.connection_opened();
+
+        let mut stats = SessionStats::default();
+        stats.record_received("ping", 8);
+        stats.record_received("ping", 8);
+        stats.record_sent("pong", 8);
+        metrics.record_session(&stats);
+
+        let text = metrics.render();
+        for line in [
+            "bitcoin_handshake_handshake_attempts_total 3",
+            "bitcoin_handshake_handshake_successes_total 2",
+            "bitcoin_handshake_handshake_failures_total{reason=\"timeout\"} 1",
+            "bitcoin_handshake_active_connections 1",
+            "bitcoin_handshake_messages_total{direction=\"received\",command=\"ping\"} 2",
+            "bitcoin_handshake_bytes_total{direction=\"sent\",command=\"pong\"} 32",
+            "bitcoin_handshake_handshake_duration_seconds_bucket{le=\"0.05\"} 0",
+            "bitcoin_handshake_handshake_duration_seconds_bucket{le=\"0.1\"} 1",
+            "bitcoin_handshake_handshake_duration_seconds_bucket{le=\"60\"} 1",
+            "bitcoin_handshake_handshake_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "bitcoin_handshake_handshake_duration_seconds_count 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+}
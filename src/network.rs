@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet3,
+    Signet,
+    Regtest,
+    Custom([u8; 4]),
+}
+
+impl Network {
+    // The message start bytes that open every frame on this network
+    pub const fn magic(self) -> [u8; 4] {
+        match self {
+            Self::Mainnet => [0xF9, 0xBE, 0xB4, 0xD9],
+            Self::Testnet3 => [0x0B, 0x11, 0x09, 0x07],
+            Self::Signet => [0x0A, 0x03, 0xCF, 0x40],
+            Self::Regtest => [0xFA, 0xBF, 0xB5, 0xDA],
+            Self::Custom(magic) => magic,
+        }
+    }
+
+    // Custom networks have no well-known port, so they fall back to mainnet's
+    pub const fn default_port(self) -> u16 {
+        match self {
+            Self::Mainnet | Self::Custom(_) => 8333,
+            Self::Testnet3 => 18333,
+            Self::Signet => 38333,
+            Self::Regtest => 18444,
+        }
+    }
+
+    // Parses the hex form of a custom magic, e.g. `deadbeef`
+    pub fn parse_magic(s: &str) -> Result<[u8; 4], NetworkParseError> {
+        hex::decode(s)
+            .ok()
+            .and_then(|magic| <[u8; 4]>::try_from(magic).ok())
+            .ok_or_else(|| NetworkParseError::InvalidMagic(s.to_owned()))
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mainnet => write!(f, "mainnet"),
+            Self::Testnet3 => write!(f, "testnet3"),
+            Self::Signet => write!(f, "signet"),
+            Self::Regtest => write!(f, "regtest"),
+            Self::Custom(magic) => write!(f, "custom:{}", hex::encode(magic)),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = NetworkParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let network = match s {
+            "mainnet" | "main" | "bitcoin" => Self::Mainnet,
+            "testnet3" | "testnet" | "test" => Self::Testnet3,
+            "signet" => Self::Signet,
+            "regtest" => Self::Regtest,
+            _ if s.starts_with("custom:") => Self::Custom(Self::parse_magic(&s[7..])?),
+            _ => return Err(NetworkParseError::UnknownNetwork(s.to_owned())),
+        };
+        Ok(network)
+    }
+}
+
+#[derive(Debug)]
+pub enum NetworkParseError {
+    UnknownNetwork(String),
+    InvalidMagic(String),
+}
+
+impl std::fmt::Display for NetworkParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownNetwork(name) => write!(
+                f,
+                "unknown network {name:?}, expected one of mainnet, testnet3, signet, regtest",
+            ),
+            Self::InvalidMagic(magic) => {
+                write!(
+                    f,
+                    "invalid magic {magic:?}, expected exactly 4 hex-encoded bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NetworkParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_round_trip() {
+        for network in [
+            Network::Mainnet,
+            Network::Testnet3,
+            Network::Signet,
+            Network::Regtest,
+            Network::Custom([0xDE, 0xAD, 0xBE, 0xEF]),
+        ] {
+            assert_eq!(network.to_string().parse::<Network>().unwrap(), network);
+        }
+        assert!("moonnet".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_default_port() {
+        assert_eq!(Network::Mainnet.default_port(), 8333);
+        assert_eq!(Network::Testnet3.default_port(), 18333);
+        assert_eq!(Network::Signet.default_port(), 38333);
+        assert_eq!(Network::Regtest.default_port(), 18444);
+        assert_eq!(
+            Network::Custom([0xDE, 0xAD, 0xBE, 0xEF]).default_port(),
+            8333
+        );
+    }
+
+    #[test]
+    fn test_parse_magic() {
+        assert_eq!(
+            Network::parse_magic("deadbeef").unwrap(),
+            [0xDE, 0xAD, 0xBE, 0xEF],
+        );
+        assert_eq!(
+            Network::parse_magic("FABFB5DA").unwrap(),
+            Network::Regtest.magic(),
+        );
+        for invalid in ["", "deadbe", "deadbeef00", "nothex!!"] {
+            assert!(matches!(
+                Network::parse_magic(invalid),
+                Err(NetworkParseError::InvalidMagic(_)),
+            ));
+        }
+    }
+}
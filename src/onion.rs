@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use crate::utils::{base32_encode, sha3_256, BASE32_ALPHABET};
+
+const VERSION: u8 = 3;
+const ENCODED_LEN: usize = 56;
+
+// A Tor v3 onion service address: an ed25519 public key, a two byte checksum over it, and a
+// version byte, base32 encoded ahead of ".onion"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OnionAddress {
+    public_key: [u8; 32],
+}
+
+impl OnionAddress {
+    pub fn from_public_key(public_key: [u8; 32]) -> Self {
+        Self { public_key }
+    }
+
+    pub fn public_key(&self) -> &[u8; 32] {
+        &self.public_key
+    }
+
+    // The name to hand a proxy, which resolves it itself
+    pub fn hostname(&self) -> String {
+        let mut decoded = [0u8; 35];
+        decoded[..32].copy_from_slice(&self.public_key);
+        decoded[32..34].copy_from_slice(&checksum(&self.public_key));
+        decoded[34] = VERSION;
+
+        let mut hostname = base32_encode(&decoded);
+        hostname.push_str(".onion");
+        hostname
+    }
+}
+
+// CHECKSUM = SHA3-256(".onion checksum" | PUBKEY | VERSION)[:2], per Tor's rend-spec-v3
+fn checksum(public_key: &[u8; 32]) -> [u8; 2] {
+    let mut data = b".onion checksum".to_vec();
+    data.extend(public_key);
+    data.push(VERSION);
+    let hash = sha3_256(&data);
+    [hash[0], hash[1]]
+}
+
+impl FromStr for OnionAddress {
+    type Err = OnionAddressError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let encoded = address
+            .strip_suffix(".onion")
+            .ok_or(OnionAddressError::NotOnion)?;
+        if encoded.len() != ENCODED_LEN {
+            return Err(OnionAddressError::Length(encoded.len()));
+        }
+
+        let mut decoded = Vec::with_capacity(35);
+        let mut bits = 0u16;
+        let mut bit_count = 0;
+        for character in encoded.chars() {
+            let value = BASE32_ALPHABET
+                .iter()
+                .position(|&symbol| char::from(symbol) == character.to_ascii_lowercase())
+                .ok_or(OnionAddressError::InvalidCharacter(character))?;
+            bits = (bits << 5) | value as u16;
+            bit_count += 5;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                decoded.push((bits >> bit_count) as u8);
+            }
+        }
+
+        let version = decoded[34];
+        if version != VERSION {
+            return Err(OnionAddressError::Version(version));
+        }
+        let public_key: [u8; 32] = decoded[..32].try_into().expect("35 bytes were decoded");
+        if decoded[32..34] != checksum(&public_key) {
+            return Err(OnionAddressError::Checksum);
+        }
+        Ok(Self { public_key })
+    }
+}
+
+impl std::fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.hostname().fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnionAddressError {
+    NotOnion,
+    Length(usize),
+    InvalidCharacter(char),
+    Version(u8),
+    Checksum,
+}
+
+impl std::fmt::Display for OnionAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotOnion => write!(f, "address does not end in .onion"),
+            Self::Length(length) => write!(
+                f,
+                "onion address has {length} character(s) before .onion, but v3 addresses have {ENCODED_LEN}",
+            ),
+            Self::InvalidCharacter(character) => {
+                write!(f, "{character:?} is not valid in an onion address")
+            }
+            Self::Version(version) => {
+                write!(f, "onion address version {version} is not supported, only {VERSION}")
+            }
+            Self::Checksum => write!(f, "onion address checksum does not match"),
+        }
+    }
+}
+
+impl std::error::Error for OnionAddressError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // DuckDuckGo's onion service
+    const DUCKDUCKGO: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    #[test]
+    fn test_parse_onion_address() {
+        let address: OnionAddress = DUCKDUCKGO.parse().unwrap();
+        assert_eq!(address.hostname(), DUCKDUCKGO);
+        assert_eq!(
+            DUCKDUCKGO
+                .to_uppercase()
+                .replace(".ONION", ".onion")
+                .parse(),
+            Ok(address),
+        );
+
+        let address = OnionAddress::from_public_key([0x5A; 32]);
+        assert_eq!(address.hostname().parse(), Ok(address));
+    }
+
+    #[test]
+    fn test_reject_bad_checksum() {
+        // The same key with a corrupted checksum
+        let mut corrupted = DUCKDUCKGO.to_owned();
+        corrupted.replace_range(52..53, "x");
+        assert_eq!(
+            corrupted.parse::<OnionAddress>(),
+            Err(OnionAddressError::Checksum)
+        );
+
+        // A different key under the original checksum
+        let mut corrupted = DUCKDUCKGO.to_owned();
+        corrupted.replace_range(0..1, "e");
+        assert_eq!(
+            corrupted.parse::<OnionAddress>(),
+            Err(OnionAddressError::Checksum)
+        );
+    }
+
+    #[test]
+    fn test_reject_malformed_onion_addresses() {
+        assert_eq!(
+            "expyuzz4wqqyqhjn.onion".parse::<OnionAddress>(),
+            Err(OnionAddressError::Length(16)),
+        );
+        assert_eq!(
+            DUCKDUCKGO[1..].parse::<OnionAddress>(),
+            Err(OnionAddressError::Length(55)),
+        );
+        assert_eq!(
+            DUCKDUCKGO.replace('x', "1").parse::<OnionAddress>(),
+            Err(OnionAddressError::InvalidCharacter('1')),
+        );
+        assert_eq!(
+            "example.com".parse::<OnionAddress>(),
+            Err(OnionAddressError::NotOnion),
+        );
+
+        // The last character carries the version byte's low bits
+        let mut wrong_version = DUCKDUCKGO.to_owned();
+        wrong_version.replace_range(55..56, "e");
+        assert_eq!(
+            wrong_version.parse::<OnionAddress>(),
+            Err(OnionAddressError::Version(4)),
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::{borrow::Cow, fmt::Display, io, str::FromStr, time::Duration};
+
+use crate::{
+    crawl::CrawledPeer,
+    disconnect::DisconnectReason,
+    handshake::{HandshakeError, HandshakeOutcome},
+    json::JsonValue,
+    network::Network,
+    services::ServiceFlags,
+    survey::{PeerSummary, SurveyFailure, SurveyResult},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Csv,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = OutputFormatParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            _ => Err(OutputFormatParseError(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutputFormatParseError(String);
+
+impl std::fmt::Display for OutputFormatParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown output format {:?}, expected text or csv",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for OutputFormatParseError {}
+
+pub const CSV_COLUMNS: [&str; 12] = [
+    "address",
+    "network",
+    "outcome",
+    "error",
+    "protocol_version",
+    "user_agent",
+    "services_hex",
+    "services",
+    "start_height",
+    "connect_ms",
+    "handshake_ms",
+    "disconnect",
+];
+
+// One peer's result from any mode that reports on several, flattened for tabular output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRow {
+    pub address: String,
+    pub network: Network,
+    pub outcome: Result<PeerSummary, String>,
+    pub disconnect: DisconnectReason,
+}
+
+impl PeerRow {
+    // A survey closes each connection as soon as its handshake is done
+    pub fn from_survey<A: Display>(result: &SurveyResult<A>, network: Network) -> Self {
+        let disconnect = match &result.outcome {
+            Ok(_) => DisconnectReason::Completed,
+            Err(e) => e.disconnect_reason(),
+        };
+        Self::new(
+            &result.candidate,
+            network,
+            result.outcome.as_ref(),
+            disconnect,
+        )
+    }
+
+    pub fn from_crawl(crawled: &CrawledPeer, network: Network) -> Self {
+        let outcome = crawled.outcome.as_ref().map(|summary| &summary.peer);
+        Self::new(
+            &crawled.address,
+            network,
+            outcome,
+            crawled.disconnect_reason(),
+        )
+    }
+
+    fn new(
+        address: &dyn Display,
+        network: Network,
+        outcome: Result<&PeerSummary, &SurveyFailure>,
+        disconnect: DisconnectReason,
+    ) -> Self {
+        Self {
+            address: address.to_string(),
+            network,
+            outcome: outcome.cloned().map_err(|e| e.to_string()),
+            disconnect,
+        }
+    }
+
+    // In the order of `CSV_COLUMNS`, with fields that don't apply left empty
+    pub fn csv_fields(&self) -> [String; CSV_COLUMNS.len()] {
+        let address = self.address.clone();
+        let network = self.network.to_string();
+        match &self.outcome {
+            Ok(summary) => [
+                address,
+                network,
+                "ok".to_owned(),
+                String::new(),
+                summary.version.to_string(),
+                summary.user_agent.clone(),
+                format!("{:#x}", summary.services.bits()),
+                summary.services.to_string(),
+                summary.start_height.to_string(),
+                millis(summary.connect_time),
+                millis(summary.latency),
+                self.disconnect.to_string(),
+            ],
+            Err(e) => [
+                address,
+                network,
+                "failed".to_owned(),
+                e.clone(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                self.disconnect.to_string(),
+            ],
+        }
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+// Quotes a field as RFC 4180 asks when it holds a separator, quote, or line break, doubling any
+// quotes inside
+pub fn escape_csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn write_csv_record<W: io::Write>(writer: &mut W, fields: &[impl AsRef<str>]) -> io::Result<()> {
+    let mut separator = "";
+    for field in fields {
+        write!(writer, "{separator}{}", escape_csv_field(field.as_ref()))?;
+        separator = ",";
+    }
+    // RFC 4180 ends every record with CRLF, which spreadsheets and parsers expect
+    writer.write_all(b"\r\n")
+}
+
+// A header, then one record per peer
+pub fn write_csv<W: io::Write>(mut writer: W, rows: &[PeerRow]) -> io::Result<()> {
+    write_csv_record(&mut writer, &CSV_COLUMNS)?;
+    for row in rows {
+        write_csv_record(&mut writer, &row.csv_fields())?;
+    }
+    writer.flush()
+}
+
+fn services_json(services: ServiceFlags) -> JsonValue {
+    JsonValue::object([
+        ("hex", format!("{:#x}", services.bits()).into()),
+        ("names", services.names().into()),
+    ])
+}
+
+// The result of a successful handshake, for --json; `connect_time` is how long connecting took
+// before the handshake started
+pub fn handshake_json(
+    peer: &dyn Display,
+    network: Network,
+    outcome: &HandshakeOutcome,
+    connect_time: Duration,
+) -> JsonValue {
+    let peer_version = &outcome.peer_version;
+    JsonValue::object([
+        ("peer", peer.to_string().into()),
+        ("network", network.to_string().into()),
+        ("success", true.into()),
+        ("version", peer_version.version().into()),
+        ("services", services_json(peer_version.services())),
+        (
+            "user_agent",
+            String::from_utf8_lossy(peer_version.user_agent_bytes())
+                .into_owned()
+                .into(),
+        ),
+        ("start_height", peer_version.start_height().into()),
+        // Peers too old to send the flag relay everything
+        ("relay", peer_version.relay().unwrap_or(true).into()),
+        ("clock_skew_secs", outcome.clock_skew.into()),
+        ("negotiated_version", outcome.negotiated_version.into()),
+        (
+            "timing",
+            JsonValue::object([
+                ("connect_ms", JsonValue::millis(connect_time)),
+                ("handshake_ms", JsonValue::millis(outcome.timings.total)),
+            ]),
+        ),
+    ])
+}
+
+pub fn failure_json(peer: &dyn Display, network: Network, error: &HandshakeError) -> JsonValue {
+    JsonValue::object([
+        ("peer", peer.to_string().into()),
+        ("network", network.to_string().into()),
+        ("success", false.into()),
+        (
+            "error",
+            JsonValue::object([
+                ("kind", error.kind().into()),
+                ("message", error.to_string().into()),
+            ]),
+        ),
+        ("disconnect", error.disconnect_reason().to_string().into()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{
+        connect::ConnectError,
+        handshake::{HandshakeStep, HandshakeTimings},
+        version_payload::VersionPayload,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_escape_csv_field() {
+        assert_eq!(escape_csv_field("/Satoshi:25.0.0/"), "/Satoshi:25.0.0/");
+        assert_eq!(escape_csv_field(""), "");
+        assert_eq!(escape_csv_field("/a,b/"), "\"/a,b/\"");
+        assert_eq!(escape_csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(escape_csv_field("two\nlines"), "\"two\nlines\"");
+    }
+
+    #[test]
+    fn test_write_csv_mixed_batch() {
+        let summary = PeerSummary {
+            user_agent: "/Satoshi:25.0.0(\"quoted\", with comma)/".to_owned(),
+            version: 70016,
+            services: ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            start_height: 801_474,
+            connect_time: Duration::from_micros(12_345),
+            latency: Duration::from_millis(80),
+        };
+        let results = [
+            SurveyResult {
+                candidate: SocketAddr::from(([192, 0, 2, 1], 8333)),
+                outcome: Ok(summary),
+            },
+            SurveyResult {
+                candidate: "[2001:db8::1]:8333".parse().unwrap(),
+                outcome: Err(SurveyFailure::TimedOut(Duration::from_secs(30))),
+            },
+            SurveyResult {
+                candidate: SocketAddr::from(([192, 0, 2, 3], 8333)),
+                outcome: Err(SurveyFailure::Handshake(HandshakeError::Connect(
+                    ConnectError::TimedOut(Duration::from_secs(10)),
+                ))),
+            },
+        ];
+        let rows: Vec<_> = results
+            .iter()
+            .map(|result| PeerRow::from_survey(result, Network::Mainnet))
+            .collect();
+
+        let mut csv = Vec::new();
+        write_csv(&mut csv, &rows).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "address,network,outcome,error,protocol_version,user_agent,services_hex,services,\
+             start_height,connect_ms,handshake_ms,disconnect\r\n\
+             192.0.2.1:8333,mainnet,ok,,70016,\"/Satoshi:25.0.0(\"\"quoted\"\", with comma)/\",\
+             0x9,NETWORK|WITNESS,801474,12.345,80.000,completed\r\n\
+             [2001:db8::1]:8333,mainnet,failed,no handshake within 30s,,,,,,,,handshake_timeout\r\n\
+             192.0.2.3:8333,mainnet,failed,could not connect: connection timed out after 10s,\
+             ,,,,,,,connect_failed\r\n",
+        );
+    }
+
+    #[test]
+    fn test_handshake_json() {
+        let outcome = HandshakeOutcome {
+            peer_version: VersionPayload::builder()
+                .version(70016)
+                .services(ServiceFlags::from_bits(0x0409 | 0x1_0000))
+                .user_agent("/Satoshi:25.0.0/")
+                .start_height(801_474)
+                .relay(Some(false))
+                .build()
+                .unwrap(),
+            negotiated_version: 70016,
+            clock_skew: Some(-3),
+            timings: HandshakeTimings {
+                total: Duration::from_micros(81_250),
+                ..HandshakeTimings::default()
+            },
+            other_messages: Vec::new(),
+        };
+        let peer = SocketAddr::from(([192, 0, 2, 1], 8333));
+        assert_eq!(
+            handshake_json(&peer, Network::Mainnet, &outcome, Duration::from_millis(12))
+                .to_string(),
+            r#"{"peer":"192.0.2.1:8333","network":"mainnet","success":true,"version":70016,"#
+                .to_owned()
+                + r#""services":{"hex":"0x10409","names":["NETWORK","WITNESS","NETWORK_LIMITED"]},"#
+                + r#""user_agent":"/Satoshi:25.0.0/","start_height":801474,"relay":false,"#
+                + r#""clock_skew_secs":-3,"negotiated_version":70016,"#
+                + r#""timing":{"connect_ms":12.000,"handshake_ms":81.250}}"#,
+        );
+    }
+
+    #[test]
+    fn test_failure_json() {
+        let error = HandshakeError::Timeout {
+            step: HandshakeStep::AwaitingVerack,
+            timeout: Duration::from_secs(60),
+        };
+        let peer = "[2001:db8::1]:8333".parse::<SocketAddr>().unwrap();
+        assert_eq!(
+            failure_json(&peer, Network::Testnet3, &error).to_string(),
+            r#"{"peer":"[2001:db8::1]:8333","network":"testnet3","success":false,"#.to_owned()
+                + r#""error":{"kind":"timeout","#
+                + r#""message":"handshake did not finish within 60s (awaiting verack)"},"#
+                + r#""disconnect":"handshake_timeout"}"#,
+        );
+    }
+}
@@ -0,0 +1,374 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use crate::{
+    onion::{OnionAddress, OnionAddressError},
+    seeds::{DnsResolver, Resolver},
+};
+
+// Where to find a peer, as given on the command line: an IP address or a hostname, with or
+// without a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerAddress {
+    Ip {
+        ip: IpAddr,
+        port: Option<u16>,
+    },
+    Host {
+        host: String,
+        port: Option<u16>,
+    },
+    Onion {
+        address: OnionAddress,
+        port: Option<u16>,
+    },
+}
+
+impl PeerAddress {
+    pub fn port(&self) -> Option<u16> {
+        match *self {
+            Self::Ip { port, .. } | Self::Host { port, .. } | Self::Onion { port, .. } => port,
+        }
+    }
+
+    // Looks up a hostname through DNS, using `default_port` when none was given
+    pub async fn resolve(&self, default_port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        self.resolve_with(&DnsResolver, default_port).await
+    }
+
+    pub async fn resolve_with<R: Resolver>(
+        &self,
+        resolver: &R,
+        default_port: u16,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        let port = self.port().unwrap_or(default_port);
+        match self {
+            Self::Ip { ip, .. } => Ok(vec![SocketAddr::new(*ip, port)]),
+            Self::Host { host, .. } => {
+                let addresses = resolver.resolve(host, port).await?;
+                if addresses.is_empty() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{host} has no addresses"),
+                    ));
+                }
+                Ok(addresses)
+            }
+            // Resolving one would leak it to the DNS resolver, and there would be nothing to find
+            Self::Onion { address, .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!(
+                    "{address} can only be reached through a Tor proxy, which is not supported yet"
+                ),
+            )),
+        }
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = PeerAddressError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        if address.is_empty() {
+            return Err(PeerAddressError::Empty);
+        }
+        if let Ok(socket_address) = address.parse::<SocketAddr>() {
+            return Ok(Self::Ip {
+                ip: socket_address.ip(),
+                port: Some(socket_address.port()),
+            });
+        }
+        // A bare IPv6 address is all colons, so it has to be tried before splitting off a port
+        if let Ok(ip) = address.parse::<IpAddr>() {
+            return Ok(Self::Ip { ip, port: None });
+        }
+        if let Some(ip) = address
+            .strip_prefix('[')
+            .and_then(|address| address.strip_suffix(']'))
+        {
+            return match ip.parse::<IpAddr>() {
+                Ok(ip @ IpAddr::V6(_)) => Ok(Self::Ip { ip, port: None }),
+                _ => Err(PeerAddressError::InvalidHost(address.to_owned())),
+            };
+        }
+
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .map_err(|_| PeerAddressError::InvalidPort(port.to_owned()))?;
+                (host, Some(port))
+            }
+            None => (address, None),
+        };
+        if host.ends_with(".onion") {
+            let address = host.parse().map_err(PeerAddressError::InvalidOnion)?;
+            return Ok(Self::Onion { address, port });
+        }
+        if !is_hostname(host) {
+            return Err(PeerAddressError::InvalidHost(host.to_owned()));
+        }
+        Ok(Self::Host {
+            host: host.to_owned(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip {
+                ip,
+                port: Some(port),
+            } => SocketAddr::new(*ip, *port).fmt(f),
+            Self::Ip { ip, port: None } => ip.fmt(f),
+            Self::Host {
+                host,
+                port: Some(port),
+            } => write!(f, "{host}:{port}"),
+            Self::Host { host, port: None } => host.fmt(f),
+            Self::Onion {
+                address,
+                port: Some(port),
+            } => write!(f, "{address}:{port}"),
+            Self::Onion {
+                address,
+                port: None,
+            } => address.fmt(f),
+        }
+    }
+}
+
+// Dot-separated labels of letters, digits and hyphens, none of them empty or starting or
+// ending with a hyphen
+fn is_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-')
+        })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeerAddressError {
+    Empty,
+    InvalidHost(String),
+    InvalidPort(String),
+    InvalidOnion(OnionAddressError),
+}
+
+impl std::fmt::Display for PeerAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "address is empty"),
+            Self::InvalidHost(host) => write!(f, "{host:?} is not an IP address or hostname"),
+            Self::InvalidPort(port) => write!(f, "{port:?} is not a valid port"),
+            Self::InvalidOnion(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PeerAddressError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use super::*;
+
+    fn host(host: &str, port: Option<u16>) -> PeerAddress {
+        PeerAddress::Host {
+            host: host.to_owned(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_parse_ip_addresses() {
+        let cases = [
+            ("203.0.113.7", "203.0.113.7", None),
+            ("203.0.113.7:18333", "203.0.113.7", Some(18333)),
+            ("::1", "::1", None),
+            ("2001:db8::8333", "2001:db8::8333", None),
+            ("[2001:db8::1]", "2001:db8::1", None),
+            ("[2001:db8::1]:8333", "2001:db8::1", Some(8333)),
+            (
+                "[::ffff:203.0.113.7]:8333",
+                "::ffff:203.0.113.7",
+                Some(8333),
+            ),
+        ];
+        for (address, ip, port) in cases {
+            assert_eq!(
+                address.parse(),
+                Ok(PeerAddress::Ip {
+                    ip: ip.parse().unwrap(),
+                    port,
+                }),
+                "{address}",
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_hostnames() {
+        assert_eq!(
+            "seed.bitcoin.sipa.be".parse(),
+            Ok(host("seed.bitcoin.sipa.be", None)),
+        );
+        assert_eq!(
+            "node.example.com:8333".parse(),
+            Ok(host("node.example.com", Some(8333))),
+        );
+        assert_eq!("localhost".parse(), Ok(host("localhost", None)));
+        assert_eq!(
+            "my-node.example.:38333".parse(),
+            Ok(host("my-node.example.", Some(38333))),
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_addresses() {
+        assert_eq!("".parse::<PeerAddress>(), Err(PeerAddressError::Empty));
+        assert_eq!(
+            "node.example.com:".parse::<PeerAddress>(),
+            Err(PeerAddressError::InvalidPort(String::new())),
+        );
+        assert_eq!(
+            "node.example.com:65536".parse::<PeerAddress>(),
+            Err(PeerAddressError::InvalidPort("65536".to_owned())),
+        );
+        assert_eq!(
+            "[203.0.113.7]".parse::<PeerAddress>(),
+            Err(PeerAddressError::InvalidHost("[203.0.113.7]".to_owned())),
+        );
+        for invalid in [
+            "[2001:db8::1]:",
+            "[2001:db8::1",
+            "2001:db8::1]:8333",
+            "node..example",
+            "-node.example",
+            "node_1.example",
+            "node example",
+            ":8333",
+        ] {
+            assert!(invalid.parse::<PeerAddress>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for address in [
+            "203.0.113.7",
+            "203.0.113.7:8333",
+            "2001:db8::1",
+            "[2001:db8::1]:8333",
+            "node.example.com",
+            "node.example.com:8333",
+        ] {
+            let parsed: PeerAddress = address.parse().unwrap();
+            assert_eq!(parsed.to_string(), address);
+        }
+    }
+
+    #[test]
+    fn test_parse_onion_addresses() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let address: OnionAddress = onion.parse().unwrap();
+        assert_eq!(
+            format!("{onion}:8333").parse(),
+            Ok(PeerAddress::Onion {
+                address,
+                port: Some(8333),
+            }),
+        );
+        assert_eq!(
+            onion.parse(),
+            Ok(PeerAddress::Onion {
+                address,
+                port: None,
+            }),
+        );
+        assert_eq!(
+            "expyuzz4wqqyqhjn.onion:8333".parse::<PeerAddress>(),
+            Err(PeerAddressError::InvalidOnion(OnionAddressError::Length(
+                16
+            ))),
+        );
+    }
+
+    struct CannedResolver;
+
+    impl Resolver for CannedResolver {
+        async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            match host {
+                "dual.example" => Ok(vec![
+                    SocketAddr::new("203.0.113.1".parse().unwrap(), port),
+                    SocketAddr::new("203.0.113.2".parse().unwrap(), port),
+                    SocketAddr::new("2001:db8::1".parse().unwrap(), port),
+                ]),
+                "empty.example" => Ok(Vec::new()),
+                _ => Err(Error::new(ErrorKind::NotFound, "no such host")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve() {
+        let resolved = host("dual.example", None)
+            .resolve_with(&CannedResolver, 8333)
+            .await
+            .unwrap();
+        assert_eq!(
+            resolved,
+            [
+                "203.0.113.1:8333".parse::<SocketAddr>().unwrap(),
+                "203.0.113.2:8333".parse().unwrap(),
+                "[2001:db8::1]:8333".parse().unwrap(),
+            ],
+        );
+
+        let resolved = host("dual.example", Some(18333))
+            .resolve_with(&CannedResolver, 8333)
+            .await
+            .unwrap();
+        assert!(resolved.iter().all(|address| address.port() == 18333));
+
+        // Literal addresses never reach the resolver
+        let resolved = "[2001:db8::2]"
+            .parse::<PeerAddress>()
+            .unwrap()
+            .resolve_with(&CannedResolver, 38333)
+            .await
+            .unwrap();
+        assert_eq!(resolved, ["[2001:db8::2]:38333".parse().unwrap()]);
+
+        // Never looked up, since there is no proxy to hand it to
+        let onion: PeerAddress = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+            .parse()
+            .unwrap();
+        let result = onion.resolve_with(&CannedResolver, 8333).await;
+        assert!(
+            matches!(&result, Err(e) if e.kind() == ErrorKind::Unsupported),
+            "{result:?}",
+        );
+
+        for missing in ["missing.example", "empty.example"] {
+            let result = host(missing, None)
+                .resolve_with(&CannedResolver, 8333)
+                .await;
+            assert!(
+                matches!(&result, Err(e) if e.kind() == ErrorKind::NotFound),
+                "{result:?}",
+            );
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::net::SocketAddr;
+
+// One `ip:port` per line, with blank lines and `#` comments ignored, including at the end of a line
+pub fn parse_peer_list(contents: &str) -> Result<Vec<SocketAddr>, PeerListError> {
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some((index + 1, line))
+        })
+        .map(|(line_number, line)| {
+            line.parse().map_err(|_| PeerListError::InvalidAddress {
+                line_number,
+                address: line.to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeerListError {
+    InvalidAddress { line_number: usize, address: String },
+}
+
+impl std::fmt::Display for PeerListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidAddress {
+                line_number,
+                address,
+            } => write!(
+                f,
+                "line {line_number}: {address:?} is not an ip:port address"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PeerListError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_peer_list() {
+        let contents = "\
+# mainnet nodes
+203.0.113.7:8333
+
+  198.51.100.23:8333  # behind a slow link
+[2001:db8::1]:8333
+";
+        assert_eq!(
+            parse_peer_list(contents),
+            Ok(vec![
+                "203.0.113.7:8333".parse().unwrap(),
+                "198.51.100.23:8333".parse().unwrap(),
+                "[2001:db8::1]:8333".parse().unwrap(),
+            ]),
+        );
+        assert_eq!(parse_peer_list("# nothing yet\n"), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn test_parse_peer_list_rejects_invalid_lines() {
+        assert_eq!(
+            parse_peer_list("203.0.113.7:8333\n203.0.113.8\n"),
+            Err(PeerListError::InvalidAddress {
+                line_number: 2,
+                address: "203.0.113.8".to_owned(),
+            }),
+        );
+    }
+}
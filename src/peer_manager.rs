@@ -0,0 +1,504 @@
+use std::{collections::HashMap, net::SocketAddr, ops::ControlFlow, time::Duration};
+
+use binrw::{meta::WriteEndian, BinWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, WriteHalf},
+    net::TcpStream,
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    connection_state::ConnectionState,
+    handshake::{connect_and_handshake, HandshakeError},
+    handshake_config::HandshakeConfig,
+    keepalive::{keepalive_with_outbox, KeepaliveError, Outgoing},
+    message::MessageType,
+    message_preparable::MessagePreparable,
+    messaging_system::MessagingSystem,
+    session::DEFAULT_PING_INTERVAL,
+};
+
+// Peers are told apart by the address they're connected on
+pub type PeerId = SocketAddr;
+
+// What the peers' sessions report, in the order it happened for each peer
+#[derive(Debug)]
+pub enum PeerEvent {
+    Connected(PeerId),
+    Message(PeerId, MessageType),
+    // The session ended on its own; the peer is already gone from the manager
+    Failed(PeerId, KeepaliveError),
+    // We hung up, whether asked to or to make room for another peer
+    Disconnected(PeerId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // Turn new peers away once full
+    RejectNew,
+    // Hang up on whichever peer has been connected longest
+    EvictOldest,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PeerLimits {
+    pub max_connections: usize,
+    pub eviction: EvictionPolicy,
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 8,
+            eviction: EvictionPolicy::RejectNew,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            // Bitcoin Core's own timeout for an unanswered ping
+            pong_timeout: Duration::from_secs(20 * 60),
+        }
+    }
+}
+
+struct Peer<T> {
+    outbox: mpsc::UnboundedSender<Outgoing<WriteHalf<T>>>,
+    task: JoinHandle<()>,
+    // When the peer joined, relative to the others, for eviction
+    joined: u64,
+}
+
+// Owns a set of established connections, each kept alive by a task of its own that reports what
+// arrives as `PeerEvent`s. Dropping the manager, or a peer, aborts the tasks involved
+pub struct PeerManager<T = TcpStream> {
+    config: HandshakeConfig,
+    limits: PeerLimits,
+    peers: HashMap<PeerId, Peer<T>>,
+    joined: u64,
+    events: mpsc::UnboundedSender<PeerEvent>,
+}
+
+impl<T> PeerManager<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // The events for every peer arrive on the receiver handed back alongside
+    pub fn new(
+        config: HandshakeConfig,
+        limits: PeerLimits,
+    ) -> (Self, mpsc::UnboundedReceiver<PeerEvent>) {
+        let (events, receiver) = mpsc::unbounded_channel();
+        let manager = Self {
+            config,
+            limits,
+            peers: HashMap::new(),
+            joined: 0,
+            events,
+        };
+        (manager, receiver)
+    }
+
+    pub fn config(&self) -> &HandshakeConfig {
+        &self.config
+    }
+
+    // Takes over a connection that finished its handshake, evicting another peer first if the
+    // manager is full and the policy allows
+    pub fn add(
+        &mut self,
+        messaging_system: MessagingSystem<T>,
+    ) -> Result<PeerId, PeerManagerError> {
+        let peer_id = messaging_system.remote_addr();
+        let state = messaging_system.state();
+        if state != ConnectionState::HandshakeComplete {
+            return Err(PeerManagerError::NotEstablished(peer_id, state));
+        }
+        self.make_room(peer_id)?;
+
+        let (outbox, outgoing) = mpsc::unbounded_channel();
+        let _ = self.events.send(PeerEvent::Connected(peer_id));
+        let task = tokio::spawn(run_session(
+            peer_id,
+            messaging_system,
+            self.limits,
+            outgoing,
+            self.events.clone(),
+        ));
+        self.joined += 1;
+        self.peers.insert(
+            peer_id,
+            Peer {
+                outbox,
+                task,
+                joined: self.joined,
+            },
+        );
+        Ok(peer_id)
+    }
+
+    // Hangs up on the peer, returning whether it was connected
+    pub fn disconnect(&mut self, peer_id: PeerId) -> bool {
+        self.prune();
+        match self.peers.remove(&peer_id) {
+            Some(peer) => {
+                peer.task.abort();
+                let _ = self.events.send(PeerEvent::Disconnected(peer_id));
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Queues the message for every connected peer, returning how many that was. A peer that
+    // can't take it fails with a `PeerEvent::Failed` like any other session
+    pub fn broadcast<P>(&mut self, payload: P) -> usize
+    where
+        P: MessagePreparable + BinWrite + WriteEndian + Clone + Send + 'static,
+        for<'a> <P as BinWrite>::Args<'a>: Default,
+    {
+        self.prune();
+        self.peers
+            .values()
+            .filter(|peer| {
+                let payload = payload.clone();
+                let send: Outgoing<WriteHalf<T>> =
+                    Box::new(move |sender| Box::pin(sender.send_payload(payload)));
+                peer.outbox.send(send).is_ok()
+            })
+            .count()
+    }
+
+    // Connected peers, oldest first
+    pub fn peers(&mut self) -> Vec<PeerId> {
+        self.prune();
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|(_, peer)| peer.joined);
+        peers.into_iter().map(|(&peer_id, _)| peer_id).collect()
+    }
+
+    pub fn len(&mut self) -> usize {
+        self.prune();
+        self.peers.len()
+    }
+
+    pub fn is_empty(&mut self) -> bool {
+        self.len() == 0
+    }
+
+    // Forgets the peers whose sessions ended on their own, which close their outboxes first
+    fn prune(&mut self) {
+        self.peers.retain(|_, peer| !peer.outbox.is_closed());
+    }
+
+    // Whether the peer could be added, without evicting anyone yet
+    fn check_room(&mut self, peer_id: PeerId) -> Result<(), PeerManagerError> {
+        self.prune();
+        if self.peers.contains_key(&peer_id) {
+            return Err(PeerManagerError::AlreadyConnected(peer_id));
+        }
+        let full = self.peers.len() >= self.limits.max_connections;
+        if full && self.limits.eviction == EvictionPolicy::RejectNew {
+            return Err(PeerManagerError::Full(self.limits.max_connections));
+        }
+        Ok(())
+    }
+
+    fn make_room(&mut self, peer_id: PeerId) -> Result<(), PeerManagerError> {
+        self.check_room(peer_id)?;
+        if self.peers.len() < self.limits.max_connections {
+            return Ok(());
+        }
+        let oldest = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| peer.joined)
+            .map(|(&peer_id, _)| peer_id);
+        match (self.limits.eviction, oldest) {
+            (EvictionPolicy::EvictOldest, Some(oldest)) => {
+                self.disconnect(oldest);
+                Ok(())
+            }
+            _ => Err(PeerManagerError::Full(self.limits.max_connections)),
+        }
+    }
+}
+
+impl PeerManager<TcpStream> {
+    // Dials the peer and handshakes with it before adding it. A manager that would refuse the
+    // peer does so before dialing, but only evicts anyone once the handshake is done
+    pub async fn connect(&mut self, address: SocketAddr) -> Result<PeerId, PeerManagerError> {
+        self.check_room(address)?;
+        let connecting = MessagingSystem::try_new(address);
+        let (messaging_system, _) = connect_and_handshake(connecting, &self.config).await?;
+        self.add(messaging_system)
+    }
+}
+
+impl<T> Drop for PeerManager<T> {
+    fn drop(&mut self) {
+        for peer in self.peers.values() {
+            peer.task.abort();
+        }
+    }
+}
+
+async fn run_session<T>(
+    peer_id: PeerId,
+    messaging_system: MessagingSystem<T>,
+    limits: PeerLimits,
+    mut outbox: mpsc::UnboundedReceiver<Outgoing<WriteHalf<T>>>,
+    events: mpsc::UnboundedSender<PeerEvent>,
+) where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut sender, mut receiver) = messaging_system.into_split();
+    let result = keepalive_with_outbox(
+        &mut sender,
+        &mut receiver,
+        limits.ping_interval,
+        limits.pong_timeout,
+        &mut outbox,
+        |message| match events.send(PeerEvent::Message(peer_id, message)) {
+            Ok(()) => ControlFlow::Continue(()),
+            // Nobody is listening any more
+            Err(_) => ControlFlow::Break(()),
+        },
+    )
+    .await;
+    // Closed before the event goes out, so the manager never counts a peer it reported gone
+    drop(outbox);
+    if let Err(e) = result {
+        let _ = events.send(PeerEvent::Failed(peer_id, e));
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerManagerError {
+    // Holds the limit
+    Full(usize),
+    AlreadyConnected(PeerId),
+    // Only connections past their handshake can be added
+    NotEstablished(PeerId, ConnectionState),
+    Handshake(HandshakeError),
+}
+
+impl std::fmt::Display for PeerManagerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full(max_connections) => {
+                write!(
+                    f,
+                    "already connected to the maximum of {max_connections} peer(s)"
+                )
+            }
+            Self::AlreadyConnected(peer_id) => write!(f, "already connected to {peer_id}"),
+            Self::NotEstablished(peer_id, state) => {
+                write!(f, "connection to {peer_id} is not established ({state})")
+            }
+            Self::Handshake(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for PeerManagerError {}
+
+impl From<HandshakeError> for PeerManagerError {
+    fn from(value: HandshakeError) -> Self {
+        Self::Handshake(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use crate::{
+        addr_payload::GetAddrPayload,
+        command::{command_name, Command},
+        messaging_system::MessageReceiveError,
+        network::Network,
+        testing::MockPeer,
+        verack_payload::VerackPayload,
+        version_payload::VersionPayload,
+    };
+
+    use super::*;
+
+    fn handshaking_peer() -> MockPeer {
+        MockPeer::new(Network::Mainnet)
+            .wait_for(Command::Version)
+            .send(VersionPayload::builder().nonce(2).build().unwrap())
+            .send(VerackPayload)
+            .wait_for(Command::Verack)
+    }
+
+    async fn established(
+        peer: MockPeer,
+        address: &str,
+    ) -> (MessagingSystem<DuplexStream>, JoinHandle<Vec<MessageType>>) {
+        let (stream, task) = peer.spawn();
+        let mut messaging_system = MessagingSystem::from_stream(stream, address.parse().unwrap());
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await
+            .unwrap();
+        (messaging_system, task)
+    }
+
+    fn commands(received: &[MessageType]) -> Vec<String> {
+        received
+            .iter()
+            .map(|message| command_name(&message.command_raw()).to_owned())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_peers_exchange_messages_and_one_fails() {
+        let (mut manager, mut events) =
+            PeerManager::new(HandshakeConfig::default(), PeerLimits::default());
+
+        // Asks for addresses, and asks again once asked in turn
+        let chatty = || {
+            handshaking_peer()
+                .send(GetAddrPayload)
+                .wait_for(Command::GetAddr)
+                .send(GetAddrPayload)
+        };
+        let (first, first_peer) = established(chatty(), "192.0.2.1:8333").await;
+        let (second, second_peer) = established(chatty(), "192.0.2.2:8333").await;
+        // Garbage where the next header should be
+        let (failing, _) =
+            established(handshaking_peer().send_raw([0xAB; 24]), "192.0.2.3:8333").await;
+
+        let first = manager.add(first).unwrap();
+        let second = manager.add(second).unwrap();
+        let failing = manager.add(failing).unwrap();
+
+        let mut connected = Vec::new();
+        let mut messages = Vec::new();
+        loop {
+            match events.recv().await.unwrap() {
+                PeerEvent::Connected(peer_id) => connected.push(peer_id),
+                PeerEvent::Message(peer_id, MessageType::GetAddr) => messages.push(peer_id),
+                PeerEvent::Failed(peer_id, e) => {
+                    assert_eq!(peer_id, failing);
+                    assert!(matches!(
+                        e,
+                        KeepaliveError::Receive(MessageReceiveError::Parsing(_))
+                    ));
+                    break;
+                }
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        assert_eq!(connected, [first, second, failing]);
+        while messages.len() < 2 {
+            match events.recv().await.unwrap() {
+                PeerEvent::Message(peer_id, MessageType::GetAddr) => messages.push(peer_id),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        messages.sort();
+        assert_eq!(messages, [first, second]);
+
+        // The failed peer is gone, and the others both get the broadcast
+        assert_eq!(manager.peers(), [first, second]);
+        assert_eq!(manager.broadcast(GetAddrPayload), 2);
+        let mut answered = Vec::new();
+        while answered.len() < 2 {
+            match events.recv().await.unwrap() {
+                PeerEvent::Message(peer_id, MessageType::GetAddr) => answered.push(peer_id),
+                event => panic!("unexpected {event:?}"),
+            }
+        }
+        answered.sort();
+        assert_eq!(answered, [first, second]);
+
+        assert!(manager.disconnect(first));
+        assert!(!manager.disconnect(first));
+        assert!(matches!(
+            events.recv().await,
+            Some(PeerEvent::Disconnected(peer_id)) if peer_id == first
+        ));
+        assert_eq!(
+            commands(&first_peer.await.unwrap()),
+            ["version", "verack", "getaddr"]
+        );
+
+        // Dropping the manager hangs up on whoever is left
+        drop(manager);
+        assert_eq!(
+            commands(&second_peer.await.unwrap()),
+            ["version", "verack", "getaddr"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let limits = PeerLimits {
+            max_connections: 1,
+            ..PeerLimits::default()
+        };
+        let (mut manager, _events) = PeerManager::new(HandshakeConfig::default(), limits);
+        let (first, _first_peer) = established(handshaking_peer(), "192.0.2.1:8333").await;
+        let (second, _second_peer) = established(handshaking_peer(), "192.0.2.2:8333").await;
+        let first = manager.add(first).unwrap();
+        assert!(matches!(
+            manager.add(second),
+            Err(PeerManagerError::Full(1))
+        ));
+        assert_eq!(manager.peers(), [first]);
+
+        let limits = PeerLimits {
+            max_connections: 1,
+            eviction: EvictionPolicy::EvictOldest,
+            ..PeerLimits::default()
+        };
+        let (mut manager, mut events) = PeerManager::new(HandshakeConfig::default(), limits);
+        let (first, first_peer) = established(handshaking_peer(), "192.0.2.1:8333").await;
+        let (second, _second_peer) = established(handshaking_peer(), "192.0.2.2:8333").await;
+        let (duplicate, _duplicate_peer) = established(handshaking_peer(), "192.0.2.2:8333").await;
+        let first = manager.add(first).unwrap();
+        let second = manager.add(second).unwrap();
+        assert!(matches!(
+            manager.add(duplicate),
+            Err(PeerManagerError::AlreadyConnected(peer_id)) if peer_id == second
+        ));
+        assert_eq!(manager.peers(), [second]);
+        first_peer.await.unwrap();
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            seen.push(match event {
+                PeerEvent::Connected(peer_id) => ("connected", peer_id),
+                PeerEvent::Disconnected(peer_id) => ("disconnected", peer_id),
+                event => panic!("unexpected {event:?}"),
+            });
+        }
+        assert_eq!(
+            seen,
+            [
+                ("connected", first),
+                ("disconnected", first),
+                ("connected", second)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_before_handshake_is_rejected() {
+        let (mut manager, _events) =
+            PeerManager::new(HandshakeConfig::default(), PeerLimits::default());
+        let (stream, _peer) = handshaking_peer().spawn();
+        let messaging_system =
+            MessagingSystem::from_stream(stream, "192.0.2.1:8333".parse().unwrap());
+        assert!(matches!(
+            manager.add(messaging_system),
+            Err(PeerManagerError::NotEstablished(
+                _,
+                ConnectionState::Connected
+            ))
+        ));
+        assert!(manager.is_empty());
+    }
+}
@@ -0,0 +1,58 @@
+use binrw::binrw;
+
+use crate::{command::Command, message_preparable::MessagePreparable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[binrw]
+#[brw(little)]
+pub struct PingPayload {
+    nonce: u64,
+}
+
+impl PingPayload {
+    pub fn new(nonce: u64) -> Self {
+        Self { nonce }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    // The pong a peer must answer this ping with, echoing its nonce (BIP 31)
+    pub fn pong(&self) -> PongPayload {
+        PongPayload::new(self.nonce)
+    }
+}
+
+impl MessagePreparable for PingPayload {
+    const COMMAND_TYPE: Command = Command::Ping;
+
+    fn size_hint(&self) -> usize {
+        8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[binrw]
+#[brw(little)]
+pub struct PongPayload {
+    nonce: u64,
+}
+
+impl PongPayload {
+    pub fn new(nonce: u64) -> Self {
+        Self { nonce }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+}
+
+impl MessagePreparable for PongPayload {
+    const COMMAND_TYPE: Command = Command::Pong;
+
+    fn size_hint(&self) -> usize {
+        8
+    }
+}
@@ -0,0 +1,31 @@
+// Protocol versions at which notable features were introduced, mirroring Bitcoin Core's version.h
+
+// The protocol version we advertise unless told otherwise
+pub const PROTOCOL_VERSION: i32 = 70014;
+
+// Peers advertising anything older are disconnected by Bitcoin Core
+pub const MIN_PEER_PROTO_VERSION: i32 = 31800;
+
+// Version messages before this version stop after the addr_recv field
+pub const ADDR_FROM_VERSION: i32 = 106;
+
+// BIP 37: version message carries the relay flag
+pub const RELAY_VERSION: i32 = 70001;
+
+// BIP 130: sendheaders
+pub const SENDHEADERS_VERSION: i32 = 70012;
+
+// BIP 133: feefilter
+pub const FEEFILTER_VERSION: i32 = 70013;
+
+// BIP 339: wtxidrelay
+pub const WTXID_RELAY_VERSION: i32 = 70016;
+
+// BIP 155: sendaddrv2 is only sent to peers at or above this version
+pub const SENDADDRV2_VERSION: i32 = 70016;
+
+// Bitcoin Core refuses to process messages with larger payloads (MAX_PROTOCOL_MESSAGE_LENGTH)
+pub const MAX_MESSAGE_SIZE: u32 = 4_000_000;
+
+// Hard ceiling on any payload, whatever limit was configured (MAX_SIZE in serialize.h)
+pub const MAX_SIZE: u32 = 0x0200_0000;
@@ -0,0 +1,371 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::clock::{Clock, SystemClock};
+
+// Each record is a direction byte, the time as microseconds since the Unix epoch (u64 LE), the
+// length of the bytes (u32 LE), and the bytes themselves, exactly as they crossed the wire
+const RECORD_HEADER_SIZE: usize = 1 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received,
+    Sent,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Received => b'<',
+            Self::Sent => b'>',
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            b'<' => Some(Self::Received),
+            b'>' => Some(Self::Sent),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    pub timestamp: SystemTime,
+    pub bytes: Vec<u8>,
+}
+
+impl Record {
+    fn to_bytes(&self) -> Vec<u8> {
+        let micros = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + self.bytes.len());
+        record.push(self.direction.to_byte());
+        record.extend(micros.to_le_bytes());
+        record.extend((self.bytes.len() as u32).to_le_bytes());
+        record.extend(&self.bytes);
+        record
+    }
+}
+
+// Splits a recording back into its records, in the order they were made
+pub fn read_records(mut data: &[u8]) -> Result<Vec<Record>, RecordingError> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while !data.is_empty() {
+        if data.len() < RECORD_HEADER_SIZE {
+            return Err(RecordingError::Truncated { offset });
+        }
+        let direction =
+            Direction::from_byte(data[0]).ok_or(RecordingError::InvalidDirection { offset })?;
+        let micros = u64::from_le_bytes(data[1..9].try_into().expect("eight bytes"));
+        let len = u32::from_le_bytes(data[9..13].try_into().expect("four bytes")) as usize;
+        let Some(bytes) = data.get(RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + len) else {
+            return Err(RecordingError::Truncated { offset });
+        };
+
+        records.push(Record {
+            direction,
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            bytes: bytes.to_vec(),
+        });
+        data = &data[RECORD_HEADER_SIZE + len..];
+        offset += RECORD_HEADER_SIZE + len;
+    }
+    Ok(records)
+}
+
+// Concatenates the bytes that went one way, e.g. to parse everything the peer sent
+pub fn stream_bytes(records: &[Record], direction: Direction) -> Vec<u8> {
+    records
+        .iter()
+        .filter(|record| record.direction == direction)
+        .flat_map(|record| record.bytes.iter().copied())
+        .collect()
+}
+
+#[derive(Debug)]
+enum Entry {
+    Record(Record),
+    Finish,
+}
+
+// Hands bytes to the recording's writer task, so recording never waits on the file
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    sender: mpsc::UnboundedSender<Entry>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Recorder {
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        // Once the recording is finished, later bytes have nowhere to go
+        let _ = self.sender.send(Entry::Record(Record {
+            direction,
+            timestamp: self.clock.now(),
+            bytes: bytes.to_vec(),
+        }));
+    }
+}
+
+// Writes records in the background until finished; connections may share it through
+// `recorder`
+pub struct Recording<W> {
+    sender: mpsc::UnboundedSender<Entry>,
+    writer: JoinHandle<io::Result<W>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<W> Recording<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    pub fn new(mut writer: W) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let writer = tokio::spawn(async move {
+            while let Some(entry) = receiver.recv().await {
+                match entry {
+                    Entry::Record(record) => writer.write_all(&record.to_bytes()).await?,
+                    Entry::Finish => break,
+                }
+            }
+            writer.flush().await?;
+            Ok(writer)
+        });
+        Self {
+            sender,
+            writer,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    // Stamps the records of recorders handed out from here on
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    pub fn recorder(&self) -> Recorder {
+        Recorder {
+            sender: self.sender.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    // Writes out everything recorded so far and hands back the writer; anything recorded
+    // afterwards is dropped
+    pub async fn finish(self) -> io::Result<W> {
+        let _ = self.sender.send(Entry::Finish);
+        self.writer
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)))
+    }
+}
+
+impl Recording<tokio::io::BufWriter<tokio::fs::File>> {
+    pub async fn create(path: impl AsRef<std::path::Path>) -> io::Result<Self> {
+        let file = tokio::fs::File::create(path).await?;
+        Ok(Self::new(tokio::io::BufWriter::new(file)))
+    }
+}
+
+// A transport that records every byte read from or written to it, when given a recorder
+#[derive(Debug)]
+pub struct Recorded<T> {
+    inner: T,
+    recorder: Option<Recorder>,
+}
+
+impl<T> Recorded<T> {
+    pub fn new(inner: T, recorder: Option<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Recorded<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(recorder)) = (&polled, &self.recorder) {
+            recorder.record(Direction::Received, &buf.filled()[filled..]);
+        }
+        polled
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Recorded<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(recorder)) = (&polled, &self.recorder) {
+            recorder.record(Direction::Sent, &buf[..*written]);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecordingError {
+    // The recording ends partway through the record starting at `offset`
+    Truncated { offset: usize },
+    InvalidDirection { offset: usize },
+}
+
+impl std::fmt::Display for RecordingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { offset } => {
+                write!(
+                    f,
+                    "recording ends partway through the record at byte {offset}"
+                )
+            }
+            Self::InvalidDirection { offset } => {
+                write!(f, "record at byte {offset} has no valid direction")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecordingError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        command::command_name, handshake_config::HandshakeConfig, message::parse_message,
+        network::Network, testing::MockClock, MessagingSystem,
+    };
+
+    use super::*;
+
+    fn parse_all(mut data: &[u8]) -> Vec<String> {
+        let mut commands = Vec::new();
+        while !data.is_empty() {
+            let (message, bytes_read) = parse_message(Network::Mainnet, data).unwrap();
+            commands.push(command_name(&message.command_raw()));
+            data = &data[bytes_read..];
+        }
+        commands
+    }
+
+    #[tokio::test]
+    async fn test_recorded_handshake_replays() {
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let recording = Recording::new(Vec::new()).clock(MockClock::new(started));
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let ours = Recorded::new(ours, Some(recording.recorder()));
+        let mut ours = MessagingSystem::from_stream(ours, "192.0.2.2:8333".parse().unwrap());
+        let mut theirs = MessagingSystem::from_stream(theirs, "192.0.2.1:8333".parse().unwrap());
+
+        let config = HandshakeConfig::default();
+        let (our_outcome, their_outcome) =
+            tokio::join!(ours.handshake(&config), theirs.handshake(&config));
+        our_outcome.unwrap();
+        their_outcome.unwrap();
+        theirs.send_ping(9).await.unwrap();
+        let received = ours.receive_message().await.unwrap();
+        drop(ours);
+
+        let records = read_records(&recording.finish().await.unwrap()).unwrap();
+        assert!(records.iter().all(|record| record.timestamp == started));
+        assert_eq!(
+            parse_all(&stream_bytes(&records, Direction::Sent)),
+            ["version", "verack"],
+        );
+        assert_eq!(
+            parse_all(&stream_bytes(&records, Direction::Received)),
+            ["version", "verack", "ping"],
+        );
+        assert_eq!(
+            command_name(&received.command_raw()),
+            "ping",
+            "recording shouldn't disturb the connection",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_finish_flushes_and_ignores_later_bytes() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let recording = Recording::new(Vec::new()).clock(clock.clone());
+        let recorder = recording.recorder();
+        clock.advance(Duration::from_micros(1_500));
+        recorder.record(Direction::Sent, b"before");
+        let recorded = recording.finish().await.unwrap();
+        recorder.record(Direction::Sent, b"after");
+
+        let records = read_records(&recorded).unwrap();
+        assert_eq!(
+            records,
+            [Record {
+                direction: Direction::Sent,
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_500),
+                bytes: b"before".to_vec(),
+            }],
+        );
+    }
+
+    #[test]
+    fn test_read_records_rejects_damage() {
+        let record = Record {
+            direction: Direction::Received,
+            timestamp: UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            bytes: vec![1, 2, 3],
+        };
+        let mut data = record.to_bytes();
+        data.extend(record.to_bytes());
+        assert_eq!(read_records(&data).unwrap(), [record.clone(), record]);
+
+        assert_eq!(
+            read_records(&data[..data.len() - 1]),
+            Err(RecordingError::Truncated { offset: 16 }),
+        );
+        data[16] = b'?';
+        assert_eq!(
+            read_records(&data),
+            Err(RecordingError::InvalidDirection { offset: 16 }),
+        );
+    }
+}
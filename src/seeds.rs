@@ -0,0 +1,365 @@
+use std::{collections::HashSet, future::Future, net::SocketAddr};
+
+use rand::seq::SliceRandom;
+
+use crate::{log::event, network::Network};
+
+// Well-known DNS seeds, as listed in Bitcoin Core's chainparams
+pub fn dns_seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Mainnet => &[
+            "seed.bitcoin.sipa.be",
+            "dnsseed.bluematt.me",
+            "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+            "seed.bitcoinstats.com",
+            "seed.bitcoin.jonasschnelli.ch",
+            "seed.btc.petertodd.net",
+            "seed.bitcoin.sprovoost.nl",
+            "dnsseed.emzy.de",
+            "seed.bitcoin.wiz.biz",
+        ],
+        Network::Testnet3 => &[
+            "testnet-seed.bitcoin.jonasschnelli.ch",
+            "seed.tbtc.petertodd.net",
+            "seed.testnet.bitcoin.sprovoost.nl",
+            "testnet-seed.bluematt.me",
+        ],
+        Network::Signet => &["seed.signet.bitcoin.sprovoost.nl"],
+        Network::Regtest | Network::Custom(_) => &[],
+    }
+}
+
+// Resolves the seeds in random order until one of them yields addresses, which are returned
+// shuffled so repeated runs spread across the network
+pub async fn resolve_candidates<R, Fut>(
+    seeds: &[&str],
+    port: u16,
+    mut resolve: R,
+) -> Vec<SocketAddr>
+where
+    R: FnMut(String) -> Fut,
+    Fut: Future<Output = std::io::Result<Vec<SocketAddr>>>,
+{
+    let mut seeds = seeds.to_vec();
+    seeds.shuffle(&mut rand::thread_rng());
+
+    for seed in seeds {
+        match resolve(format!("{seed}:{port}")).await {
+            Ok(mut candidates) if !candidates.is_empty() => {
+                candidates.shuffle(&mut rand::thread_rng());
+                return candidates;
+            }
+            Ok(_) => event!(Warn, "seed {seed} returned no addresses"),
+            Err(e) => event!(Warn, "failed to resolve seed {seed}: {e}"),
+        }
+    }
+
+    Vec::new()
+}
+
+// Looks up the addresses behind a hostname, so tests can stand in for DNS
+pub trait Resolver {
+    fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = std::io::Result<Vec<SocketAddr>>> + Send;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn contains(self, socket_address: &SocketAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => socket_address.is_ipv4(),
+            Self::Ipv6 => socket_address.is_ipv6(),
+        }
+    }
+}
+
+// Resolves a single seed into shuffled candidates of the wanted family, each listed once
+pub async fn resolve_seed<R: Resolver>(
+    resolver: &R,
+    seed: &str,
+    port: u16,
+    family: AddressFamily,
+) -> Result<Vec<SocketAddr>, SeedError> {
+    let addresses = resolver
+        .resolve(seed, port)
+        .await
+        .map_err(|e| SeedError::Resolve(seed.to_owned(), e))?;
+
+    let mut seen = HashSet::new();
+    let mut candidates: Vec<_> = addresses
+        .into_iter()
+        .filter(|socket_address| family.contains(socket_address) && seen.insert(*socket_address))
+        .collect();
+    if candidates.is_empty() {
+        return Err(SeedError::NoAddresses(seed.to_owned()));
+    }
+    candidates.shuffle(&mut rand::thread_rng());
+    Ok(candidates)
+}
+
+// Runs `attempt` against each candidate in turn, stopping at the first success
+pub async fn try_candidates<A, T, E, F, Fut>(
+    candidates: Vec<A>,
+    max_attempts: usize,
+    mut attempt: F,
+) -> Result<(A, T), SeedError>
+where
+    A: Clone + std::fmt::Display,
+    F: FnMut(A) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::fmt::Display,
+{
+    if candidates.is_empty() {
+        return Err(SeedError::NoCandidates);
+    }
+
+    let mut attempts = 0;
+    for candidate in candidates.into_iter().take(max_attempts) {
+        attempts += 1;
+        match attempt(candidate.clone()).await {
+            Ok(value) => return Ok((candidate, value)),
+            Err(e) => event!(Warn, "handshake with {candidate} failed: {e}"),
+        }
+    }
+
+    Err(SeedError::AllAttemptsFailed(attempts))
+}
+
+#[derive(Debug)]
+pub enum SeedError {
+    NoCandidates,
+    AllAttemptsFailed(usize),
+    Resolve(String, std::io::Error),
+    // The seed resolved, but to nothing usable
+    NoAddresses(String),
+}
+
+impl std::fmt::Display for SeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoCandidates => write!(f, "no candidate addresses could be resolved"),
+            Self::AllAttemptsFailed(attempts) => {
+                write!(f, "all {attempts} handshake attempt(s) failed")
+            }
+            Self::Resolve(seed, e) => write!(f, "failed to resolve seed {seed}: {e}"),
+            Self::NoAddresses(seed) => write!(f, "seed {seed} returned no usable addresses"),
+        }
+    }
+}
+
+impl std::error::Error for SeedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Resolve(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error, ErrorKind};
+
+    use crate::log::{self, Level};
+
+    use super::*;
+
+    #[test]
+    fn test_dns_seeds() {
+        assert!(dns_seeds(Network::Mainnet).contains(&"seed.bitcoin.sipa.be"));
+        assert!(!dns_seeds(Network::Testnet3).is_empty());
+        assert!(!dns_seeds(Network::Signet).is_empty());
+        assert!(dns_seeds(Network::Regtest).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_candidates_skips_failing_seeds() {
+        let candidates =
+            resolve_candidates(&["bad.example", "good.example"], 8333, |host| async move {
+                match host.as_str() {
+                    "good.example:8333" => Ok(vec![
+                        "10.0.0.1:8333".parse().unwrap(),
+                        "10.0.0.2:8333".parse().unwrap(),
+                    ]),
+                    _ => Err(Error::new(ErrorKind::NotFound, "no such host")),
+                }
+            })
+            .await;
+
+        let mut candidates = candidates;
+        candidates.sort();
+        assert_eq!(
+            candidates,
+            vec![
+                "10.0.0.1:8333".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:8333".parse().unwrap(),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resolve_candidates_all_failing() {
+        let candidates = resolve_candidates(&["bad.example"], 8333, |_| async {
+            Err(Error::new(ErrorKind::NotFound, "no such host"))
+        })
+        .await;
+
+        assert!(candidates.is_empty());
+    }
+
+    // Answers from a fixed table, and fails for any host not in it
+    struct CannedResolver(Vec<(&'static str, Vec<SocketAddr>)>);
+
+    impl Resolver for CannedResolver {
+        async fn resolve(&self, host: &str, port: u16) -> std::io::Result<Vec<SocketAddr>> {
+            let (_, addresses) = self
+                .0
+                .iter()
+                .find(|(known, _)| *known == host)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such host"))?;
+            Ok(addresses
+                .iter()
+                .map(|socket_address| SocketAddr::new(socket_address.ip(), port))
+                .collect())
+        }
+    }
+
+    fn canned_resolver() -> CannedResolver {
+        CannedResolver(vec![
+            (
+                "seed.example",
+                [
+                    "203.0.113.1:0",
+                    "[2001:db8::1]:0",
+                    "203.0.113.2:0",
+                    "203.0.113.1:0",
+                ]
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect(),
+            ),
+            ("empty.example", Vec::new()),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_resolve_seed() {
+        let resolver = canned_resolver();
+
+        let mut candidates = resolve_seed(&resolver, "seed.example", 18333, AddressFamily::Any)
+            .await
+            .unwrap();
+        candidates.sort();
+        assert_eq!(
+            candidates,
+            [
+                "203.0.113.1:18333".parse::<SocketAddr>().unwrap(),
+                "203.0.113.2:18333".parse().unwrap(),
+                "[2001:db8::1]:18333".parse().unwrap(),
+            ],
+        );
+
+        let candidates = resolve_seed(&resolver, "seed.example", 8333, AddressFamily::Ipv6)
+            .await
+            .unwrap();
+        assert_eq!(candidates, ["[2001:db8::1]:8333".parse().unwrap()]);
+
+        let candidates = resolve_seed(&resolver, "seed.example", 8333, AddressFamily::Ipv4)
+            .await
+            .unwrap();
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates.iter().all(SocketAddr::is_ipv4));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_seed_failures() {
+        let resolver = canned_resolver();
+
+        let result = resolve_seed(&resolver, "missing.example", 8333, AddressFamily::Any).await;
+        assert!(matches!(
+            result,
+            Err(SeedError::Resolve(seed, e))
+                if seed == "missing.example" && e.kind() == ErrorKind::NotFound,
+        ));
+
+        let result = resolve_seed(&resolver, "empty.example", 8333, AddressFamily::Any).await;
+        assert!(matches!(result, Err(SeedError::NoAddresses(seed)) if seed == "empty.example"));
+    }
+
+    #[tokio::test]
+    async fn test_try_candidates_until_success() {
+        let candidates: Vec<SocketAddr> = (1..=4)
+            .map(|i| format!("10.0.0.{i}:8333").parse().unwrap())
+            .collect();
+
+        let mut tried = Vec::new();
+        let result = try_candidates(candidates, 10, |candidate| {
+            tried.push(candidate);
+            let succeed = tried.len() == 3;
+            async move {
+                if succeed {
+                    Ok(candidate.port())
+                } else {
+                    Err("connection refused")
+                }
+            }
+        })
+        .await;
+
+        assert!(matches!(result, Ok((candidate, 8333)) if candidate == tried[2]));
+        assert_eq!(tried.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_try_candidates_respects_max_attempts() {
+        let candidates: Vec<SocketAddr> = (1..=4)
+            .map(|i| format!("10.0.0.{i}:8333").parse().unwrap())
+            .collect();
+
+        let capture = log::capture();
+        let result = try_candidates(candidates, 2, |_| async { Err::<(), _>("refused") }).await;
+        assert!(matches!(result, Err(SeedError::AllAttemptsFailed(2))));
+        let failures: Vec<_> = capture
+            .records()
+            .into_iter()
+            .map(|record| (record.level, record.message))
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                (
+                    Level::Warn,
+                    "handshake with 10.0.0.1:8333 failed: refused".to_owned()
+                ),
+                (
+                    Level::Warn,
+                    "handshake with 10.0.0.2:8333 failed: refused".to_owned()
+                ),
+            ],
+        );
+
+        let result =
+            try_candidates(Vec::<SocketAddr>::new(), 2, |_| async { Ok::<_, &str>(()) }).await;
+        assert!(matches!(result, Err(SeedError::NoCandidates)));
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    pub const NONE: Self = Self(0);
+    pub const NODE_NETWORK: Self = Self(1 << 0);
+    pub const NODE_BLOOM: Self = Self(1 << 2);
+    pub const NODE_WITNESS: Self = Self(1 << 3);
+    pub const NODE_COMPACT_FILTERS: Self = Self(1 << 6);
+    pub const NODE_NETWORK_LIMITED: Self = Self(1 << 10);
+
+    const NAMED_FLAGS: [(Self, &'static str); 5] = [
+        (Self::NODE_NETWORK, "NETWORK"),
+        (Self::NODE_BLOOM, "BLOOM"),
+        (Self::NODE_WITNESS, "WITNESS"),
+        (Self::NODE_COMPACT_FILTERS, "COMPACT_FILTERS"),
+        (Self::NODE_NETWORK_LIMITED, "NETWORK_LIMITED"),
+    ];
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // The flags we have a name for; any others only show in the bits
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMED_FLAGS
+            .into_iter()
+            .filter(|&(flag, _)| self.contains(flag))
+            .map(|(_, name)| name)
+            .collect()
+    }
+}
+
+impl std::fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == Self::NONE {
+            return write!(f, "NONE");
+        }
+
+        let mut remaining = self.0;
+        let mut separator = "";
+        for (flag, name) in Self::NAMED_FLAGS {
+            if self.contains(flag) {
+                write!(f, "{separator}{name}")?;
+                remaining &= !flag.0;
+                separator = "|";
+            }
+        }
+
+        // Bits we don't have a name for are still worth showing
+        if remaining != 0 {
+            write!(f, "{separator}{remaining:#x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Accepts either a hex bitmask (`0x409`) or a comma-separated list of names (`NETWORK,WITNESS`),
+// where names are case-insensitive and may carry the `NODE_` prefix
+impl FromStr for ServiceFlags {
+    type Err = ServiceFlagsParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return u64::from_str_radix(hex, 16)
+                .map(Self)
+                .map_err(|_| ServiceFlagsParseError::InvalidBitmask(s.to_owned()));
+        }
+
+        let mut services = Self::NONE;
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let upper = name.to_ascii_uppercase();
+            let upper = upper.strip_prefix("NODE_").unwrap_or(&upper);
+            if upper == "NONE" {
+                continue;
+            }
+            let (flag, _) = Self::NAMED_FLAGS
+                .into_iter()
+                .find(|(_, flag_name)| *flag_name == upper)
+                .ok_or_else(|| ServiceFlagsParseError::UnknownName(name.to_owned()))?;
+            services |= flag;
+        }
+        Ok(services)
+    }
+}
+
+#[derive(Debug)]
+pub enum ServiceFlagsParseError {
+    UnknownName(String),
+    InvalidBitmask(String),
+}
+
+impl std::fmt::Display for ServiceFlagsParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownName(name) => write!(f, "unknown service name {name:?}"),
+            Self::InvalidBitmask(bitmask) => write!(f, "invalid service bitmask {bitmask:?}"),
+        }
+    }
+}
+
+impl std::error::Error for ServiceFlagsParseError {}
+
+impl BitOr for ServiceFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for ServiceFlags {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitAndAssign for ServiceFlags {
+    fn bitand_assign(&mut self, rhs: Self) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl Not for ServiceFlags {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        Self(!self.0)
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(value: ServiceFlags) -> Self {
+        value.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains() {
+        let services = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+
+        assert!(services.contains(ServiceFlags::NODE_NETWORK));
+        assert!(services.contains(ServiceFlags::NODE_WITNESS));
+        assert!(services.contains(ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS));
+        assert!(!services.contains(ServiceFlags::NODE_BLOOM));
+        assert!(services.contains(ServiceFlags::NONE));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ServiceFlags::NONE.to_string(), "NONE");
+        assert_eq!(
+            (ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS).to_string(),
+            "NETWORK|WITNESS",
+        );
+        assert_eq!(
+            ServiceFlags::from_bits(0x0409 | 0x1_0000).to_string(),
+            "NETWORK|WITNESS|NETWORK_LIMITED|0x10000",
+        );
+    }
+
+    #[test]
+    fn test_from_str_names() {
+        assert_eq!(
+            "NETWORK,WITNESS".parse::<ServiceFlags>().unwrap(),
+            ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+        );
+        assert_eq!(
+            "node_network, network_limited"
+                .parse::<ServiceFlags>()
+                .unwrap(),
+            ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_NETWORK_LIMITED,
+        );
+        assert_eq!("".parse::<ServiceFlags>().unwrap(), ServiceFlags::NONE);
+        assert_eq!("NONE".parse::<ServiceFlags>().unwrap(), ServiceFlags::NONE);
+    }
+
+    #[test]
+    fn test_from_str_bitmask() {
+        assert_eq!(
+            "0x409".parse::<ServiceFlags>().unwrap(),
+            ServiceFlags::NODE_NETWORK
+                | ServiceFlags::NODE_WITNESS
+                | ServiceFlags::NODE_NETWORK_LIMITED,
+        );
+        assert!(matches!(
+            "0xZZ".parse::<ServiceFlags>(),
+            Err(ServiceFlagsParseError::InvalidBitmask(_)),
+        ));
+    }
+
+    #[test]
+    fn test_from_str_unknown_name() {
+        let result = "NETWORK,TELEPORT".parse::<ServiceFlags>();
+        assert!(matches!(
+            &result,
+            Err(ServiceFlagsParseError::UnknownName(name)) if name == "TELEPORT",
+        ));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "unknown service name \"TELEPORT\"",
+        );
+    }
+}
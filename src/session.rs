@@ -0,0 +1,240 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::{Instant, MissedTickBehavior},
+};
+
+use crate::{
+    addr_payload::{AddrV2Host, GetAddrPayload},
+    command::command_name,
+    disconnect::DisconnectReason,
+    message::{MessageType, ParsedMessage},
+    messaging_system::{MessageReceiveError, MessageSendError, MessagingSystem},
+    services::ServiceFlags,
+};
+
+// Bitcoin Core pings its peers this often
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    Deadline,
+    // The `shutdown` future resolved first, or the connection's `cancel` token was cancelled
+    Interrupted,
+}
+
+#[derive(Debug)]
+pub struct SessionSummary {
+    pub end: SessionEnd,
+    pub elapsed: Duration,
+    // How many messages of each command arrived, keyed by command name
+    pub received: BTreeMap<String, u64>,
+    pub pings_sent: u64,
+}
+
+// Keeps an established connection open for `duration`, answering the peer's pings, pinging it
+// every `ping_interval`, and showing every received message to `on_message` along the way
+pub async fn stay_connected<T, S, F>(
+    messaging_system: &mut MessagingSystem<T>,
+    duration: Duration,
+    ping_interval: Duration,
+    shutdown: S,
+    mut on_message: F,
+) -> Result<SessionSummary, SessionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Future<Output = ()>,
+    F: FnMut(&ParsedMessage),
+{
+    let started = Instant::now();
+    let deadline = tokio::time::sleep(duration);
+    let mut pinger = tokio::time::interval_at(started + ping_interval, ping_interval);
+    pinger.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    tokio::pin!(deadline, shutdown);
+
+    let mut received = BTreeMap::new();
+    let mut pings_sent = 0;
+    let end = loop {
+        tokio::select! {
+            _ = &mut deadline => break SessionEnd::Deadline,
+            _ = &mut shutdown => break SessionEnd::Interrupted,
+            _ = pinger.tick() => {
+                let nonce = messaging_system.config().random_nonce();
+                messaging_system.send_ping(nonce).await?;
+                pings_sent += 1;
+            }
+            parsed = messaging_system.receive_parsed_message() => match parsed {
+                Ok(parsed) => {
+                    on_message(&parsed);
+                    *received
+                        .entry(command_name(&parsed.header().command_raw()))
+                        .or_insert(0) += 1;
+                    if let MessageType::Ping(ping_payload) = parsed.message() {
+                        messaging_system.send_pong(ping_payload.nonce()).await?;
+                    }
+                }
+                // Quiet stretches are expected while merely observing
+                Err(MessageReceiveError::Timeout { .. }) => {}
+                Err(MessageReceiveError::Interrupted) => break SessionEnd::Interrupted,
+                Err(e) => return Err(e.into()),
+            },
+        }
+    };
+
+    Ok(SessionSummary {
+        end,
+        elapsed: started.elapsed(),
+        received,
+        pings_sent,
+    })
+}
+
+// An address a peer told us about, from either addr or addrv2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownAddress {
+    pub host: AddrV2Host,
+    pub port: u16,
+    pub services: ServiceFlags,
+    pub last_seen: SystemTime,
+}
+
+impl KnownAddress {
+    // Only addresses on the IP networks can be connected to directly
+    pub fn socket_address(&self) -> Option<SocketAddr> {
+        self.host.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+impl std::fmt::Display for KnownAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            AddrV2Host::Ip(IpAddr::V6(ip)) | AddrV2Host::Cjdns(ip) => {
+                write!(f, "[{ip}]:{}", self.port)
+            }
+            host => write!(f, "{host}:{}", self.port),
+        }
+    }
+}
+
+// Asks the peer for the addresses it knows and gathers every addr and addrv2 that arrives
+// within `wait`, answering pings meanwhile. Peers split their answer across several messages,
+// so this only stops early if the peer hangs up or the connection is cancelled. Each address
+// appears once, in the order first heard, with the most recent time it was seen.
+pub async fn collect_addresses<T>(
+    messaging_system: &mut MessagingSystem<T>,
+    wait: Duration,
+) -> Result<Vec<KnownAddress>, SessionError>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    messaging_system.send_payload(GetAddrPayload).await?;
+
+    let deadline = tokio::time::sleep(wait);
+    tokio::pin!(deadline);
+    let mut addresses: Vec<KnownAddress> = Vec::new();
+    let mut positions: HashMap<(AddrV2Host, u16), usize> = HashMap::new();
+    let mut learn =
+        |address: KnownAddress| match positions.get(&(address.host.clone(), address.port)) {
+            Some(&position) => {
+                let known = &mut addresses[position];
+                if address.last_seen > known.last_seen {
+                    *known = address;
+                }
+            }
+            None => {
+                positions.insert((address.host.clone(), address.port), addresses.len());
+                addresses.push(address);
+            }
+        };
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            message = messaging_system.receive_message() => match message {
+                Ok(MessageType::Addr(addr_payload)) => {
+                    for entry in addr_payload.addresses() {
+                        // addr carries IPv4 addresses mapped into IPv6, unlike addrv2
+                        learn(KnownAddress {
+                            host: AddrV2Host::Ip(entry.address().ip_address().to_canonical()),
+                            port: entry.address().port(),
+                            services: entry.address().services(),
+                            last_seen: entry.last_seen(),
+                        });
+                    }
+                }
+                Ok(MessageType::AddrV2(addr_payload)) => {
+                    for entry in addr_payload.entries() {
+                        learn(KnownAddress {
+                            host: entry.host(),
+                            port: entry.port(),
+                            services: entry.services(),
+                            last_seen: entry.last_seen(),
+                        });
+                    }
+                }
+                Ok(MessageType::Ping(ping_payload)) => {
+                    messaging_system.send_pong(ping_payload.nonce()).await?;
+                }
+                Ok(_) | Err(MessageReceiveError::Timeout { .. }) => {}
+                // Whatever arrived before the peer hung up, or we were interrupted, is still worth
+                // having
+                Err(MessageReceiveError::ConnectionClosed { .. } | MessageReceiveError::Interrupted) => {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            },
+        }
+    }
+    Ok(addresses)
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Send(MessageSendError),
+    Receive(MessageReceiveError),
+}
+
+impl SessionError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Send(e) => e.exit_code(),
+            Self::Receive(e) => e.exit_code(),
+        }
+    }
+
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Send(e) => e.disconnect_reason(),
+            Self::Receive(e) => e.disconnect_reason(),
+        }
+    }
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Send(e) => e.fmt(f),
+            Self::Receive(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<MessageSendError> for SessionError {
+    fn from(value: MessageSendError) -> Self {
+        Self::Send(value)
+    }
+}
+
+impl From<MessageReceiveError> for SessionError {
+    fn from(value: MessageReceiveError) -> Self {
+        Self::Receive(value)
+    }
+}
@@ -0,0 +1,321 @@
+use std::{ops::AddAssign, time::Duration};
+
+use crate::header::Header;
+
+// Counts of what crossed a connection, kept as frames are written and read, including frames
+// that turned out to be unknown or failed to parse
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SessionStats {
+    pub received: TrafficStats,
+    pub sent: TrafficStats,
+    // Received frames with a command this crate doesn't know
+    pub unknown_commands: usize,
+    pub checksum_failures: usize,
+    // The largest payload in either direction
+    pub largest_payload: usize,
+}
+
+impl SessionStats {
+    pub(crate) fn record_received(&mut self, command: &str, payload_len: usize) {
+        self.received.record(command, payload_len);
+        self.largest_payload = self.largest_payload.max(payload_len);
+    }
+
+    pub(crate) fn record_sent(&mut self, command: &str, payload_len: usize) {
+        self.sent.record(command, payload_len);
+        self.largest_payload = self.largest_payload.max(payload_len);
+    }
+}
+
+impl AddAssign<&SessionStats> for SessionStats {
+    fn add_assign(&mut self, other: &SessionStats) {
+        self.received += &other.received;
+        self.sent += &other.sent;
+        self.unknown_commands += other.unknown_commands;
+        self.checksum_failures += other.checksum_failures;
+        self.largest_payload = self.largest_payload.max(other.largest_payload);
+    }
+}
+
+// "rx: 14 msgs / 3.2 KiB — ping×3 addr×2 inv×9; tx: 6 msgs / 410 B", with any unknown
+// commands and checksum failures after
+impl std::fmt::Display for SessionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rx: {}; tx: {}", self.received, self.sent)?;
+        if self.unknown_commands > 0 || self.checksum_failures > 0 {
+            write!(
+                f,
+                "; {} unknown, {} bad checksum(s)",
+                self.unknown_commands, self.checksum_failures
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TrafficStats {
+    pub messages: usize,
+    // Whole frames, headers included
+    pub bytes: usize,
+    // Messages and bytes by command, in the order each command was first seen
+    pub commands: Vec<(String, CommandStats)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommandStats {
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+impl AddAssign for CommandStats {
+    fn add_assign(&mut self, other: CommandStats) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+impl TrafficStats {
+    fn record(&mut self, command: &str, payload_len: usize) {
+        let counted = CommandStats {
+            messages: 1,
+            bytes: Header::HEADER_BYTE_SIZE + payload_len,
+        };
+        self.add_command(command, counted);
+    }
+
+    fn add_command(&mut self, command: &str, counted: CommandStats) {
+        self.messages += counted.messages;
+        self.bytes += counted.bytes;
+        match self.commands.iter_mut().find(|(known, _)| known == command) {
+            Some((_, stats)) => *stats += counted,
+            None => self.commands.push((command.to_owned(), counted)),
+        }
+    }
+}
+
+impl AddAssign<&TrafficStats> for TrafficStats {
+    fn add_assign(&mut self, other: &TrafficStats) {
+        for (command, counted) in &other.commands {
+            self.add_command(command, *counted);
+        }
+    }
+}
+
+impl std::fmt::Display for TrafficStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} msgs / {}", self.messages, format_bytes(self.bytes))?;
+        for (i, (command, counted)) in self.commands.iter().enumerate() {
+            let separator = if i == 0 { " — " } else { " " };
+            write!(f, "{separator}{command}×{}", counted.messages)?;
+        }
+        Ok(())
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+// Where a set of timings falls, by nearest rank, so every figure is one that was measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Percentiles {
+    pub min: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl Percentiles {
+    pub fn of(samples: &[Duration]) -> Option<Self> {
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let rank = |percent: usize| {
+            let rank = (percent * sorted.len()).div_ceil(100);
+            sorted[rank.max(1) - 1]
+        };
+        Some(Self {
+            min: *sorted.first()?,
+            median: rank(50),
+            p95: rank(95),
+            max: *sorted.last()?,
+        })
+    }
+}
+
+impl std::fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "min {:.1?}, median {:.1?}, p95 {:.1?}, max {:.1?}",
+            self.min, self.median, self.p95, self.max
+        )
+    }
+}
+
+// How repeated handshakes with one peer went, for judging its health over time
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HandshakeTally {
+    pub attempts: usize,
+    // One of each per successful handshake
+    pub connect_times: Vec<Duration>,
+    pub handshake_times: Vec<Duration>,
+    // By `HandshakeError::kind`, in the order each was first seen
+    pub failures: Vec<(&'static str, usize)>,
+}
+
+impl HandshakeTally {
+    pub fn record_success(&mut self, connect_time: Duration, handshake_time: Duration) {
+        self.attempts += 1;
+        self.connect_times.push(connect_time);
+        self.handshake_times.push(handshake_time);
+    }
+
+    pub fn record_failure(&mut self, kind: &'static str) {
+        self.attempts += 1;
+        match self.failures.iter_mut().find(|(known, _)| *known == kind) {
+            Some((_, count)) => *count += 1,
+            None => self.failures.push((kind, 1)),
+        }
+    }
+
+    pub fn successes(&self) -> usize {
+        self.handshake_times.len()
+    }
+}
+
+// "18 of 20 handshake(s) succeeded (90.0%)", then a line each for the timings and failures
+// there were
+impl std::fmt::Display for HandshakeTally {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rate = match self.attempts {
+            0 => 0.0,
+            attempts => 100.0 * self.successes() as f64 / attempts as f64,
+        };
+        write!(
+            f,
+            "{} of {} handshake(s) succeeded ({rate:.1}%)",
+            self.successes(),
+            self.attempts
+        )?;
+        if let Some(connect_times) = Percentiles::of(&self.connect_times) {
+            write!(f, "\nconnect time: {connect_times}")?;
+        }
+        if let Some(handshake_times) = Percentiles::of(&self.handshake_times) {
+            write!(f, "\nhandshake time: {handshake_times}")?;
+        }
+        for (i, (kind, count)) in self.failures.iter().enumerate() {
+            let separator = if i == 0 { "\nfailures: " } else { " " };
+            write!(f, "{separator}{kind}×{count}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(3277), "3.2 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024), "5.0 MiB");
+    }
+
+    #[test]
+    fn test_display_and_merge() {
+        let mut stats = SessionStats::default();
+        stats.record_sent("version", 102);
+        stats.record_received("ping", 8);
+        stats.record_received("addr", 31);
+        stats.record_received("ping", 8);
+        assert_eq!(
+            stats.to_string(),
+            "rx: 3 msgs / 119 B — ping×2 addr×1; tx: 1 msgs / 126 B — version×1",
+        );
+
+        let mut total = stats.clone();
+        total.unknown_commands = 1;
+        total += &stats;
+        assert_eq!(total.received.messages, 6);
+        assert_eq!(
+            total.received.commands[0],
+            (
+                "ping".to_owned(),
+                CommandStats {
+                    messages: 4,
+                    bytes: 128
+                }
+            )
+        );
+        assert_eq!(total.largest_payload, 102);
+        assert_eq!(
+            total.to_string(),
+            "rx: 6 msgs / 238 B — ping×4 addr×2; tx: 2 msgs / 252 B — version×2; 1 unknown, 0 bad checksum(s)",
+        );
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let ms = Duration::from_millis;
+        assert_eq!(Percentiles::of(&[]), None);
+        assert_eq!(
+            Percentiles::of(&[ms(7)]),
+            Some(Percentiles {
+                min: ms(7),
+                median: ms(7),
+                p95: ms(7),
+                max: ms(7),
+            }),
+        );
+
+        // 1ms to 20ms, shuffled
+        let samples: Vec<_> = (1..=20).map(|i| ms((i * 7) % 20 + 1)).collect();
+        assert_eq!(
+            Percentiles::of(&samples),
+            Some(Percentiles {
+                min: ms(1),
+                median: ms(10),
+                p95: ms(19),
+                max: ms(20),
+            }),
+        );
+        assert_eq!(
+            Percentiles::of(&[ms(4), ms(1), ms(3)]).unwrap().to_string(),
+            "min 1.0ms, median 3.0ms, p95 4.0ms, max 4.0ms",
+        );
+    }
+
+    #[test]
+    fn test_handshake_tally() {
+        let ms = Duration::from_millis;
+        let mut tally = HandshakeTally::default();
+        assert_eq!(tally.to_string(), "0 of 0 handshake(s) succeeded (0.0%)");
+
+        tally.record_success(ms(10), ms(40));
+        tally.record_failure("timeout");
+        tally.record_success(ms(30), ms(20));
+        tally.record_failure("connect");
+        tally.record_failure("timeout");
+        assert_eq!(tally.successes(), 2);
+        assert_eq!(
+            tally.to_string(),
+            "2 of 5 handshake(s) succeeded (40.0%)\n\
+             connect time: min 10.0ms, median 10.0ms, p95 30.0ms, max 30.0ms\n\
+             handshake time: min 20.0ms, median 20.0ms, p95 40.0ms, max 40.0ms\n\
+             failures: timeout×2 connect×1",
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Semaphore,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    connect::ConnectError,
+    disconnect::DisconnectReason,
+    handshake::{HandshakeError, HandshakeOutcome},
+    handshake_config::HandshakeConfig,
+    messaging_system::{MessagingSystem, DEFAULT_CLOSE_GRACE},
+    services::ServiceFlags,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSummary {
+    pub user_agent: String,
+    pub version: i32,
+    pub services: ServiceFlags,
+    pub start_height: i32,
+    // From starting to connect to having a connection
+    pub connect_time: Duration,
+    // From sending our version to receiving the peer's verack
+    pub latency: Duration,
+}
+
+impl PeerSummary {
+    pub(crate) fn from_outcome(outcome: &HandshakeOutcome, connect_time: Duration) -> Self {
+        let peer_version = &outcome.peer_version;
+        Self {
+            user_agent: String::from_utf8_lossy(peer_version.user_agent_bytes()).into_owned(),
+            version: peer_version.version(),
+            services: peer_version.services(),
+            start_height: peer_version.start_height(),
+            connect_time,
+            latency: outcome.timings.total,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SurveyResult<A = SocketAddr> {
+    pub candidate: A,
+    pub outcome: Result<PeerSummary, SurveyFailure>,
+}
+
+// Handshakes with every candidate, at most `concurrency` at a time, giving each one
+// `peer_timeout` from connecting to a finished handshake so a stuck peer can't hold up the
+// report. Successes come first, fastest first, then failures in candidate order. Once `cancel`
+// is cancelled, handshakes in flight are abandoned and their connections closed, and only the
+// candidates that were already done with are reported.
+pub async fn survey<A, T, C, Fut>(
+    candidates: Vec<A>,
+    config: &HandshakeConfig,
+    concurrency: usize,
+    peer_timeout: Duration,
+    connect: C,
+    cancel: &CancellationToken,
+) -> Vec<SurveyResult<A>>
+where
+    A: Clone,
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: Fn(A) -> Fut,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>> + Send + 'static,
+{
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let tasks: Vec<_> = candidates
+        .into_iter()
+        .map(|candidate| {
+            let connecting = connect(candidate.clone());
+            let config = config.clone();
+            let permits = permits.clone();
+            let cancel = cancel.clone();
+            let task = tokio::spawn(async move {
+                let _permit = tokio::select! {
+                    permit = permits.acquire_owned() => permit.expect("never closed"),
+                    _ = cancel.cancelled() => return Err(SurveyFailure::Interrupted),
+                };
+                let surveying = survey_one(connecting, &config, &cancel);
+                match tokio::time::timeout(peer_timeout, surveying).await {
+                    Ok(outcome) => outcome,
+                    Err(_) => Err(SurveyFailure::TimedOut(peer_timeout)),
+                }
+            });
+            (candidate, task)
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(tasks.len());
+    for (candidate, task) in tasks {
+        let outcome = task.await.unwrap_or(Err(SurveyFailure::Panicked));
+        if !matches!(outcome, Err(SurveyFailure::Interrupted)) {
+            results.push(SurveyResult { candidate, outcome });
+        }
+    }
+    results.sort_by_key(|result| match &result.outcome {
+        Ok(summary) => (false, summary.latency),
+        Err(_) => (true, Duration::ZERO),
+    });
+    results
+}
+
+async fn survey_one<T, Fut>(
+    connecting: Fut,
+    config: &HandshakeConfig,
+    cancel: &CancellationToken,
+) -> Result<PeerSummary, SurveyFailure>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>>,
+{
+    let started = Instant::now();
+    let mut messaging_system = connect_until_cancelled(connecting, cancel).await?;
+    let connect_time = started.elapsed();
+    let result = messaging_system.handshake(config).await;
+    let _ = messaging_system.close(DEFAULT_CLOSE_GRACE).await;
+
+    Ok(PeerSummary::from_outcome(&result?, connect_time))
+}
+
+// Abandons connecting once `cancel` is, and hands the connection the token so that the
+// handshake after it stops too
+pub(crate) async fn connect_until_cancelled<T, Fut>(
+    connecting: Fut,
+    cancel: &CancellationToken,
+) -> Result<MessagingSystem<T>, SurveyFailure>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    Fut: Future<Output = Result<MessagingSystem<T>, ConnectError>>,
+{
+    let mut messaging_system = tokio::select! {
+        connected = connecting => connected.map_err(HandshakeError::Connect)?,
+        _ = cancel.cancelled() => return Err(SurveyFailure::Interrupted),
+    };
+    messaging_system.cancel = cancel.clone();
+    Ok(messaging_system)
+}
+
+#[derive(Debug)]
+pub enum SurveyFailure {
+    Handshake(HandshakeError),
+    TimedOut(Duration),
+    Panicked,
+    // Given up on by request, so neither a success nor the peer's failure
+    Interrupted,
+}
+
+impl SurveyFailure {
+    pub fn disconnect_reason(&self) -> DisconnectReason {
+        match self {
+            Self::Handshake(e) => e.disconnect_reason(),
+            Self::TimedOut(_) => DisconnectReason::HandshakeTimeout,
+            Self::Panicked => DisconnectReason::Aborted,
+            Self::Interrupted => DisconnectReason::Cancelled,
+        }
+    }
+}
+
+impl std::fmt::Display for SurveyFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Handshake(e) => e.fmt(f),
+            Self::TimedOut(timeout) => write!(f, "no handshake within {timeout:?}"),
+            Self::Panicked => write!(f, "handshake task panicked"),
+            Self::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl std::error::Error for SurveyFailure {}
+
+impl From<HandshakeError> for SurveyFailure {
+    fn from(value: HandshakeError) -> Self {
+        if value.is_interrupted() {
+            Self::Interrupted
+        } else {
+            Self::Handshake(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::{io::DuplexStream, time::Instant};
+
+    use crate::version_payload::VersionPayload;
+
+    use super::*;
+
+    const PEER_TIMEOUT: Duration = Duration::from_secs(30);
+
+    fn candidate(i: u8) -> SocketAddr {
+        SocketAddr::from(([192, 0, 2, i], 8333))
+    }
+
+    // Answers like a node whose user agent names its address, after `delay`
+    fn responsive_peer(
+        candidate: SocketAddr,
+        delay: Duration,
+    ) -> Result<MessagingSystem<DuplexStream>, ConnectError> {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let mut theirs = MessagingSystem::from_stream(theirs, candidate);
+            let config = HandshakeConfig::default()
+                .user_agent(&format!("/peer:{}/", candidate.ip()))
+                .start_height(800_000);
+            let _ = theirs.handshake(&config).await;
+        });
+        Ok(MessagingSystem::from_stream(ours, candidate))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_survey_mixed_outcomes() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let started = Instant::now();
+        let results = survey(
+            (1..=6).map(candidate).collect(),
+            &HandshakeConfig::default(),
+            2,
+            PEER_TIMEOUT,
+            |candidate| {
+                let in_flight = in_flight.clone();
+                let most_in_flight = most_in_flight.clone();
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now, Ordering::SeqCst);
+                    let result = match candidate.ip().to_string().as_str() {
+                        "192.0.2.1" => responsive_peer(candidate, Duration::from_secs(2)),
+                        "192.0.2.2" => {
+                            Err(std::io::Error::from(ErrorKind::ConnectionRefused).into())
+                        }
+                        "192.0.2.3" => std::future::pending().await,
+                        "192.0.2.4" => responsive_peer(candidate, Duration::from_secs(1)),
+                        "192.0.2.5" => Err(ConnectError::TimedOut(Duration::from_secs(10))),
+                        // Connects, but is gone before our version can be sent
+                        _ => Ok(MessagingSystem::from_stream(
+                            tokio::io::duplex(1024).0,
+                            candidate,
+                        )),
+                    };
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    result
+                }
+            },
+            &CancellationToken::new(),
+        )
+        .await;
+
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+        // The stuck peer is given up on without holding up the others
+        assert!(started.elapsed() < PEER_TIMEOUT + Duration::from_secs(2));
+
+        let candidates: Vec<_> = results.iter().map(|result| result.candidate).collect();
+        assert_eq!(
+            candidates,
+            [4, 1, 2, 3, 5, 6].map(candidate),
+            "{results:#?}",
+        );
+
+        let Ok(summary) = &results[0].outcome else {
+            panic!("expected a successful handshake");
+        };
+        assert_eq!(summary.user_agent, "/peer:192.0.2.4/");
+        assert_eq!(summary.start_height, 800_000);
+        assert_eq!(
+            summary.version,
+            VersionPayload::builder().build().unwrap().version()
+        );
+        assert!(results[1].outcome.is_ok());
+
+        assert!(matches!(
+            &results[2].outcome,
+            Err(SurveyFailure::Handshake(HandshakeError::Connect(ConnectError::Io(e))))
+                if e.kind() == ErrorKind::ConnectionRefused,
+        ));
+        assert!(matches!(
+            &results[3].outcome,
+            Err(SurveyFailure::TimedOut(timeout)) if *timeout == PEER_TIMEOUT,
+        ));
+        assert!(matches!(
+            &results[4].outcome,
+            Err(SurveyFailure::Handshake(HandshakeError::Connect(
+                ConnectError::TimedOut(_)
+            ))),
+        ));
+        assert!(matches!(
+            &results[5].outcome,
+            Err(SurveyFailure::Handshake(HandshakeError::Send(_))),
+        ));
+    }
+}
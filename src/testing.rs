@@ -0,0 +1,177 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use binrw::{meta::WriteEndian, BinWrite};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream, ReadHalf},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::{
+    clock::Clock,
+    command::{command_name, Command},
+    decoder::MessageDecoder,
+    message::{prepare_message, MessageType},
+    message_preparable::MessagePreparable,
+    network::Network,
+};
+
+#[derive(Debug)]
+enum Action {
+    Send(Vec<u8>),
+    WaitFor(Command),
+    Delay(Duration),
+    Close,
+}
+
+// Plays the remote end of a connection from a script, recording every message sent to it
+#[derive(Debug)]
+pub struct MockPeer {
+    network: Network,
+    script: Vec<Action>,
+}
+
+impl MockPeer {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            script: Vec::new(),
+        }
+    }
+
+    // Written verbatim, for bytes no well-behaved peer would send
+    pub fn send_raw(mut self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.script.push(Action::Send(bytes.into()));
+        self
+    }
+
+    pub fn send<P>(self, payload: P) -> Self
+    where
+        P: MessagePreparable,
+        P: BinWrite + WriteEndian,
+        for<'a> <P as BinWrite>::Args<'a>: Default,
+    {
+        let frame =
+            prepare_message(self.network, payload).expect("mock peer payloads should serialize");
+        self.send_raw(frame)
+    }
+
+    // Holds the script until a message with this command arrives; anything before it is recorded
+    // too. Panics if the other end hangs up first.
+    pub fn wait_for(mut self, command: Command) -> Self {
+        self.script.push(Action::WaitFor(command));
+        self
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.script.push(Action::Delay(delay));
+        self
+    }
+
+    // Stops writing, though messages are still recorded until the other end hangs up
+    pub fn close(mut self) -> Self {
+        self.script.push(Action::Close);
+        self
+    }
+
+    // Runs the script against one end of an in-memory pipe and returns the other end. The task
+    // finishes with everything the peer received once that end is dropped.
+    pub fn spawn(self) -> (DuplexStream, JoinHandle<Vec<MessageType>>) {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        let (reader, mut writer) = tokio::io::split(theirs);
+        let (sender, mut incoming) = mpsc::unbounded_channel();
+        tokio::spawn(read_messages(reader, self.network, sender));
+
+        let task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            for action in self.script {
+                match action {
+                    Action::Send(bytes) => {
+                        if writer.write_all(&bytes).await.is_err() {
+                            // The other end is gone, so nothing later in the script can matter
+                            break;
+                        }
+                    }
+                    Action::WaitFor(command) => loop {
+                        let Some(message) = incoming.recv().await else {
+                            panic!("connection closed while the mock peer waited for {command}");
+                        };
+                        let found = command_name(&message.command_raw()) == command.as_str();
+                        received.push(message);
+                        if found {
+                            break;
+                        }
+                    },
+                    Action::Delay(delay) => tokio::time::sleep(delay).await,
+                    Action::Close => {
+                        let _ = writer.shutdown().await;
+                    }
+                }
+            }
+
+            while let Some(message) = incoming.recv().await {
+                received.push(message);
+            }
+            received
+        });
+        (ours, task)
+    }
+}
+
+// Stops at the end of the stream or at the first frame that doesn't parse
+async fn read_messages(
+    mut reader: ReadHalf<DuplexStream>,
+    network: Network,
+    sender: mpsc::UnboundedSender<MessageType>,
+) {
+    let mut decoder = MessageDecoder::new(network, None);
+    loop {
+        loop {
+            match decoder.next_message() {
+                Ok(Some(message)) => {
+                    if sender.send(message.into_message()).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(_) => return,
+            }
+        }
+        match decoder.read_from(&mut reader).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+    }
+}
+
+// A clock that only moves when told to. Clones share the same time, so a test can keep one to
+// move the clock it handed over
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().expect("mock clock lock poisoned") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("mock clock lock poisoned") += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().expect("mock clock lock poisoned")
+    }
+}
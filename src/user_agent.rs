@@ -0,0 +1,196 @@
+// Bitcoin Core refuses user agents (subversions) longer than this
+pub const MAX_SUBVERSION_LENGTH: usize = 256;
+
+// Builds a BIP 14 user agent identifying this crate, e.g. `/bitcoin-handshake:0.1.0/`
+pub fn default_agent() -> String {
+    let agent = format!("/{}:{}/", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    debug_assert!(validate_bip14(&agent).is_ok());
+    agent
+}
+
+// Adds a comment to the last component, so `/a:1/` becomes `/a:1(probe)/` and `/a:1(x)/`
+// becomes `/a:1(x; probe)/`
+pub fn append_comment(agent: &str, comment: &str) -> Result<String, UserAgentError> {
+    validate_bip14(agent)?;
+    if let Some(c) = comment.chars().find(|&c| !is_comment_char(c) || c == ';') {
+        return Err(UserAgentError::IllegalCharacter(c));
+    }
+
+    let agent = match agent.strip_suffix(")/") {
+        Some(prefix) => format!("{prefix}; {comment})/"),
+        None => format!("{}({comment})/", &agent[..agent.len() - 1]),
+    };
+    validate_bip14(&agent)?;
+    Ok(agent)
+}
+
+// The minimal checks every user agent must pass before it goes on the wire
+pub fn validate_user_agent(user_agent: &str) -> Result<(), UserAgentError> {
+    if user_agent.len() > MAX_SUBVERSION_LENGTH {
+        return Err(UserAgentError::TooLong(user_agent.len()));
+    }
+    if user_agent.contains('\0') {
+        return Err(UserAgentError::ContainsNul);
+    }
+    Ok(())
+}
+
+// Checks the `/Name:Version(comments)/Name:Version/` structure described by BIP 14
+pub fn validate_bip14(user_agent: &str) -> Result<(), UserAgentError> {
+    validate_user_agent(user_agent)?;
+
+    let components = user_agent
+        .strip_prefix('/')
+        .and_then(|rest| rest.strip_suffix('/'))
+        .filter(|components| !components.is_empty())
+        .ok_or(UserAgentError::Malformed)?;
+
+    for component in components.split('/') {
+        let (name, rest) = component.split_once(':').ok_or(UserAgentError::Malformed)?;
+        let (version, comments) = match rest.split_once('(') {
+            Some((version, comments)) => (
+                version,
+                Some(
+                    comments
+                        .strip_suffix(')')
+                        .ok_or(UserAgentError::Malformed)?,
+                ),
+            ),
+            None => (rest, None),
+        };
+
+        if name.is_empty() || version.is_empty() {
+            return Err(UserAgentError::Malformed);
+        }
+        if let Some(c) = name
+            .chars()
+            .chain(version.chars())
+            .find(|&c| !is_name_char(c))
+        {
+            return Err(UserAgentError::IllegalCharacter(c));
+        }
+        if let Some(c) = comments
+            .into_iter()
+            .flat_map(str::chars)
+            .find(|&c| !is_comment_char(c))
+        {
+            return Err(UserAgentError::IllegalCharacter(c));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_graphic() && !matches!(c, '/' | ':' | '(' | ')')
+}
+
+fn is_comment_char(c: char) -> bool {
+    (c.is_ascii_graphic() || c == ' ') && !matches!(c, '/' | '(' | ')')
+}
+
+#[derive(Debug)]
+pub enum UserAgentError {
+    TooLong(usize),
+    ContainsNul,
+    IllegalCharacter(char),
+    Malformed,
+}
+
+impl std::fmt::Display for UserAgentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::TooLong(length) => write!(
+                f,
+                "user agent is {length} bytes but may be at most {MAX_SUBVERSION_LENGTH} bytes",
+            ),
+            Self::ContainsNul => write!(f, "user agent must not contain NUL characters"),
+            Self::IllegalCharacter(c) => write!(f, "user agent contains illegal character {c:?}"),
+            Self::Malformed => write!(f, "user agent is not of the form /Name:Version/"),
+        }
+    }
+}
+
+impl std::error::Error for UserAgentError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_agent() {
+        assert_eq!(
+            default_agent(),
+            format!("/bitcoin-handshake:{}/", env!("CARGO_PKG_VERSION")),
+        );
+        assert!(validate_bip14(&default_agent()).is_ok());
+    }
+
+    #[test]
+    fn test_append_comment() {
+        assert_eq!(
+            append_comment("/bitcoin-handshake:0.1.0/", "probe").unwrap(),
+            "/bitcoin-handshake:0.1.0(probe)/",
+        );
+        assert_eq!(
+            append_comment("/bitcoin-handshake:0.1.0(probe)/", "linux").unwrap(),
+            "/bitcoin-handshake:0.1.0(probe; linux)/",
+        );
+        assert!(matches!(
+            append_comment("/bitcoin-handshake:0.1.0/", "a)b"),
+            Err(UserAgentError::IllegalCharacter(')')),
+        ));
+    }
+
+    #[test]
+    fn test_validate_user_agent() {
+        assert!(validate_user_agent("").is_ok());
+        assert!(validate_user_agent("/Satoshi:0.7.2/").is_ok());
+        assert!(validate_user_agent(&"a".repeat(MAX_SUBVERSION_LENGTH)).is_ok());
+        assert!(matches!(
+            validate_user_agent(&"a".repeat(MAX_SUBVERSION_LENGTH + 1)),
+            Err(UserAgentError::TooLong(257)),
+        ));
+        assert!(matches!(
+            validate_user_agent("/Satoshi\0:0.7.2/"),
+            Err(UserAgentError::ContainsNul),
+        ));
+    }
+
+    #[test]
+    fn test_validate_bip14() {
+        assert!(validate_bip14("/Satoshi:0.7.2/").is_ok());
+        assert!(validate_bip14("/BitcoinJ:0.2(iPad; U; CPU OS 3_2_1)/AndroidBuild:0.8/").is_ok());
+
+        assert!(matches!(validate_bip14(""), Err(UserAgentError::Malformed)));
+        assert!(matches!(
+            validate_bip14("Satoshi:0.7.2"),
+            Err(UserAgentError::Malformed),
+        ));
+        assert!(matches!(
+            validate_bip14("/Satoshi/"),
+            Err(UserAgentError::Malformed),
+        ));
+        assert!(matches!(
+            validate_bip14("/Satoshi:0.7.2(unterminated/"),
+            Err(UserAgentError::Malformed),
+        ));
+        assert!(matches!(
+            validate_bip14("/Sato shi:0.7.2/"),
+            Err(UserAgentError::IllegalCharacter(' ')),
+        ));
+        assert!(matches!(
+            validate_bip14("/Satoshi:0.7.2\u{e9}/"),
+            Err(UserAgentError::IllegalCharacter('\u{e9}')),
+        ));
+    }
+
+    #[test]
+    fn test_validate_bip14_too_long() {
+        let agent = format!("/Satoshi:{}/", "1".repeat(MAX_SUBVERSION_LENGTH));
+        assert!(matches!(
+            validate_bip14(&agent),
+            Err(UserAgentError::TooLong(_)),
+        ));
+    }
+}
@@ -11,3 +11,208 @@ pub fn double_sha256_hash(data: &[u8]) -> [u8; 32] {
 
     hash.into()
 }
+
+// Double SHA-256 over data that arrives in pieces, finishing with the second pass only once
+#[derive(Debug, Clone, Default)]
+pub struct DoubleSha256 {
+    hasher: Sha256,
+}
+
+impl DoubleSha256 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        let hash = self.hasher.finalize();
+
+        let mut hasher = Sha256::new();
+        hasher.update(hash);
+        let hash = hasher.finalize();
+
+        hash.into()
+    }
+}
+
+// RFC 4648 base32 in lowercase and without padding, as Tor and I2P write their addresses
+pub const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity((data.len() * 8).div_ceil(5));
+    let mut bits = 0u16;
+    let mut bit_count = 0;
+    for &byte in data {
+        bits = (bits << 8) | u16::from(byte);
+        bit_count += 8;
+        while bit_count >= 5 {
+            bit_count -= 5;
+            encoded.push(BASE32_ALPHABET[usize::from((bits >> bit_count) & 0x1F)] as char);
+        }
+    }
+    // Zero-fill the last group
+    if bit_count > 0 {
+        encoded.push(BASE32_ALPHABET[usize::from((bits << (5 - bit_count)) & 0x1F)] as char);
+    }
+    encoded
+}
+
+// SHA3-256 (FIPS 202), which Tor uses for onion address checksums
+pub fn sha3_256(data: &[u8]) -> [u8; 32] {
+    const RATE: usize = 136;
+
+    let mut state = [0u64; 25];
+    let mut absorb = |block: &[u8; RATE]| {
+        for (lane, bytes) in state.iter_mut().zip(block.chunks_exact(8)) {
+            *lane ^= u64::from_le_bytes(bytes.try_into().expect("chunks of eight"));
+        }
+        keccak_f1600(&mut state);
+    };
+
+    let mut blocks = data.chunks_exact(RATE);
+    for block in blocks.by_ref() {
+        absorb(block.try_into().expect("chunks of the rate"));
+    }
+    let remainder = blocks.remainder();
+    let mut last = [0u8; RATE];
+    last[..remainder.len()].copy_from_slice(remainder);
+    last[remainder.len()] ^= 0x06;
+    last[RATE - 1] ^= 0x80;
+    absorb(&last);
+
+    let mut hash = [0u8; 32];
+    for (bytes, lane) in hash.chunks_exact_mut(8).zip(state) {
+        bytes.copy_from_slice(&lane.to_le_bytes());
+    }
+    hash
+}
+
+fn keccak_f1600(state: &mut [u64; 25]) {
+    const ROUND_CONSTANTS: [u64; 24] = [
+        0x0000_0000_0000_0001,
+        0x0000_0000_0000_8082,
+        0x8000_0000_0000_808A,
+        0x8000_0000_8000_8000,
+        0x0000_0000_0000_808B,
+        0x0000_0000_8000_0001,
+        0x8000_0000_8000_8081,
+        0x8000_0000_0000_8009,
+        0x0000_0000_0000_008A,
+        0x0000_0000_0000_0088,
+        0x0000_0000_8000_8009,
+        0x0000_0000_8000_000A,
+        0x0000_0000_8000_808B,
+        0x8000_0000_0000_008B,
+        0x8000_0000_0000_8089,
+        0x8000_0000_0000_8003,
+        0x8000_0000_0000_8002,
+        0x8000_0000_0000_0080,
+        0x0000_0000_0000_800A,
+        0x8000_0000_8000_000A,
+        0x8000_0000_8000_8081,
+        0x8000_0000_0000_8080,
+        0x0000_0000_8000_0001,
+        0x8000_0000_8000_8008,
+    ];
+    // Rotation for, and destination of, each lane visited by the combined rho and pi steps
+    const ROTATIONS: [u32; 24] = [
+        1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+    ];
+    const LANES: [usize; 24] = [
+        10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+    ];
+
+    for round_constant in ROUND_CONSTANTS {
+        // Theta
+        let mut columns = [0u64; 5];
+        for (x, column) in columns.iter_mut().enumerate() {
+            *column = (0..25)
+                .step_by(5)
+                .fold(0, |parity, y| parity ^ state[y + x]);
+        }
+        for x in 0..5 {
+            let d = columns[(x + 4) % 5] ^ columns[(x + 1) % 5].rotate_left(1);
+            for y in (0..25).step_by(5) {
+                state[y + x] ^= d;
+            }
+        }
+
+        // Rho and pi
+        let mut carried = state[1];
+        for (&lane, rotation) in LANES.iter().zip(ROTATIONS) {
+            let next = state[lane];
+            state[lane] = carried.rotate_left(rotation);
+            carried = next;
+        }
+
+        // Chi
+        for y in (0..25).step_by(5) {
+            let row: [u64; 5] = state[y..y + 5].try_into().expect("rows of five");
+            for x in 0..5 {
+                state[y + x] = row[x] ^ (!row[(x + 1) % 5] & row[(x + 2) % 5]);
+            }
+        }
+
+        // Iota
+        state[0] ^= round_constant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_incremental_matches_one_shot() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        for chunk_size in [1, 7, 4096, data.len()] {
+            let mut hasher = DoubleSha256::new();
+            for chunk in data.chunks(chunk_size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), double_sha256_hash(&data));
+        }
+    }
+
+    #[test]
+    fn test_incremental_empty() {
+        assert_eq!(DoubleSha256::new().finalize(), double_sha256_hash(&[]));
+        assert_eq!(
+            hex::encode(&DoubleSha256::new().finalize()[..4]),
+            "5df6e0e2",
+        );
+    }
+
+    #[test]
+    fn test_base32_encode() {
+        // RFC 4648's vectors, lowercased and unpadded
+        assert_eq!(base32_encode(b""), "");
+        assert_eq!(base32_encode(b"f"), "my");
+        assert_eq!(base32_encode(b"fo"), "mzxq");
+        assert_eq!(base32_encode(b"foo"), "mzxw6");
+        assert_eq!(base32_encode(b"foob"), "mzxw6yq");
+        assert_eq!(base32_encode(b"fooba"), "mzxw6ytb");
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+    }
+
+    #[test]
+    fn test_sha3_256() {
+        assert_eq!(
+            hex::encode(sha3_256(b"")),
+            "a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a",
+        );
+        assert_eq!(
+            hex::encode(sha3_256(b"abc")),
+            "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532",
+        );
+        // Long enough to take more than one block
+        assert_eq!(
+            hex::encode(sha3_256(&[0xA3; 200])),
+            "79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787",
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    io::{Read, Seek, Write},
+    str::Utf8Error,
+};
+
+use binrw::{
+    meta::{EndianKind, ReadEndian, WriteEndian},
+    BinRead, BinResult, BinWrite, Endian,
+};
+
+use crate::protocol::MAX_SIZE;
+
+// Bitcoin's variable-length integer, used for every length and count prefix on the wire
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CompactSize(pub u64);
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompactSizeArgs {
+    // Bitcoin Core refuses e.g. 0xFD followed by a value that would have fit in one byte
+    pub require_minimal: bool,
+}
+
+impl CompactSize {
+    pub fn encoded_len(self) -> usize {
+        match self.0 {
+            0..=0xFC => 1,
+            0xFD..=0xFFFF => 3,
+            0x1_0000..=0xFFFF_FFFF => 5,
+            _ => 9,
+        }
+    }
+}
+
+impl From<u64> for CompactSize {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<CompactSize> for u64 {
+    fn from(value: CompactSize) -> Self {
+        value.0
+    }
+}
+
+impl BinRead for CompactSize {
+    type Args<'a> = CompactSizeArgs;
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        _endian: Endian,
+        args: Self::Args<'_>,
+    ) -> BinResult<Self> {
+        // The encoding is always little endian, whatever the surrounding struct uses
+        let endian = Endian::Little;
+        let pos = reader.stream_position()?;
+        let (value, minimum) = match u8::read_options(reader, endian, ())? {
+            value @ 0..=0xFC => (value as u64, 0),
+            0xFD => (u16::read_options(reader, endian, ())? as u64, 0xFD),
+            0xFE => (u32::read_options(reader, endian, ())? as u64, 0x1_0000),
+            0xFF => (u64::read_options(reader, endian, ())?, 0x1_0000_0000),
+        };
+
+        if args.require_minimal && value < minimum {
+            return Err(binrw::Error::AssertFail {
+                pos,
+                message: format!("non-minimal encoding of compact size {value}"),
+            });
+        }
+
+        Ok(Self(value))
+    }
+}
+
+impl ReadEndian for CompactSize {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Little);
+}
+
+impl BinWrite for CompactSize {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        _endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<()> {
+        let endian = Endian::Little;
+        match self.0 {
+            value @ 0..=0xFC => (value as u8).write_options(writer, endian, ()),
+            value @ 0xFD..=0xFFFF => {
+                0xFDu8.write_options(writer, endian, ())?;
+                (value as u16).write_options(writer, endian, ())
+            }
+            value @ 0x1_0000..=0xFFFF_FFFF => {
+                0xFEu8.write_options(writer, endian, ())?;
+                (value as u32).write_options(writer, endian, ())
+            }
+            value => {
+                0xFFu8.write_options(writer, endian, ())?;
+                value.write_options(writer, endian, ())
+            }
+        }
+    }
+}
+
+impl WriteEndian for CompactSize {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Little);
+}
+
+// A CompactSize length followed by that many bytes, which is how strings go over the wire
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct VarString(Vec<u8>);
+
+#[derive(Debug, Clone, Copy)]
+pub struct VarStringArgs {
+    // Longer strings are refused before anything is allocated for them
+    pub max_length: usize,
+}
+
+impl Default for VarStringArgs {
+    fn default() -> Self {
+        Self {
+            max_length: MAX_SIZE as usize,
+        }
+    }
+}
+
+impl VarString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn to_str(&self) -> Result<&str, Utf8Error> {
+        std::str::from_utf8(&self.0)
+    }
+}
+
+impl From<&str> for VarString {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for VarString {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for VarString {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl BinRead for VarString {
+    type Args<'a> = VarStringArgs;
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: Endian,
+        args: Self::Args<'_>,
+    ) -> BinResult<Self> {
+        let pos = reader.stream_position()?;
+        let CompactSize(len) = CompactSize::read_options(reader, endian, Default::default())?;
+
+        if len > args.max_length as u64 {
+            return Err(binrw::Error::AssertFail {
+                pos,
+                message: format!(
+                    "string length {len} exceeds the maximum of {}",
+                    args.max_length,
+                ),
+            });
+        }
+
+        // The length is untrusted, so only grow as bytes actually arrive
+        let mut s = Vec::new();
+        reader.take(len).read_to_end(&mut s)?;
+        if (s.len() as u64) < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(Self(s))
+    }
+}
+
+impl ReadEndian for VarString {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Little);
+}
+
+impl BinWrite for VarString {
+    type Args<'a> = ();
+
+    fn write_options<W: Write + Seek>(
+        &self,
+        writer: &mut W,
+        endian: Endian,
+        _args: Self::Args<'_>,
+    ) -> BinResult<()> {
+        CompactSize(self.0.len() as u64).write_options(writer, endian, ())?;
+        writer.write_all(&self.0)?;
+        Ok(())
+    }
+}
+
+impl WriteEndian for VarString {
+    const ENDIAN: EndianKind = EndianKind::Endian(Endian::Little);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::message::MessageParseError;
+
+    use super::*;
+
+    #[test]
+    fn test_compact_size_boundaries() {
+        for (value, encoded) in [
+            (0, "00"),
+            (0xFC, "FC"),
+            (0xFD, "FDFD00"),
+            (0xFFFF, "FDFFFF"),
+            (0x1_0000, "FE00000100"),
+            (0xFFFF_FFFF, "FEFFFFFFFF"),
+            (0x1_0000_0000, "FF0000000001000000"),
+            (u64::MAX, "FFFFFFFFFFFFFFFFFF"),
+        ] {
+            let encoded = hex::decode(encoded).unwrap();
+            let compact_size = CompactSize(value);
+            assert_eq!(compact_size.encoded_len(), encoded.len());
+
+            let mut written = Cursor::new(Vec::new());
+            compact_size.write(&mut written).unwrap();
+            assert_eq!(written.into_inner(), encoded);
+
+            let mut cursor = Cursor::new(&encoded);
+            let args = CompactSizeArgs {
+                require_minimal: true,
+            };
+            assert_eq!(
+                CompactSize::read_args(&mut cursor, args).unwrap(),
+                compact_size
+            );
+            assert_eq!(cursor.position() as usize, encoded.len());
+        }
+    }
+
+    #[test]
+    fn test_compact_size_non_minimal() {
+        for encoded in ["FDFC00", "FEFFFF0000", "FFFFFFFFFF00000000"] {
+            let encoded = hex::decode(encoded).unwrap();
+
+            // Tolerated unless minimal encodings are required
+            assert!(CompactSize::read(&mut Cursor::new(&encoded)).is_ok());
+
+            let args = CompactSizeArgs {
+                require_minimal: true,
+            };
+            let error = CompactSize::read_args(&mut Cursor::new(&encoded), args).unwrap_err();
+            assert!(matches!(
+                MessageParseError::from(error),
+                MessageParseError::MalformedData(_),
+            ));
+        }
+    }
+
+    #[test]
+    fn test_compact_size_truncated() {
+        let encoded = hex::decode("FE0100").unwrap();
+        assert!(CompactSize::read(&mut Cursor::new(&encoded)).is_err());
+    }
+
+    #[test]
+    fn test_var_string_round_trip() {
+        let encoded = hex::decode("0F2F5361746F7368693A302E372E322F").unwrap();
+
+        let var_string = VarString::read(&mut Cursor::new(&encoded)).unwrap();
+        assert_eq!(var_string, VarString::from("/Satoshi:0.7.2/"));
+        assert_eq!(var_string.as_bytes(), b"/Satoshi:0.7.2/");
+        assert_eq!(var_string.to_str().unwrap(), "/Satoshi:0.7.2/");
+
+        let mut written = Cursor::new(Vec::new());
+        var_string.write(&mut written).unwrap();
+        assert_eq!(written.into_inner(), encoded);
+
+        let mut written = Cursor::new(Vec::new());
+        VarString::default().write(&mut written).unwrap();
+        assert_eq!(written.into_inner(), [0]);
+    }
+
+    #[test]
+    fn test_var_string_invalid_utf8() {
+        let var_string = VarString::from(b"/\xFF\xFE/".to_vec());
+        assert!(var_string.to_str().is_err());
+    }
+
+    #[test]
+    fn test_var_string_max_length() {
+        let encoded = hex::decode("036162630102").unwrap();
+
+        let args = VarStringArgs { max_length: 3 };
+        let mut cursor = Cursor::new(&encoded);
+        let var_string = VarString::read_args(&mut cursor, args).unwrap();
+        assert_eq!(var_string.as_bytes(), b"abc");
+        assert_eq!(cursor.position(), 4);
+
+        let args = VarStringArgs { max_length: 2 };
+        let error = VarString::read_args(&mut Cursor::new(&encoded), args).unwrap_err();
+        assert!(matches!(
+            MessageParseError::from(error),
+            MessageParseError::MalformedData(_),
+        ));
+    }
+
+    #[test]
+    fn test_var_string_truncated() {
+        let encoded = hex::decode("05616263").unwrap();
+        assert!(VarString::read(&mut Cursor::new(&encoded)).is_err());
+    }
+
+    #[test]
+    fn test_var_string_length_beyond_payload() {
+        let mut encoded = hex::decode("FEFFFFFFFF").unwrap();
+        encoded.extend([0x61; 10]);
+
+        let args = VarStringArgs {
+            max_length: usize::MAX,
+        };
+        let started = std::time::Instant::now();
+        let error = VarString::read_args(&mut Cursor::new(&encoded), args).unwrap_err();
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+        assert!(matches!(error, binrw::Error::Io(_)));
+    }
+}
@@ -1,18 +1,31 @@
 use std::{
     io::SeekFrom,
-    net::{IpAddr, Ipv4Addr},
-    time::{SystemTime, UNIX_EPOCH},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::Utf8Error,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use binrw::{binrw, BinRead, BinResult, BinWrite};
 
-use crate::{command::Command, message_preparable::MessagePreparable};
+use crate::{
+    command::Command,
+    message_preparable::MessagePreparable,
+    protocol::{ADDR_FROM_VERSION, PROTOCOL_VERSION, RELAY_VERSION},
+    services::ServiceFlags,
+    user_agent::{default_agent, validate_user_agent, UserAgentError, MAX_SUBVERSION_LENGTH},
+    varint::{CompactSize, VarString, VarStringArgs},
+};
+
+// Roughly Bitcoin Core's MAX_FUTURE_BLOCK_TIME, past which a peer's clock is suspicious
+pub const DEFAULT_MAX_CLOCK_SKEW: Duration = Duration::from_secs(70 * 60);
 
 #[derive(Debug)]
 #[binrw]
 #[brw(little)]
-struct NetworkAddress {
-    services: u64,
+pub struct NetworkAddress {
+    #[br(map = ServiceFlags::from_bits)]
+    #[bw(map = |services: &ServiceFlags| services.bits())]
+    services: ServiceFlags,
     #[brw(big)]
     #[br(parse_with = read_ip_addr)]
     #[bw(write_with = write_ip_addr)]
@@ -21,6 +34,44 @@ struct NetworkAddress {
     port: u16,
 }
 
+impl NetworkAddress {
+    pub const BYTE_SIZE: usize = 26;
+
+    pub fn new(socket_address: SocketAddr, services: ServiceFlags) -> Self {
+        Self {
+            services,
+            ip_address: socket_address.ip(),
+            port: socket_address.port(),
+        }
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn ip_address(&self) -> IpAddr {
+        self.ip_address
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn socket_address(&self) -> SocketAddr {
+        SocketAddr::new(self.ip_address.to_canonical(), self.port)
+    }
+}
+
+impl Default for NetworkAddress {
+    fn default() -> Self {
+        Self {
+            services: ServiceFlags::NONE,
+            ip_address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            port: 0,
+        }
+    }
+}
+
 #[binrw::parser(reader, endian)]
 fn read_ip_addr() -> BinResult<IpAddr> {
     Ok(IpAddr::from(<[u8; 16]>::read_options(reader, endian, ())?))
@@ -40,64 +91,31 @@ fn write_ip_addr(ip_address: &IpAddr) -> BinResult<()> {
 #[brw(little)]
 pub struct VersionPayload {
     version: i32,
-    services: u64,
+    #[br(map = ServiceFlags::from_bits)]
+    #[bw(map = |services: &ServiceFlags| services.bits())]
+    services: ServiceFlags,
     pub timestamp: i64,
     addr_recv: NetworkAddress,
+    // Ancient peers stop after addr_recv, so everything up to the relay flag is versioned too
+    #[br(if(version >= ADDR_FROM_VERSION))]
+    #[bw(if(*version >= ADDR_FROM_VERSION))]
     addr_from: NetworkAddress,
+    #[br(if(version >= ADDR_FROM_VERSION))]
+    #[bw(if(*version >= ADDR_FROM_VERSION))]
     nonce: u64,
-    #[br(parse_with = read_string)]
-    #[bw(write_with = write_string)]
-    user_agent: Vec<u8>,
+    #[br(if(version >= ADDR_FROM_VERSION))]
+    #[br(args_raw = VarStringArgs { max_length: MAX_SUBVERSION_LENGTH })]
+    #[bw(if(*version >= ADDR_FROM_VERSION))]
+    user_agent: VarString,
+    #[br(if(version >= ADDR_FROM_VERSION))]
+    #[bw(if(*version >= ADDR_FROM_VERSION))]
     last_block: i32,
-    #[br(parse_with = read_optional_bool)]
-    #[bw(write_with = write_optional_bool)]
+    // BIP 37 only defines the relay byte from RELAY_VERSION onward
+    #[br(if(version >= RELAY_VERSION), parse_with = read_optional_bool)]
+    #[bw(if(*version >= RELAY_VERSION), write_with = write_optional_bool)]
     relay: Option<bool>,
 }
 
-#[binrw::parser(reader, endian)]
-fn read_string() -> BinResult<Vec<u8>> {
-    let b = u8::read_options(reader, endian, ())?;
-    let len = match b {
-        len @ 0..=0xFC => len as u64,
-        0xFD => u16::read_options(reader, endian, ())? as u64,
-        0xFE => u32::read_options(reader, endian, ())? as u64,
-        0xFF => u64::read_options(reader, endian, ())?,
-    };
-
-    let mut s = Vec::with_capacity(len as usize);
-
-    for _ in 0..len {
-        // How to read an array of data?
-        s.push(u8::read_options(reader, endian, ())?);
-    }
-
-    Ok(s)
-}
-
-#[binrw::writer(writer, endian)]
-fn write_string(s: &Vec<u8>) -> BinResult<()> {
-    let len = s.len() as u64;
-    match len {
-        0..=0xFC => {
-            (len as u8).write_options(writer, endian, ())?;
-        }
-        0xFD..=0xFFFF => {
-            0xFDu8.write_options(writer, endian, ())?;
-            (len as u16).write_options(writer, endian, ())?;
-        }
-        0x1_0000..=0xFFFF_FFFF => {
-            0xFEu8.write_options(writer, endian, ())?;
-            (len as u32).write_options(writer, endian, ())?;
-        }
-        0x1_0000_0000..=0xFFFF_FFFF_FFFF_FFFF => {
-            0xFFu8.write_options(writer, endian, ())?;
-            len.write_options(writer, endian, ())?;
-        }
-    };
-
-    s.write_options(writer, endian, ())
-}
-
 #[binrw::parser(reader, endian)]
 fn read_optional_bool() -> BinResult<Option<bool>> {
     let b = match u8::read_options(reader, endian, ()) {
@@ -131,37 +149,229 @@ fn write_optional_bool(s: &Option<bool>) -> BinResult<()> {
 }
 
 impl VersionPayload {
-    pub fn create(timestamp: SystemTime, remote_ip_address: IpAddr, remote_port: u16) -> Self {
+    pub fn builder() -> VersionPayloadBuilder {
+        VersionPayloadBuilder::new()
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn services(&self) -> ServiceFlags {
+        self.services
+    }
+
+    pub fn addr_recv(&self) -> &NetworkAddress {
+        &self.addr_recv
+    }
+
+    pub fn addr_from(&self) -> &NetworkAddress {
+        &self.addr_from
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn user_agent(&self) -> Result<&str, Utf8Error> {
+        self.user_agent.to_str()
+    }
+
+    pub fn user_agent_bytes(&self) -> &[u8] {
+        self.user_agent.as_bytes()
+    }
+
+    // Seconds the peer's clock is ahead of `now` (negative when behind), or `None` when either
+    // side has no usable time to compare
+    pub fn clock_skew(&self, now: SystemTime) -> Option<i64> {
+        if self.timestamp == 0 {
+            return None;
+        }
+
+        let now = i64::try_from(now.duration_since(UNIX_EPOCH).ok()?.as_secs()).ok()?;
+        Some(self.timestamp.saturating_sub(now))
+    }
+
+    pub fn start_height(&self) -> i32 {
+        self.last_block
+    }
+
+    pub fn relay(&self) -> Option<bool> {
+        self.relay
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VersionPayloadBuilder {
+    version: i32,
+    services: ServiceFlags,
+    timestamp: Option<SystemTime>,
+    addr_recv: SocketAddr,
+    addr_from: SocketAddr,
+    nonce: u64,
+    user_agent: String,
+    start_height: i32,
+    relay: Option<bool>,
+}
+
+impl VersionPayloadBuilder {
+    pub fn new() -> Self {
         Self {
-            version: 70014,
-            services: 0,
-            timestamp: timestamp.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+            version: PROTOCOL_VERSION,
+            services: ServiceFlags::NONE,
+            timestamp: None,
+            addr_recv: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+            addr_from: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8333),
+            nonce: 0,
+            user_agent: default_agent(),
+            start_height: 0,
+            relay: None,
+        }
+    }
+
+    pub fn version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    // Defaults to the time of the `build()` call
+    pub fn timestamp(mut self, timestamp: SystemTime) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn addr_recv(mut self, addr_recv: SocketAddr) -> Self {
+        self.addr_recv = addr_recv;
+        self
+    }
+
+    pub fn addr_from(mut self, addr_from: SocketAddr) -> Self {
+        self.addr_from = addr_from;
+        self
+    }
+
+    pub fn nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_owned();
+        self
+    }
+
+    pub fn start_height(mut self, start_height: i32) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    pub fn relay(mut self, relay: Option<bool>) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    pub fn build(self) -> Result<VersionPayload, VersionPayloadBuildError> {
+        validate_user_agent(&self.user_agent)?;
+
+        if self.relay.is_some() && self.version < RELAY_VERSION {
+            return Err(VersionPayloadBuildError::RelayUnsupported(self.version));
+        }
+
+        let timestamp = self.timestamp.unwrap_or_else(SystemTime::now);
+        let timestamp = timestamp
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|duration| i64::try_from(duration.as_secs()).ok())
+            .ok_or(VersionPayloadBuildError::TimestampOutOfRange)?;
+
+        Ok(VersionPayload {
+            version: self.version,
+            services: self.services,
+            timestamp,
             addr_recv: NetworkAddress {
-                services: 0,
-                ip_address: remote_ip_address,
-                port: remote_port,
+                services: ServiceFlags::NONE,
+                ip_address: self.addr_recv.ip(),
+                port: self.addr_recv.port(),
             },
             addr_from: NetworkAddress {
-                services: 0,
-                ip_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
-                port: 8333,
+                services: ServiceFlags::NONE,
+                ip_address: self.addr_from.ip(),
+                port: self.addr_from.port(),
             },
-            nonce: 0,
-            user_agent: Vec::new(),
-            last_block: 0,
-            relay: None,
+            nonce: self.nonce,
+            user_agent: self.user_agent.into(),
+            last_block: self.start_height,
+            relay: self.relay,
+        })
+    }
+}
+
+impl Default for VersionPayloadBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum VersionPayloadBuildError {
+    UserAgent(UserAgentError),
+    TimestampOutOfRange,
+    RelayUnsupported(i32),
+}
+
+impl std::fmt::Display for VersionPayloadBuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UserAgent(e) => e.fmt(f),
+            Self::TimestampOutOfRange => write!(f, "timestamp is out of range"),
+            Self::RelayUnsupported(version) => write!(
+                f,
+                "relay flag requires protocol version {RELAY_VERSION} but version is {version}",
+            ),
         }
     }
 }
 
+impl std::error::Error for VersionPayloadBuildError {}
+
+impl From<UserAgentError> for VersionPayloadBuildError {
+    fn from(value: UserAgentError) -> Self {
+        Self::UserAgent(value)
+    }
+}
+
 impl MessagePreparable for VersionPayload {
     const COMMAND_TYPE: Command = Command::Version;
+
+    fn size_hint(&self) -> usize {
+        // version, services, timestamp and addr_recv
+        let mut size = 4 + 8 + 8 + NetworkAddress::BYTE_SIZE;
+        if self.version >= ADDR_FROM_VERSION {
+            let user_agent = self.user_agent.as_bytes().len();
+            size += NetworkAddress::BYTE_SIZE
+                + 8
+                + CompactSize(user_agent as u64).encoded_len()
+                + user_agent
+                + 4;
+        }
+        if self.version >= RELAY_VERSION && self.relay.is_some() {
+            size += 1;
+        }
+        size
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use crate::message::MessageParseError;
+
     use super::*;
 
     #[test]
@@ -196,10 +406,317 @@ mod tests {
         let version_payload = VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap();
 
         assert_eq!(
-            &String::from_utf8(version_payload.user_agent.clone()).unwrap(),
+            version_payload.user_agent.to_str().unwrap(),
             "/Satoshi:0.7.2/",
         );
-        assert_eq!(version_payload.last_block, 212672);
+        assert_eq!(version_payload.start_height(), 212672);
+        assert_eq!(version_payload.services, ServiceFlags::NODE_NETWORK);
+        assert_eq!(
+            version_payload.addr_recv.services,
+            ServiceFlags::NODE_NETWORK
+        );
+        assert_eq!(
+            version_payload.addr_from.services,
+            ServiceFlags::NODE_NETWORK
+        );
+        assert_eq!(version_payload.services.to_string(), "NETWORK");
+
+        let mut encoded = Cursor::new(Vec::new());
+        version_payload.write(&mut encoded).unwrap();
+
+        assert_eq!(encoded.into_inner(), raw_binary);
+    }
+
+    #[test]
+    fn test_satoshi_0_7_2_fixture_accessors() {
+        let raw_binary = hex::decode("62EA0000010000000000000011B2D05000000000010000000000000000000000000000000000FFFF000000000000010000000000000000000000000000000000FFFF0000000000003B2EB35D8CE617650F2F5361746F7368693A302E372E322FC03E0300").unwrap();
+
+        let version_payload = VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap();
+
+        assert_eq!(version_payload.version(), 60002);
+        assert_eq!(version_payload.services(), ServiceFlags::NODE_NETWORK);
+        assert_eq!(version_payload.timestamp, 0x50D0B211);
+        for address in [version_payload.addr_recv(), version_payload.addr_from()] {
+            assert_eq!(address.services(), ServiceFlags::NODE_NETWORK);
+            assert_eq!(
+                address.ip_address(),
+                IpAddr::V6(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped())
+            );
+            assert_eq!(address.port(), 0);
+        }
+        assert_eq!(version_payload.nonce(), 0x6517E68C5DB32E3B);
+        assert_eq!(version_payload.user_agent_bytes(), b"/Satoshi:0.7.2/");
+        assert_eq!(version_payload.start_height(), 212672);
+        // Older than BIP 37, so there is no relay byte to read
+        assert_eq!(version_payload.relay(), None);
+    }
+
+    #[test]
+    fn test_create_old_protocol_version_matches_fixture_layout() {
+        let raw_binary = hex::decode("62EA0000010000000000000011B2D05000000000010000000000000000000000000000000000FFFF000000000000010000000000000000000000000000000000FFFF0000000000003B2EB35D8CE617650F2F5361746F7368693A302E372E322FC03E0300").unwrap();
+
+        let version_payload = VersionPayload::builder()
+            .version(60002)
+            .timestamp(UNIX_EPOCH + Duration::from_secs(0x50D0B211))
+            .addr_recv(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0))
+            .user_agent("/Satoshi:0.7.2/")
+            .nonce(0x6517E68C5DB32E3B)
+            .start_height(212672)
+            .build()
+            .unwrap();
+
+        let mut encoded = Cursor::new(Vec::new());
+        version_payload.write(&mut encoded).unwrap();
+        let encoded = encoded.into_inner();
+
+        // Only the services fields and addr_from differ from the fixture
+        assert_eq!(encoded.len(), raw_binary.len());
+        assert_eq!(encoded[0..4], raw_binary[0..4]);
+        assert_eq!(encoded[12..20], raw_binary[12..20]);
+        assert_eq!(encoded[28..46], raw_binary[28..46]);
+        assert_eq!(encoded[72..], raw_binary[72..]);
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_user_agent() {
+        let result = VersionPayload::builder().user_agent("a\0b").build();
+        assert!(matches!(
+            result,
+            Err(VersionPayloadBuildError::UserAgent(
+                UserAgentError::ContainsNul
+            )),
+        ));
+    }
+
+    #[test]
+    fn test_builder_rejects_timestamp_before_epoch() {
+        let result = VersionPayload::builder()
+            .timestamp(UNIX_EPOCH - Duration::from_secs(1))
+            .build();
+        assert!(matches!(
+            result,
+            Err(VersionPayloadBuildError::TimestampOutOfRange),
+        ));
+    }
+
+    // The Satoshi 0.7.2 fixture with its user agent swapped for the given var_str bytes
+    fn version_payload_with_user_agent(user_agent: &[u8]) -> Vec<u8> {
+        let mut raw_binary = hex::decode("62EA0000010000000000000011B2D05000000000010000000000000000000000000000000000FFFF000000000000010000000000000000000000000000000000FFFF0000000000003B2EB35D8CE61765").unwrap();
+        raw_binary.extend(user_agent);
+        raw_binary.extend(hex::decode("C03E0300").unwrap());
+        raw_binary
+    }
+
+    #[test]
+    fn test_user_agent_invalid_utf8() {
+        let raw_binary = version_payload_with_user_agent(b"\x04/\xFF\xFE/");
+
+        let version_payload = VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap();
+
+        assert_eq!(version_payload.user_agent_bytes(), b"/\xFF\xFE/");
+        assert!(version_payload.user_agent().is_err());
+    }
+
+    #[test]
+    fn test_user_agent_too_long() {
+        let mut user_agent = vec![0xFD, 0x01, 0x01];
+        user_agent.extend([b'a'; 257]);
+        let raw_binary = version_payload_with_user_agent(&user_agent);
+
+        let result = VersionPayload::read(&mut Cursor::new(&raw_binary));
+        let error = result.map_err(MessageParseError::from).unwrap_err();
+        assert!(matches!(error, MessageParseError::MalformedData(_)));
+        assert_eq!(error.to_string(), "malformed data at payload offset 80");
+    }
+
+    #[test]
+    fn test_user_agent_huge_declared_length_fails_fast() {
+        // Declares a 10 MB user agent but supplies none of it
+        let raw_binary = version_payload_with_user_agent(&[0xFE, 0x80, 0x96, 0x98, 0x00]);
+
+        let result = VersionPayload::read(&mut Cursor::new(&raw_binary));
+        assert!(matches!(
+            result.map_err(MessageParseError::from),
+            Err(MessageParseError::MalformedData(_)),
+        ));
+    }
+
+    #[test]
+    fn test_truncated_payload_keeps_error_detail() {
+        // Cut off partway through addr_recv
+        let raw_binary =
+            hex::decode("7E1101000000000000000000C515CF6100000000000000000000").unwrap();
+
+        let error = MessageParseError::from(
+            VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap_err(),
+        );
+        let source = std::error::Error::source(&error).unwrap().to_string();
+        assert!(source.contains("addr_recv"));
+    }
+
+    #[test]
+    fn test_addr_from_local_address() {
+        let cases = [
+            (
+                "192.168.1.20:50123",
+                "000000000000000000000000000000000000FFFFC0A80114C3CB",
+            ),
+            (
+                "[2001:db8::1]:50123",
+                "000000000000000020010DB8000000000000000000000001C3CB",
+            ),
+        ];
+
+        for (local_address, expected_addr_from) in cases {
+            let version_payload = VersionPayload::builder()
+                .timestamp(UNIX_EPOCH)
+                .addr_from(local_address.parse().unwrap())
+                .build()
+                .unwrap();
+
+            let mut encoded = Cursor::new(Vec::new());
+            version_payload.write(&mut encoded).unwrap();
+            let encoded = encoded.into_inner();
+
+            assert_eq!(encoded[46..72], hex::decode(expected_addr_from).unwrap());
+            assert_eq!(
+                version_payload.addr_from().socket_address(),
+                local_address.parse().unwrap(),
+            );
+        }
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let mut version_payload = VersionPayload::builder()
+            .timestamp(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+            .build()
+            .unwrap();
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(version_payload.clock_skew(now), Some(0));
+        assert_eq!(
+            version_payload.clock_skew(now - Duration::from_secs(90)),
+            Some(90),
+        );
+        assert_eq!(
+            version_payload.clock_skew(now + DEFAULT_MAX_CLOCK_SKEW),
+            Some(-4200),
+        );
+        assert_eq!(
+            version_payload.clock_skew(UNIX_EPOCH - Duration::from_secs(1)),
+            None,
+        );
+
+        // A zero timestamp means the peer didn't tell us its time at all
+        version_payload.timestamp = 0;
+        assert_eq!(version_payload.clock_skew(now), None);
+    }
+
+    #[test]
+    fn test_relay_round_trip_by_version() {
+        for (version, relay, expected_length) in [
+            (60002, None, 85 + default_agent().len()),
+            (70001, Some(true), 86 + default_agent().len()),
+            (70016, Some(false), 86 + default_agent().len()),
+        ] {
+            let version_payload = VersionPayload::builder()
+                .version(version)
+                .timestamp(UNIX_EPOCH)
+                .relay(relay)
+                .build()
+                .unwrap();
+
+            let mut encoded = Cursor::new(Vec::new());
+            version_payload.write(&mut encoded).unwrap();
+            let encoded = encoded.into_inner();
+            assert_eq!(encoded.len(), expected_length);
+
+            let decoded = VersionPayload::read(&mut Cursor::new(&encoded)).unwrap();
+            assert_eq!(decoded.version(), version);
+            assert_eq!(decoded.relay(), relay);
+        }
+    }
+
+    #[test]
+    fn test_relay_rejected_before_relay_version() {
+        let result = VersionPayload::builder()
+            .version(60002)
+            .relay(Some(true))
+            .build();
+        assert!(matches!(
+            result,
+            Err(VersionPayloadBuildError::RelayUnsupported(60002)),
+        ));
+
+        // Even when set directly, the byte is never written for old versions
+        let mut version_payload = VersionPayload::builder()
+            .version(60002)
+            .timestamp(UNIX_EPOCH)
+            .build()
+            .unwrap();
+        version_payload.relay = Some(true);
+
+        let mut encoded = Cursor::new(Vec::new());
+        version_payload.write(&mut encoded).unwrap();
+        assert_eq!(encoded.into_inner().len(), 85 + default_agent().len());
+    }
+
+    #[test]
+    fn test_trailing_byte_not_relay_before_relay_version() {
+        let mut raw_binary = hex::decode("62EA0000010000000000000011B2D05000000000010000000000000000000000000000000000FFFF000000000000010000000000000000000000000000000000FFFF0000000000003B2EB35D8CE617650F2F5361746F7368693A302E372E322FC03E0300").unwrap();
+        raw_binary.push(0x01);
+
+        let mut cursor = Cursor::new(&raw_binary);
+        let version_payload = VersionPayload::read(&mut cursor).unwrap();
+
+        assert_eq!(version_payload.relay(), None);
+        assert_eq!(cursor.position() as usize, raw_binary.len() - 1);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_pre_106_version_payload() {
+        // Only version, services, timestamp, and addr_recv are present before version 106
+        let raw_binary = hex::decode("6400000001000000000000004B11D04C00000000010000000000000000000000000000000000FFFF0A000001208D").unwrap();
+
+        let version_payload = VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap();
+
+        assert_eq!(version_payload.version(), 100);
+        assert_eq!(version_payload.services(), ServiceFlags::NODE_NETWORK);
+        assert_eq!(
+            version_payload.addr_recv().socket_address(),
+            "10.0.0.1:8333".parse().unwrap(),
+        );
+        assert_eq!(
+            version_payload.addr_from().socket_address(),
+            "[::]:0".parse().unwrap(),
+        );
+        assert_eq!(version_payload.nonce(), 0);
+        assert_eq!(version_payload.user_agent_bytes(), b"");
+        assert_eq!(version_payload.start_height(), 0);
+        assert_eq!(version_payload.relay(), None);
+
+        let mut encoded = Cursor::new(Vec::new());
+        version_payload.write(&mut encoded).unwrap();
+
+        assert_eq!(encoded.into_inner(), raw_binary);
+    }
+
+    #[test]
+    fn test_serialize_deserialize_version_0_3_payload() {
+        let raw_binary = hex::decode("387C000001000000000000004B11D04C00000000010000000000000000000000000000000000FFFF0A000001208D010000000000000000000000000000000000FFFF0A000002208DEFBEADDE0DF0ADBA00A0860100").unwrap();
+
+        let version_payload = VersionPayload::read(&mut Cursor::new(&raw_binary)).unwrap();
+
+        assert_eq!(version_payload.version(), 31800);
+        assert_eq!(
+            version_payload.addr_from().socket_address(),
+            "10.0.0.2:8333".parse().unwrap(),
+        );
+        assert_eq!(version_payload.nonce(), 0xBAADF00DDEADBEEF);
+        assert_eq!(version_payload.user_agent_bytes(), b"");
+        assert_eq!(version_payload.start_height(), 100000);
+        assert_eq!(version_payload.relay(), None);
 
         let mut encoded = Cursor::new(Vec::new());
         version_payload.write(&mut encoded).unwrap();
@@ -0,0 +1,12 @@
+use binrw::binrw;
+
+use crate::{command::Command, message_preparable::MessagePreparable};
+
+#[derive(Debug)]
+#[binrw]
+#[brw(little)]
+pub struct WtxidRelayPayload;
+
+impl MessagePreparable for WtxidRelayPayload {
+    const COMMAND_TYPE: Command = Command::WtxidRelay;
+}
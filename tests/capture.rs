@@ -0,0 +1,86 @@
+use bitcoin_handshake::{
+    capture::{decode_capture, summarize, CaptureEntry, CapturedFrame},
+    command::command_name,
+    network::Network,
+    MessageParseError, MessageType,
+};
+
+// A version, verack, unknown sendheaders, and ping as a Core 25.0 node might send them, then a
+// pong whose last payload byte was flipped, five stray bytes, and a final ping
+const CAPTURE: &[u8] = include_bytes!("fixtures/capture.bin");
+
+fn frame(entry: &CaptureEntry) -> &CapturedFrame {
+    match entry {
+        CaptureEntry::Frame(frame) => frame,
+        entry => panic!("expected a frame, got {entry:?}"),
+    }
+}
+
+#[test]
+fn test_decode_capture_fixture() {
+    let entries = decode_capture(Network::Mainnet, CAPTURE);
+    assert_eq!(entries.len(), 7, "{entries:#?}");
+
+    let frames: Vec<_> = [0, 1, 2, 3, 4, 6]
+        .into_iter()
+        .map(|i| frame(&entries[i]))
+        .collect();
+    let listing: Vec<_> = frames
+        .iter()
+        .map(|frame| {
+            (
+                frame.offset,
+                command_name(&frame.header.command_raw()),
+                frame.header.payload_size(),
+                frame.checksum_valid,
+            )
+        })
+        .collect();
+    assert_eq!(
+        listing,
+        [
+            (0, "version".to_owned(), 102, true),
+            (126, "verack".to_owned(), 0, true),
+            (150, "sendheaders".to_owned(), 0, true),
+            (174, "ping".to_owned(), 8, true),
+            (206, "pong".to_owned(), 8, false),
+            (243, "ping".to_owned(), 8, true),
+        ],
+    );
+
+    let Ok(version) = &frames[0].message else {
+        panic!("expected the version to decode");
+    };
+    assert_eq!(
+        summarize(version).unwrap(),
+        "version 70016, services NETWORK|WITNESS|NETWORK_LIMITED, \
+         user agent \"/Satoshi:25.0.0/\", start height 801474, nonce 0x6f3c95a10e27d8b4",
+    );
+    assert!(matches!(frames[1].message, Ok(MessageType::Verack)));
+    assert!(matches!(
+        &frames[2].message,
+        Ok(message @ MessageType::Unknown { .. })
+            if summarize(message).unwrap() == "unknown command \"sendheaders\"",
+    ));
+    assert!(matches!(
+        &frames[3].message,
+        Ok(MessageType::Ping(ping)) if ping.nonce() == 0x1d2c_3b4a_5968_7786,
+    ));
+    assert!(matches!(
+        frames[4].message,
+        Err(MessageParseError::IncorrectChecksum { .. }),
+    ));
+    assert!(matches!(
+        &frames[5].message,
+        Ok(MessageType::Ping(ping)) if ping.nonce() == 7,
+    ));
+
+    assert!(matches!(
+        entries[5],
+        CaptureEntry::LostSync {
+            offset: 238,
+            skipped: 5,
+            reason: MessageParseError::MissingMagicNumber,
+        },
+    ));
+}
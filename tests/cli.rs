@@ -0,0 +1,188 @@
+use std::{
+    io::Read,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Output, Stdio},
+    time::Duration,
+};
+
+use bitcoin_handshake::network::Network;
+
+// Variables for one run of the binary, applied to that child alone and never to this process,
+// which starts from none of ours and a config directory of its own
+struct ScopedEnv {
+    vars: Vec<(String, String)>,
+    config_home: PathBuf,
+}
+
+impl ScopedEnv {
+    fn new(name: &str) -> Self {
+        let config_home =
+            std::env::temp_dir().join(format!("bitcoin-handshake-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&config_home);
+        std::fs::create_dir_all(config_home.join("bitcoin-handshake")).unwrap();
+        Self {
+            vars: Vec::new(),
+            config_home,
+        }
+    }
+
+    fn var(mut self, name: &str, value: &str) -> Self {
+        self.vars.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    fn config_file(self, contents: &str) -> Self {
+        std::fs::write(
+            self.config_home
+                .join("bitcoin-handshake")
+                .join("config.toml"),
+            contents,
+        )
+        .unwrap();
+        self
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_bitcoin-handshake"));
+        for (name, _) in std::env::vars() {
+            if name.starts_with("BITCOIN_HANDSHAKE_") {
+                command.env_remove(name);
+            }
+        }
+        command
+            .env("XDG_CONFIG_HOME", &self.config_home)
+            .envs(self.vars.iter().map(|(name, value)| (name, value)))
+            .args(args)
+            .stdin(Stdio::null());
+        command
+    }
+
+    fn output(&self, args: &[&str]) -> Output {
+        self.command(args).output().unwrap()
+    }
+
+    // The network magic the binary opens its version message with, when pointed at a listener
+    fn magic_sent(&self, args: &[&str]) -> [u8; 4] {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut child = KillOnDrop(
+            self.command(&[&["--address", &address], args].concat())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut magic = [0; 4];
+        stream.read_exact(&mut magic).unwrap();
+        let _ = child.0.kill();
+        magic
+    }
+}
+
+impl Drop for ScopedEnv {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.config_home);
+    }
+}
+
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_flag_wins_over_env_var() {
+    let env = ScopedEnv::new("flag-over-env").var("BITCOIN_HANDSHAKE_NETWORK", "regtest");
+    assert_eq!(env.magic_sent(&[]), Network::Regtest.magic());
+    assert_eq!(
+        env.magic_sent(&["--network", "signet"]),
+        Network::Signet.magic()
+    );
+}
+
+#[test]
+fn test_env_var_wins_over_config_file() {
+    let env = ScopedEnv::new("env-over-file").config_file("network = \"testnet3\"\n");
+    assert_eq!(env.magic_sent(&[]), Network::Testnet3.magic());
+
+    let env = env.var("BITCOIN_HANDSHAKE_NETWORK", "regtest");
+    assert_eq!(env.magic_sent(&[]), Network::Regtest.magic());
+}
+
+#[test]
+fn test_env_var_peers() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let env = ScopedEnv::new("env-peers").var("BITCOIN_HANDSHAKE_ADDRESS", &address);
+    let _child = KillOnDrop(
+        env.command(&[])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap(),
+    );
+    listener.accept().unwrap();
+}
+
+#[test]
+fn test_env_var_parse_errors_match_flags() {
+    let env = ScopedEnv::new("parse-errors");
+    let long_agent = format!("/{}/", "x".repeat(300));
+    for (name, flag, value) in [
+        ("BITCOIN_HANDSHAKE_NETWORK", "--network", "foo"),
+        (
+            "BITCOIN_HANDSHAKE_CONNECT_TIMEOUT",
+            "--connect-timeout",
+            "0s",
+        ),
+        ("BITCOIN_HANDSHAKE_USER_AGENT", "--user-agent", &long_agent),
+    ] {
+        let from_flag = env.output(&["--address", "127.0.0.1", flag, value]);
+        let from_env = ScopedEnv::new("parse-errors-env")
+            .var(name, value)
+            .output(&["--address", "127.0.0.1"]);
+        assert_eq!(from_flag.status.code(), Some(1));
+        assert_eq!(from_env.status.code(), Some(1));
+        let stderr = String::from_utf8(from_env.stderr).unwrap();
+        assert!(stderr.starts_with("error: invalid value"), "{stderr}");
+        assert_eq!(stderr, String::from_utf8(from_flag.stderr).unwrap());
+    }
+}
+
+#[test]
+fn test_failed_csv_write_keeps_recording() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let peer = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        std::io::Write::write_all(&mut stream, b"not a version message").unwrap();
+    });
+    let env = ScopedEnv::new("csv-write-fails");
+    let record = env.config_home.join("capture.bin");
+    let output = env.output(&[
+        "--address",
+        &address,
+        "--all",
+        "--output",
+        "csv",
+        "--output-file",
+        &env.config_home
+            .join("missing")
+            .join("peers.csv")
+            .to_string_lossy(),
+        "--record",
+        &record.to_string_lossy(),
+    ]);
+    peer.join().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(std::fs::metadata(&record).unwrap().len() > 0);
+}
@@ -0,0 +1,1032 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncWriteExt, DuplexStream},
+    net::{TcpListener, TcpStream},
+};
+use tokio_util::codec::Framed;
+
+use bitcoin_handshake::{
+    codec::{BitcoinCodec, OutgoingMessage},
+    command::command_name,
+    connect::ConnectError,
+    disconnect::{DisconnectReason, Rejection, Violation},
+    double_sha256_hash, exit_code,
+    handshake::handshake_any,
+    log::{self, Level},
+    message_preparable::MessagePreparable,
+    network::Network,
+    parse_message, prepare_message,
+    protocol::PROTOCOL_VERSION,
+    seeds::SeedError,
+    services::ServiceFlags,
+    testing::{MockClock, MockPeer},
+    Command, HandshakeConfig, HandshakeError, HandshakeStep, HandshakeTimings, Header,
+    MessageReceiveError, MessageType, MessagingSystem, PingPayload, VerackPayload, VersionPayload,
+};
+
+#[test]
+fn test_prepare_and_parse_round_trip() {
+    let version = VersionPayload::builder().nonce(7).build().unwrap();
+    let frame = prepare_message(Network::Regtest, version).unwrap();
+
+    let header = Header::peek(&frame).unwrap().into_header();
+    assert!(matches!(header.command_type(), Ok(Command::Version)));
+
+    let (message, bytes_read) = parse_message(Network::Regtest, &frame).unwrap();
+    assert_eq!(bytes_read, frame.len());
+    assert!(matches!(message, MessageType::Version(version) if version.nonce() == 7));
+
+    let frame = prepare_message(Network::Regtest, VerackPayload).unwrap();
+    assert!(matches!(
+        parse_message(Network::Regtest, &frame),
+        Ok((MessageType::Verack, 24)),
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_over_in_memory_transport() {
+    let (ours, theirs) = tokio::io::duplex(1024);
+    let mut ours = Framed::new(ours, BitcoinCodec::new(Network::Regtest));
+    let mut theirs = Framed::new(theirs, BitcoinCodec::new(Network::Regtest));
+
+    let peer = tokio::spawn(async move {
+        let Some(Ok(MessageType::Version(_))) = theirs.next().await else {
+            panic!("expected a version message");
+        };
+        let version = VersionPayload::builder().nonce(2).build().unwrap();
+        theirs
+            .send(OutgoingMessage::Version(version))
+            .await
+            .unwrap();
+        theirs.send(OutgoingMessage::Verack).await.unwrap();
+        assert!(matches!(theirs.next().await, Some(Ok(MessageType::Verack))));
+    });
+
+    let version = VersionPayload::builder().nonce(1).build().unwrap();
+    ours.send(OutgoingMessage::Version(version)).await.unwrap();
+    let Some(Ok(MessageType::Version(version))) = ours.next().await else {
+        panic!("expected a version message");
+    };
+    assert_eq!(version.nonce(), 2);
+    assert!(matches!(ours.next().await, Some(Ok(MessageType::Verack))));
+    ours.send(OutgoingMessage::Verack).await.unwrap();
+
+    peer.await.unwrap();
+}
+
+// Answers our version with the given frames, then stops writing
+fn responder(frames: Vec<Vec<u8>>) -> MockPeer {
+    frames
+        .into_iter()
+        .fold(
+            MockPeer::new(Network::Mainnet).wait_for(Command::Version),
+            |peer, frame| peer.send_raw(frame),
+        )
+        .close()
+}
+
+fn raw_frame(command: &[u8; 12], payload: &[u8]) -> Vec<u8> {
+    let mut frame = Network::Mainnet.magic().to_vec();
+    frame.extend(command);
+    frame.extend((payload.len() as u32).to_le_bytes());
+    frame.extend(&double_sha256_hash(payload)[..4]);
+    frame.extend(payload);
+    frame
+}
+
+fn sendheaders_frame() -> Vec<u8> {
+    raw_frame(b"sendheaders\0", &[])
+}
+
+fn peer_version_frame() -> Vec<u8> {
+    let version = VersionPayload::builder().nonce(2).build().unwrap();
+    prepare_message(Network::Mainnet, version).unwrap()
+}
+
+#[tokio::test]
+async fn test_handshake_happy_path() {
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send_raw(sendheaders_frame())
+        .send(VerackPayload)
+        .wait_for(Command::Verack)
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(outcome.peer_version.nonce(), 2);
+    assert_eq!(outcome.negotiated_version, PROTOCOL_VERSION);
+    assert!(matches!(
+        outcome.other_messages.as_slice(),
+        [MessageType::Unknown { command, .. }] if command == b"sendheaders\0",
+    ));
+
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    assert!(matches!(
+        received.as_slice(),
+        [MessageType::Version(_), MessageType::Verack]
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_clock_skew_against_pinned_clock() {
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(
+            VersionPayload::builder()
+                .nonce(2)
+                .timestamp(now + Duration::from_secs(90))
+                .build()
+                .unwrap(),
+        )
+        .send(VerackPayload)
+        .wait_for(Command::Verack)
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let config = HandshakeConfig::default().clock(MockClock::new(now));
+    let outcome = messaging_system.handshake(&config).await.unwrap();
+    assert_eq!(outcome.clock_skew, Some(90));
+
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    assert!(matches!(
+        received.as_slice(),
+        [MessageType::Version(version), MessageType::Verack]
+            if version.clock_skew(now) == Some(0)
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_logs_messages_in_order() {
+    let capture = log::capture();
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send_raw(sendheaders_frame())
+        .send(VerackPayload)
+        .wait_for(Command::Verack)
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+    messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    let MessageType::Version(our_version) = &received[0] else {
+        panic!("expected our version first");
+    };
+    let sent_len = our_version.size_hint();
+    let received_len = peer_version_frame().len() - Header::HEADER_BYTE_SIZE;
+
+    let mut records: Vec<_> = capture
+        .records()
+        .into_iter()
+        .map(|record| (record.level, record.message))
+        .collect();
+    let (level, done) = records.pop().unwrap();
+    assert_eq!(level, Level::Info);
+    assert!(
+        done.starts_with("handshake with 192.0.2.2:8333 done in "),
+        "{done}"
+    );
+    assert!(
+        done.ends_with(&format!(", protocol version {PROTOCOL_VERSION}")),
+        "{done}"
+    );
+    assert_eq!(
+        records,
+        [
+            (
+                Level::Debug,
+                "handshake with 192.0.2.2:8333 started".to_owned()
+            ),
+            (
+                Level::Debug,
+                format!("sent version to 192.0.2.2:8333 ({sent_len} byte payload)"),
+            ),
+            (
+                Level::Debug,
+                format!("received version from 192.0.2.2:8333 ({received_len} byte payload)"),
+            ),
+            (
+                Level::Debug,
+                "sent verack to 192.0.2.2:8333 (0 byte payload)".to_owned(),
+            ),
+            (
+                Level::Debug,
+                "received sendheaders from 192.0.2.2:8333 (0 byte payload)".to_owned(),
+            ),
+            (
+                Level::Warn,
+                "192.0.2.2:8333 sent unknown message \"sendheaders\"".to_owned(),
+            ),
+            (
+                Level::Debug,
+                "received verack from 192.0.2.2:8333 (0 byte payload)".to_owned(),
+            ),
+        ],
+    );
+}
+
+// Synthetic, not captured from a node: the messages Bitcoin Core 25.0 sends around its verack, in
+// its order, but with made-up values such as the nonce and getheaders locator. The burst Core holds
+// back until our verack arrives comes ahead of its own verack, as some peers and replaying proxies
+// deliver it
+fn synthetic_core_25_transcript() -> Vec<Vec<u8>> {
+    let version = VersionPayload::builder()
+        .version(70016)
+        .services(
+            ServiceFlags::NODE_NETWORK
+                | ServiceFlags::NODE_WITNESS
+                | ServiceFlags::NODE_NETWORK_LIMITED,
+        )
+        .nonce(0x6f3c_95a1_0e27_d8b4)
+        .user_agent("/Satoshi:25.0.0/")
+        .start_height(801_474)
+        .relay(Some(true))
+        .build()
+        .unwrap();
+
+    let mut getheaders = 70016u32.to_le_bytes().to_vec();
+    getheaders.push(1);
+    getheaders.extend([0x6f; 32]);
+    getheaders.extend([0; 32]);
+
+    vec![
+        prepare_message(Network::Mainnet, version).unwrap(),
+        raw_frame(b"wtxidrelay\0\0", &[]),
+        raw_frame(b"sendaddrv2\0\0", &[]),
+        raw_frame(b"sendheaders\0", &[]),
+        // Low-bandwidth compact block relay, version 2
+        raw_frame(b"sendcmpct\0\0\0", &[0, 2, 0, 0, 0, 0, 0, 0, 0]),
+        raw_frame(
+            b"ping\0\0\0\0\0\0\0\0",
+            &0x1d2c_3b4a_5968_7786u64.to_le_bytes(),
+        ),
+        raw_frame(b"getheaders\0\0", &getheaders),
+        // 1000 sat/kvB
+        raw_frame(b"feefilter\0\0\0", &1000u64.to_le_bytes()),
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+    ]
+}
+
+#[tokio::test]
+async fn test_handshake_with_interleaved_messages() {
+    let (stream, peer) = responder(synthetic_core_25_transcript()).spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(
+        outcome.peer_version.user_agent().unwrap(),
+        "/Satoshi:25.0.0/"
+    );
+    assert_eq!(outcome.negotiated_version, PROTOCOL_VERSION);
+
+    let skipped: Vec<_> = outcome
+        .other_messages
+        .iter()
+        .map(|message| command_name(&message.command_raw()))
+        .collect();
+    assert_eq!(
+        skipped,
+        [
+            "wtxidrelay",
+            "sendaddrv2",
+            "sendheaders",
+            "sendcmpct",
+            "ping",
+            "getheaders",
+            "feefilter",
+        ],
+    );
+
+    drop(messaging_system);
+    // Our verack answers the version straight away, and the pong follows once the ping arrives
+    let received = peer.await.unwrap();
+    assert!(matches!(
+        received.as_slice(),
+        [MessageType::Version(_), MessageType::Verack, MessageType::Pong(pong)] if pong.nonce() == 0x1d2c_3b4a_5968_7786,
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_verack_before_version() {
+    let (stream, peer) = responder(vec![
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        peer_version_frame(),
+    ])
+    .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(outcome.peer_version.nonce(), 2);
+    assert!(outcome.other_messages.is_empty());
+
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    assert!(matches!(
+        received.as_slice(),
+        [MessageType::Version(_), MessageType::Verack]
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_duplicate_verack() {
+    let (stream, peer) = responder(vec![
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        peer_version_frame(),
+    ])
+    .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    assert!(matches!(
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await,
+        Err(HandshakeError::UnexpectedMessage(Command::Verack)),
+    ));
+
+    drop(messaging_system);
+    // We never got as far as acknowledging them
+    assert!(matches!(
+        peer.await.unwrap().as_slice(),
+        [MessageType::Version(_)]
+    ));
+}
+
+#[tokio::test]
+async fn test_handshake_peer_never_sends_verack() {
+    let (stream, peer) = responder(vec![peer_version_frame()]).spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    assert!(matches!(
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await,
+        Err(HandshakeError::Receive(
+            MessageReceiveError::ConnectionClosed { buffered: 0 }
+        )),
+    ));
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_handshake_answers_pings_on_either_side_of_verack() {
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send(PingPayload::new(11))
+        .send(VerackPayload)
+        .send(PingPayload::new(12))
+        .wait_for(Command::Pong)
+        .wait_for(Command::Pong)
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert!(matches!(
+        outcome.other_messages.as_slice(),
+        [MessageType::Ping(ping)] if ping.nonce() == 11,
+    ));
+    // After the handshake, answering is up to the caller; the peer hangs up once both are in
+    messaging_system.auto_pong = true;
+    assert!(matches!(
+        messaging_system.receive_message().await,
+        Err(MessageReceiveError::ConnectionClosed { buffered: 0 }),
+    ));
+
+    drop(messaging_system);
+    let pongs: Vec<_> = peer
+        .await
+        .unwrap()
+        .into_iter()
+        .filter_map(|message| match message {
+            MessageType::Pong(pong) => Some(pong.nonce()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(pongs, [11, 12]);
+}
+
+#[tokio::test]
+async fn test_handshake_peer_closes_mid_version() {
+    let version = peer_version_frame();
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send_raw(&version[..40])
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    assert!(matches!(
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await,
+        Err(HandshakeError::Receive(
+            MessageReceiveError::ConnectionClosed { buffered: 40 }
+        )),
+    ));
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_handshake_garbage_instead_of_verack() {
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send_raw(b"HTTP/1.1 400 Bad Request\r\n\r\n".as_slice())
+        .send(VerackPayload)
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    assert!(matches!(
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await,
+        Err(HandshakeError::Receive(MessageReceiveError::Parsing(_))),
+    ));
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_handshake_failures_map_to_exit_codes() {
+    async fn failure(peer: MockPeer) -> HandshakeError {
+        let (stream, peer) = peer.spawn();
+        let mut messaging_system =
+            MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+        let e = messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await
+            .unwrap_err();
+        drop(messaging_system);
+        peer.await.unwrap();
+        e
+    }
+
+    let garbage = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send_raw(b"HTTP/1.1 400 Bad Request\r\n\r\n".as_slice());
+    assert_eq!(
+        failure(garbage).await.exit_code(),
+        exit_code::PROTOCOL_ERROR
+    );
+
+    let obsolete = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(
+            VersionPayload::builder()
+                .version(31000)
+                .nonce(2)
+                .build()
+                .unwrap(),
+        );
+    assert_eq!(
+        failure(obsolete).await.exit_code(),
+        exit_code::PEER_REJECTED
+    );
+
+    let hangs_up = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .close();
+    assert_eq!(failure(hangs_up).await.exit_code(), exit_code::SESSION_IO);
+
+    let idle = HandshakeError::Receive(MessageReceiveError::Timeout {
+        waited: Duration::from_secs(20),
+        buffered: 0,
+    });
+    assert_eq!(idle.exit_code(), exit_code::CONNECTION_FAILED);
+
+    let deadline = HandshakeError::Timeout {
+        step: HandshakeStep::AwaitingVerack,
+        timeout: Duration::from_secs(60),
+    };
+    assert_eq!(deadline.exit_code(), idle.exit_code());
+
+    let refused = HandshakeError::Connect(ConnectError::Io(ErrorKind::ConnectionRefused.into()));
+    assert_eq!(refused.exit_code(), exit_code::CONNECTION_FAILED);
+    assert_eq!(
+        exit_code::describe(refused.exit_code()),
+        "connection failed"
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_failures_record_disconnect_reasons() {
+    async fn closed_after(peer: MockPeer) -> (DisconnectReason, DisconnectReason) {
+        let (stream, peer) = peer.spawn();
+        let mut messaging_system =
+            MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+        let e = messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            messaging_system.disconnect_reason(),
+            Some(e.disconnect_reason())
+        );
+        let report = messaging_system.close(Duration::ZERO).await.unwrap();
+        peer.await.unwrap();
+        (e.disconnect_reason(), report.reason)
+    }
+
+    let mut bad_checksum = peer_version_frame();
+    bad_checksum[20] ^= 0xff;
+    let reasons = closed_after(responder(vec![bad_checksum])).await;
+    assert_eq!(reasons.0, DisconnectReason::ChecksumMismatch);
+    assert_eq!(reasons.1, reasons.0);
+
+    let version = peer_version_frame();
+    let hangs_up = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send_raw(&version[..40])
+        .close();
+    assert_eq!(closed_after(hangs_up).await.1, DisconnectReason::PeerClosed);
+
+    let duplicate_verack = responder(vec![
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+        prepare_message(Network::Mainnet, VerackPayload).unwrap(),
+    ]);
+    let reason = closed_after(duplicate_verack).await.1;
+    assert_eq!(
+        reason,
+        DisconnectReason::ProtocolViolation(Violation::UnexpectedMessage(Command::Verack))
+    );
+    assert_eq!(reason.to_string(), "protocol_violation:unexpected_verack");
+
+    let garbage = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send_raw(b"HTTP/1.1 400 Bad Request\r\n\r\n".as_slice())
+        .close();
+    assert_eq!(
+        closed_after(garbage).await.1,
+        DisconnectReason::ProtocolViolation(Violation::BadMagic)
+    );
+
+    let obsolete = responder(vec![prepare_message(
+        Network::Mainnet,
+        VersionPayload::builder()
+            .version(31000)
+            .nonce(2)
+            .build()
+            .unwrap(),
+    )
+    .unwrap()]);
+    assert_eq!(
+        closed_after(obsolete).await.1,
+        DisconnectReason::Rejected(Rejection::ObsoletePeer)
+    );
+}
+
+#[tokio::test]
+async fn test_session_disconnect_reason_after_handshake() {
+    async fn after_handshake(hang_up: bool) -> DisconnectReason {
+        let peer = MockPeer::new(Network::Mainnet)
+            .wait_for(Command::Version)
+            .send(VersionPayload::builder().nonce(2).build().unwrap())
+            .send(VerackPayload)
+            .wait_for(Command::Verack);
+        let peer = if hang_up {
+            peer.close()
+        } else {
+            peer.wait_for(Command::GetAddr)
+        };
+        let (stream, peer) = peer.spawn();
+        let mut messaging_system =
+            MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+        messaging_system
+            .handshake(&HandshakeConfig::default())
+            .await
+            .unwrap();
+        if hang_up {
+            assert!(messaging_system.receive_message().await.is_err());
+        } else {
+            messaging_system
+                .send_message(Command::GetAddr)
+                .await
+                .unwrap();
+        }
+        let report = messaging_system.close(Duration::ZERO).await.unwrap();
+        peer.await.unwrap();
+        report.reason
+    }
+
+    assert_eq!(after_handshake(false).await, DisconnectReason::Completed);
+    assert_eq!(after_handshake(true).await, DisconnectReason::PeerClosed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_peer_slow_to_verack() {
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .delay(Duration::from_secs(60))
+        .send(VerackPayload)
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+    let config = HandshakeConfig::default().handshake_timeout(Duration::from_secs(5));
+
+    assert!(matches!(
+        messaging_system.handshake(&config).await,
+        Err(HandshakeError::Timeout {
+            step: HandshakeStep::AwaitingVerack,
+            ..
+        }),
+    ));
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_handshake_timings() {
+    async fn timings_with(peer: MockPeer, initiator: bool) -> HandshakeTimings {
+        let (stream, peer) = peer.spawn();
+        let mut messaging_system =
+            MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+        let config = HandshakeConfig::default();
+        let outcome = if initiator {
+            messaging_system.handshake(&config).await
+        } else {
+            messaging_system.accept_handshake(&config).await
+        }
+        .unwrap();
+        drop(messaging_system);
+        peer.await.unwrap();
+        outcome.timings
+    }
+    let ms = Duration::from_millis;
+
+    let in_order = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .delay(ms(70))
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .delay(ms(30))
+        .send(VerackPayload);
+    let timings = timings_with(in_order, true).await;
+    assert_eq!(
+        timings,
+        HandshakeTimings {
+            connect: None,
+            version_rtt: Some(ms(70)),
+            verack: ms(100),
+            total: ms(100),
+        }
+    );
+    assert_eq!(
+        timings.to_string(),
+        "version RTT 70ms, verack 100ms, total 100ms"
+    );
+
+    // The verack overtaking the version changes nothing about when each arrived
+    let verack_first = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .delay(ms(40))
+        .send(VerackPayload)
+        .delay(ms(50))
+        .send(VersionPayload::builder().nonce(2).build().unwrap());
+    assert_eq!(
+        timings_with(verack_first, true).await,
+        HandshakeTimings {
+            connect: None,
+            version_rtt: Some(ms(90)),
+            verack: ms(40),
+            total: ms(90),
+        }
+    );
+
+    // Opened by the peer, whose version comes before ours and so has no round trip to time
+    let opened_by_peer = MockPeer::new(Network::Mainnet)
+        .delay(ms(50))
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .wait_for(Command::Version)
+        .delay(ms(20))
+        .send(VerackPayload);
+    assert_eq!(
+        timings_with(opened_by_peer, false).await,
+        HandshakeTimings {
+            connect: None,
+            version_rtt: None,
+            verack: ms(20),
+            total: ms(70),
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_timings_include_connect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let peer = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut theirs = MessagingSystem::from_stream(stream, address);
+        theirs
+            .accept_handshake(&HandshakeConfig::default())
+            .await
+            .unwrap();
+    });
+
+    let mut messaging_system = MessagingSystem::try_new(address).await.unwrap();
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(outcome.timings.connect, messaging_system.connect_time());
+    assert!(outcome.timings.connect.is_some());
+    assert!(outcome.timings.to_string().starts_with(&format!(
+        "connect {:.0?}, ",
+        outcome.timings.connect.unwrap()
+    )));
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_handshake_deadline_while_peer_trickles_messages() {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let responder = tokio::spawn(async move {
+        let mut stream = theirs;
+        stream.write_all(&peer_version_frame()).await.unwrap();
+        // Never a verack, but never quiet long enough for a read timeout either
+        while stream.write_all(&sendheaders_frame()).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+
+    let mut messaging_system =
+        MessagingSystem::from_stream(ours, "192.0.2.2:8333".parse().unwrap());
+    let config = HandshakeConfig::default().handshake_timeout(Duration::from_millis(300));
+
+    let started = Instant::now();
+    let result = messaging_system.handshake(&config).await;
+    let elapsed = started.elapsed();
+    assert!(matches!(
+        result,
+        Err(HandshakeError::Timeout {
+            step: HandshakeStep::AwaitingVerack,
+            timeout,
+        }) if timeout == Duration::from_millis(300),
+    ));
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+
+    drop(messaging_system);
+    responder.await.unwrap();
+}
+
+fn candidates(count: u8) -> Vec<SocketAddr> {
+    (1..=count)
+        .map(|i| SocketAddr::from(([192, 0, 2, i], 8333)))
+        .collect()
+}
+
+// Refuses the first candidate, hangs up on the second after its version, and completes the
+// handshake with the third and any after it
+async fn scripted_connector(
+    candidate: SocketAddr,
+) -> Result<MessagingSystem<DuplexStream>, ConnectError> {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    match candidate.ip().to_string().as_str() {
+        "192.0.2.1" => return Err(std::io::Error::from(ErrorKind::ConnectionRefused).into()),
+        "192.0.2.2" => {
+            tokio::spawn(async move {
+                let mut theirs = theirs;
+                theirs.write_all(&peer_version_frame()).await.unwrap();
+            });
+        }
+        _ => {
+            tokio::spawn(async move {
+                let mut theirs = MessagingSystem::from_stream(theirs, candidate);
+                theirs.handshake(&HandshakeConfig::default()).await.unwrap();
+            });
+        }
+    }
+    Ok(MessagingSystem::from_stream(ours, candidate))
+}
+
+#[tokio::test]
+async fn test_handshake_any_stops_at_first_success() {
+    let mut tried = Vec::new();
+    let (candidate, _, outcome) =
+        handshake_any(candidates(4), &HandshakeConfig::default(), |candidate| {
+            tried.push(candidate);
+            scripted_connector(candidate)
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(candidate, candidates(3)[2]);
+    assert_eq!(tried, candidates(3));
+    assert_eq!(outcome.negotiated_version, PROTOCOL_VERSION);
+}
+
+#[tokio::test]
+async fn test_handshake_any_all_candidates_fail() {
+    let result = handshake_any(
+        candidates(2),
+        &HandshakeConfig::default(),
+        scripted_connector,
+    )
+    .await;
+    assert!(matches!(result, Err(SeedError::AllAttemptsFailed(2))));
+
+    let result = handshake_any(Vec::new(), &HandshakeConfig::default(), scripted_connector).await;
+    assert!(matches!(result, Err(SeedError::NoCandidates)));
+}
+
+#[tokio::test]
+async fn test_outbound_handshake_against_listener() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listen_address = listener.local_addr().unwrap();
+
+    // Answers three connections in turn, carrying on past the one that fails
+    let server = tokio::spawn(async move {
+        let mut user_agents = Vec::new();
+        for _ in 0..3 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut messaging_system = MessagingSystem::try_from_accepted(stream).unwrap();
+            let config = HandshakeConfig::default().user_agent("/listener/");
+            let user_agent = messaging_system
+                .accept_handshake(&config)
+                .await
+                .map(|outcome| outcome.peer_version.user_agent().unwrap().to_owned());
+            user_agents.push(user_agent);
+        }
+        user_agents
+    });
+
+    for client in 0..3 {
+        if client == 1 {
+            let mut stream = TcpStream::connect(listen_address).await.unwrap();
+            stream.write_all(&[0; 24]).await.unwrap();
+            continue;
+        }
+        let mut messaging_system = MessagingSystem::try_new(listen_address).await.unwrap();
+        let config = HandshakeConfig::default().user_agent(&format!("/client:{client}/"));
+        let outcome = messaging_system.handshake(&config).await.unwrap();
+        assert_eq!(outcome.peer_version.user_agent().unwrap(), "/listener/");
+        assert_eq!(messaging_system.remote_addr(), listen_address);
+    }
+
+    let user_agents = server.await.unwrap();
+    assert!(matches!(&user_agents[0], Ok(user_agent) if user_agent == "/client:0/"));
+    assert!(matches!(
+        &user_agents[1],
+        Err(HandshakeError::Receive(MessageReceiveError::Parsing(_))),
+    ));
+    assert!(matches!(&user_agents[2], Ok(user_agent) if user_agent == "/client:2/"));
+}
+
+#[tokio::test]
+async fn test_accept_handshake_rejects_verack_first() {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let mut messaging_system =
+        MessagingSystem::from_stream(ours, "192.0.2.2:8333".parse().unwrap());
+    let mut theirs = theirs;
+    theirs
+        .write_all(&prepare_message(Network::Mainnet, VerackPayload).unwrap())
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        messaging_system
+            .accept_handshake(&HandshakeConfig::default())
+            .await,
+        Err(HandshakeError::UnexpectedMessage(Command::Verack)),
+    ));
+}
+
+#[tokio::test]
+async fn test_stats_count_every_frame() {
+    let mut bad_checksum = raw_frame(b"ping\0\0\0\0\0\0\0\0", &7u64.to_le_bytes());
+    bad_checksum[20] ^= 0xFF;
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send_raw(sendheaders_frame())
+        .send(VerackPayload)
+        .wait_for(Command::Verack)
+        .send_raw(raw_frame(b"sendheaders\0", &[]))
+        .send_raw(bad_checksum)
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+    messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    assert!(matches!(
+        messaging_system.receive_message().await,
+        Ok(MessageType::Unknown { .. }),
+    ));
+    assert!(matches!(
+        messaging_system.receive_message().await,
+        Err(MessageReceiveError::Parsing(_)),
+    ));
+
+    let stats = messaging_system.stats();
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    let MessageType::Version(our_version) = &received[0] else {
+        panic!("expected our version first");
+    };
+    let version_len = peer_version_frame().len() - Header::HEADER_BYTE_SIZE;
+
+    let commands: Vec<_> = stats
+        .received
+        .commands
+        .iter()
+        .map(|(command, counted)| (command.as_str(), counted.messages, counted.bytes))
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            ("version", 1, 24 + version_len),
+            ("sendheaders", 2, 48),
+            ("verack", 1, 24),
+            ("ping", 1, 32),
+        ],
+    );
+    assert_eq!(stats.received.messages, 5);
+    assert_eq!(stats.received.bytes, 24 * 5 + version_len + 8);
+    assert_eq!(stats.unknown_commands, 2);
+    assert_eq!(stats.checksum_failures, 1);
+    assert_eq!(stats.sent.messages, 2);
+    assert_eq!(stats.sent.bytes, 24 * 2 + our_version.size_hint());
+    assert_eq!(
+        stats.largest_payload,
+        version_len.max(our_version.size_hint())
+    );
+}
+
+#[tokio::test]
+async fn test_handshake_interrupted_midway_shuts_down() {
+    // Takes our version, then never answers
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+    let cancel = messaging_system.cancel.clone();
+    let config = HandshakeConfig::default();
+
+    let (result, ()) = tokio::join!(messaging_system.handshake(&config), async {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        cancel.cancel();
+    },);
+    let e = result.unwrap_err();
+    assert!(e.is_interrupted(), "{e:?}");
+    assert_eq!(e.kind(), "interrupted");
+    assert_eq!(e.exit_code(), exit_code::INTERRUPTED);
+
+    // The peer sees our side shut down, with the connection still open
+    let received = peer.await.unwrap();
+    assert!(
+        matches!(received[..], [MessageType::Version(_)]),
+        "{received:?}"
+    );
+    drop(messaging_system);
+}
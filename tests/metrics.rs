@@ -0,0 +1,80 @@
+#![cfg(feature = "metrics")]
+
+use std::sync::Arc;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use bitcoin_handshake::{
+    metrics::{serve, Metrics},
+    network::Network,
+    testing::MockPeer,
+    Command, HandshakeConfig, MessagingSystem, PingPayload, VerackPayload, VersionPayload,
+};
+
+async fn get(address: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint_after_mock_session() {
+    let (stream, peer) = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send(VerackPayload)
+        .wait_for(Command::Verack)
+        .send(PingPayload::new(7))
+        .close()
+        .spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+
+    let metrics = Arc::new(Metrics::new());
+    metrics.connection_opened();
+    let outcome = messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    metrics.record_handshake(Ok(outcome.timings.total));
+    messaging_system.receive_message().await.unwrap();
+    metrics.record_session(&messaging_system.stats());
+    drop(messaging_system);
+    metrics.connection_closed();
+    peer.await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, metrics));
+
+    let response = get(address, "/metrics").await;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+    assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+    for line in [
+        "bitcoin_handshake_handshake_attempts_total 1",
+        "bitcoin_handshake_handshake_successes_total 1",
+        "bitcoin_handshake_active_connections 0",
+        "bitcoin_handshake_messages_total{direction=\"received\",command=\"ping\"} 1",
+        "bitcoin_handshake_messages_total{direction=\"sent\",command=\"version\"} 1",
+        "bitcoin_handshake_bytes_total{direction=\"received\",command=\"verack\"} 24",
+        "bitcoin_handshake_handshake_duration_seconds_count 1",
+    ] {
+        assert!(
+            body.lines().any(|l| l == line),
+            "missing {line:?} in\n{body}"
+        );
+    }
+
+    assert!(get(address, "/other")
+        .await
+        .starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
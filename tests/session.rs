@@ -0,0 +1,263 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use tokio::{io::DuplexStream, time::Instant};
+
+use bitcoin_handshake::{
+    addr_payload::{AddrPayload, AddrV2Entry, AddrV2Host, AddrV2Payload, TimestampedAddress},
+    command::command_name,
+    network::Network,
+    services::ServiceFlags,
+    session::{collect_addresses, stay_connected, SessionEnd},
+    testing::MockPeer,
+    version_payload::NetworkAddress,
+    Command, HandshakeConfig, MessageReceiveError, MessageType, MessagingSystem, PingPayload,
+    VerackPayload, VersionPayload,
+};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+// Completes the handshake, then pings us once, sends an unsolicited pong a minute in, answers
+// our pings, and logs what it received
+async fn scripted_peer() -> (
+    MessagingSystem<DuplexStream>,
+    tokio::task::JoinHandle<Vec<MessageType>>,
+) {
+    let (ours, theirs) = tokio::io::duplex(64 * 1024);
+    let mut ours = MessagingSystem::from_stream(ours, "192.0.2.2:8333".parse().unwrap());
+    let mut theirs = MessagingSystem::from_stream(theirs, "192.0.2.1:8333".parse().unwrap());
+    let config = HandshakeConfig::default();
+    let (our_outcome, their_outcome) =
+        tokio::join!(ours.handshake(&config), theirs.handshake(&config));
+    our_outcome.unwrap();
+    their_outcome.unwrap();
+
+    let peer = tokio::spawn(async move {
+        theirs.send_ping(5).await.unwrap();
+
+        let mut received = Vec::new();
+        let unsolicited = tokio::time::sleep(MINUTE);
+        tokio::pin!(unsolicited);
+        let mut sent_unsolicited = false;
+        loop {
+            tokio::select! {
+                _ = &mut unsolicited, if !sent_unsolicited => {
+                    theirs.send_pong(99).await.unwrap();
+                    sent_unsolicited = true;
+                }
+                message = theirs.receive_message() => match message {
+                    Ok(MessageType::Ping(ping_payload)) => {
+                        theirs.send_pong(ping_payload.nonce()).await.unwrap();
+                        received.push(MessageType::Ping(ping_payload));
+                    }
+                    Ok(message) => received.push(message),
+                    Err(MessageReceiveError::Timeout { .. }) => {}
+                    Err(_) => break,
+                },
+            }
+        }
+        received
+    });
+    (ours, peer)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stay_connected_until_deadline() {
+    let (mut messaging_system, peer) = scripted_peer().await;
+
+    let started = Instant::now();
+    let mut logged = Vec::new();
+    let summary = stay_connected(
+        &mut messaging_system,
+        9 * MINUTE,
+        2 * MINUTE,
+        std::future::pending(),
+        |parsed| logged.push(command_name(&parsed.header().command_raw())),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(summary.end, SessionEnd::Deadline);
+    assert_eq!(summary.elapsed, 9 * MINUTE);
+    assert_eq!(started.elapsed(), 9 * MINUTE);
+    assert_eq!(summary.pings_sent, 4);
+    assert_eq!(
+        summary.received.into_iter().collect::<Vec<_>>(),
+        [("ping".to_owned(), 1), ("pong".to_owned(), 5)],
+    );
+    assert_eq!(logged, ["ping", "pong", "pong", "pong", "pong", "pong"]);
+
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    assert_eq!(received.len(), 5);
+    assert!(matches!(received[0], MessageType::Pong(pong) if pong.nonce() == 5));
+    assert!(received[1..]
+        .iter()
+        .all(|message| matches!(message, MessageType::Ping(_))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_stay_connected_interrupted() {
+    let (mut messaging_system, peer) = scripted_peer().await;
+
+    let interrupted = tokio::time::sleep(3 * MINUTE);
+    let summary = stay_connected(
+        &mut messaging_system,
+        9 * MINUTE,
+        2 * MINUTE,
+        interrupted,
+        |_| (),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(summary.end, SessionEnd::Interrupted);
+    assert_eq!(summary.elapsed, 3 * MINUTE);
+    assert_eq!(summary.pings_sent, 1);
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}
+
+// Completes the handshake, then answers getaddr as `script` says
+async fn addr_peer(
+    script: impl FnOnce(MockPeer) -> MockPeer,
+) -> (
+    MessagingSystem<DuplexStream>,
+    tokio::task::JoinHandle<Vec<MessageType>>,
+) {
+    let peer = MockPeer::new(Network::Mainnet)
+        .wait_for(Command::Version)
+        .send(VersionPayload::builder().nonce(2).build().unwrap())
+        .send(VerackPayload)
+        .wait_for(Command::GetAddr);
+    let (stream, peer) = script(peer).spawn();
+    let mut messaging_system =
+        MessagingSystem::from_stream(stream, "192.0.2.2:8333".parse().unwrap());
+    messaging_system
+        .handshake(&HandshakeConfig::default())
+        .await
+        .unwrap();
+    (messaging_system, peer)
+}
+
+fn addr(socket_address: &str, unix_time: u64) -> TimestampedAddress {
+    TimestampedAddress::new(
+        UNIX_EPOCH + Duration::from_secs(unix_time),
+        NetworkAddress::new(socket_address.parse().unwrap(), ServiceFlags::NODE_NETWORK),
+    )
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_collect_addresses_across_batches() {
+    let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"
+        .parse()
+        .unwrap();
+    let (mut messaging_system, peer) = addr_peer(|peer| {
+        peer.send(AddrPayload::new(vec![
+            addr("198.51.100.1:8333", 1_700_000_000),
+            addr("[2001:db8::1]:8333", 1_700_000_100),
+        ]))
+        .send(PingPayload::new(21))
+        .delay(Duration::from_secs(2))
+        .send(AddrV2Payload::new(vec![
+            // Heard of again, more recently
+            AddrV2Entry::new(
+                UNIX_EPOCH + Duration::from_secs(1_700_000_500),
+                &AddrV2Host::Ip("198.51.100.1".parse().unwrap()),
+                8333,
+                ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+            ),
+            AddrV2Entry::new(
+                UNIX_EPOCH + Duration::from_secs(1_700_000_200),
+                &AddrV2Host::TorV3(onion),
+                8333,
+                ServiceFlags::NODE_NETWORK,
+            ),
+        ]))
+        .send(PingPayload::new(22))
+    })
+    .await;
+
+    let started = Instant::now();
+    let addresses = collect_addresses(&mut messaging_system, Duration::from_secs(5))
+        .await
+        .unwrap();
+    assert_eq!(started.elapsed(), Duration::from_secs(5));
+
+    let listing: Vec<_> = addresses
+        .iter()
+        .map(|address| {
+            (
+                address.to_string(),
+                address.services,
+                address
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        listing,
+        [
+            (
+                "198.51.100.1:8333".to_owned(),
+                ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS,
+                1_700_000_500,
+            ),
+            (
+                "[2001:db8::1]:8333".to_owned(),
+                ServiceFlags::NODE_NETWORK,
+                1_700_000_100,
+            ),
+            (
+                "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion:8333".to_owned(),
+                ServiceFlags::NODE_NETWORK,
+                1_700_000_200,
+            ),
+        ],
+    );
+    assert_eq!(
+        addresses[1].socket_address(),
+        Some("[2001:db8::1]:8333".parse().unwrap())
+    );
+    assert_eq!(addresses[2].socket_address(), None);
+
+    drop(messaging_system);
+    let received = peer.await.unwrap();
+    let pongs: Vec<_> = received
+        .iter()
+        .filter_map(|message| match message {
+            MessageType::Pong(pong_payload) => Some(pong_payload.nonce()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(pongs, [21, 22]);
+    assert!(received
+        .iter()
+        .any(|message| matches!(message, MessageType::GetAddr)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_collect_addresses_until_peer_hangs_up() {
+    let (mut messaging_system, peer) = addr_peer(|peer| {
+        peer.send(AddrPayload::new(vec![addr(
+            "203.0.113.9:18333",
+            1_700_000_000,
+        )]))
+        .close()
+    })
+    .await;
+
+    let started = Instant::now();
+    let addresses = collect_addresses(&mut messaging_system, Duration::from_secs(30))
+        .await
+        .unwrap();
+    assert!(started.elapsed() < Duration::from_secs(30));
+    assert_eq!(addresses.len(), 1);
+    assert_eq!(addresses[0].to_string(), "203.0.113.9:18333");
+
+    drop(messaging_system);
+    peer.await.unwrap();
+}